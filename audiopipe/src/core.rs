@@ -1,7 +1,8 @@
 use std::cmp::min;
+use std::fmt::{self, Write};
 use std::io;
 use std::pin::Pin;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::{Arc, Mutex, Weak};
 use std::task::{Context, Poll, Waker};
 use std::time::Duration;
@@ -11,9 +12,11 @@ use dasp::{Frame, Signal};
 use dasp_graph::{process, BoxedNodeSend, Buffer, Input, NodeData};
 use futures::Sink;
 use log::warn;
+use petgraph::algo::has_path_connecting;
 use petgraph::graph::NodeIndex;
 use petgraph::Direction;
 
+use crate::extra::NormalizerNode;
 use crate::streamio::StreamWrite;
 
 // Choose a type of graph for audio processing.
@@ -26,29 +29,109 @@ enum Node {
     NoOp,
     Input { node: InputNode, channels: u8 },
     Output { node: OutputNode, channels: u8 },
+    Signal(SignalNode),
     Boxed(BoxedNodeSend),
 }
 
 impl dasp_graph::Node for Node {
     fn process(&mut self, inputs: &[Input], output: &mut [Buffer]) {
         match self {
-            Node::NoOp => {}
+            Node::NoOp => {
+                // pass-through, mixes all inputs into the output buffers (if
+                // there are any, the bottom node has none)
+                output.iter_mut().for_each(|b| b.silence());
+
+                for input in inputs.iter() {
+                    for (out, buffer) in output.iter_mut().zip(input.buffers()) {
+                        for (o, sample) in out.iter_mut().zip(buffer.iter()) {
+                            *o += *sample;
+                        }
+                    }
+                }
+            }
             Node::Input { node, .. } => node.process(inputs, output),
             Node::Output { node, .. } => node.process(inputs, output),
+            Node::Signal(node) => node.process(inputs, output),
             Node::Boxed(n) => n.process(inputs, output),
         }
     }
 }
 
+/// Sizes of the ring buffers which carry audio into and out of the graph, in
+/// frames. Larger buffers make playback more robust against hiccups, smaller
+/// ones reduce latency.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct BufferConfig {
+    pub input: usize,
+    pub output: usize,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        BufferConfig {
+            input: 512,
+            output: 8192,
+        }
+    }
+}
+
+/// Estimated latency of the audio graph, calculated from how much audio is
+/// currently waiting in its buffers.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Latency {
+    /// Time between two ticks of the graph.
+    pub tick: Duration,
+    /// Audio waiting in the fullest input buffer.
+    pub input: Duration,
+    pub input_capacity: Duration,
+    /// Audio waiting in the fullest output buffer.
+    pub output: Duration,
+    pub output_capacity: Duration,
+}
+
+impl Latency {
+    pub fn total(&self) -> Duration {
+        self.input + self.tick + self.output
+    }
+}
+
+/// What kind of node a node in the audio graph is, see [`NodeInfo`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum NodeKind {
+    /// Mixes its inputs together.
+    Mix,
+    /// Plays audio from an [`AudioSource`].
+    Input,
+    /// Collects audio for an [`OutputSignal`].
+    Output,
+    Signal,
+    Other,
+}
+
+/// The state of a single node of the audio graph, see [`Core::node_info`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NodeInfo {
+    pub kind: NodeKind,
+    /// Whether the node produces audio. Only inputs can be stopped, or be
+    /// dropped while still in the graph.
+    pub running: bool,
+    /// Frames waiting in the node's buffer. Always 0 for nodes without one.
+    pub buffered: usize,
+    pub capacity: usize,
+    /// The nodes this node plays into.
+    pub outputs: Vec<NodeIndex>,
+}
+
 struct CoreData {
     graph: Graph,
     processor: Processor,
     bottom: NodeIndex,
     default_output: Option<NodeIndex>,
+    buffers: BufferConfig,
 }
 
 impl CoreData {
-    fn new() -> Self {
+    fn new(buffers: BufferConfig) -> Self {
         let mut graph = Graph::with_capacity(10, 10);
         let processor = Processor::with_capacity(10);
 
@@ -59,6 +142,7 @@ impl CoreData {
             processor,
             bottom,
             default_output: None,
+            buffers,
         }
     }
 
@@ -75,8 +159,9 @@ impl CoreData {
     fn add_input_to(&mut self, output: Option<NodeIndex>) -> AudioSource {
         let shared = Arc::new(AudioSourceShared {
             running: AtomicBool::new(false),
+            gain: AtomicU32::new(1.0f32.to_bits()),
             data: Mutex::new(AudioSourceShared1 {
-                buffer: Bounded::from(vec![[0.0; 2]; 512]),
+                buffer: Bounded::from(vec![[0.0; 2]; self.buffers.input]),
                 write_waker: None,
             }),
         });
@@ -98,9 +183,35 @@ impl CoreData {
         AudioSource { shared, node }
     }
 
+    fn add_signal<S>(&mut self, signal: S, output: Option<NodeIndex>) -> NodeIndex
+    where
+        S: Signal<Frame = [f32; 2]> + Send + 'static,
+    {
+        let node = self.graph.add_node(NodeData::new(
+            Node::Signal(SignalNode(Box::new(signal))),
+            vec![Buffer::default(); 2],
+        ));
+
+        if let Some(output) = output {
+            self.graph.add_edge(node, output, ());
+        }
+
+        node
+    }
+
     fn add_output(&mut self) -> OutputSignal {
+        let output = self.add_output_node();
+
+        if self.default_output.is_none() {
+            self.default_output = Some(output.node);
+        }
+
+        output
+    }
+
+    fn add_output_node(&mut self) -> OutputSignal {
         let shared = Arc::new(Mutex::new(OutputNodeShared {
-            buffer: Bounded::from(vec![[0.0; 2]; 8192]),
+            buffer: Bounded::from(vec![[0.0; 2]; self.buffers.output]),
         }));
 
         let node = self.graph.add_node(NodeData::new(
@@ -115,11 +226,83 @@ impl CoreData {
 
         self.graph.add_edge(node, self.bottom, ());
 
-        if self.default_output.is_none() {
-            self.default_output = Some(node);
+        OutputSignal { shared, node }
+    }
+
+    fn clone_signal(&mut self, source: NodeIndex) -> OutputSignal {
+        let fork = self
+            .graph
+            .add_node(NodeData::new(Node::NoOp, vec![Buffer::default(); 2]));
+
+        let targets: Vec<_> = self
+            .graph
+            .neighbors_directed(source, Direction::Outgoing)
+            .collect();
+
+        for target in targets {
+            while let Some(edge) = self.graph.find_edge(source, target) {
+                self.graph.remove_edge(edge);
+            }
+
+            self.graph.add_edge(fork, target, ());
         }
 
-        OutputSignal { shared, node }
+        self.graph.add_edge(source, fork, ());
+
+        let output = self.add_output_node();
+        self.graph.add_edge(fork, output.node, ());
+        output
+    }
+
+    fn add_normalizer(&mut self, source: NodeIndex, window_samples: usize) -> NodeIndex {
+        let normalizer = self.add_node(NodeData::new(
+            NormalizerNode::new(window_samples, NormalizerNode::DEFAULT_HEADROOM_DB),
+            vec![Buffer::default(); 2],
+        ));
+
+        let targets: Vec<_> = self
+            .graph
+            .neighbors_directed(source, Direction::Outgoing)
+            .collect();
+
+        for target in targets {
+            while let Some(edge) = self.graph.find_edge(source, target) {
+                self.graph.remove_edge(edge);
+            }
+
+            self.graph.add_edge(normalizer, target, ());
+        }
+
+        self.graph.add_edge(source, normalizer, ());
+
+        normalizer
+    }
+
+    fn disconnect_node(&mut self, node: NodeIndex) {
+        for dir in [Direction::Outgoing, Direction::Incoming] {
+            // removing an edge moves another one into its index, so look up
+            // the first remaining one each time
+            while let Some(edge) = self.graph.first_edge(node, dir) {
+                self.graph.remove_edge(edge);
+            }
+        }
+    }
+
+    fn set_default_output(&mut self, node: NodeIndex) {
+        self.default_output = Some(node);
+    }
+
+    fn reconnect_node_to_default_output(&mut self, node: NodeIndex) -> bool {
+        let output = match self.default_output {
+            None => return false,
+            Some(output) => output,
+        };
+
+        if self.graph.find_edge(node, output).is_none() {
+            self.graph.add_edge(node, output, ());
+        }
+
+        true
     }
 
     fn tick(&mut self) {
@@ -128,12 +311,141 @@ impl CoreData {
             Node::NoOp => true,
             Node::Input { node, .. } => node.shared.strong_count() > 0,
             Node::Output { .. } => true,
+            Node::Signal(node) => !node.0.is_exhausted(),
             Node::Boxed(_) => true,
         });
 
         process(&mut self.processor, &mut self.graph, self.bottom);
     }
 
+    fn latency(&self, sample_rate: u32) -> Latency {
+        let to_duration =
+            |frames: usize| Duration::from_secs_f64(frames as f64 / sample_rate as f64);
+
+        let mut input = 0;
+        let mut output = 0;
+
+        for idx in self.graph.node_indices() {
+            match &self.graph[idx].node {
+                Node::Input { node, .. } => {
+                    if let Some(shared) = node.shared.upgrade() {
+                        input = input.max(shared.data.lock().unwrap().buffer.len());
+                    }
+                }
+                Node::Output { node, .. } => {
+                    output = output.max(node.shared.lock().unwrap().buffer.len());
+                }
+                _ => {}
+            }
+        }
+
+        Latency {
+            tick: to_duration(Buffer::LEN),
+            input: to_duration(input),
+            input_capacity: to_duration(self.buffers.input),
+            output: to_duration(output),
+            output_capacity: to_duration(self.buffers.output),
+        }
+    }
+
+    fn dump_graph(&self) -> String {
+        let mut s = String::from("digraph {\n");
+
+        for idx in self.graph.node_indices() {
+            let mut label = match &self.graph[idx].node {
+                Node::NoOp if idx == self.bottom => "bottom".to_string(),
+                Node::NoOp => "mix".to_string(),
+                Node::Input { node, .. } => match node.shared.upgrade() {
+                    None => "input (dropped)".to_string(),
+                    Some(shared) => format!(
+                        "input ({}, {} buffered)",
+                        if shared.running.load(Ordering::Relaxed) {
+                            "running"
+                        } else {
+                            "stopped"
+                        },
+                        shared.data.lock().unwrap().buffer.len()
+                    ),
+                },
+                Node::Output { node, .. } => format!(
+                    "output ({} buffered)",
+                    node.shared.lock().unwrap().buffer.len()
+                ),
+                Node::Signal(_) => "signal".to_string(),
+                Node::Boxed(_) => "boxed".to_string(),
+            };
+
+            if Some(idx) == self.default_output {
+                label.push_str(", default");
+            }
+
+            writeln!(
+                s,
+                "    {} [label=\"{}: {}\"];",
+                idx.index(),
+                idx.index(),
+                label
+            )
+            .unwrap();
+        }
+
+        for edge in self.graph.edge_indices() {
+            let (from, to) = self.graph.edge_endpoints(edge).unwrap();
+            writeln!(s, "    {} -> {};", from.index(), to.index()).unwrap();
+        }
+
+        s.push('}');
+        s
+    }
+
+    fn node_info(&self, node: NodeIndex) -> Option<NodeInfo> {
+        let data = self.graph.node_weight(node)?;
+
+        let (kind, running, buffered, capacity) = match &data.node {
+            Node::NoOp => (NodeKind::Mix, true, 0, 0),
+            Node::Input { node, .. } => match node.shared.upgrade() {
+                None => (NodeKind::Input, false, 0, 0),
+                Some(shared) => {
+                    let data = shared.data.lock().unwrap();
+                    (
+                        NodeKind::Input,
+                        shared.running.load(Ordering::Relaxed),
+                        data.buffer.len(),
+                        data.buffer.max_len(),
+                    )
+                }
+            },
+            Node::Output { node, .. } => {
+                let shared = node.shared.lock().unwrap();
+                (
+                    NodeKind::Output,
+                    true,
+                    shared.buffer.len(),
+                    shared.buffer.max_len(),
+                )
+            }
+            Node::Signal(_) => (NodeKind::Signal, true, 0, 0),
+            Node::Boxed(_) => (NodeKind::Other, true, 0, 0),
+        };
+
+        Some(NodeInfo {
+            kind,
+            running,
+            buffered,
+            capacity,
+            outputs: self
+                .graph
+                .neighbors_directed(node, Direction::Outgoing)
+                .collect(),
+        })
+    }
+
+    fn is_connected(&self, from: NodeIndex, to: NodeIndex) -> bool {
+        self.graph.node_weight(from).is_some()
+            && self.graph.node_weight(to).is_some()
+            && has_path_connecting(&self.graph, from, to, None)
+    }
+
     fn sinks(&self) -> impl Iterator<Item = NodeIndex> + '_ {
         self.graph
             .neighbors_directed(self.bottom, Direction::Incoming)
@@ -148,7 +460,11 @@ pub struct Core {
 
 impl Core {
     pub fn new(sample_rate: u32) -> Self {
-        let data = Arc::new(Mutex::new(CoreData::new()));
+        Core::with_buffers(sample_rate, BufferConfig::default())
+    }
+
+    pub fn with_buffers(sample_rate: u32, buffers: BufferConfig) -> Self {
+        let data = Arc::new(Mutex::new(CoreData::new(buffers)));
         let c = Core { data, sample_rate };
         tokio::spawn(c.clone().run());
         c
@@ -164,10 +480,92 @@ impl Core {
         self.data.lock().unwrap().add_input_to(output)
     }
 
+    /// Plays `signal` into `output`, or leaves it unconnected if it is
+    /// `None`. The node is removed once the signal is exhausted.
+    pub fn add_signal<S>(&self, signal: S, output: Option<NodeIndex>) -> NodeIndex
+    where
+        S: Signal<Frame = [f32; 2]> + Send + 'static,
+    {
+        self.data.lock().unwrap().add_signal(signal, output)
+    }
+
     pub fn add_output(&self) -> OutputSignal {
         self.data.lock().unwrap().add_output()
     }
 
+    /// Forks the audio produced by `source` into a new output. A pass-through
+    /// node is inserted between `source` and everything it was connected to,
+    /// so the original signal keeps playing unchanged.
+    ///
+    /// `source` needs to be a node that produces audio, such as an
+    /// [`AudioSource`]. Output nodes don't pass on their input.
+    pub fn clone_signal(&self, source: NodeIndex) -> OutputSignal {
+        self.data.lock().unwrap().clone_signal(source)
+    }
+
+    /// Inserts a [`NormalizerNode`] between `source` and everything it plays
+    /// into, which normalizes the peak level over the last `window_ms`
+    /// milliseconds. Returns the new node.
+    pub fn add_normalizer(&self, source: NodeIndex, window_ms: u32) -> NodeIndex {
+        let window_samples = self.sample_rate as usize * window_ms as usize / 1000;
+        self.data
+            .lock()
+            .unwrap()
+            .add_normalizer(source, window_samples)
+    }
+
+    /// Makes `node` the node that inputs added with [`Core::add_input`] play
+    /// into, instead of the first output. Inputs that exist already stay
+    /// connected to where they are.
+    pub fn set_default_output(&self, node: NodeIndex) {
+        self.data.lock().unwrap().set_default_output(node)
+    }
+
+    /// Removes all connections from and to `node`, so that it keeps its state
+    /// but doesn't play into anything anymore. Use
+    /// [`Core::reconnect_node_to_default_output`] to connect it again.
+    pub fn disconnect_node(&self, node: NodeIndex) {
+        self.data.lock().unwrap().disconnect_node(node)
+    }
+
+    /// Connects `node` to the default output, like [`Core::add_input`] does
+    /// for new inputs. Returns `false` if there is no default output yet.
+    pub fn reconnect_node_to_default_output(&self, node: NodeIndex) -> bool {
+        self.data
+            .lock()
+            .unwrap()
+            .reconnect_node_to_default_output(node)
+    }
+
+    /// Returns an estimate of the latency the graph currently adds, from
+    /// pushing audio into an [`AudioSource`] until it can be read from an
+    /// [`OutputSignal`].
+    pub fn latency(&self) -> Latency {
+        self.data.lock().unwrap().latency(self.sample_rate)
+    }
+
+    /// Describes the nodes and connections of the audio graph in Graphviz DOT
+    /// format, for finding out why some audio isn't where it should be.
+    pub fn dump_graph(&self) -> String {
+        self.data.lock().unwrap().dump_graph()
+    }
+
+    /// Returns the current state of `node`, or `None` if it isn't part of
+    /// the graph (anymore).
+    pub fn node_info(&self, node: NodeIndex) -> Option<NodeInfo> {
+        self.data.lock().unwrap().node_info(node)
+    }
+
+    /// Returns whether audio from `from` ends up in `to`, directly or
+    /// through other nodes.
+    pub fn is_connected(&self, from: NodeIndex, to: NodeIndex) -> bool {
+        self.data.lock().unwrap().is_connected(from, to)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
     async fn run(self) {
         let mut interval = tokio::time::interval(Duration::from_secs_f64(
             Buffer::LEN as f64 / self.sample_rate as f64,
@@ -187,6 +585,8 @@ type SampleBuffer = Bounded<Vec<[f32; 2]>>;
 #[derive(Debug)]
 struct AudioSourceShared {
     running: AtomicBool,
+    /// Bits of the `f32` every sample is multiplied with.
+    gain: AtomicU32,
     data: Mutex<AudioSourceShared1>,
 }
 
@@ -211,6 +611,22 @@ impl AudioSource {
         self.shared.running.load(Ordering::Relaxed)
     }
 
+    /// Sets the factor the samples from this source are multiplied with
+    /// before they are mixed with other audio. Defaults to 1.
+    pub fn set_gain(&self, gain: f32) {
+        self.shared.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn gain(&self) -> f32 {
+        f32::from_bits(self.shared.gain.load(Ordering::Relaxed))
+    }
+
+    /// Returns how many frames fit into the buffer of this source.
+    pub fn capacity(&self) -> usize {
+        let data = self.shared.data.lock().unwrap();
+        data.buffer.max_len()
+    }
+
     pub fn push(&self, sample: [f32; 2]) -> Option<[f32; 2]> {
         let mut data = self.shared.data.lock().unwrap();
         data.buffer.push(sample)
@@ -296,6 +712,7 @@ impl dasp_graph::Node for InputNode {
         };
 
         if shared.running.load(Ordering::Relaxed) {
+            let gain = f32::from_bits(shared.gain.load(Ordering::Relaxed));
             let mut data = shared.data.lock().unwrap();
             let mut underflow = 0;
 
@@ -309,7 +726,7 @@ impl dasp_graph::Node for InputNode {
                 };
 
                 for ch in 0..2 {
-                    output[ch][i] = sample[ch];
+                    output[ch][i] = sample[ch] * gain;
                 }
             }
 
@@ -326,6 +743,26 @@ impl dasp_graph::Node for InputNode {
     }
 }
 
+struct SignalNode(Box<dyn Signal<Frame = [f32; 2]> + Send>);
+
+impl fmt::Debug for SignalNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignalNode").finish_non_exhaustive()
+    }
+}
+
+impl dasp_graph::Node for SignalNode {
+    fn process(&mut self, _inputs: &[Input], output: &mut [Buffer]) {
+        for i in 0..Buffer::LEN {
+            let frame = self.0.next();
+
+            for ch in 0..2 {
+                output[ch][i] = frame[ch];
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 struct OutputNodeShared {
     buffer: Bounded<Vec<[f32; 2]>>,
@@ -380,6 +817,12 @@ impl OutputSignal {
     pub fn node(&self) -> NodeIndex {
         self.node
     }
+
+    /// Returns the number of frames that can be read before the output runs
+    /// out and only returns silence.
+    pub fn buffered(&self) -> usize {
+        self.shared.lock().unwrap().buffer.len()
+    }
 }
 
 // fn nodedata_map<F, T, U>(node: NodeData<T>, op: F) -> NodeData<U>
@@ -388,3 +831,240 @@ impl OutputSignal {
 // {
 //     NodeData::new(op(node.node), node.buffers)
 // }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use dasp::Signal;
+    use dasp_graph::Buffer;
+    use petgraph::Direction;
+
+    use super::{BufferConfig, CoreData, NodeKind};
+    use crate::extra::Tone;
+
+    #[test]
+    fn test_clone_signal() {
+        let mut data = CoreData::new(BufferConfig::default());
+        let mut output = data.add_output();
+        let input = data.add_input_to(Some(output.node()));
+        let mut clone = data.clone_signal(input.node());
+
+        let forks: Vec<_> = data
+            .graph
+            .neighbors_directed(input.node(), Direction::Outgoing)
+            .collect();
+        assert_eq!(1, forks.len());
+
+        let mut targets: Vec<_> = data
+            .graph
+            .neighbors_directed(forks[0], Direction::Outgoing)
+            .collect();
+        targets.sort();
+        let mut expected = vec![output.node(), clone.node()];
+        expected.sort();
+        assert_eq!(expected, targets);
+        assert_eq!(Some(output.node()), data.default_output);
+
+        input.set_running(true);
+
+        for _ in 0..Buffer::LEN {
+            input.push([0.5, 0.25]);
+        }
+
+        data.tick();
+
+        for _ in 0..Buffer::LEN {
+            assert_eq!([0.5, 0.25], output.next());
+            assert_eq!([0.5, 0.25], clone.next());
+        }
+    }
+
+    #[test]
+    fn test_add_normalizer() {
+        let mut data = CoreData::new(BufferConfig::default());
+        let mut output = data.add_output();
+        let input = data.add_input_to(Some(output.node()));
+        let normalizer = data.add_normalizer(input.node(), 480);
+
+        assert!(data.graph.find_edge(input.node(), output.node()).is_none());
+        assert!(data.graph.find_edge(input.node(), normalizer).is_some());
+        assert!(data.graph.find_edge(normalizer, output.node()).is_some());
+
+        input.set_running(true);
+
+        for _ in 0..Buffer::LEN {
+            input.push([0.25, -0.125]);
+        }
+
+        data.tick();
+
+        // 1dB of headroom
+        let [l, r] = output.next();
+        assert!((l - 0.891).abs() < 0.001, "{}", l);
+        assert!((r + 0.446).abs() < 0.001, "{}", r);
+    }
+
+    #[test]
+    fn test_latency() {
+        let mut data = CoreData::new(BufferConfig {
+            input: 480,
+            output: 960,
+        });
+        let output = data.add_output();
+        let input = data.add_input_to(Some(output.node()));
+        input.set_running(true);
+
+        for _ in 0..240 {
+            input.push([0.0, 0.0]);
+        }
+
+        let latency = data.latency(48000);
+        assert_eq!(frames(240), latency.input);
+        assert_eq!(frames(480), latency.input_capacity);
+        assert_eq!(Duration::ZERO, latency.output);
+        assert_eq!(frames(960), latency.output_capacity);
+
+        data.tick();
+
+        let latency = data.latency(48000);
+        assert_eq!(frames(240 - Buffer::LEN), latency.input);
+        assert_eq!(frames(Buffer::LEN), latency.output);
+        assert_eq!(
+            latency.input + latency.tick + latency.output,
+            latency.total()
+        );
+    }
+
+    #[test]
+    fn test_signal_node() {
+        let mut data = CoreData::new(BufferConfig::default());
+        let mut output = data.add_output();
+        let tone = Tone::new(48000, 1000.0, 0.5, frames(Buffer::LEN));
+        let node = data.add_signal(tone, Some(output.node()));
+
+        data.tick();
+        assert!(data.graph.node_weight(node).is_some());
+
+        let peak = (0..Buffer::LEN)
+            .map(|_| output.next()[0].abs())
+            .fold(0.0, f32::max);
+        assert!(peak > 0.45 && peak < 0.51);
+
+        // the tone is over now, so the node is removed on the next tick
+        let nodes = data.graph.node_count();
+        data.tick();
+        assert_eq!(nodes - 1, data.graph.node_count());
+    }
+
+    #[test]
+    fn test_dump_graph() {
+        let mut data = CoreData::new(BufferConfig::default());
+        let output = data.add_output();
+        let input = data.add_input_to(Some(output.node()));
+
+        let expected = format!(
+            "digraph {{\n    \
+             {bottom} [label=\"{bottom}: bottom\"];\n    \
+             {output} [label=\"{output}: output (0 buffered), default\"];\n    \
+             {input} [label=\"{input}: input (stopped, 0 buffered)\"];\n    \
+             {output} -> {bottom};\n    \
+             {input} -> {output};\n\
+             }}",
+            bottom = data.bottom.index(),
+            output = output.node().index(),
+            input = input.node().index(),
+        );
+
+        assert_eq!(expected, data.dump_graph());
+    }
+
+    #[test]
+    fn test_input_gain() {
+        let mut data = CoreData::new(BufferConfig::default());
+        let mut output = data.add_output();
+        let input = data.add_input_to(Some(output.node()));
+        input.set_running(true);
+        input.set_gain(0.5);
+        assert_eq!(0.5, input.gain());
+
+        for _ in 0..Buffer::LEN {
+            input.push([0.5, -1.0]);
+        }
+
+        data.tick();
+
+        assert_eq!([0.25, -0.5], output.next());
+    }
+
+    #[test]
+    fn test_disconnect_node() {
+        let mut data = CoreData::new(BufferConfig::default());
+        let mut output = data.add_output();
+        let input = data.add_input_to(Some(output.node()));
+        input.set_running(true);
+
+        data.disconnect_node(input.node());
+        assert_eq!(0, data.graph.edges(input.node()).count());
+
+        for _ in 0..Buffer::LEN {
+            input.push([0.5, 0.5]);
+        }
+
+        data.tick();
+        assert_eq!([0.0, 0.0], output.next());
+        // the input still has its samples since nothing read them
+        assert_eq!(Buffer::LEN, input.shared.data.lock().unwrap().buffer.len());
+
+        assert!(data.reconnect_node_to_default_output(input.node()));
+        assert!(data.reconnect_node_to_default_output(input.node()));
+        assert_eq!(1, data.graph.edges(input.node()).count());
+    }
+
+    #[test]
+    fn test_set_default_output() {
+        let mut data = CoreData::new(BufferConfig::default());
+        let first = data.add_output();
+        let old = data.add_input_to(data.default_output);
+
+        let second = data.add_output();
+        assert_eq!(Some(first.node()), data.default_output);
+
+        data.set_default_output(second.node());
+        let new = data.add_input_to(data.default_output);
+
+        assert!(data.graph.find_edge(old.node(), first.node()).is_some());
+        assert!(data.graph.find_edge(new.node(), second.node()).is_some());
+        assert!(data.graph.find_edge(new.node(), first.node()).is_none());
+    }
+
+    #[test]
+    fn test_node_info() {
+        let mut data = CoreData::new(BufferConfig::default());
+        let output = data.add_output();
+        let input = data.add_input_to(Some(output.node()));
+        input.push([0.5, 0.5]);
+
+        let info = data.node_info(input.node()).unwrap();
+        assert_eq!(NodeKind::Input, info.kind);
+        assert!(!info.running);
+        assert_eq!(1, info.buffered);
+        assert_eq!(BufferConfig::default().input, info.capacity);
+        assert_eq!(vec![output.node()], info.outputs);
+
+        let info = data.node_info(output.node()).unwrap();
+        assert_eq!(NodeKind::Output, info.kind);
+        assert_eq!(vec![data.bottom], info.outputs);
+
+        assert!(data.is_connected(input.node(), output.node()));
+        assert!(data.is_connected(input.node(), data.bottom));
+        assert!(!data.is_connected(output.node(), input.node()));
+
+        data.disconnect_node(input.node());
+        assert!(!data.is_connected(input.node(), output.node()));
+    }
+
+    fn frames(n: usize) -> Duration {
+        Duration::from_secs_f64(n as f64 / 48000.0)
+    }
+}
@@ -1,7 +1,12 @@
+use std::collections::VecDeque;
+use std::f64::consts::TAU;
+use std::time::Duration;
+
 use dasp::interpolate::linear::Linear;
 use dasp::sample::Duplex;
 use dasp::signal::interpolate::Converter;
 use dasp::{Frame, Signal};
+use dasp_graph::{Buffer, Input};
 
 pub struct Tap<S> {
     running: bool,
@@ -63,3 +68,164 @@ where
         }
     }
 }
+
+/// A sine tone of fixed frequency and length, for testing the audio path.
+#[derive(Debug, Clone)]
+pub struct Tone {
+    step: f64,
+    phase: f64,
+    amplitude: f32,
+    remaining: usize,
+}
+
+impl Tone {
+    pub fn new(sample_rate: u32, frequency: f64, amplitude: f32, duration: Duration) -> Self {
+        Tone {
+            step: frequency / sample_rate as f64,
+            phase: 0.0,
+            amplitude,
+            remaining: (duration.as_secs_f64() * sample_rate as f64).round() as usize,
+        }
+    }
+}
+
+impl Signal for Tone {
+    type Frame = [f32; 2];
+
+    fn next(&mut self) -> Self::Frame {
+        if self.remaining == 0 {
+            return Self::Frame::EQUILIBRIUM;
+        }
+
+        let sample = (self.phase * TAU).sin() as f32 * self.amplitude;
+        self.phase = (self.phase + self.step).fract();
+        self.remaining -= 1;
+
+        [sample; 2]
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+/// Scales the mix of its inputs so that the loudest sample of the last
+/// `window_samples` frames stays `headroom_db` below full scale. Both
+/// channels get the same gain. Quiet passages are boosted by at most
+/// [`NormalizerNode::MAX_GAIN_DB`], so that silence doesn't turn into noise.
+#[derive(Debug, Clone)]
+pub struct NormalizerNode {
+    window_samples: usize,
+    /// The level the peak is scaled to.
+    peak: f32,
+    /// Magnitudes of the frames in the window that are louder than all
+    /// frames after them, with their position, loudest first. The front is
+    /// the peak of the window.
+    history: VecDeque<(u64, f32)>,
+    position: u64,
+}
+
+impl NormalizerNode {
+    /// How much quiet audio is amplified at most.
+    pub const MAX_GAIN_DB: f32 = 20.0;
+
+    /// The headroom [`Core::add_normalizer`](crate::Core::add_normalizer)
+    /// leaves.
+    pub const DEFAULT_HEADROOM_DB: f32 = 1.0;
+
+    pub fn new(window_samples: usize, headroom_db: f32) -> Self {
+        NormalizerNode {
+            window_samples: window_samples.max(1),
+            peak: db_to_gain(-headroom_db),
+            history: VecDeque::new(),
+            position: 0,
+        }
+    }
+
+    fn normalize(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let magnitude = frame[0].abs().max(frame[1].abs());
+
+        while matches!(self.history.back(), Some(&(_, m)) if m <= magnitude) {
+            self.history.pop_back();
+        }
+
+        self.history.push_back((self.position, magnitude));
+
+        while matches!(self.history.front(), Some(&(pos, _)) if pos + (self.window_samples as u64) <= self.position)
+        {
+            self.history.pop_front();
+        }
+
+        self.position += 1;
+
+        let max = self.history.front().map_or(0.0, |&(_, m)| m);
+        let gain = if max > 0.0 {
+            (self.peak / max).min(db_to_gain(Self::MAX_GAIN_DB))
+        } else {
+            1.0
+        };
+
+        [frame[0] * gain, frame[1] * gain]
+    }
+}
+
+impl dasp_graph::Node for NormalizerNode {
+    fn process(&mut self, inputs: &[Input], output: &mut [Buffer]) {
+        for i in 0..Buffer::LEN {
+            let mut frame = [0.0; 2];
+
+            for input in inputs.iter() {
+                for (ch, buffer) in input.buffers().iter().take(2).enumerate() {
+                    frame[ch] += buffer[i];
+                }
+            }
+
+            let frame = self.normalize(frame);
+
+            for (ch, out) in output.iter_mut().take(2).enumerate() {
+                out[i] = frame[ch];
+            }
+        }
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10.0f32.powf(db / 20.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::NormalizerNode;
+
+    #[test]
+    fn test_normalizer() {
+        let mut node = NormalizerNode::new(4, 0.0);
+
+        assert_eq!([1.0, -0.5], node.normalize([0.5, -0.25]));
+
+        // the louder first frame is still in the window
+        for _ in 0..3 {
+            assert_eq!([0.5, 0.5], node.normalize([0.25, 0.25]));
+        }
+
+        // and has left it now
+        assert_eq!([1.0, 1.0], node.normalize([0.25, 0.25]));
+        assert_eq!([0.0, 0.0], node.normalize([0.0, 0.0]));
+    }
+
+    #[test]
+    fn test_normalizer_limits() {
+        let mut node = NormalizerNode::new(100, 6.0);
+
+        let [l, _] = node.normalize([2.0, 0.0]);
+        assert!((l - 0.501).abs() < 0.001, "{}", l);
+
+        // very quiet audio is only boosted by 20dB
+        let mut node = NormalizerNode::new(100, 0.0);
+        let [l, _] = node.normalize([0.001, 0.0]);
+        assert!((l - 0.01).abs() < 0.0001, "{}", l);
+
+        let mut node = NormalizerNode::new(100, 0.0);
+        assert_eq!([0.0, 0.0], node.normalize([0.0, 0.0]));
+    }
+}
@@ -1,8 +1,12 @@
-pub use crate::core::{AudioSource, Core, OutputSignal};
+pub use petgraph::graph::NodeIndex;
+
+pub use crate::core::{AudioSource, BufferConfig, Core, Latency, NodeInfo, NodeKind, OutputSignal};
 
 pub mod core;
 pub mod extra;
 pub mod streamio;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 
 #[cfg(test)]
 mod tests {
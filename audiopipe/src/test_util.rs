@@ -0,0 +1,114 @@
+//! Helpers for testing code that produces audio without a [`Core`] or a
+//! Mumble connection.
+//!
+//! [`Core`]: crate::Core
+
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::Sink;
+
+use crate::streamio::StreamWrite;
+
+/// An audio output that keeps all frames written to it. Clones share the
+/// same frames, so a test can keep one to look at what was written to a
+/// sink that was moved somewhere else.
+#[derive(Debug, Clone, Default)]
+pub struct TestSink {
+    frames: Arc<Mutex<Vec<[f32; 2]>>>,
+}
+
+impl TestSink {
+    pub fn new() -> Self {
+        TestSink::default()
+    }
+
+    /// Returns a copy of the frames written so far.
+    pub fn frames(&self) -> Vec<[f32; 2]> {
+        self.frames.lock().unwrap().clone()
+    }
+
+    /// Returns how many frames have been written so far.
+    pub fn len(&self) -> usize {
+        self.frames.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes and returns the frames written so far.
+    pub fn take(&self) -> Vec<[f32; 2]> {
+        std::mem::take(&mut *self.frames.lock().unwrap())
+    }
+}
+
+impl StreamWrite<[f32; 2]> for TestSink {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[[f32; 2]],
+    ) -> Poll<io::Result<usize>> {
+        self.frames.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Sink<[f32; 2]> for TestSink {
+    type Error = ();
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: [f32; 2]) -> Result<(), Self::Error> {
+        self.frames.lock().unwrap().push(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::task::noop_waker_ref;
+    use futures::{executor, SinkExt};
+
+    use super::TestSink;
+    use crate::streamio::StreamWrite;
+
+    #[test]
+    fn test_sink() {
+        let sink = TestSink::new();
+        let mut writer = sink.clone();
+
+        executor::block_on(writer.send([0.5, -0.5])).unwrap();
+
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let written = Pin::new(&mut writer).poll_write(&mut cx, &[[0.25, 0.25], [1.0, 0.0]]);
+        assert!(matches!(written, Poll::Ready(Ok(2))));
+
+        assert_eq!(3, sink.len());
+        assert_eq!(vec![[0.5, -0.5], [0.25, 0.25], [1.0, 0.0]], sink.take());
+        assert!(sink.is_empty());
+    }
+}
@@ -1,4 +1,7 @@
-use std::path::Path;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use cmdparser::{CommandDispatcher, ExecSource, SimpleExecutor};
@@ -11,6 +14,47 @@ fn main() {
     let git_hash = String::from_utf8(output.stdout).unwrap();
     println!("cargo:rustc-env=GIT_HASH={}", git_hash);
     println!("cargo:rustc-env=DATABASE_URL={}", read_config("../srvrc"));
+
+    embed_migrations();
+}
+
+/// Generates `migrations.rs` in the output directory, which embeds the
+/// migrations so they can be applied at startup.
+fn embed_migrations() {
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let dir = manifest_dir.join("../migrations").canonicalize().unwrap();
+    let migrations = miglib::load_dir(&dir).expect("Could not load migrations");
+
+    let mut src = String::new();
+    writeln!(src, "pub fn embedded() -> Vec<miglib::Migration> {{").unwrap();
+    writeln!(src, "    vec![").unwrap();
+
+    for m in migrations.iter() {
+        let root = m.root();
+
+        writeln!(
+            src,
+            "        miglib::Migration::embedded({:?}, {:#x}, {}, {:?}, include_str!({:?}), include_str!({:?})),",
+            root.file_name().unwrap().to_str().unwrap(),
+            m.id().as_u128(),
+            m.date().timestamp(),
+            m.name(),
+            root.join("apply.sql"),
+            root.join("unapply.sql"),
+        )
+        .unwrap();
+    }
+
+    writeln!(src, "    ]").unwrap();
+    writeln!(src, "}}").unwrap();
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("migrations.rs");
+    fs::write(out, src).unwrap();
+
+    // keep rerunning on any change to the package like before, so that
+    // GIT_HASH stays up to date
+    println!("cargo:rerun-if-changed=.");
+    println!("cargo:rerun-if-changed={}", dir.display());
 }
 
 #[allow(clippy::single_match)]
@@ -0,0 +1,111 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// How long a web session stays valid after it was created, in days.
+const SESSION_LIFETIME_DAYS: i64 = 30;
+
+/// Manages web login sessions. Sessions are stored in the database, so they
+/// survive bot restarts and reconnects to the Mumble server.
+#[derive(Debug, Clone)]
+pub struct AuthService {
+    db: PgPool,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Session {
+    id: Uuid,
+    registered_user_id: u32,
+    created: DateTime<Utc>,
+    expires: DateTime<Utc>,
+}
+
+impl AuthService {
+    pub fn new(db: PgPool) -> Self {
+        AuthService { db }
+    }
+
+    /// Creates a new session for the Mumble user with the given registered
+    /// user ID.
+    pub async fn create_session(&self, registered_user_id: u32) -> sqlx::Result<Session> {
+        let created = Utc::now();
+        let session = Session {
+            id: Uuid::new_v4(),
+            registered_user_id,
+            created,
+            expires: created + Duration::days(SESSION_LIFETIME_DAYS),
+        };
+
+        sqlx::query!(
+            // language=SQL
+            "INSERT INTO session (id, registered_user_id, created, expires) VALUES ($1, $2, $3, $4)",
+            session.id,
+            i64::from(session.registered_user_id),
+            session.created,
+            session.expires,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(session)
+    }
+
+    /// Returns the session with the given ID, or `None` if it does not exist
+    /// or has expired.
+    pub async fn validate_session(&self, id: Uuid) -> sqlx::Result<Option<Session>> {
+        let row = sqlx::query!(
+            // language=SQL
+            "SELECT id, registered_user_id, created, expires FROM session \
+             WHERE id = $1 AND expires > now()",
+            id
+        )
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(row.map(|row| Session {
+            id: row.id,
+            registered_user_id: row.registered_user_id as u32,
+            created: row.created,
+            expires: row.expires,
+        }))
+    }
+
+    /// Deletes the session with the given ID. Returns whether the session
+    /// existed.
+    pub async fn delete_session(&self, id: Uuid) -> sqlx::Result<bool> {
+        // language=SQL
+        let result = sqlx::query!("DELETE FROM session WHERE id = $1", id)
+            .execute(&self.db)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Removes all expired sessions from the database.
+    pub async fn delete_expired_sessions(&self) -> sqlx::Result<u64> {
+        // language=SQL
+        let result = sqlx::query!("DELETE FROM session WHERE expires <= now()")
+            .execute(&self.db)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+impl Session {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn registered_user_id(&self) -> u32 {
+        self.registered_user_id
+    }
+
+    pub fn created(&self) -> DateTime<Utc> {
+        self.created
+    }
+
+    pub fn expires(&self) -> DateTime<Utc> {
+        self.expires
+    }
+}
@@ -3,25 +3,48 @@ use std::cmp::{max, min};
 use std::fmt::Write;
 use std::num::ParseIntError;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
+use chrono::Local;
 use clap::{App, AppSettings, Arg, ArgGroup};
-use log::debug;
-use sqlx::postgres::PgArguments;
-use sqlx::Arguments;
+use log::{debug, error};
+use sqlx::PgConnection;
 use url::Url;
-use uuid::Uuid;
 
+use audiopipe::extra::Tone;
+use audiopipe::{Core, NodeIndex};
+use msgtools::proxy::{CallError, ResultExt};
 use msgtools::Ac;
 
+use crate::db::entity::playlist::SmartPlaylist;
 use crate::db::entity::{playlist, Playlist};
 use crate::db::object;
-use crate::entity::import::ImportError;
+use crate::db::object::playlist::NestingMode;
+use crate::db::objgen::{self, Entity};
+use crate::db::query::TitleQuery;
 use crate::entity::Track;
 use crate::fmt::HtmlDisplayExt;
+use crate::logging::level_str;
+use crate::player::media::MediaCache;
+use crate::player::radio::{DbRadio, RadioSource, YoutubeRadio};
 use crate::player::treepath::{TreePath, TreePathBuf};
-use crate::{Bot, Result, StreamExt};
-
-const COMMAND_PREFIX: char = ';';
+use crate::player::{NestingModeError, PlayMode, Room1Like, DEFAULT_SLOT};
+use crate::spotify;
+use crate::{Bot, Error, FmtDuration, Result, StreamExt};
+
+pub(crate) mod autoplay;
+mod playlist_stats;
+mod search;
+mod tags;
+
+/// Length of the tone played by `;selftest`.
+const SELFTEST_TONE_LENGTH: Duration = Duration::from_secs(1);
+/// How long `;selftest` waits for audio to come back after the tone ended.
+const SELFTEST_GRACE: Duration = Duration::from_millis(500);
+/// The returned tone needs to be at least this loud. The encoder turns the
+/// volume down to 10%, so this is well below what the test tone should reach.
+const SELFTEST_MIN_PEAK: f32 = 0.01;
 
 pub async fn handle_message_event(bot: &mut Bot, ev: &mumble::event::Message) -> Result {
     let name: Cow<_> = match ev.actor {
@@ -34,7 +57,7 @@ pub async fn handle_message_event(bot: &mut Bot, ev: &mumble::event::Message) ->
 
     println!("{}: {}", name, ev.message);
 
-    if let Some(msg) = ev.message.strip_prefix(COMMAND_PREFIX) {
+    if let Some(msg) = ev.message.strip_prefix(bot.config.command_prefix) {
         let msg = msg.trim();
         handle_command(bot, ev, msg).await?;
     }
@@ -45,8 +68,8 @@ pub async fn handle_message_event(bot: &mut Bot, ev: &mumble::event::Message) ->
 macro_rules! match_commands {
     ($cmde:expr, $bot:expr, $ev:expr, $args:expr, $out:expr, $($cmd:ident)*) => {
         match $cmde {
-            $(stringify!($cmd) => $cmd($bot, $ev, $args, &mut $out).await?,)*
-            _ => {}
+            $(stringify!($cmd) => $cmd($bot, $ev, $args, &mut $out).await,)*
+            _ => Ok(()),
         }
     };
 }
@@ -59,10 +82,15 @@ async fn handle_command(bot: &mut Bot, ev: &mumble::event::Message, msg: &str) -
         let args = &cmdline[1..];
         let mut out = String::new();
 
-        match_commands! {
+        let result = match_commands! {
             cmd, bot, ev, args, out,
-            skip pause play list random new newsub load web quit
-            playlist track
+            skip pause play list random repeat radio volume mute unmute new newsub load pl web quit
+            playlist track tag queue search loglevel autoplay debug cache users selftest
+        };
+
+        // a failing command shouldn't stop the ones after it from running
+        if let Err(e) = result {
+            write_error(cmd, &e, &mut out);
         }
 
         if !out.is_empty() {
@@ -81,6 +109,43 @@ async fn handle_command(bot: &mut Bot, ev: &mumble::event::Message, msg: &str) -
     Ok(())
 }
 
+fn write_error(cmd: &str, e: &Error, out: &mut String) {
+    match e {
+        Error::User(message) => writeln!(out, "error: {}", message).unwrap(),
+        Error::Save(objgen::Error::Deleted) => {
+            writeln!(out, "error: object was deleted by another process").unwrap()
+        }
+        e if e.is_user_facing() => {
+            writeln!(out, "error: {}", html_escape::encode_text(&e.to_string())).unwrap()
+        }
+        e => {
+            let id = format!("{:08x}", rand::random::<u32>());
+            error!("[{}] command '{}' failed: {}", id, cmd, e);
+            writeln!(
+                out,
+                "internal error while running <code>{}</code>, error id <code>{}</code>",
+                html_escape::encode_text(cmd),
+                id
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// Fails unless the sender of `ev` is a registered user listed in the
+/// `admins` config option.
+async fn require_admin(bot: &Bot, ev: &mumble::event::Message) -> Result {
+    let user = match ev.actor {
+        None => None,
+        Some(actor) => bot.client.get_user(actor).await?,
+    };
+
+    match user.and_then(|u| u.registered_id()) {
+        Some(id) if bot.config.admins.contains(&id) => Ok(()),
+        _ => Err(Error::PermissionDenied),
+    }
+}
+
 fn app_for_command(name: &'static str) -> App {
     App::new(name)
         .setting(AppSettings::DisableVersionFlag)
@@ -131,11 +196,38 @@ async fn pause(
 
 async fn play(bot: &Bot, ev: &mumble::event::Message, args: &[String], out: &mut String) -> Result {
     let matches = app_for_command("play")
-        .about("Start playing the current track")
+        .about("Start playing the current track, or the given track right away")
+        .args(&[Arg::new("track")
+            .value_name("TRACK")
+            .about("The code of the track to play")])
         .try_get_matches_from(args.iter());
     unwrap_matches!(matches, out);
 
-    bot.room.proxy().play().await?;
+    let code = match matches.value_of("track") {
+        None => {
+            bot.room.proxy().play().await?;
+            return Ok(());
+        }
+        Some(code) => code,
+    };
+
+    let mut db = bot.db.acquire().await?;
+    let track = Track::load_by_code(code, &mut *db)
+        .await
+        .map_err(Error::not_found("track", code))?;
+    drop(db);
+
+    match bot.room.proxy().play_now(track.clone()).await? {
+        Ok(()) => writeln!(out, "now playing {}", track.html()).unwrap(),
+        Err(e) => {
+            let message = format!(
+                "{}: {}",
+                track.html(),
+                html_escape::encode_text(&e.to_string())
+            );
+            return Err(Error::user(message));
+        }
+    }
 
     Ok(())
 }
@@ -187,13 +279,7 @@ async fn list(bot: &Bot, ev: &mumble::event::Message, args: &[String], out: &mut
         End::Relative(v) => start + v,
     };
 
-    let pl = match bot.room.proxy().playlist().await {
-        Ok(v) => v,
-        Err(e) => {
-            writeln!(out, "failed to get playlist: {}", e).unwrap();
-            return Ok(());
-        }
-    };
+    let pl = bot.room.proxy().playlist().await?;
 
     let max_length = bot.client.max_message_length().await;
 
@@ -281,6 +367,168 @@ async fn random(
     Ok(())
 }
 
+async fn repeat(
+    bot: &Bot,
+    ev: &mumble::event::Message,
+    args: &[String],
+    out: &mut String,
+) -> Result {
+    let matches = app_for_command("repeat")
+        .about("Sets whether the playlist starts over after the last track")
+        .args(&[Arg::new("state")
+            .value_name("STATE")
+            .about("Whether to repeat the playlist")
+            .possible_values(&["on", "off"])
+            .required(true)])
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+
+    let mode = match matches.value_of("state").unwrap() {
+        "on" => PlayMode::Repeat,
+        _ => PlayMode::Once,
+    };
+
+    bot.room.proxy().set_mode(mode).await?;
+
+    if mode == PlayMode::Repeat {
+        writeln!(out, "Repeat is now on").unwrap();
+    } else {
+        writeln!(out, "Repeat is now off").unwrap();
+    }
+
+    Ok(())
+}
+
+async fn radio(
+    bot: &Bot,
+    ev: &mumble::event::Message,
+    args: &[String],
+    out: &mut String,
+) -> Result {
+    let matches = app_for_command("radio")
+        .about("Keeps playing related tracks once the playlist ends")
+        .args(&[
+            Arg::new("state")
+                .value_name("STATE")
+                .about("Whether to turn the radio on")
+                .possible_values(&["on", "off"])
+                .required(true),
+            Arg::new("source")
+                .short('s')
+                .long("source")
+                .value_name("SOURCE")
+                .about("Where to find related tracks")
+                .possible_values(&["youtube", "db"])
+                .default_value("youtube"),
+        ])
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+
+    if matches.value_of("state").unwrap() == "off" {
+        bot.room.proxy().set_radio(None).await?;
+        writeln!(out, "Radio is now off").unwrap();
+        return Ok(());
+    }
+
+    let source: Arc<dyn RadioSource> = match matches.value_of("source").unwrap() {
+        "db" => Arc::new(DbRadio::new(bot.db.clone())),
+        _ => Arc::new(YoutubeRadio::new(bot.db.clone())),
+    };
+
+    bot.room.proxy().set_radio(Some(source)).await?;
+    writeln!(out, "Radio is now on").unwrap();
+
+    Ok(())
+}
+
+async fn volume(
+    bot: &Bot,
+    ev: &mumble::event::Message,
+    args: &[String],
+    out: &mut String,
+) -> Result {
+    let matches = app_for_command("volume")
+        .about("Set the playback volume")
+        .args(&[Arg::new("percent")
+            .value_name("PERCENT")
+            .about("The new volume, from 0 to 200. With a sign, such as +10 or -10, changes the volume by that much")
+            .required(true)
+            .allow_hyphen_values(true)])
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+
+    let arg = matches.value_of("percent").unwrap();
+    let volume = change_volume(bot.room.proxy(), arg).await?;
+
+    writeln!(out, "Volume is now {}%", volume_percent(volume)).unwrap();
+
+    Ok(())
+}
+
+/// Sets the volume of `room` to the percentage in `arg`, or changes it by
+/// that much if it has a sign. Returns the new volume.
+async fn change_volume(room: &impl Room1Like, arg: &str) -> Result<f32> {
+    if arg.starts_with(&['+', '-'][..]) {
+        let delta: i32 = arg
+            .parse()
+            .map_err(|_| Error::user("volume change must be a number such as +10 or -10"))?;
+
+        Ok(room.adjust_volume(delta as f32 / 100.0).await?)
+    } else {
+        let percent: u32 = match arg.parse() {
+            Ok(percent) if percent <= 200 => percent,
+            _ => return Err(Error::user("volume must be a number from 0 to 200")),
+        };
+
+        let volume = percent as f32 / 100.0;
+        room.set_volume(volume).await?;
+        Ok(volume)
+    }
+}
+
+async fn mute(bot: &Bot, ev: &mumble::event::Message, args: &[String], out: &mut String) -> Result {
+    let matches = app_for_command("mute")
+        .about("Mute playback, keeping the volume for unmuting")
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+
+    bot.room.proxy().set_muted(true).await?;
+    let volume = bot.room.proxy().volume().await?;
+
+    writeln!(
+        out,
+        "Muted, unmuting goes back to {}%",
+        volume_percent(volume)
+    )
+    .unwrap();
+
+    Ok(())
+}
+
+async fn unmute(
+    bot: &Bot,
+    ev: &mumble::event::Message,
+    args: &[String],
+    out: &mut String,
+) -> Result {
+    let matches = app_for_command("unmute")
+        .about("Unmute playback at the volume it had before muting")
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+
+    bot.room.proxy().set_muted(false).await?;
+    let volume = bot.room.proxy().volume().await?;
+
+    writeln!(out, "Volume is now {}%", volume_percent(volume)).unwrap();
+
+    Ok(())
+}
+
+/// Formats a room's volume as a percentage.
+fn volume_percent(volume: f32) -> u32 {
+    (volume * 100.0).round() as u32
+}
+
 async fn new(bot: &Bot, ev: &mumble::event::Message, args: &[String], out: &mut String) -> Result {
     let matches = app_for_command("new")
         .about("Create a new playlist")
@@ -328,21 +576,25 @@ async fn newsub(
         .try_get_matches_from(args.iter());
     unwrap_matches!(matches, out);
 
-    let path = matches.value_of("path").unwrap();
-    let path = match TreePathBuf::from_str(path) {
-        Ok(v) => v,
-        Err(e) => {
-            writeln!(out, "error: {}: {}", e, path).unwrap();
-            return Ok(());
-        }
-    };
+    let path_str = matches.value_of("path").unwrap();
+    let path = parse_tree_path(path_str)?;
 
-    bot.room
+    let result = bot
+        .room
         .proxy()
         .add_playlist(Ac::new(Playlist::new()), path)
-        .await?;
-
-    Ok(())
+        .await
+        .flatten_call();
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(CallError::Handler(e)) => Err(Error::user(format!(
+            "can't add a playlist to {}: {}",
+            html_escape::encode_text(path_str),
+            e
+        ))),
+        Err(CallError::Proxy(e)) => Err(e.into()),
+    }
 }
 
 async fn load(bot: &Bot, ev: &mumble::event::Message, args: &[String], out: &mut String) -> Result {
@@ -354,24 +606,151 @@ async fn load(bot: &Bot, ev: &mumble::event::Message, args: &[String], out: &mut
         .try_get_matches_from(args.iter());
     unwrap_matches!(matches, out);
 
-    let mut db = match bot.db.acquire().await {
-        Ok(v) => v,
-        Err(e) => {
-            writeln!(out, "failed to acquire database connection: {}", e).unwrap();
-            return Ok(());
+    let mut db = bot.db.acquire().await?;
+
+    let code = matches.value_of("code").unwrap();
+    let playlist = Playlist::load_by_code(code, &mut *db)
+        .await
+        .map_err(Error::not_found("playlist", code))?;
+
+    // in one batch, so that nothing else can change the playlist before
+    // it starts playing
+    let mut batch = bot.room.proxy().batch();
+    let set = batch.set_playlist(Ac::new(playlist));
+    let played = batch.play();
+    batch.send().await?;
+
+    set.recv().await?;
+    played.recv().await?;
+
+    Ok(())
+}
+
+async fn pl(bot: &Bot, ev: &mumble::event::Message, args: &[String], out: &mut String) -> Result {
+    let matches = app_for_command("pl")
+        .about("Keep multiple playlists loaded and switch between them")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommands([
+            App::new("load").about("Load a playlist into a slot").args([
+                Arg::new("code")
+                    .value_name("CODE")
+                    .about("The code of the playlist to load")
+                    .required(true),
+                Arg::new("slot")
+                    .value_name("SLOT")
+                    .about("The slot to load the playlist into")
+                    .default_value(DEFAULT_SLOT),
+                Arg::new("play")
+                    .short('p')
+                    .long("play")
+                    .about("Switch to the slot right away"),
+            ]),
+            App::new("switch")
+                .about("Continue playing from the playlist in another slot")
+                .args([Arg::new("slot")
+                    .value_name("SLOT")
+                    .about("The slot to switch to")
+                    .required(true)]),
+            App::new("slots").about("List the slots and their playlists"),
+        ])
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+
+    match matches.subcommand() {
+        Some(("load", matches)) => {
+            let mut db = bot.db.acquire().await?;
+
+            let code = matches.value_of("code").unwrap();
+            let slot = matches.value_of("slot").unwrap();
+            let playlist = Playlist::load_by_code(code, &mut *db)
+                .await
+                .map_err(Error::not_found("playlist", code))?;
+
+            // in one batch, so that nothing else can switch slots in
+            // between
+            let mut batch = bot.room.proxy().batch();
+            let loaded = batch.load_slot(slot.to_string(), Ac::new(playlist));
+            let switched = matches
+                .is_present("play")
+                .then(|| batch.switch_slot(slot.to_string()));
+            batch.send().await?;
+
+            loaded.recv().await?;
+
+            if let Some(switched) = switched {
+                switched.recv().await?;
+            }
         }
-    };
+        Some(("switch", matches)) => {
+            let slot = matches.value_of("slot").unwrap();
+
+            if !bot.room.proxy().switch_slot(slot.to_string()).await? {
+                return Err(Error::user(format!(
+                    "no playlist loaded in slot '{}'",
+                    slot
+                )));
+            }
+        }
+        Some(("slots", _)) => {
+            let slots = bot.room.proxy().slots().await?;
+
+            for slot in slots {
+                let marker = if slot.active { "▶" } else { "" };
+
+                writeln!(
+                    out,
+                    "{} <b>{}</b>: {}",
+                    marker,
+                    html_escape::encode_text(&slot.name),
+                    slot.playlist.html()
+                )
+                .unwrap();
+            }
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+async fn autoplay(
+    bot: &Bot,
+    ev: &mumble::event::Message,
+    args: &[String],
+    out: &mut String,
+) -> Result {
+    let matches = app_for_command("autoplay")
+        .about("Queue a random track similar to the given one")
+        .args(&[Arg::new("code")
+            .value_name("CODE")
+            .about("The code of the track to find similar tracks to")
+            .required(true)])
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+
+    let mut db = bot.db.acquire().await?;
 
     let code = matches.value_of("code").unwrap();
-    let playlist = match Playlist::load_by_code(code, &mut *db).await {
-        Ok(v) => v,
-        Err(e) => {
-            writeln!(out, "failed to load playlist: {}", e).unwrap();
+    let track = Track::load_by_code(code, &mut *db)
+        .await
+        .map_err(Error::not_found("track", code))?;
+
+    let next = match autoplay::find_similar_tracks(&track, &mut *db)
+        .await?
+        .into_iter()
+        .next()
+    {
+        None => {
+            writeln!(out, "no tracks similar to {} found", track.html()).unwrap();
             return Ok(());
         }
+        Some(v) => v,
     };
 
-    bot.room.proxy().set_playlist(Ac::new(playlist)).await?;
+    let requested_by = actor_name(bot, ev).await?;
+
+    writeln!(out, "queued {}", next.html()).unwrap();
+    bot.room.proxy().add_to_queue(next, requested_by).await?;
 
     Ok(())
 }
@@ -429,14 +808,30 @@ async fn playlist(
                         .short('t')
                         .long("track")
                         .value_name("TRACK")
-                        .about("Adds the track with the specified code TRACK")
+                        .about("Adds the track with the specified code TRACK. Use TRACK@PATH to add it to the nested playlist at PATH")
                         .multiple_occurrences(true),
+                    Arg::new("at")
+                        .long("at")
+                        .value_name("PATH")
+                        .about("Adds tracks to the nested playlist at PATH instead of the top level")
+                        .requires("track"),
                     Arg::new("sync")
                         .short('s')
                         .long("sync")
                         .about("Syncs the playlist against the configured external source")
                         .conflicts_with("track"),
                 ]),
+            app_for_command("copy")
+                .about("Copy a playlist, including its sub-playlists")
+                .args([
+                    Arg::new("code")
+                        .value_name("CODE")
+                        .about("The code of the playlist to copy")
+                        .required(true),
+                    Arg::new("new_code")
+                        .value_name("NEWCODE")
+                        .about("Use the provided code for the copy"),
+                ]),
             app_for_command("delete")
                 .short_flag('R')
                 .args([
@@ -446,6 +841,15 @@ async fn playlist(
                         .required(true)
                         .multiple_values(true),
                 ]),
+            app_for_command("restore")
+                .about("Restore deleted playlists")
+                .args([
+                    Arg::new("code")
+                        .value_name("CODE")
+                        .about("The code of the playlist to restore")
+                        .required(true)
+                        .multiple_values(true),
+                ]),
             app_for_command("query").short_flag('Q')
                 .args([
                     Arg::new("title")
@@ -460,18 +864,48 @@ async fn playlist(
                         .value_name("CODE")
                         .about("Only shows playlists containing CODE")
                         .multiple_occurrences(true),
+                    Arg::new("deleted")
+                        .long("deleted")
+                        .about("Shows deleted playlists instead"),
+                    Arg::new("fuzzy")
+                        .short('f')
+                        .long("fuzzy")
+                        .about("Also shows playlists with titles similar to TITLE, best matches first"),
+                ]),
+            app_for_command("stats")
+                .about("Show the length and the most played tracks of a playlist")
+                .args([
+                    Arg::new("code")
+                        .value_name("CODE")
+                        .about("The code of the playlist")
+                        .required(true),
+                ]),
+            app_for_command("shuffle-today")
+                .about("Play a playlist in an order that is shuffled once a day")
+                .args([
+                    Arg::new("code")
+                        .value_name("CODE")
+                        .about("The code of the playlist")
+                        .required(true),
+                ]),
+            app_for_command("mode")
+                .about("Show or set how a playlist is played when it is nested in the active playlist")
+                .args([
+                    Arg::new("mode")
+                        .value_name("MODE")
+                        .possible_values(&["show", "flatten", "roundrobin"])
+                        .default_value("show")
+                        .about("flatten mixes the playlist's tracks in with the other entries, roundrobin plays it as a single entry taking turns with the others"),
+                    Arg::new("path")
+                        .value_name("PATH")
+                        .default_value("-")
+                        .about("The path to the nested playlist in the active playlist"),
                 ]),
         ])
         .try_get_matches_from(args.iter());
     unwrap_matches!(matches, out);
 
-    let mut db = match bot.db.acquire().await {
-        Ok(v) => v,
-        Err(e) => {
-            writeln!(out, "failed to acquire database connection: {}", e).unwrap();
-            return Ok(());
-        }
-    };
+    let mut db = bot.db.acquire().await?;
 
     match matches.subcommand() {
         Some(("create", matches)) => {
@@ -484,13 +918,8 @@ async fn playlist(
             let mut pl = Playlist::new();
 
             if let Some(from) = from {
-                let url = match Url::parse(from) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        writeln!(out, "failed to parse URL: {}", e).unwrap();
-                        return Ok(());
-                    }
-                };
+                let url = Url::parse(from)
+                    .map_err(|e| Error::user(format!("failed to parse URL: {}", e)))?;
 
                 if (url.domain() == Some("www.youtube.com") || url.domain() == Some("youtube.com"))
                     && url.path() == "/playlist"
@@ -504,25 +933,12 @@ async fn playlist(
                     }
 
                     if let Some(list) = list {
-                        let res: Result<_, ImportError> =
-                            Playlist::import_by_youtube_id(&list, &mut *db).await;
-
-                        match res {
-                            Ok(v) => {
-                                pl = v;
-                            }
-                            Err(e) => {
-                                writeln!(out, "failed to import playlist: {}", e).unwrap();
-                                return Ok(());
-                            }
-                        }
+                        pl = Playlist::import_by_youtube_id(&list, &mut *db).await?;
                     } else {
-                        writeln!(out, "could not parse YouTube playlist URL").unwrap();
-                        return Ok(());
+                        return Err(Error::user("could not parse YouTube playlist URL"));
                     }
                 } else {
-                    writeln!(out, "don't know how to parse this URL").unwrap();
-                    return Ok(());
+                    return Err(Error::user("don't know how to parse this URL"));
                 }
             }
 
@@ -538,9 +954,12 @@ async fn playlist(
                     pl.set_title(name);
                 }
 
-                if let Err(e) = pl.save(&mut *db).await {
-                    writeln!(out, "failed to save playlist: {}", e).unwrap();
-                    return Ok(());
+                match pl.save(&mut *db).await {
+                    Err(objgen::Error::CodeTaken(code)) => {
+                        let existing = object::Playlist::load_by_code_any(&code, &mut *db).await?;
+                        return Err(code_taken(&code, existing.html()));
+                    }
+                    result => result?,
                 }
 
                 if from.is_some() {
@@ -551,46 +970,62 @@ async fn playlist(
             }
 
             if play {
-                let _ = bot.room.proxy().set_playlist(Ac::new(pl)).await;
+                bot.room.proxy().set_playlist(Ac::new(pl)).await?;
             }
         }
         Some(("modify", matches)) => {
             let code = matches.value_of("code").unwrap();
             let title = matches.value_of("title");
             let track = matches.values_of("track");
+            let at = match matches.value_of("at") {
+                None => TreePathBuf::root(),
+                Some(at) => parse_tree_path(at)?,
+            };
             let sync = matches.is_present("sync");
 
-            let mut playlist = match Playlist::load_by_code(code, &mut *db).await {
-                Ok(v) => v,
-                Err(e) => {
-                    writeln!(out, "failed to load playlist <code>{}</code>: {}", code, e).unwrap();
-                    return Ok(());
-                }
-            };
+            let mut playlist = Playlist::load_by_code(code, &mut *db)
+                .await
+                .map_err(Error::not_found("playlist", code))?;
 
             if let Some(title) = title {
                 playlist.set_title(title);
             }
 
             for track in track.into_iter().flatten() {
-                let track_ent = match Track::load_by_code(track, &mut *db).await {
-                    Ok(v) => v,
-                    Err(e) => {
-                        writeln!(out, "failed to load track <code>{}</code>: {}", track, e)
-                            .unwrap();
-                        return Ok(());
+                let (track, path) = match track.split_once('@') {
+                    None => (track, at.clone()),
+                    Some((track, path)) => (track, parse_tree_path(path)?),
+                };
+
+                let parent_len = match playlist.get_playlist(&path) {
+                    None => {
+                        return Err(Error::user(format!(
+                            "<code>{}</code> is not a playlist in {}",
+                            path,
+                            playlist.html()
+                        )))
                     }
+                    Some(parent) => parent.entries().len(),
                 };
 
-                playlist.add_track(track_ent, TreePathBuf::root()).unwrap();
+                let track_ent = Track::load_by_code(track, &mut *db)
+                    .await
+                    .map_err(Error::not_found("track", track))?;
+
+                writeln!(
+                    out,
+                    "added {} at <code>{}</code>",
+                    track_ent.html(),
+                    path.join(&[parent_len as u32])
+                )
+                .unwrap();
+
+                playlist.add_track(track_ent, &path).unwrap();
             }
 
             if sync {
                 if playlist.object().youtube_id().is_some() {
-                    if let Err(e) = playlist.update_content_from_youtube(&mut *db).await {
-                        writeln!(out, "failed to update playlist: {}", e).unwrap();
-                        return Ok(());
-                    }
+                    playlist.update_content_from_youtube(&mut *db).await?;
 
                     writeln!(out, "finished syncing from YouTube").unwrap();
                 } else {
@@ -603,10 +1038,25 @@ async fn playlist(
                 }
             }
 
-            if let Err(e) = playlist.save(&mut *db).await {
-                writeln!(out, "failed to save playlist: {}", e).unwrap();
-                return Ok(());
+            save_merging(&mut playlist, &mut *db, out).await?;
+        }
+        Some(("copy", matches)) => {
+            let code = matches.value_of("code").unwrap();
+            let new_code = matches.value_of("new_code");
+
+            let playlist = Playlist::load_by_code(code, &mut *db)
+                .await
+                .map_err(Error::not_found("playlist", code))?;
+
+            let mut copy = playlist.detach_deep();
+
+            if let Some(new_code) = new_code {
+                copy.set_code(new_code);
             }
+
+            copy.save(&mut *db).await?;
+
+            writeln!(out, "copied {} to {}", playlist.html(), copy.html()).unwrap();
         }
         Some(("delete", matches)) => {
             for code in matches.values_of("code").into_iter().flatten() {
@@ -626,50 +1076,181 @@ async fn playlist(
                 writeln!(out, "deleted playlist {}", playlist.html()).unwrap();
             }
         }
-        Some(("query", matches)) => {
-            let mut query = "SELECT * FROM playlist WHERE deleted = false".to_string();
-            let mut argn = 1;
-            let mut args = PgArguments::default();
-
+        Some(("restore", matches)) => {
             for code in matches.values_of("code").into_iter().flatten() {
-                writeln!(query, " AND code LIKE ${}", argn).unwrap();
-                argn += 1;
-                args.add(format!("%{}%", code));
-            }
+                let mut playlist = match Playlist::load_by_code_any(code, &mut *db).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        writeln!(out, "failed to load playlist {}: {}", code, e).unwrap();
+                        continue;
+                    }
+                };
+
+                if !playlist.object().deleted() {
+                    writeln!(out, "playlist {} is not deleted", playlist.html()).unwrap();
+                    continue;
+                }
+
+                if let Err(e) = playlist.restore(&mut *db).await {
+                    writeln!(out, "failed to restore playlist {}: {}", code, e).unwrap();
+                    continue;
+                }
 
-            for code in matches.values_of("title").into_iter().flatten() {
-                writeln!(query, " AND title LIKE ${}", argn).unwrap();
-                argn += 1;
-                args.add(format!("%{}%", code));
+                writeln!(out, "restored playlist {}", playlist.html()).unwrap();
+
+                for entry in playlist.deleted_entries() {
+                    match entry.content() {
+                        playlist::Content::Track(t) => {
+                            writeln!(out, "contains deleted track {}", t.html()).unwrap()
+                        }
+                        playlist::Content::Playlist(pl) => {
+                            writeln!(out, "contains deleted playlist {}", pl.html()).unwrap()
+                        }
+                    }
+                }
             }
+        }
+        Some(("query", matches)) => {
+            let codes = matches.values_of("code").into_iter().flatten();
+            let titles = matches.values_of("title").into_iter().flatten();
+
+            let deleted = matches.is_present("deleted");
+            let fuzzy = matches.is_present("fuzzy");
 
-            writeln!(query, " ORDER BY code").unwrap();
+            if !deleted && !fuzzy {
+                for pl in bot.playlists.query(codes, titles) {
+                    writeln!(out, "{}", pl.html()).unwrap();
+                }
+
+                return Ok(());
+            }
 
+            // deleted playlists aren't in the index
+            let (query, args) = TitleQuery::new("playlist")
+                .deleted(deleted)
+                .codes(codes)
+                .titles(titles)
+                .fuzzy(fuzzy)
+                .build_args();
             let mut stream = sqlx::query_as_with(&query, args).fetch(&mut *db);
 
             while let Some(res) = stream.next().await {
-                let pl: object::Playlist = match res {
-                    Ok(v) => v,
-                    Err(e) => {
-                        writeln!(out, "failed to load playlist: {}", e).unwrap();
-                        return Ok(());
-                    }
-                };
+                let pl: object::Playlist = res?;
 
                 writeln!(out, "{}", pl.html()).unwrap();
             }
+
+            return Ok(());
         }
-        _ => unreachable!(),
-    }
+        Some(("stats", matches)) => {
+            let code = matches.value_of("code").unwrap();
 
-    Ok(())
-}
+            let playlist = Playlist::load_by_code(code, &mut *db)
+                .await
+                .map_err(Error::not_found("playlist", code))?;
 
-async fn track(
-    bot: &mut Bot,
-    ev: &mumble::event::Message,
-    args: &[String],
-    out: &mut String,
+            let stats = playlist_stats::playlist_stats(&playlist, &mut *db).await?;
+            out.push_str(&playlist_stats::format_stats(&playlist, &stats));
+
+            return Ok(());
+        }
+        Some(("shuffle-today", matches)) => {
+            let code = matches.value_of("code").unwrap();
+
+            let object = object::Playlist::load_by_code(code, &mut *db)
+                .await
+                .map_err(Error::not_found("playlist", code))?;
+
+            let today = Local::today().naive_local();
+            let playlist =
+                SmartPlaylist::daily_random(today, object.id().unwrap(), &mut *db).await?;
+
+            writeln!(out, "playing {}", playlist.html()).unwrap();
+            bot.room.proxy().set_playlist(Ac::new(playlist)).await?;
+
+            return Ok(());
+        }
+        Some(("mode", matches)) => {
+            let path = parse_tree_path(matches.value_of("path").unwrap())?;
+            let mode = match matches.value_of("mode").unwrap() {
+                "flatten" => Some(NestingMode::Flatten),
+                "roundrobin" => Some(NestingMode::RoundRobin),
+                _ => None,
+            };
+
+            let active = bot.room.proxy().playlist().await?;
+            let not_a_playlist = || {
+                Error::user(format!(
+                    "<code>{}</code> is not a playlist in {}",
+                    path,
+                    active.html()
+                ))
+            };
+
+            let (playlist, verb) = match mode {
+                None => {
+                    let playlist = active.get_playlist(&path).ok_or_else(not_a_playlist)?;
+                    (playlist.clone(), "is")
+                }
+                Some(mode) => {
+                    let room = bot.room.proxy();
+
+                    match room.set_nesting_mode(path.clone(), mode).await? {
+                        Ok(playlist) => (playlist, "is now"),
+                        Err(NestingModeError::NotAPlaylist) => return Err(not_a_playlist()),
+                        Err(e) => {
+                            let message = format!(
+                                "{}: {}",
+                                active.html(),
+                                html_escape::encode_text(&e.to_string())
+                            );
+                            return Err(Error::user(message));
+                        }
+                    }
+                }
+            };
+
+            let (name, effect) = match playlist.object().nesting_mode() {
+                NestingMode::Flatten => (
+                    "flatten",
+                    "its tracks are mixed in with the other entries of the playlist it is in",
+                ),
+                NestingMode::RoundRobin => (
+                    "roundrobin",
+                    "it takes turns with the other entries of the playlist it is in, playing one of its tracks each time",
+                ),
+            };
+
+            writeln!(
+                out,
+                "{} {} in {} mode: {}",
+                playlist.html(),
+                verb,
+                name,
+                effect
+            )
+            .unwrap();
+
+            if path.is_empty() {
+                writeln!(out, "This only matters once it is nested in another one.").unwrap();
+            }
+
+            return Ok(());
+        }
+        _ => unreachable!(),
+    }
+
+    // keep queries up to date with what was just changed
+    bot.playlists.refresh(&mut *db).await?;
+
+    Ok(())
+}
+
+async fn track(
+    bot: &mut Bot,
+    ev: &mumble::event::Message,
+    args: &[String],
+    out: &mut String,
 ) -> Result {
     let matches = app_for_command("track")
         .about("The track management interface")
@@ -694,8 +1275,13 @@ async fn track(
                         .short('y')
                         .long("youtube")
                         .value_name("URL"),
+                    Arg::new("url")
+                        .short('u')
+                        .long("url")
+                        .value_name("URL")
+                        .about("Import the track from a YouTube or Spotify link"),
                 ])
-                .group(ArgGroup::new("source").args(&["path", "youtube"])),
+                .group(ArgGroup::new("source").args(&["path", "youtube", "url"])),
             app_for_command("modify").short_flag('M').args([
                 Arg::new("code")
                     .value_name("CODE")
@@ -706,6 +1292,12 @@ async fn track(
                     .long("title")
                     .value_name("TITLE")
                     .about("Sets the track title to TITLE."),
+                Arg::new("gain")
+                    .short('g')
+                    .long("gain")
+                    .value_name("DB")
+                    .about("Makes the track louder or quieter by DB decibels when playing it. Use 0 to reset")
+                    .allow_hyphen_values(true),
             ]),
             app_for_command("delete")
                 .short_flag('R')
@@ -714,6 +1306,19 @@ async fn track(
                     .about("The code of the track to delete")
                     .required(true)
                     .multiple_values(true)]),
+            app_for_command("restore")
+                .about("Restore deleted tracks")
+                .args([Arg::new("code")
+                    .value_name("CODE")
+                    .about("The code of the track to restore")
+                    .required(true)
+                    .multiple_values(true)]),
+            app_for_command("reimport")
+                .about("Update the title of a track from its YouTube video")
+                .args([Arg::new("code")
+                    .value_name("CODE")
+                    .about("The code of the track to update")
+                    .required(true)]),
             app_for_command("query").short_flag('Q').args([
                 Arg::new("title")
                     .short('t')
@@ -727,73 +1332,34 @@ async fn track(
                     .value_name("CODE")
                     .about("Only shows playlists containing CODE")
                     .multiple_occurrences(true),
+                Arg::new("deleted")
+                    .long("deleted")
+                    .about("Shows deleted tracks instead"),
+                Arg::new("fuzzy")
+                    .short('f')
+                    .long("fuzzy")
+                    .about("Also shows tracks with titles similar to TITLE, best matches first"),
             ]),
         ])
         .try_get_matches_from(args.iter());
     unwrap_matches!(matches, out);
 
-    let mut db = match bot.db.acquire().await {
-        Ok(v) => v,
-        Err(e) => {
-            writeln!(out, "failed to acquire database connection: {}", e).unwrap();
-            return Ok(());
-        }
-    };
+    let mut db = bot.db.acquire().await?;
 
     match matches.subcommand() {
         Some(("create", matches)) => {
             let name = matches.value_of("name");
             let code = matches.value_of("code");
             let path = matches.value_of("path");
-            let youtube = matches.value_of("youtube");
+            let url = matches.value_of("youtube").or(matches.value_of("url"));
 
             let mut track = Track::new();
 
             if let Some(path) = path {
                 let _ = path;
-                writeln!(out, "importing from a path is unimplemented!").unwrap();
-                return Ok(());
-            } else if let Some(youtube) = youtube {
-                let url = match Url::parse(youtube) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        writeln!(out, "failed to parse URL: {}", e).unwrap();
-                        return Ok(());
-                    }
-                };
-
-                if (url.domain() == Some("www.youtube.com") || url.domain() == Some("youtube.com"))
-                    && url.path() == "/watch"
-                {
-                    let mut video = None;
-
-                    for (k, v) in url.query_pairs() {
-                        if k == "v" {
-                            video = Some(v);
-                        }
-                    }
-
-                    if let Some(video) = video {
-                        let res: Result<_, ImportError> =
-                            Track::import_by_youtube_id(&video, &mut *db).await;
-
-                        match res {
-                            Ok(v) => {
-                                track = v;
-                            }
-                            Err(e) => {
-                                writeln!(out, "failed to import track: {}", e).unwrap();
-                                return Ok(());
-                            }
-                        }
-                    } else {
-                        writeln!(out, "could not parse YouTube video URL").unwrap();
-                        return Ok(());
-                    }
-                } else {
-                    writeln!(out, "don't know how to parse this URL").unwrap();
-                    return Ok(());
-                }
+                return Err(Error::user("importing from a path is unimplemented!"));
+            } else if let Some(url) = url {
+                track = import_url(bot, url, &mut *db, out).await?;
             }
 
             if track.object().id().is_some() {
@@ -808,12 +1374,15 @@ async fn track(
                     track.set_title(Some(name.to_string()));
                 }
 
-                if let Err(e) = track.save(&mut *db).await {
-                    writeln!(out, "failed to save track: {}", e).unwrap();
-                    return Ok(());
+                match track.save(&mut *db).await {
+                    Err(objgen::Error::CodeTaken(code)) => {
+                        let existing = object::Track::load_by_code_any(&code, &mut *db).await?;
+                        return Err(code_taken(&code, existing.html()));
+                    }
+                    result => result?,
                 }
 
-                if youtube.is_some() {
+                if url.is_some() {
                     writeln!(out, "imported {}", track.html()).unwrap();
                 } else {
                     writeln!(out, "created {}", track.html()).unwrap();
@@ -823,22 +1392,31 @@ async fn track(
         Some(("modify", matches)) => {
             let code = matches.value_of("code").unwrap();
             let title = matches.value_of("title");
-
-            let mut track = match Track::load_by_code(code, &mut *db).await {
-                Ok(v) => v,
-                Err(e) => {
-                    writeln!(out, "failed to load playlist <code>{}</code>: {}", code, e).unwrap();
-                    return Ok(());
-                }
+            let gain = match matches.value_of("gain") {
+                None => None,
+                Some(gain) => match gain.parse::<f64>() {
+                    Ok(gain) if gain.is_finite() => Some(gain),
+                    _ => return Err(Error::user(format!("invalid gain: {}", gain))),
+                },
             };
 
+            let mut track = Track::load_by_code(code, &mut *db)
+                .await
+                .map_err(Error::not_found("track", code))?;
+
             if let Some(title) = title {
                 track.set_title(Some(title.to_string()));
             }
 
-            if let Err(e) = track.save(&mut *db).await {
-                writeln!(out, "failed to save track: {}", e).unwrap();
-                return Ok(());
+            if let Some(gain) = gain {
+                track.set_gain_db(if gain == 0.0 { None } else { Some(gain) });
+            }
+
+            save_merging(&mut track, &mut *db, out).await?;
+
+            if gain.is_some() {
+                let applied = track.object().gain_db().unwrap_or(0.0);
+                writeln!(out, "gain of {} is now {:+.1} dB", track.html(), applied).unwrap();
             }
         }
         Some(("delete", matches)) => {
@@ -859,37 +1437,61 @@ async fn track(
                 writeln!(out, "deleted track {}", track.html()).unwrap();
             }
         }
-        Some(("query", matches)) => {
-            let mut query = "SELECT * FROM track WHERE deleted = false".to_string();
-            let mut argn = 1;
-            let mut args = PgArguments::default();
-
+        Some(("restore", matches)) => {
             for code in matches.values_of("code").into_iter().flatten() {
-                writeln!(query, " AND code LIKE ${}", argn).unwrap();
-                argn += 1;
-                args.add(format!("%{}%", code));
-            }
+                let mut track = match object::Track::load_by_code_any(code, &mut *db).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        writeln!(out, "failed to load track {}: {}", code, e).unwrap();
+                        continue;
+                    }
+                };
+
+                if !track.deleted() {
+                    writeln!(out, "track {} is not deleted", track.html()).unwrap();
+                    continue;
+                }
+
+                if let Err(e) = track.restore(&mut *db).await {
+                    writeln!(out, "failed to restore track {}: {}", code, e).unwrap();
+                    continue;
+                }
 
-            for code in matches.values_of("title").into_iter().flatten() {
-                writeln!(query, " AND title LIKE ${}", argn).unwrap();
-                argn += 1;
-                args.add(format!("%{}%", code));
+                writeln!(out, "restored track {}", track.html()).unwrap();
             }
+        }
+        Some(("reimport", matches)) => {
+            let code = matches.value_of("code").unwrap();
 
-            writeln!(query, " ORDER BY code").unwrap();
+            let mut track = Track::load_by_code(code, &mut *db)
+                .await
+                .map_err(Error::not_found("track", code))?;
 
-            let mut stream = sqlx::query_as_with(&query, args).fetch(&mut *db);
+            track.reimport_from_youtube(&mut *db).await?;
 
-            while let Some(res) = stream.next().await {
-                let t: object::Track = match res {
-                    Ok(v) => v,
-                    Err(e) => {
-                        writeln!(out, "failed to load track: {}", e).unwrap();
-                        return Ok(());
+            writeln!(out, "updated {}", track.html()).unwrap();
+        }
+        Some(("query", matches)) => {
+            let (query, args) = TitleQuery::new("track")
+                .deleted(matches.is_present("deleted"))
+                .codes(matches.values_of("code").into_iter().flatten())
+                .titles(matches.values_of("title").into_iter().flatten())
+                .fuzzy(matches.is_present("fuzzy"))
+                .build_args();
+
+            let tracks: Vec<object::Track> = sqlx::query_as_with(&query, args)
+                .fetch_all(&mut *db)
+                .await?;
+            let ids: Vec<_> = tracks.iter().filter_map(|t| t.id()).collect();
+            let mut tags = Track::load_tags(&ids, &mut *db).await?;
+
+            for t in tracks.iter() {
+                match t.id().and_then(|id| tags.remove(&id)) {
+                    None => writeln!(out, "{}", t.html()).unwrap(),
+                    Some(tags) => {
+                        writeln!(out, "{} ({})", t.html(), tags::format_tags(&tags)).unwrap()
                     }
-                };
-
-                writeln!(out, "{}", t.html()).unwrap();
+                }
             }
         }
         _ => unreachable!(),
@@ -898,6 +1500,135 @@ async fn track(
     Ok(())
 }
 
+async fn tag(bot: &Bot, ev: &mumble::event::Message, args: &[String], out: &mut String) -> Result {
+    let matches = app_for_command("tag")
+        .about("Tag tracks to find them with search --tag")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommands([
+            app_for_command("add").about("Add tags to a track").args([
+                Arg::new("code")
+                    .value_name("CODE")
+                    .about("The code of the track to tag")
+                    .required(true),
+                Arg::new("tag")
+                    .value_name("TAG")
+                    .about("The tags to add")
+                    .required(true)
+                    .multiple_values(true),
+            ]),
+            app_for_command("remove")
+                .about("Remove tags from a track")
+                .args([
+                    Arg::new("code")
+                        .value_name("CODE")
+                        .about("The code of the track")
+                        .required(true),
+                    Arg::new("tag")
+                        .value_name("TAG")
+                        .about("The tags to remove")
+                        .required(true)
+                        .multiple_values(true),
+                ]),
+        ])
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+
+    let (add, matches) = match matches.subcommand() {
+        Some(("add", matches)) => (true, matches),
+        Some(("remove", matches)) => (false, matches),
+        _ => unreachable!(),
+    };
+
+    let code = matches.value_of("code").unwrap();
+    let names = matches
+        .values_of("tag")
+        .unwrap()
+        .map(|tag| {
+            tags::normalize_tag(tag).ok_or_else(|| {
+                Error::user(format!(
+                    "invalid tag <code>{}</code>, tags can't contain spaces",
+                    html_escape::encode_text(tag)
+                ))
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut db = bot.db.acquire().await?;
+
+    let track = Track::load_by_code(code, &mut *db)
+        .await
+        .map_err(Error::not_found("track", code))?;
+
+    for name in names.iter() {
+        let changed = if add {
+            track.add_tag(name, &mut *db).await?
+        } else {
+            track.remove_tag(name, &mut *db).await?
+        };
+
+        if !changed {
+            let state = if add { "already has" } else { "does not have" };
+            writeln!(out, "{} {} tag <code>{}</code>", track.html(), state, name).unwrap();
+        }
+    }
+
+    let tags = track.tags(&mut *db).await?;
+    writeln!(
+        out,
+        "tags of {}: {}",
+        track.html(),
+        tags::format_tags(&tags)
+    )
+    .unwrap();
+
+    Ok(())
+}
+
+async fn queue(
+    bot: &Bot,
+    ev: &mumble::event::Message,
+    args: &[String],
+    out: &mut String,
+) -> Result {
+    let matches = app_for_command("queue")
+        .about("Add tracks to the queue")
+        .args(&[Arg::new("track")
+            .value_name("TRACK")
+            .about("The code of the track, or a YouTube or Spotify link to import it from")
+            .required(true)
+            .multiple_values(true)])
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+
+    let mut db = bot.db.acquire().await?;
+    let requested_by = actor_name(bot, ev).await?;
+
+    for arg in matches.values_of("track").unwrap() {
+        let track = if Url::parse(arg).is_ok() {
+            let mut track = import_url(bot, arg, &mut *db, out).await?;
+
+            if track.object().id().is_none() {
+                track.save(&mut *db).await?;
+                writeln!(out, "imported {}", track.html()).unwrap();
+            }
+
+            track
+        } else {
+            Track::load_by_code(arg, &mut *db)
+                .await
+                .map_err(Error::not_found("track", arg))?
+        };
+
+        writeln!(out, "queued {}", track.html()).unwrap();
+        bot.room
+            .proxy()
+            .add_to_queue(track, requested_by.clone())
+            .await?;
+    }
+
+    Ok(())
+}
+
 async fn web(
     bot: &mut Bot,
     ev: &mumble::event::Message,
@@ -915,13 +1646,21 @@ async fn web(
         let user = match user {
             None => {
                 // wtf
-                writeln!(out, "couldn't find your user data, please reconnect").unwrap();
-                return Ok(());
+                return Err(Error::user(
+                    "couldn't find your user data, please reconnect",
+                ));
             }
             Some(v) => v,
         };
 
-        let token = Uuid::new_v4();
+        let registered_id = match user.registered_id() {
+            None => return Err(Error::PermissionDenied),
+            Some(v) => v,
+        };
+
+        let session = bot.auth.create_session(registered_id).await?;
+
+        let token = session.id();
 
         debug!("login token {} for user {}", token, user.name());
 
@@ -960,6 +1699,552 @@ async fn quit(
     Ok(())
 }
 
+async fn search(
+    bot: &Bot,
+    ev: &mumble::event::Message,
+    args: &[String],
+    out: &mut String,
+) -> Result {
+    let matches = app_for_command("search")
+        .about("Search tracks and playlists by title")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            App::new("db")
+                .about("Search titles by similarity, for when the exact words aren't known")
+                .arg(
+                    Arg::new("terms")
+                        .value_name("TERMS")
+                        .about("The words to search for")
+                        .required(true)
+                        .multiple_values(true),
+                ),
+        )
+        .args(&[
+            Arg::new("query")
+                .value_name("QUERY")
+                .about("The words to search for")
+                .required_unless_present("tag")
+                .multiple_values(true),
+            Arg::new("tag")
+                .long("tag")
+                .value_name("NAME")
+                .about("Only show tracks with this tag"),
+            Arg::new("page")
+                .long("page")
+                .value_name("N")
+                .about("Show page N of the results")
+                .default_value("1"),
+        ])
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+
+    if let Some(matches) = matches.subcommand_matches("db") {
+        let terms = matches
+            .values_of("terms")
+            .unwrap()
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let mut db = bot.db.acquire().await?;
+        let results = search::fuzzy_search(&terms, &mut *db).await?;
+
+        if results.is_empty() {
+            writeln!(out, "no results").unwrap();
+        }
+
+        for (kind, code, title) in results {
+            writeln!(
+                out,
+                "<code>{}</code> {} ({})",
+                html_escape::encode_text(&code),
+                html_escape::encode_text(&title),
+                kind
+            )
+            .unwrap();
+        }
+
+        return Ok(());
+    }
+
+    let query = matches
+        .values_of("query")
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let tag = match matches.value_of("tag") {
+        None => None,
+        Some(tag) => {
+            Some(tags::normalize_tag(tag).ok_or_else(|| Error::user("tags can't contain spaces"))?)
+        }
+    };
+    let page: u32 = match matches.value_of("page").unwrap().parse() {
+        Ok(page) if page > 0 => page,
+        _ => return Err(Error::user("page must be a positive number")),
+    };
+
+    let mut db = bot.db.acquire().await?;
+    let results = search::search(&query, tag.as_deref(), page, &mut *db).await?;
+
+    if results.is_empty() {
+        writeln!(out, "no results").unwrap();
+        return Ok(());
+    }
+
+    let max_len = bot.client.max_message_length().await?;
+    let text = search::format_results(&results, page, max_len.map(|l| l as usize));
+    writeln!(out, "{}", text).unwrap();
+
+    Ok(())
+}
+
+async fn loglevel(
+    bot: &Bot,
+    ev: &mumble::event::Message,
+    args: &[String],
+    out: &mut String,
+) -> Result {
+    let matches = app_for_command("loglevel")
+        .about("Change the log level at runtime")
+        .args(&[
+            Arg::new("module")
+                .short('m')
+                .long("module")
+                .value_name("MODULE")
+                .about("The module to change the level for, instead of the default level"),
+            Arg::new("level")
+                .value_name("LEVEL")
+                .required(true)
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                .about("The new log level"),
+        ])
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+
+    require_admin(bot, ev).await?;
+
+    let level = matches.value_of("level").unwrap().parse().unwrap();
+
+    match matches.value_of("module") {
+        None => {
+            bot.log.set_default_level(level);
+            writeln!(out, "Default log level is now {}", level_str(level)).unwrap();
+        }
+        Some(module) => {
+            bot.log.set_level(module, level);
+            writeln!(
+                out,
+                "Log level for {} is now {}",
+                html_escape::encode_text(module),
+                level_str(level)
+            )
+            .unwrap();
+        }
+    }
+
+    Ok(())
+}
+
+async fn debug(
+    bot: &Bot,
+    ev: &mumble::event::Message,
+    args: &[String],
+    out: &mut String,
+) -> Result {
+    let matches = app_for_command("debug")
+        .about("Show diagnostic information")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommands([
+            App::new("latency").about("Estimate the latency of the audio pipeline"),
+            App::new("encoder").about("Show how many voice packets were sent"),
+            App::new("graph").about("Show the audio graph in Graphviz DOT format"),
+            App::new("route").about("Show where the current track's audio goes"),
+        ])
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+
+    match matches.subcommand() {
+        Some(("latency", _)) => {
+            let latency = bot.audio.latency();
+            let frame = bot.client.audio_frame_length().await?;
+
+            writeln!(
+                out,
+                "input buffer: {} (of {})",
+                fmt_ms(latency.input),
+                fmt_ms(latency.input_capacity)
+            )
+            .unwrap();
+            writeln!(out, "mixer tick: {}", fmt_ms(latency.tick)).unwrap();
+            writeln!(
+                out,
+                "output buffer: {} (of {})",
+                fmt_ms(latency.output),
+                fmt_ms(latency.output_capacity)
+            )
+            .unwrap();
+            writeln!(out, "encoder frame: {}", fmt_ms(frame)).unwrap();
+            writeln!(out, "total: {}", fmt_ms(latency.total() + frame)).unwrap();
+        }
+        Some(("encoder", _)) => {
+            let stats = bot.client.encoder_stats().await?;
+
+            writeln!(out, "packets sent: {}", stats.packets_sent).unwrap();
+            writeln!(
+                out,
+                "bytes sent: {:.1} KiB",
+                stats.bytes_sent as f64 / 1024.0
+            )
+            .unwrap();
+            writeln!(out, "underruns: {}", stats.underruns).unwrap();
+        }
+        Some(("graph", _)) => {
+            require_admin(bot, ev).await?;
+
+            let dot = bot.audio.dump_graph();
+            writeln!(out, "<pre>{}</pre>", html_escape::encode_text(&dot)).unwrap();
+        }
+        Some(("route", _)) => {
+            require_admin(bot, ev).await?;
+
+            let mumble_input = bot.client.audio_input().await?;
+
+            match bot.room.proxy().player_node().await? {
+                None => writeln!(out, "player: no track loaded").unwrap(),
+                Some(node) => {
+                    write_node_info(out, "player", node, &bot.audio);
+
+                    let status = if bot.audio.is_connected(node, mumble_input) {
+                        "connected"
+                    } else {
+                        "<b>not connected</b>"
+                    };
+
+                    writeln!(out, "player to Mumble input: {}", status).unwrap();
+                }
+            }
+
+            write_node_info(out, "Mumble input", mumble_input, &bot.audio);
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// Writes a line describing the audio graph node `node` for `;debug route`.
+fn write_node_info(out: &mut String, name: &str, node: NodeIndex, audio: &Core) {
+    let info = match audio.node_info(node) {
+        None => {
+            writeln!(out, "{}: node {} (not in graph)", name, node.index()).unwrap();
+            return;
+        }
+        Some(v) => v,
+    };
+
+    let outputs: Vec<_> = info.outputs.iter().map(|n| n.index().to_string()).collect();
+
+    writeln!(
+        out,
+        "{}: node {} ({:?}{}), {}/{} frames buffered, plays into [{}]",
+        name,
+        node.index(),
+        info.kind,
+        if info.running { "" } else { ", stopped" },
+        info.buffered,
+        info.capacity,
+        outputs.join(", ")
+    )
+    .unwrap();
+}
+
+async fn cache(
+    bot: &Bot,
+    ev: &mumble::event::Message,
+    args: &[String],
+    out: &mut String,
+) -> Result {
+    let matches = app_for_command("cache")
+        .about("Manage the media cache")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommands([App::new("dedupe").about("Merge cached files with identical content")])
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+
+    match matches.subcommand() {
+        Some(("dedupe", _)) => {
+            let stats = MediaCache::new(bot.db.clone(), bot.config.cache_size_bytes())
+                .dedupe()
+                .await?;
+
+            writeln!(
+                out,
+                "checked {} files, merged {}, reclaimed {:.1} MiB",
+                stats.files,
+                stats.merged,
+                stats.reclaimed as f64 / (1024.0 * 1024.0)
+            )
+            .unwrap();
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+async fn users(
+    bot: &Bot,
+    ev: &mumble::event::Message,
+    args: &[String],
+    out: &mut String,
+) -> Result {
+    let matches = app_for_command("users")
+        .about("Show users of the server")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommands([App::new("registered").about("List the users registered on the server")])
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+
+    match matches.subcommand() {
+        Some(("registered", _)) => {
+            require_admin(bot, ev).await?;
+
+            // the request is dropped if the server refuses to answer it
+            let mut users = bot.client.request_user_list().await.map_err(|_| {
+                Error::user(
+                    "the server did not send the user list, the bot might not be allowed to see it",
+                )
+            })?;
+            users.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
+
+            for user in users.iter() {
+                let last_seen = match user.last_seen {
+                    None => "never".to_string(),
+                    Some(t) => t.format("%Y-%m-%d %H:%M").to_string(),
+                };
+
+                writeln!(
+                    out,
+                    "{} (#{}), last seen {}",
+                    html_escape::encode_text(&user.name),
+                    user.id,
+                    last_seen
+                )
+                .unwrap();
+            }
+
+            writeln!(out, "{} registered users", users.len()).unwrap();
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+async fn selftest(
+    bot: &Bot,
+    ev: &mumble::event::Message,
+    args: &[String],
+    out: &mut String,
+) -> Result {
+    let matches = app_for_command("selftest")
+        .about("Play a test tone through the server's loopback and check that it comes back")
+        .try_get_matches_from(args.iter());
+    unwrap_matches!(matches, out);
+    require_admin(bot, ev).await?;
+
+    let input = bot.client.audio_input().await?;
+
+    // mute the room while testing, music playing would make the returned
+    // audio look fine even if the tone never made it through
+    let room = bot.room.proxy();
+    let was_muted = room.muted().await?;
+    room.set_muted(true).await?;
+
+    let stats = run_selftest(bot, input).await;
+
+    room.set_muted(was_muted).await?;
+    let stats = stats?;
+
+    writeln!(out, "encoded: {} packets", stats.encoded).unwrap();
+    writeln!(out, "sent: {} packets", stats.sent).unwrap();
+    writeln!(out, "returned: {} packets", stats.received).unwrap();
+    writeln!(
+        out,
+        "decoded: {} packets, peak {:.3}",
+        stats.decoded, stats.peak
+    )
+    .unwrap();
+
+    let failed = if stats.encoded == 0 {
+        Some("encoder produced no audio")
+    } else if stats.sent == 0 {
+        Some("failed to send audio")
+    } else if stats.received == 0 {
+        Some("server did not send audio back")
+    } else if stats.decoded == 0 {
+        Some("returned audio could not be decoded")
+    } else if stats.peak < SELFTEST_MIN_PEAK {
+        Some("returned audio is silent")
+    } else {
+        None
+    };
+
+    match failed {
+        None => writeln!(out, "<b>self-test passed</b>").unwrap(),
+        Some(reason) => writeln!(out, "<b>self-test failed:</b> {}", reason).unwrap(),
+    }
+
+    Ok(())
+}
+
+/// Plays the self-test tone into `input` with the server's loopback turned
+/// on and returns what came back.
+async fn run_selftest(bot: &Bot, input: NodeIndex) -> Result<mumble::LoopbackStats> {
+    bot.client.start_loopback().await?;
+    bot.audio.add_signal(
+        Tone::new(bot.audio.sample_rate(), 440.0, 0.5, SELFTEST_TONE_LENGTH),
+        Some(input),
+    );
+    tokio::time::sleep(SELFTEST_TONE_LENGTH + SELFTEST_GRACE).await;
+    Ok(bot.client.stop_loopback().await?)
+}
+
+/// Saves an entity. If it was changed by someone else since it was loaded,
+/// both changes are merged and saving is tried again.
+async fn save_merging<E>(entity: &mut E, db: &mut PgConnection, out: &mut String) -> Result
+where
+    E: Entity + Send,
+{
+    match entity.save(&mut *db).await {
+        Err(objgen::Error::OutdatedState(_)) => {}
+        result => return Ok(result?),
+    }
+
+    let report = match entity.refresh_and_merge(&mut *db).await {
+        Ok(report) => report,
+        Err(objgen::Error::Conflict(c)) => {
+            return Err(Error::user(format!(
+                "it was changed by someone else in the meantime, with conflicting changes to {}",
+                c
+            )))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if !report.replayed.is_empty() {
+        writeln!(
+            out,
+            "merged with changes made in the meantime, applied your changes to {}",
+            report.replayed.join(", ")
+        )
+        .unwrap();
+    }
+
+    if !report.updated.is_empty() {
+        writeln!(out, "took over new {}", report.updated.join(", ")).unwrap();
+    }
+
+    entity.save(&mut *db).await?;
+
+    Ok(())
+}
+
+/// The error for when saving an object failed because `existing` has its
+/// code already.
+fn code_taken(code: &str, existing: impl std::fmt::Display) -> Error {
+    Error::user(format!(
+        "code '{}' is already used by {}",
+        html_escape::encode_text(code),
+        existing
+    ))
+}
+
+/// Returns the name of the user who sent the message, if it came from a
+/// user.
+async fn actor_name(bot: &Bot, ev: &mumble::event::Message) -> Result<Option<String>> {
+    let name = match ev.actor {
+        None => None,
+        Some(actor) => actor
+            .get(&*bot.client.state().await?)
+            .map(|user| user.name().to_string()),
+    };
+
+    Ok(name)
+}
+
+/// Loads or imports the track behind a YouTube or Spotify link. A newly
+/// imported track is returned unsaved.
+async fn import_url(
+    bot: &Bot,
+    url: &str,
+    db: &mut PgConnection,
+    out: &mut String,
+) -> Result<Track> {
+    let url = Url::parse(url).map_err(|e| Error::user(format!("failed to parse URL: {}", e)))?;
+
+    if (url.domain() == Some("www.youtube.com") || url.domain() == Some("youtube.com"))
+        && url.path() == "/watch"
+    {
+        let mut video = None;
+
+        for (k, v) in url.query_pairs() {
+            if k == "v" {
+                video = Some(v);
+            }
+        }
+
+        if let Some(video) = video {
+            Ok(Track::import_by_youtube_id(&video, db).await?)
+        } else {
+            Err(Error::user("could not parse YouTube video URL"))
+        }
+    } else if let Some(id) = spotify::parse_track_url(&url) {
+        let client = bot
+            .spotify
+            .as_ref()
+            .ok_or_else(|| Error::user("Spotify is not configured"))?;
+
+        let (track, video) = Track::import_by_spotify_id(id, client, db).await?;
+
+        if let Some(video) = video {
+            writeln!(
+                out,
+                "matched YouTube video <a href=\"https://www.youtube.com/watch?v={}\">{}</a> ({})",
+                html_escape::encode_double_quoted_attribute(&video.id),
+                html_escape::encode_text(&video.title),
+                video
+                    .duration
+                    .map_or("unknown length".to_string(), |d| FmtDuration(d).to_string()),
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "if this is the wrong video, change the YouTube provider of the track"
+            )
+            .unwrap();
+        }
+
+        Ok(track)
+    } else {
+        Err(Error::user("don't know how to parse this URL"))
+    }
+}
+
+fn parse_tree_path(s: &str) -> Result<TreePathBuf> {
+    TreePathBuf::from_str(s).map_err(|e| {
+        Error::user(format!(
+            "invalid path <code>{}</code>: {}",
+            html_escape::encode_text(s),
+            e
+        ))
+    })
+}
+
+fn fmt_ms(d: Duration) -> String {
+    format!("{:.1} ms", d.as_secs_f64() * 1000.0)
+}
+
 // TODO: make this in cmdparser public so I don't have to copy it
 /// Tokenize script source, removing comments (starting with `//`).
 /// Returns a list of command executions (command + arguments)
@@ -985,30 +2270,129 @@ fn tokenize(s: &str) -> Vec<Vec<String>> {
         current.clear();
     }
 
-    for line in s.lines() {
-        let get = |i| line.chars().nth(i);
-
-        for (pos, c) in line.chars().enumerate() {
-            if esc {
-                sb.push(c);
-                esc = false;
-            // } else if !quoted && c == '/' && get(pos + 1) == Some('/') {
-            //     break;
-            } else if !quoted && c == ';' {
-                next_command(&mut sb, &mut current, &mut commands);
-            } else if !quoted && c == ' ' {
-                next_token(&mut sb, &mut current);
-            } else if c == '"' {
-                quoted = !quoted;
-            } else if c == '\\' {
-                esc = true;
+    let chars: Vec<char> = s.chars().collect();
+
+    for (pos, &c) in chars.iter().enumerate() {
+        if esc {
+            sb.push(c);
+            esc = false;
+        // } else if !quoted && c == '/' && chars.get(pos + 1) == Some(&'/') {
+        //     break;
+        } else if c == '\r' && chars.get(pos + 1) == Some(&'\n') {
+            // handled with the following \n
+        } else if c == '\n' || c == '\r' {
+            if quoted {
+                sb.push(' ');
             } else {
-                sb.push(c);
+                next_command(&mut sb, &mut current, &mut commands);
             }
+        } else if !quoted && c == ';' {
+            next_command(&mut sb, &mut current, &mut commands);
+        } else if !quoted && c == ' ' {
+            next_token(&mut sb, &mut current);
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == '\\' {
+            esc = true;
+        } else {
+            sb.push(c);
         }
-
-        next_command(&mut sb, &mut current, &mut commands);
     }
 
+    next_command(&mut sb, &mut current, &mut commands);
+
     commands
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use msgtools::proxy;
+
+    use crate::player::Room1Like;
+    use crate::Error;
+
+    use super::{change_volume, tokenize};
+
+    fn cmd(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// A room that only knows its volume, and records the calls changing it.
+    #[derive(Default)]
+    struct MockRoom {
+        volume: Mutex<f32>,
+        calls: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Room1Like for MockRoom {
+        async fn set_volume(&self, volume: f32) -> proxy::Result {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("set_volume {}", volume));
+            *self.volume.lock().unwrap() = volume;
+            Ok(())
+        }
+
+        async fn adjust_volume(&self, delta: f32) -> proxy::Result<f32> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("adjust_volume {}", delta));
+            let mut volume = self.volume.lock().unwrap();
+            *volume = (*volume + delta).clamp(0.0, 2.0);
+            Ok(*volume)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_change_volume() {
+        let room = MockRoom::default();
+
+        assert_eq!(0.5, change_volume(&room, "50").await.unwrap());
+        assert_eq!(0.75, change_volume(&room, "+25").await.unwrap());
+        assert_eq!(0.25, change_volume(&room, "-50").await.unwrap());
+        assert_eq!(
+            vec!["set_volume 0.5", "adjust_volume 0.25", "adjust_volume -0.5"],
+            *room.calls.lock().unwrap()
+        );
+
+        // invalid values don't get to the room
+        assert!(matches!(
+            change_volume(&room, "250").await,
+            Err(Error::User(_))
+        ));
+        assert!(matches!(
+            change_volume(&room, "+a").await,
+            Err(Error::User(_))
+        ));
+        assert_eq!(3, room.calls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            vec![cmd(&["skip"]), cmd(&["playlist", "-Q", "-t", "a b"])],
+            tokenize("skip; playlist -Q -t \"a b\"")
+        );
+        assert_eq!(
+            vec![cmd(&["play"]), cmd(&["pause"])],
+            tokenize("play\r\npause\n")
+        );
+    }
+
+    #[test]
+    fn test_tokenize_quoted_newline() {
+        assert_eq!(
+            vec![
+                cmd(&["track", "-M", "1", "-n", "first line second line"]),
+                cmd(&["skip"])
+            ],
+            tokenize("track -M 1 -n \"first line\nsecond line\"\nskip")
+        );
+    }
+}
@@ -0,0 +1,34 @@
+use sqlx::PgConnection;
+
+use crate::entity::Track;
+
+/// Returns up to 5 random tracks which share the genre or an artist with
+/// `track`.
+pub async fn find_similar_tracks(track: &Track, db: &mut PgConnection) -> sqlx::Result<Vec<Track>> {
+    let object = track.object();
+
+    let id = match object.id() {
+        None => return Ok(Vec::new()),
+        Some(v) => v,
+    };
+
+    let rows = sqlx::query!(
+        // language=SQL
+        "SELECT t.id FROM track t \
+         WHERE t.id != $1 AND t.deleted = FALSE \
+         AND (t.genre = $2 OR EXISTS( \
+             SELECT 1 FROM track_artist a \
+             JOIN track_artist b ON a.artist = b.artist \
+             WHERE a.track = t.id AND b.track = $1)) \
+         ORDER BY random() \
+         LIMIT 5",
+        id,
+        object.genre()
+    )
+    .fetch_all(&mut *db)
+    .await?;
+
+    let ids: Vec<_> = rows.into_iter().map(|row| row.id).collect();
+
+    Track::load_batch(&ids, db).await
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use sqlx::PgConnection;
+
+use crate::db::entity::playlist::PlayCount;
+use crate::db::entity::Playlist;
+use crate::fmt::HtmlDisplayExt;
+use crate::FmtDuration;
+
+/// Number of tracks listed as the most played ones.
+const TOP_TRACKS: usize = 5;
+
+#[derive(Debug, Clone)]
+pub struct PlaylistStats {
+    /// Number of tracks in the playlist and its nested playlists, counting
+    /// tracks that appear more than once each time.
+    pub tracks: usize,
+    /// The sum of the lengths of all tracks with a known length.
+    pub duration: Duration,
+    /// Number of tracks whose length is unknown, because they were never
+    /// played.
+    pub unknown_duration: usize,
+    /// The most played tracks, most played first.
+    pub top: Vec<PlayCount>,
+}
+
+pub async fn playlist_stats(
+    playlist: &Playlist,
+    db: &mut PgConnection,
+) -> sqlx::Result<PlaylistStats> {
+    let counts = playlist.play_count(db).await?;
+
+    let known: HashMap<_, _> = counts
+        .iter()
+        .filter_map(|c| Some((c.track.object().id()?, c.duration?)))
+        .collect();
+    let durations: Vec<_> = playlist
+        .tracks_deep()
+        .into_iter()
+        .map(|t| t.object().id().and_then(|id| known.get(&id).copied()))
+        .collect();
+
+    Ok(collect_stats(&durations, counts))
+}
+
+/// Sums up `durations`, which has an entry for each track in the playlist,
+/// and picks the most played tracks from `counts`.
+fn collect_stats(durations: &[Option<Duration>], mut counts: Vec<PlayCount>) -> PlaylistStats {
+    let duration = durations.iter().flatten().sum();
+    let unknown_duration = durations.iter().filter(|d| d.is_none()).count();
+
+    // stable, so tracks played equally often stay in playlist order
+    counts.sort_by(|a, b| b.count.cmp(&a.count));
+    counts.retain(|c| c.count > 0);
+    counts.truncate(TOP_TRACKS);
+
+    PlaylistStats {
+        tracks: durations.len(),
+        duration,
+        unknown_duration,
+        top: counts,
+    }
+}
+
+pub fn format_stats(playlist: &Playlist, stats: &PlaylistStats) -> String {
+    let mut s = String::new();
+
+    writeln!(s, "Statistics for {}:", playlist.html()).unwrap();
+    write!(
+        s,
+        "{} tracks, total length {}",
+        stats.tracks,
+        FmtDuration(stats.duration)
+    )
+    .unwrap();
+
+    if stats.unknown_duration > 0 {
+        write!(
+            s,
+            " <i>(not counting {} tracks that were never played)</i>",
+            stats.unknown_duration
+        )
+        .unwrap();
+    }
+
+    s.push('\n');
+
+    if stats.top.is_empty() {
+        s.push_str("None of the tracks were played yet.");
+        return s;
+    }
+
+    s.push_str("<table><tr><th>Plays</th><th>Track</th></tr>");
+
+    for c in stats.top.iter() {
+        write!(
+            s,
+            "<tr><td align=\"right\">{}</td><td>{}</td></tr>",
+            c.count,
+            c.track.html()
+        )
+        .unwrap();
+    }
+
+    s.push_str("</table>");
+
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::db::entity::playlist::PlayCount;
+    use crate::db::entity::Track;
+
+    use super::{collect_stats, TOP_TRACKS};
+
+    #[test]
+    fn test_collect_stats() {
+        let mut counts = Vec::new();
+
+        for i in 0..TOP_TRACKS + 2 {
+            let mut track = Track::new();
+            track.set_title(Some(format!("Track {}", i)));
+
+            counts.push(PlayCount {
+                track,
+                count: i as u64 % 4,
+                duration: None,
+            });
+        }
+
+        let minute = Some(Duration::from_secs(60));
+        let durations = [minute, None, minute, minute, None];
+        let stats = collect_stats(&durations, counts);
+
+        assert_eq!(5, stats.tracks);
+        assert_eq!(2, stats.unknown_duration);
+        assert_eq!(Duration::from_secs(3 * 60), stats.duration);
+
+        let top: Vec<_> = stats.top.iter().map(|c| c.track.title().unwrap()).collect();
+        assert_eq!(
+            vec!["Track 3", "Track 2", "Track 6", "Track 1", "Track 5"],
+            top
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use std::fmt::Write;
+
+use sqlx::{PgConnection, Row};
+
+use crate::db::object;
+use crate::db::query;
+use crate::fmt::HtmlDisplayExt;
+
+/// Number of tracks and of playlists shown per page.
+pub const PAGE_SIZE: usize = 10;
+
+/// Room left at the end of a message for closing the table and the notes
+/// below it.
+const TRAILER_RESERVE: usize = 200;
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchResults {
+    pub tracks: Vec<object::Track>,
+    pub playlists: Vec<object::Playlist>,
+    /// Whether there are more results on the next page.
+    pub more: bool,
+}
+
+impl SearchResults {
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty() && self.playlists.is_empty()
+    }
+}
+
+/// Searches the titles of tracks and playlists. `page` starts at 1. With a
+/// `tag`, only tracks with that tag are found, and no playlists.
+pub async fn search(
+    query: &str,
+    tag: Option<&str>,
+    page: u32,
+    db: &mut PgConnection,
+) -> sqlx::Result<SearchResults> {
+    let offset = (page.saturating_sub(1) as usize * PAGE_SIZE) as i64;
+    // fetch one more than needed to find out if there's another page
+    let limit = PAGE_SIZE as i64 + 1;
+
+    let mut tracks = object::Track::search(query, tag, limit, offset, &mut *db).await?;
+    let mut playlists = match tag {
+        None => object::Playlist::search(query, limit, offset, &mut *db).await?,
+        Some(_) => Vec::new(),
+    };
+
+    let more = tracks.len() > PAGE_SIZE || playlists.len() > PAGE_SIZE;
+    tracks.truncate(PAGE_SIZE);
+    playlists.truncate(PAGE_SIZE);
+
+    Ok(SearchResults {
+        tracks,
+        playlists,
+        more,
+    })
+}
+
+/// Number of results shown by `;search db`.
+pub const FUZZY_LIMIT: u32 = 10;
+
+/// Finds the tracks and playlists whose title is most similar to `terms`.
+/// Returns the kind (`track` or `playlist`), code and title of each.
+pub async fn fuzzy_search(
+    terms: &str,
+    db: &mut PgConnection,
+) -> sqlx::Result<Vec<(String, String, String)>> {
+    let (sql, binds) = query::fuzzy_title_query(terms, FUZZY_LIMIT);
+
+    sqlx::query_with(&sql, query::to_args(binds))
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .map(|row| {
+            let title: Option<String> = row.try_get("title")?;
+            Ok((
+                row.try_get("kind")?,
+                row.try_get("code")?,
+                title.unwrap_or_default(),
+            ))
+        })
+        .collect()
+}
+
+/// Formats the results as a table. Rows that would make the text longer than
+/// `max_len` are left out.
+pub fn format_results(results: &SearchResults, page: u32, max_len: Option<usize>) -> String {
+    let rows = results
+        .tracks
+        .iter()
+        .map(|t| format!("<tr><td>track</td><td>{}</td></tr>", t.html()))
+        .chain(
+            results
+                .playlists
+                .iter()
+                .map(|pl| format!("<tr><td>playlist</td><td>{}</td></tr>", pl.html())),
+        )
+        .collect::<Vec<_>>();
+
+    let mut s = String::from("<table><tr><th>Type</th><th>Result</th></tr>");
+    let mut omitted = 0;
+
+    for (idx, row) in rows.iter().enumerate() {
+        if let Some(max_len) = max_len {
+            if s.len() + row.len() + TRAILER_RESERVE > max_len {
+                omitted = rows.len() - idx;
+                break;
+            }
+        }
+
+        s.push_str(row);
+    }
+
+    s.push_str("</table>");
+
+    if omitted > 0 {
+        write!(
+            s,
+            "\n<i>({} results omitted, the message would be too long)</i>",
+            omitted
+        )
+        .unwrap();
+    }
+
+    if results.more {
+        write!(
+            s,
+            "\npage {}, use <code>--page {}</code> to see more",
+            page,
+            page + 1
+        )
+        .unwrap();
+    }
+
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use crate::db::object;
+
+    use super::{format_results, SearchResults};
+
+    fn results() -> SearchResults {
+        let track = |code: &str, title: &str| {
+            let mut t = object::Track::new();
+            t.set_code(code);
+            t.set_title(Some(title.to_string()));
+            t
+        };
+
+        let mut playlist = object::Playlist::new();
+        playlist.set_code("00001");
+        playlist.set_title("Night Drive");
+
+        SearchResults {
+            tracks: vec![track("00000001", "Drive"), track("00000002", "Night Drive")],
+            playlists: vec![playlist],
+            more: true,
+        }
+    }
+
+    #[test]
+    fn test_format_results() {
+        let text = format_results(&results(), 1, None);
+
+        assert_eq!(
+            "<table><tr><th>Type</th><th>Result</th></tr>\
+             <tr><td>track</td><td><code>00000001</code> Drive</td></tr>\
+             <tr><td>track</td><td><code>00000002</code> Night Drive</td></tr>\
+             <tr><td>playlist</td><td><code>00001</code> Night Drive</td></tr>\
+             </table>\n\
+             page 1, use <code>--page 2</code> to see more",
+            text
+        );
+    }
+
+    #[test]
+    fn test_format_results_truncated() {
+        let text = format_results(&results(), 1, Some(350));
+
+        assert!(text.contains("00000001"));
+        assert!(!text.contains("00000002"));
+        assert!(text.contains("(2 results omitted"));
+        assert!(text.len() <= 350);
+    }
+}
@@ -0,0 +1,46 @@
+/// Turns a tag name given in a command into the form it is stored in, or
+/// returns `None` if it isn't a valid tag name. Tags are lowercase and can't
+/// contain whitespace.
+pub fn normalize_tag(tag: &str) -> Option<String> {
+    let tag = tag.trim();
+
+    if tag.is_empty() || tag.contains(char::is_whitespace) {
+        return None;
+    }
+
+    Some(tag.to_lowercase())
+}
+
+/// Formats a list of tags as HTML.
+pub fn format_tags(tags: &[String]) -> String {
+    if tags.is_empty() {
+        return "<i>no tags</i>".to_string();
+    }
+
+    tags.iter()
+        .map(|tag| format!("<code>{}</code>", html_escape::encode_text(tag)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_tags, normalize_tag};
+
+    #[test]
+    fn test_normalize_tag() {
+        assert_eq!(Some("synthwave".to_string()), normalize_tag(" SynthWave"));
+        assert_eq!(None, normalize_tag(""));
+        assert_eq!(None, normalize_tag("night drive"));
+    }
+
+    #[test]
+    fn test_format_tags() {
+        let tags = vec!["chill".to_string(), "<b>".to_string()];
+        assert_eq!(
+            "<code>chill</code>, <code>&lt;b&gt;</code>",
+            format_tags(&tags)
+        );
+        assert_eq!("<i>no tags</i>", format_tags(&[]));
+    }
+}
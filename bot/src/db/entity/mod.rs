@@ -5,7 +5,16 @@ pub mod playlist;
 pub mod track;
 
 pub mod import {
+    use std::time::Duration;
+
+    use log::warn;
     use thiserror::Error;
+    use youtube_dl::{YoutubeDl, YoutubeDlOutput};
+
+    use crate::db::objgen;
+
+    /// How many times an import step is tried before giving up on it.
+    pub const MAX_ATTEMPTS: u32 = 3;
 
     #[derive(Debug, Error)]
     pub enum ImportError {
@@ -15,5 +24,51 @@ pub mod import {
         Sqlx(#[from] sqlx::Error),
         #[error("youtube-dl error: {0}")]
         YoutubeDl(#[from] youtube_dl::Error),
+        #[error("{0}")]
+        Spotify(#[from] crate::spotify::SpotifyError),
+        #[error("no YouTube video found for '{0}'")]
+        NoMatch(String),
+        #[error("the track can't be played from YouTube")]
+        NoYoutubeProvider,
+        #[error("{0}")]
+        Save(#[from] objgen::Error),
+    }
+
+    /// How long to wait before trying again after `attempt` (starting at 0)
+    /// failed.
+    pub fn backoff(attempt: u32) -> Duration {
+        Duration::from_millis(200 * 2u64.pow(attempt))
+    }
+
+    /// Runs youtube-dl, trying again with increasing delays if it fails, since
+    /// that is often only temporary (e.g. rate limiting).
+    pub async fn run_youtube_dl(ytdl: &YoutubeDl) -> Result<YoutubeDlOutput, youtube_dl::Error> {
+        let mut attempt = 0;
+
+        loop {
+            match ytdl.run() {
+                Ok(output) => return Ok(output),
+                Err(e) if attempt + 1 < MAX_ATTEMPTS => {
+                    warn!("youtube-dl failed, trying again: {}", e);
+                    tokio::time::sleep(backoff(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::time::Duration;
+
+        use super::backoff;
+
+        #[test]
+        fn test_backoff() {
+            assert_eq!(Duration::from_millis(200), backoff(0));
+            assert_eq!(Duration::from_millis(400), backoff(1));
+            assert_eq!(Duration::from_millis(800), backoff(2));
+        }
     }
 }
\ No newline at end of file
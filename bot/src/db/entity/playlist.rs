@@ -1,20 +1,47 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
+use std::time::Duration;
 
+use async_trait::async_trait;
 use futures::future::BoxFuture;
-use futures::{FutureExt, StreamExt};
-use sqlx::PgConnection;
+use futures::FutureExt;
+use log::warn;
+use sqlx::{Connection, PgConnection};
 use uuid::Uuid;
 
+use crate::db::object::playlist::NestingMode;
+use crate::db::objgen::{MergeReport, Merger};
 use crate::db::{entity, object, objgen};
 use crate::fmt::HtmlDisplay;
 use crate::player::treepath::TreePath;
 
+pub use shuffle_generator::SmartPlaylist;
+
 mod import;
+mod shuffle_generator;
 
 #[derive(Debug, Clone)]
 pub struct Playlist {
     object: object::Playlist,
     entries: Vec<PlaylistEntry>,
+    base: Option<Box<Base>>,
+}
+
+/// The state of a playlist as it was last loaded or saved, to find out which
+/// changes were made locally when merging, and which entries need to be
+/// written when saving.
+#[derive(Debug, Clone)]
+struct Base {
+    object: object::Playlist,
+    entries: Vec<EntryKey>,
+    /// The IDs of the entries, in the same order as `entries`.
+    entry_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum EntryKey {
+    Track(Option<Uuid>),
+    Playlist(Option<Uuid>),
 }
 
 impl Playlist {
@@ -22,6 +49,7 @@ impl Playlist {
         let mut pl = Playlist {
             object: object::Playlist::new(),
             entries: Vec::new(),
+            base: None,
         };
         pl.set_title("Playlist");
         pl
@@ -37,14 +65,17 @@ impl Playlist {
         Playlist::load_from(object, db).await
     }
 
-    fn load_from(object: object::Playlist, db: &mut PgConnection) -> BoxFuture<sqlx::Result<Self>> {
-        async move {
-            let mut playlist = Playlist::new();
-            playlist.object = object;
-            playlist.load_more(db).await?;
-            Ok(playlist)
-        }
-        .boxed()
+    /// Like [`Playlist::load_by_code`], but also finds the playlist if it has
+    /// been deleted.
+    pub async fn load_by_code_any(code: &str, db: &mut PgConnection) -> sqlx::Result<Self> {
+        let object = object::Playlist::load_by_code_any(code, db).await?;
+        Playlist::load_from(object, db).await
+    }
+
+    async fn load_from(object: object::Playlist, db: &mut PgConnection) -> sqlx::Result<Self> {
+        let mut loader = TreeLoader::default();
+        loader.load(&object, db).await?;
+        loader.build(object, &mut Vec::new())
     }
 }
 
@@ -65,21 +96,20 @@ impl Playlist {
         self.object.set_youtube_id(id);
     }
 
+    pub fn set_nesting_mode(&mut self, nesting_mode: NestingMode) {
+        self.object.set_nesting_mode(nesting_mode);
+    }
+
     pub fn push_track(&mut self, track: entity::Track) {
-        self.entries.push(PlaylistEntry {
-            id: Uuid::new_v4(),
-            content: Content::Track(track),
-        });
+        self.push_content(Content::Track(track));
     }
 
     pub fn push_playlist(&mut self, playlist: Playlist) {
-        self.entries.push(PlaylistEntry {
-            id: Uuid::new_v4(),
-            content: Content::Playlist(playlist),
-        });
+        self.push_content(Content::Playlist(playlist));
     }
 
     pub fn push_content(&mut self, content: Content) {
+        self.object.mark_changed();
         self.entries.push(PlaylistEntry {
             id: Uuid::new_v4(),
             content,
@@ -134,10 +164,70 @@ impl Playlist {
         }
     }
 
+    /// Removes the entry at `index` from the top level of the playlist.
+    pub fn remove_entry(&mut self, index: usize) -> Option<PlaylistEntry> {
+        if index >= self.entries.len() {
+            return None;
+        }
+
+        self.object.mark_changed();
+        Some(self.entries.remove(index))
+    }
+
+    /// Moves the entry at `from` so that it ends up at `to`. Returns whether
+    /// both indices were valid.
+    pub fn move_entry(&mut self, from: usize, to: usize) -> bool {
+        if from >= self.entries.len() || to >= self.entries.len() {
+            return false;
+        }
+
+        self.object.mark_changed();
+        let entry = self.entries.remove(from);
+        self.entries.insert(to, entry);
+        true
+    }
+
+    /// Creates a copy of this playlist that is saved as a new playlist and
+    /// can be edited without affecting the original.
+    ///
+    /// Sub-playlists are copied as well, recursively. A sub-playlist that
+    /// appears more than once ends up as a separate copy for each
+    /// occurrence, and a sub-playlist that is also used elsewhere is not
+    /// affected by edits to its copy. Tracks are not copied, the copy refers
+    /// to the same tracks as the original.
+    pub fn detach_deep(&self) -> Self {
+        let entries = self
+            .entries
+            .iter()
+            .map(|entry| PlaylistEntry {
+                id: Uuid::new_v4(),
+                content: match &entry.content {
+                    Content::Track(track) => Content::Track(track.clone()),
+                    Content::Playlist(playlist) => Content::Playlist(playlist.detach_deep()),
+                },
+            })
+            .collect();
+
+        Playlist {
+            object: self.object.detach_unlinked(),
+            entries,
+            base: None,
+        }
+    }
+
     pub fn entries(&self) -> &[PlaylistEntry] {
         &self.entries
     }
 
+    /// Returns the entries referring to a track or playlist that has been
+    /// deleted. Only looks at the top level, not into nested playlists.
+    pub fn deleted_entries(&self) -> impl Iterator<Item = &PlaylistEntry> {
+        self.entries.iter().filter(|entry| match &entry.content {
+            Content::Track(t) => t.object().deleted(),
+            Content::Playlist(pl) => pl.object().deleted(),
+        })
+    }
+
     pub fn get_entry(&self, path: impl AsRef<TreePath>) -> Option<&Content> {
         let path = path.as_ref();
 
@@ -171,115 +261,235 @@ impl Playlist {
         }
     }
 
+    /// Like [`get_playlist`](Self::get_playlist), but for changing the
+    /// playlist in place.
+    pub fn get_playlist_mut(&mut self, path: impl AsRef<TreePath>) -> Option<&mut Playlist> {
+        let path = path.as_ref();
+
+        if path.is_empty() {
+            return Some(self);
+        }
+
+        let idx = path.to_slice()[0];
+
+        match &mut self.entries.get_mut(idx as usize)?.content {
+            Content::Track(_) => None,
+            Content::Playlist(pl) => pl.get_playlist_mut(&path[1..]),
+        }
+    }
+
     pub fn get_track(&self, path: impl AsRef<TreePath>) -> Option<&entity::Track> {
         match self.get_entry(path) {
             Some(Content::Track(t)) => Some(t),
             _ => None,
         }
     }
+
+    /// Returns all tracks in this playlist and its nested playlists, in
+    /// playing order. Tracks appearing more than once are returned each time.
+    pub fn tracks_deep(&self) -> Vec<&entity::Track> {
+        let mut tracks = Vec::new();
+        self.collect_tracks(&mut tracks);
+        tracks
+    }
+
+    fn collect_tracks<'a>(&'a self, tracks: &mut Vec<&'a entity::Track>) {
+        for entry in self.entries.iter() {
+            match &entry.content {
+                Content::Track(t) => tracks.push(t),
+                Content::Playlist(pl) => pl.collect_tracks(tracks),
+            }
+        }
+    }
 }
 
 impl Playlist {
     pub async fn reload(&mut self, db: &mut PgConnection) -> sqlx::Result<()> {
         if let Some(id) = self.object.id() {
-            self.object = object::Playlist::load(id, db).await?;
-            self.load_more(db).await?;
+            let object = object::Playlist::load(id, &mut *db).await?;
+            *self = Playlist::load_from(object, db).await?;
         }
 
         Ok(())
     }
 
-    async fn load_more(&mut self, db: &mut PgConnection) -> sqlx::Result<()> {
-        let id = self.object.id().expect("No valid object loaded");
+    fn update_base(&mut self) {
+        self.base = Some(Box::new(Base {
+            object: self.object.clone(),
+            entries: self.entry_keys(),
+            entry_ids: self.entries.iter().map(|entry| entry.id).collect(),
+        }));
+    }
 
-        self.entries.clear();
-        // language=SQL
-        let rows = sqlx::query!(
-            "SELECT id, track, sub_playlist
-                 FROM playlist_entry
-                 WHERE playlist = $1
-                 ORDER BY index",
-            id
-        )
-        .fetch(&mut *db)
-        .collect::<Vec<_>>()
-        .await;
+    fn entry_keys(&self) -> Vec<EntryKey> {
+        self.entries
+            .iter()
+            .map(|entry| match &entry.content {
+                Content::Track(t) => EntryKey::Track(t.object().id()),
+                Content::Playlist(pl) => EntryKey::Playlist(pl.object().id()),
+            })
+            .collect()
+    }
 
-        for row in rows {
-            let row = row?;
+    /// See [`objgen::Entity::refresh_and_merge`]. The entries are merged as a
+    /// whole, changes to nested playlists are not merged.
+    pub async fn refresh_and_merge(
+        &mut self,
+        db: &mut PgConnection,
+    ) -> objgen::Result<MergeReport> {
+        let id = match self.object.id() {
+            None => return Ok(MergeReport::default()),
+            Some(id) => id,
+        };
 
-            let content = if let Some(track_id) = row.track {
-                let track = entity::Track::load(track_id, &mut *db).await?;
-                Content::Track(track)
-            } else if let Some(sub_playlist_id) = row.sub_playlist {
-                let sub_playlist = Playlist::load(sub_playlist_id, &mut *db).await?;
-                Content::Playlist(sub_playlist)
-            } else {
-                unimplemented!()
-            };
+        let mut remote = Playlist::load(id, db).await?;
+        let remote_base = remote.base.take().expect("loaded playlist has no base");
+        // without a base, treat everything as changed locally
+        let base = self.base.as_deref().unwrap_or(&remote_base);
 
-            self.entries.push(PlaylistEntry {
-                id: row.id,
-                content,
-            });
+        let mut m = Merger::new();
+        let object = self.object.merge(&base.object, &remote_base.object, &mut m);
+        let entries = self.entry_keys();
+        let merged_entries = m.field("entries", &base.entries, &entries, &remote_base.entries);
+        let report = m.finish()?;
+
+        self.object = object;
+
+        if merged_entries != entries {
+            self.entries = remote.entries;
         }
 
-        Ok(())
+        self.base = Some(remote_base);
+
+        Ok(report)
     }
 
     pub fn save<'a>(&'a mut self, db: &'a mut PgConnection) -> BoxFuture<'a, objgen::Result<()>> {
         async move {
-            self.object.save(db).await?;
+            // readers see either the old or the new entries, never a
+            // playlist that is half written
+            let mut tx = db.begin().await?;
+
+            self.object.save(&mut tx).await?;
             let id = self.object.id().unwrap();
 
-            // for now, remove everything and re-insert for simplicity
-            // might add some more intelligent update mechanism later if this
-            // becomes too slow
+            let mut current = Vec::with_capacity(self.entries.len());
 
-            // language=SQL
-            sqlx::query!("DELETE FROM playlist_entry WHERE playlist = $1", id)
-                .execute(&mut *db)
-                .await?;
-
-            for (idx, entry) in self.entries.iter_mut().enumerate() {
-                // language=SQL
-                match &mut entry.content {
+            for entry in self.entries.iter_mut() {
+                let key = match &mut entry.content {
                     Content::Track(track) => {
-                        track.save(db).await?;
-
-                        sqlx::query!(
-                            "INSERT INTO playlist_entry (id, playlist, index, track) VALUES ($1, $2, $3, $4)",
-                            entry.id,
-                            id,
-                            idx as u32,
-                            track.object().id().unwrap()
-                        )
-                        .execute(&mut *db)
-                        .await?;
+                        track.save(&mut tx).await?;
+                        EntryKey::Track(track.object().id())
                     }
                     Content::Playlist(playlist) => {
-                        playlist.save(db).await?;
-
-                        sqlx::query!(
-                            "INSERT INTO playlist_entry (id, playlist, index, track) VALUES ($1, $2, $3, $4)",
-                            entry.id,
-                            id,
-                            idx as u32,
-                            playlist.object().id().unwrap()
-                        )
-                        .execute(&mut *db)
-                        .await?;
+                        playlist.save(&mut tx).await?;
+                        EntryKey::Playlist(playlist.object().id())
                     }
-                }
+                };
+
+                current.push((entry.id, key));
             }
 
+            let saved: Vec<_> = match self.base.as_deref() {
+                // the playlist was never loaded or saved, so there is
+                // nothing to compare against
+                None => Vec::new(),
+                Some(base) => base
+                    .entry_ids
+                    .iter()
+                    .copied()
+                    .zip(base.entries.iter().cloned())
+                    .collect(),
+            };
+
+            match EntryDiff::new(&saved, &current) {
+                Some(diff) if self.base.is_some() => diff.apply(id, &mut tx).await?,
+                _ => rewrite_entries(id, &current, &mut tx).await?,
+            }
+
+            tx.commit().await?;
+
+            self.update_base();
+
             Ok(())
-        }.boxed()
+        }
+        .boxed()
     }
 
     pub fn object(&self) -> &object::Playlist {
         &self.object
     }
+
+    /// See [`object::Playlist::restore`]. The entries are left as they are.
+    pub async fn restore(&mut self, db: &mut PgConnection) -> objgen::Result<()> {
+        self.object.restore(&mut *db).await?;
+
+        // the entries in the database are still the same as before, so only
+        // the object is up to date now
+        match &mut self.base {
+            None => self.update_base(),
+            Some(base) => base.object = self.object.clone(),
+        }
+
+        Ok(())
+    }
+
+    /// Returns how often each track in this playlist and its nested playlists
+    /// was played according to the play history, in playing order. Each track
+    /// is listed once, including the ones that were never played. Tracks that
+    /// haven't been saved yet are left out.
+    pub async fn play_count(&self, db: &mut PgConnection) -> sqlx::Result<Vec<PlayCount>> {
+        let mut seen = HashSet::new();
+        let tracks: Vec<_> = self
+            .tracks_deep()
+            .into_iter()
+            .filter_map(|t| Some((t.object().id()?, t)))
+            .filter(|(id, _)| seen.insert(*id))
+            .collect();
+        let ids: Vec<Uuid> = tracks.iter().map(|(id, _)| *id).collect();
+
+        // language=SQL
+        let rows = sqlx::query!(
+            r#"SELECT track_id, count(*) AS "count!",
+                   (array_agg(duration_secs ORDER BY played_at DESC))[1] AS duration_secs
+               FROM play_history
+               WHERE track_id = ANY($1)
+               GROUP BY track_id"#,
+            &ids
+        )
+        .fetch_all(&mut *db)
+        .await?;
+
+        let mut plays: HashMap<_, _> = rows
+            .into_iter()
+            .map(|row| (row.track_id, (row.count, row.duration_secs)))
+            .collect();
+
+        let counts = tracks
+            .into_iter()
+            .map(|(id, track)| {
+                let (count, duration_secs) = plays.remove(&id).unwrap_or((0, None));
+
+                PlayCount {
+                    track: track.clone(),
+                    count: count as u64,
+                    duration: duration_secs.map(Duration::from_secs_f64),
+                }
+            })
+            .collect();
+
+        Ok(counts)
+    }
+}
+
+/// How often a track was played, see [`Playlist::play_count`].
+#[derive(Debug, Clone)]
+pub struct PlayCount {
+    pub track: entity::Track,
+    pub count: u64,
+    /// The length of the track the last time it was played. Unknown if it
+    /// was never played.
+    pub duration: Option<Duration>,
 }
 
 #[derive(Debug, Clone)]
@@ -304,6 +514,385 @@ pub enum Content {
     Playlist(Playlist),
 }
 
+/// Loads a playlist together with all of its nested playlists and their
+/// tracks. Instead of loading each entry on its own, this takes two queries
+/// per level of nesting, plus two for all the tracks.
+#[derive(Debug, Default)]
+struct TreeLoader {
+    playlists: HashMap<Uuid, object::Playlist>,
+    /// The entries of each loaded playlist, in order.
+    entries: HashMap<Uuid, Vec<EntryRow>>,
+    tracks: HashMap<Uuid, entity::Track>,
+}
+
+#[derive(Debug)]
+struct EntryRow {
+    id: Uuid,
+    track: Option<Uuid>,
+    sub_playlist: Option<Uuid>,
+}
+
+impl TreeLoader {
+    /// Loads everything needed to build `root`.
+    async fn load(&mut self, root: &object::Playlist, db: &mut PgConnection) -> sqlx::Result<()> {
+        let mut next = vec![root.id().expect("No valid object loaded")];
+        let mut track_ids = HashSet::new();
+
+        while !next.is_empty() {
+            // language=SQL
+            let rows = sqlx::query!(
+                "SELECT id, playlist, track, sub_playlist
+                     FROM playlist_entry
+                     WHERE playlist = ANY($1)
+                     ORDER BY playlist, index",
+                &next
+            )
+            .fetch_all(&mut *db)
+            .await?;
+
+            for id in next.iter() {
+                self.entries.insert(*id, Vec::new());
+            }
+
+            // playlists which have been seen before already have their
+            // entries loaded, which also stops at cycles
+            let mut sub_playlists = HashSet::new();
+
+            for row in rows {
+                track_ids.extend(row.track);
+                sub_playlists.extend(row.sub_playlist.filter(|id| !self.entries.contains_key(id)));
+
+                self.entries
+                    .entry(row.playlist)
+                    .or_default()
+                    .push(EntryRow {
+                        id: row.id,
+                        track: row.track,
+                        sub_playlist: row.sub_playlist,
+                    });
+            }
+
+            next = sub_playlists.into_iter().collect();
+
+            if !next.is_empty() {
+                let objects = object::Playlist::load_many(&next, &mut *db).await?;
+
+                for object in objects {
+                    self.playlists.insert(object.id().unwrap(), object);
+                }
+            }
+        }
+
+        let track_ids: Vec<_> = track_ids.into_iter().collect();
+        self.tracks = entity::Track::load_many(&track_ids, db).await?;
+
+        Ok(())
+    }
+
+    /// Assembles the playlist from what was loaded. `path` holds the IDs of
+    /// the playlists it is nested in. An entry that would nest a playlist in
+    /// itself is left out, since it would never end.
+    fn build(&self, object: object::Playlist, path: &mut Vec<Uuid>) -> sqlx::Result<Playlist> {
+        let id = object.id().unwrap();
+        let rows = self.entries.get(&id).map_or(&[][..], |rows| rows);
+        let mut entries = Vec::with_capacity(rows.len());
+
+        path.push(id);
+
+        for row in rows {
+            let content = if let Some(track_id) = row.track {
+                let track = self.tracks.get(&track_id).ok_or(sqlx::Error::RowNotFound)?;
+                Content::Track(track.clone())
+            } else if let Some(sub_playlist_id) = row.sub_playlist {
+                if path.contains(&sub_playlist_id) {
+                    warn!(
+                        "playlist {} contains itself, skipping entry {} of playlist {}",
+                        sub_playlist_id, row.id, id
+                    );
+                    continue;
+                }
+
+                let object = self
+                    .playlists
+                    .get(&sub_playlist_id)
+                    .ok_or(sqlx::Error::RowNotFound)?;
+                Content::Playlist(self.build(object.clone(), path)?)
+            } else {
+                unimplemented!()
+            };
+
+            entries.push(PlaylistEntry {
+                id: row.id,
+                content,
+            });
+        }
+
+        path.pop();
+
+        let mut playlist = Playlist {
+            object,
+            entries,
+            base: None,
+        };
+        playlist.update_base();
+
+        Ok(playlist)
+    }
+}
+
+/// Maximum number of playlist entries inserted with a single query.
+const ENTRY_BATCH_SIZE: usize = 200;
+
+/// Playlist entry rows collected while saving a playlist, in the form they
+/// are passed to the batch insert queries.
+#[derive(Debug, Default)]
+struct EntryRows {
+    ids: Vec<Uuid>,
+    indices: Vec<i32>,
+    targets: Vec<Uuid>,
+}
+
+impl EntryRows {
+    fn push(&mut self, id: Uuid, index: usize, target: Uuid) {
+        self.ids.push(id);
+        self.indices.push(index as i32);
+        self.targets.push(target);
+    }
+
+    fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Returns the rows in chunks of at most [`ENTRY_BATCH_SIZE`].
+    fn batches(&self) -> impl Iterator<Item = (&[Uuid], &[i32], &[Uuid])> {
+        self.ids
+            .chunks(ENTRY_BATCH_SIZE)
+            .zip(self.indices.chunks(ENTRY_BATCH_SIZE))
+            .zip(self.targets.chunks(ENTRY_BATCH_SIZE))
+            .map(|((ids, indices), targets)| (ids, indices, targets))
+    }
+}
+
+/// Splits entries into rows referring to tracks and rows referring to
+/// playlists. Entries referring to something that isn't saved are skipped.
+fn split_entries<'a, I>(entries: I) -> (EntryRows, EntryRows)
+where
+    I: IntoIterator<Item = (usize, &'a (Uuid, EntryKey))>,
+{
+    let mut tracks = EntryRows::default();
+    let mut playlists = EntryRows::default();
+
+    for (idx, (id, key)) in entries {
+        match key {
+            EntryKey::Track(Some(target)) => tracks.push(*id, idx, *target),
+            EntryKey::Playlist(Some(target)) => playlists.push(*id, idx, *target),
+            _ => {}
+        }
+    }
+
+    (tracks, playlists)
+}
+
+/// The statements needed to get the rows in `playlist_entry` from the saved
+/// entries of a playlist to the current ones. Entries keep their IDs.
+#[derive(Debug, Default)]
+struct EntryDiff {
+    deleted: Vec<Uuid>,
+    /// Entries which exist in both, but were moved or changed their target.
+    changed: (EntryRows, EntryRows),
+    inserted: (EntryRows, EntryRows),
+}
+
+impl EntryDiff {
+    /// Compares the saved and current entries, given as entry ID and target
+    /// in playlist order. Returns `None` if the diff would touch at least as
+    /// many rows as deleting and inserting everything again, for example
+    /// when an entry was inserted at the start and everything after it moved.
+    fn new(saved: &[(Uuid, EntryKey)], current: &[(Uuid, EntryKey)]) -> Option<Self> {
+        let saved_rows: HashMap<_, _> = saved
+            .iter()
+            .enumerate()
+            .map(|(idx, (id, key))| (*id, (idx, key)))
+            .collect();
+        let current_ids: HashSet<_> = current.iter().map(|(id, _)| *id).collect();
+
+        let deleted: Vec<_> = saved
+            .iter()
+            .map(|(id, _)| *id)
+            .filter(|id| !current_ids.contains(id))
+            .collect();
+
+        let changed = split_entries(current.iter().enumerate().filter(
+            |(idx, (id, key))| matches!(saved_rows.get(id), Some(&(i, k)) if i != *idx || k != key),
+        ));
+
+        let inserted = split_entries(
+            current
+                .iter()
+                .enumerate()
+                .filter(|(_, (id, _))| !saved_rows.contains_key(id)),
+        );
+
+        // changed rows are updated twice, see apply
+        let touched = deleted.len()
+            + 2 * (changed.0.len() + changed.1.len())
+            + inserted.0.len()
+            + inserted.1.len();
+
+        if touched > 0 && touched >= saved.len() + current.len() {
+            return None;
+        }
+
+        Some(EntryDiff {
+            deleted,
+            changed,
+            inserted,
+        })
+    }
+
+    async fn apply(&self, playlist: Uuid, db: &mut PgConnection) -> sqlx::Result<()> {
+        if !self.deleted.is_empty() {
+            // language=SQL
+            sqlx::query!(
+                "DELETE FROM playlist_entry WHERE playlist = $1 AND id = ANY($2)",
+                playlist,
+                &self.deleted
+            )
+            .execute(&mut *db)
+            .await?;
+        }
+
+        // The insert trigger moves entries out of the way if their index is
+        // taken, so changed entries first go to an index no other entry
+        // has, below zero, and only then to their actual index once all of
+        // them are out of the way.
+        let (tracks, playlists) = &self.changed;
+
+        for (ids, indices, targets) in tracks.batches() {
+            // language=SQL
+            sqlx::query!(
+                "UPDATE playlist_entry AS pe \
+                 SET index = -1 - e.index, track = e.track, sub_playlist = NULL \
+                 FROM UNNEST($2::uuid[], $3::int[], $4::uuid[]) AS e (id, index, track) \
+                 WHERE pe.playlist = $1 AND pe.id = e.id",
+                playlist,
+                ids,
+                indices,
+                targets
+            )
+            .execute(&mut *db)
+            .await?;
+        }
+
+        for (ids, indices, targets) in playlists.batches() {
+            // language=SQL
+            sqlx::query!(
+                "UPDATE playlist_entry AS pe \
+                 SET index = -1 - e.index, track = NULL, sub_playlist = e.sub_playlist \
+                 FROM UNNEST($2::uuid[], $3::int[], $4::uuid[]) AS e (id, index, sub_playlist) \
+                 WHERE pe.playlist = $1 AND pe.id = e.id",
+                playlist,
+                ids,
+                indices,
+                targets
+            )
+            .execute(&mut *db)
+            .await?;
+        }
+
+        if tracks.len() + playlists.len() > 0 {
+            // language=SQL
+            sqlx::query!(
+                "UPDATE playlist_entry SET index = -1 - index WHERE playlist = $1 AND index < 0",
+                playlist
+            )
+            .execute(&mut *db)
+            .await?;
+        }
+
+        let (tracks, playlists) = &self.inserted;
+        insert_entries(playlist, tracks, playlists, db).await
+    }
+}
+
+/// Replaces all entries of the playlist.
+async fn rewrite_entries(
+    playlist: Uuid,
+    entries: &[(Uuid, EntryKey)],
+    db: &mut PgConnection,
+) -> sqlx::Result<()> {
+    // language=SQL
+    sqlx::query!("DELETE FROM playlist_entry WHERE playlist = $1", playlist)
+        .execute(&mut *db)
+        .await?;
+
+    let (tracks, playlists) = split_entries(entries.iter().enumerate());
+    insert_entries(playlist, &tracks, &playlists, db).await
+}
+
+async fn insert_entries(
+    playlist: Uuid,
+    tracks: &EntryRows,
+    playlists: &EntryRows,
+    db: &mut PgConnection,
+) -> sqlx::Result<()> {
+    // insert the entries in batches instead of one query per entry, which for
+    // a playlist with a few hundred tracks takes a couple of queries instead
+    // of a few hundred round trips
+    for (ids, indices, targets) in tracks.batches() {
+        // language=SQL
+        sqlx::query!(
+            "INSERT INTO playlist_entry (id, playlist, index, track) \
+             SELECT e.id, $1, e.index, e.track \
+             FROM UNNEST($2::uuid[], $3::int[], $4::uuid[]) AS e (id, index, track)",
+            playlist,
+            ids,
+            indices,
+            targets
+        )
+        .execute(&mut *db)
+        .await?;
+    }
+
+    for (ids, indices, targets) in playlists.batches() {
+        // language=SQL
+        sqlx::query!(
+            "INSERT INTO playlist_entry (id, playlist, index, sub_playlist) \
+             SELECT e.id, $1, e.index, e.sub_playlist \
+             FROM UNNEST($2::uuid[], $3::int[], $4::uuid[]) AS e (id, index, sub_playlist)",
+            playlist,
+            ids,
+            indices,
+            targets
+        )
+        .execute(&mut *db)
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl objgen::Entity for Playlist {
+    type Object = object::Playlist;
+
+    async fn reload(&mut self, db: &mut PgConnection) -> sqlx::Result<()> {
+        Playlist::reload(self, db).await
+    }
+
+    async fn save(&mut self, db: &mut PgConnection) -> objgen::Result<()> {
+        Playlist::save(self, db).await
+    }
+
+    async fn refresh_and_merge(&mut self, db: &mut PgConnection) -> objgen::Result<MergeReport> {
+        Playlist::refresh_and_merge(self, db).await
+    }
+
+    fn object(&self) -> &object::Playlist {
+        &self.object
+    }
+}
+
 impl Display for Playlist {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         Display::fmt(&self.object, f)
@@ -315,3 +904,256 @@ impl HtmlDisplay for Playlist {
         HtmlDisplay::fmt(&self.object, f)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Connection, PgConnection};
+    use uuid::Uuid;
+
+    use super::{EntryDiff, EntryKey, EntryRows, Playlist, TreeLoader, ENTRY_BATCH_SIZE};
+    use crate::db::entity::Track;
+    use crate::db::test_util::queries;
+
+    fn entry_ids(pl: &Playlist) -> Vec<Uuid> {
+        pl.entries().iter().map(|e| e.id()).collect()
+    }
+
+    fn track(title: &str) -> Track {
+        let mut track = Track::new();
+        track.set_title(Some(title.to_string()));
+        track
+    }
+
+    #[test]
+    fn test_entry_batches() {
+        let mut rows = EntryRows::default();
+
+        for idx in 0..ENTRY_BATCH_SIZE * 2 + 1 {
+            rows.push(Uuid::new_v4(), idx, Uuid::new_v4());
+        }
+
+        let batches: Vec<_> = rows.batches().collect();
+        assert_eq!(3, batches.len());
+        assert_eq!(ENTRY_BATCH_SIZE, batches[0].0.len());
+        assert_eq!(1, batches[2].1.len());
+        assert_eq!(ENTRY_BATCH_SIZE as i32 * 2, batches[2].1[0]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the database configured in srvrc"]
+    async fn test_save_order() {
+        let mut conn = PgConnection::connect(env!("DATABASE_URL")).await.unwrap();
+        // rolled back when dropped, so nothing ends up in the database
+        let mut tx = conn.begin().await.unwrap();
+
+        let mut playlist = Playlist::new();
+
+        for i in 0..ENTRY_BATCH_SIZE * 2 + 50 {
+            if i == ENTRY_BATCH_SIZE + 3 {
+                playlist.push_playlist(Playlist::new());
+            }
+
+            let mut track = Track::new();
+            track.set_title(Some(format!("Track {}", i)));
+            playlist.push_track(track);
+        }
+
+        playlist.save(&mut tx).await.unwrap();
+        let id = playlist.object().id().unwrap();
+
+        let mut loaded = Playlist::load(id, &mut tx).await.unwrap();
+        assert_eq!(playlist.entry_keys(), loaded.entry_keys());
+        assert_eq!(entry_ids(&playlist), entry_ids(&loaded));
+
+        // saving again without changes keeps everything in place
+        loaded.save(&mut tx).await.unwrap();
+
+        let reloaded = Playlist::load(id, &mut tx).await.unwrap();
+        assert_eq!(playlist.entry_keys(), reloaded.entry_keys());
+        assert_eq!(entry_ids(&playlist), entry_ids(&reloaded));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the database configured in srvrc"]
+    async fn test_load_nested() {
+        let mut conn = PgConnection::connect(env!("DATABASE_URL")).await.unwrap();
+        // rolled back when dropped, so nothing ends up in the database
+        let mut tx = conn.begin().await.unwrap();
+
+        let playlist_with_tracks = |title: &str| {
+            let mut pl = Playlist::new();
+            pl.set_title(title);
+
+            for i in 0..20 {
+                let mut track = Track::new();
+                track.set_title(Some(format!("{} {}", title, i)));
+                pl.push_track(track);
+            }
+
+            pl
+        };
+
+        let mut inner = playlist_with_tracks("Inner");
+        inner.save(&mut tx).await.unwrap();
+        let mut middle = playlist_with_tracks("Middle");
+        middle.push_playlist(inner.clone());
+        middle.push_playlist(inner.clone());
+        let mut root = playlist_with_tracks("Root");
+        root.push_playlist(middle);
+        root.push_playlist(playlist_with_tracks("Other"));
+        root.save(&mut tx).await.unwrap();
+
+        let root_id = root.object().id().unwrap();
+        let inner_id = inner.object().id().unwrap();
+
+        // make the innermost playlist contain the root, which loading has to
+        // skip instead of going around in circles
+        sqlx::query(
+            "INSERT INTO playlist_entry (id, playlist, index, sub_playlist) \
+             VALUES ($1, $2, 100, $3)",
+        )
+        .bind(Uuid::new_v4())
+        .bind(inner_id)
+        .bind(root_id)
+        .execute(&mut tx)
+        .await
+        .unwrap();
+
+        let object = root.object().clone();
+        let mut loader = TreeLoader::default();
+        let before = queries();
+        loader.load(&object, &mut tx).await.unwrap();
+        let loaded = loader.build(object, &mut Vec::new()).unwrap();
+
+        // one level for the root, one for the middle and other playlist, one
+        // for the inner one, and the tracks of all of them
+        assert_eq!(7, queries() - before);
+        assert_eq!(root.entry_keys(), loaded.entry_keys());
+        assert_eq!(100, loaded.tracks_deep().len());
+
+        let middle = loaded.get_playlist([20]).unwrap();
+        assert_eq!(22, middle.entries().len());
+        assert_eq!(20, middle.get_playlist([21]).unwrap().entries().len());
+    }
+
+    #[test]
+    fn test_entry_diff() {
+        let new_track = || (Uuid::new_v4(), EntryKey::Track(Some(Uuid::new_v4())));
+        let saved: Vec<_> = (0..10).map(|_| new_track()).collect();
+
+        let diff = EntryDiff::new(&saved, &saved).unwrap();
+        assert!(diff.deleted.is_empty());
+        assert_eq!(0, diff.changed.0.len() + diff.inserted.0.len());
+
+        let mut current = saved.clone();
+        current.remove(3);
+        current.swap(0, 1);
+        current.push((Uuid::new_v4(), EntryKey::Playlist(Some(Uuid::new_v4()))));
+
+        let diff = EntryDiff::new(&saved, &current).unwrap();
+        assert_eq!(vec![saved[3].0], diff.deleted);
+        // the two swapped ones and the ones after the removed one
+        assert_eq!(vec![0, 1, 3, 4, 5, 6, 7, 8], diff.changed.0.indices);
+        assert_eq!(0, diff.changed.1.len());
+        assert_eq!(0, diff.inserted.0.len());
+        assert_eq!(vec![9], diff.inserted.1.indices);
+
+        // inserting at the start moves everything, so writing all entries
+        // again is cheaper
+        let mut current = saved.clone();
+        current.insert(0, new_track());
+        assert!(EntryDiff::new(&saved, &current).is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the database configured in srvrc"]
+    async fn test_save_diff() {
+        let mut conn = PgConnection::connect(env!("DATABASE_URL")).await.unwrap();
+        // rolled back when dropped, so nothing ends up in the database
+        let mut tx = conn.begin().await.unwrap();
+
+        let mut playlist = Playlist::new();
+
+        for i in 0..10 {
+            playlist.push_track(track(&format!("Track {}", i)));
+        }
+
+        playlist.push_playlist(Playlist::new());
+        playlist.save(&mut tx).await.unwrap();
+        let id = playlist.object().id().unwrap();
+
+        let mut loaded = Playlist::load(id, &mut tx).await.unwrap();
+        loaded.remove_entry(3).unwrap();
+        assert!(loaded.move_entry(0, 1));
+        assert!(loaded.move_entry(9, 0));
+        loaded.push_track(track("New Track"));
+        loaded.save(&mut tx).await.unwrap();
+
+        let reloaded = Playlist::load(id, &mut tx).await.unwrap();
+        assert_eq!(loaded.entry_keys(), reloaded.entry_keys());
+        assert_eq!(entry_ids(&loaded), entry_ids(&reloaded));
+        assert_eq!(entry_ids(&playlist)[10], entry_ids(&reloaded)[0]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the database configured in srvrc"]
+    async fn test_save_concurrent_read() {
+        let mut conn = PgConnection::connect(env!("DATABASE_URL")).await.unwrap();
+        let mut reader = PgConnection::connect(env!("DATABASE_URL")).await.unwrap();
+
+        // this has to be committed for the reader to see it, so it is
+        // removed again at the end
+        let mut playlist = Playlist::new();
+
+        for i in 0..5 {
+            playlist.push_track(track(&format!("Track {}", i)));
+        }
+
+        playlist.save(&mut conn).await.unwrap();
+        let id = playlist.object().id().unwrap();
+        let original = entry_ids(&playlist);
+        let mut track_ids: Vec<_> = playlist
+            .tracks_deep()
+            .iter()
+            .filter_map(|t| t.object().id())
+            .collect();
+
+        let mut tx = conn.begin().await.unwrap();
+        playlist.remove_entry(0).unwrap();
+        playlist.push_track(track("New Track"));
+        playlist.save(&mut tx).await.unwrap();
+        track_ids.extend(
+            playlist
+                .tracks_deep()
+                .iter()
+                .filter_map(|t| t.object().id()),
+        );
+
+        let seen = Playlist::load(id, &mut reader).await.unwrap();
+        assert_eq!(original, entry_ids(&seen));
+
+        tx.commit().await.unwrap();
+
+        let seen = Playlist::load(id, &mut reader).await.unwrap();
+        assert_eq!(entry_ids(&playlist), entry_ids(&seen));
+
+        // language=SQL
+        sqlx::query("DELETE FROM playlist_entry WHERE playlist = $1")
+            .bind(id)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        // language=SQL
+        sqlx::query("DELETE FROM playlist WHERE id = $1")
+            .bind(id)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        // language=SQL
+        sqlx::query("DELETE FROM track WHERE id = ANY($1)")
+            .bind(&track_ids)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+    }
+}
@@ -3,7 +3,7 @@ use url::Url;
 use youtube_dl::YoutubeDlOutput;
 
 use crate::db::object;
-use crate::entity::import::ImportError;
+use crate::entity::import::{self, ImportError};
 use crate::entity::Track;
 
 use super::Playlist;
@@ -43,9 +43,9 @@ impl Playlist {
 
         let url = Url::parse_with_params("https://www.youtube.com/playlist", [("list", id)])?;
 
-        let output = youtube_dl::YoutubeDl::new(url.into_string())
-            .flat_playlist(true)
-            .run()?;
+        let mut ytdl = youtube_dl::YoutubeDl::new(url.into_string());
+        ytdl.flat_playlist(true);
+        let output = import::run_youtube_dl(&ytdl).await?;
 
         let output = match output {
             YoutubeDlOutput::SingleVideo(_) => unreachable!(),
@@ -60,8 +60,23 @@ impl Playlist {
 
         self.entries.clear();
 
-        for el in output.entries.iter().flatten() {
-            let track = Track::import_from_youtube(el, Some(db)).await?;
+        let entries: Vec<_> = output.entries.into_iter().flatten().collect();
+
+        // look up all videos that are already in the database at once
+        // instead of one query per entry
+        let ids: Vec<_> = entries.iter().map(|el| el.id.clone()).collect();
+        let known = Track::load_by_youtube_ids(&ids, db).await?;
+
+        // flat playlist entries are imported without running youtube-dl, so
+        // there is nothing here worth retrying, that only happens in
+        // run_youtube_dl. A database error aborts the whole import, since
+        // the transaction it runs in can't continue after one.
+        for el in entries.iter() {
+            let track = match known.get(&el.id) {
+                Some(track) => track.clone(),
+                None => Track::import_from_youtube(el, None).await?,
+            };
+
             self.push_track(track);
         }
 
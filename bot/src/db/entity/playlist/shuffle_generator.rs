@@ -0,0 +1,71 @@
+use chrono::{Datelike, NaiveDate};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use super::Playlist;
+
+/// Playlists that are generated from other playlists instead of being
+/// stored, and only exist in memory.
+pub struct SmartPlaylist;
+
+impl SmartPlaylist {
+    /// Loads the playlist with the given ID and returns a copy of it with
+    /// the top level entries shuffled. The order only depends on the
+    /// playlist and `seed_date`, so it is the same all day.
+    ///
+    /// The copy is detached like with [`Playlist::detach_deep`] and is not
+    /// meant to be saved.
+    pub async fn daily_random(
+        seed_date: NaiveDate,
+        playlist_id: Uuid,
+        db: &mut PgConnection,
+    ) -> sqlx::Result<Playlist> {
+        let playlist = Playlist::load(playlist_id, db).await?;
+
+        let mut shuffled = playlist.detach_deep();
+        shuffled.set_title(format!("{} ({})", playlist.object().title(), seed_date));
+        shuffle(&mut shuffled.entries, seed_date, playlist_id);
+
+        Ok(shuffled)
+    }
+}
+
+/// Shuffles `items` in an order that is the same for the same `seed_date`
+/// and `id`.
+fn shuffle<T>(items: &mut [T], seed_date: NaiveDate, id: Uuid) {
+    // mix in the playlist so that different playlists don't get shuffled
+    // the same way on the same day
+    let seed = seed_date.num_days_from_ce() as u64 ^ id.as_u128() as u64;
+    items.shuffle(&mut StdRng::seed_from_u64(seed));
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDate;
+    use uuid::Uuid;
+
+    use super::shuffle;
+
+    #[test]
+    fn test_shuffle() {
+        let id = Uuid::new_v4();
+        let today = NaiveDate::from_ymd(2021, 12, 24);
+        let tomorrow = NaiveDate::from_ymd(2021, 12, 25);
+
+        let mut a: Vec<_> = (0..50).collect();
+        let mut b = a.clone();
+        let mut c = a.clone();
+        shuffle(&mut a, today, id);
+        shuffle(&mut b, today, id);
+        shuffle(&mut c, tomorrow, id);
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+
+        a.sort_unstable();
+        assert_eq!((0..50).collect::<Vec<_>>(), a);
+    }
+}
@@ -1,39 +1,92 @@
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::path::PathBuf;
 
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use futures::StreamExt;
 use sqlx::PgConnection;
 use url::Url;
 use uuid::Uuid;
 
+use crate::db::objgen::{MergeReport, Merger};
 use crate::db::{object, objgen};
 use crate::fmt::HtmlDisplay;
 
 mod import;
+mod tags;
 
 #[derive(Debug, Clone)]
 pub struct Track {
     object: object::Track,
-    providers: Vec<TrackProvider>,
+    providers: Vec<Provider>,
+    base: Option<Box<Base>>,
 }
 
+/// The state of a track as it was last loaded or saved, to find out which
+/// changes were made locally when merging.
 #[derive(Debug, Clone)]
-pub struct TrackProvider {
+struct Base {
+    object: object::Track,
+    providers: Vec<Source>,
+}
+
+/// A place a track can be played from.
+#[derive(Debug, Clone)]
+pub struct Provider {
     id: Uuid,
     source: Source,
+    /// When the provider was added. Unknown for providers added before this
+    /// was recorded.
+    created: Option<DateTime<Utc>>,
+}
+
+/// The type of a [`Provider`], i.e. of its [`Source`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ProviderKind {
+    Youtube,
+    Local,
+    Stream,
+    Spotify,
 }
 
-impl TrackProvider {
+impl Provider {
     pub fn id(&self) -> Uuid {
         self.id
     }
 
+    pub fn kind(&self) -> ProviderKind {
+        match self.source {
+            Source::Local(_) => ProviderKind::Local,
+            Source::Url(_) => ProviderKind::Stream,
+            Source::Spotify(_) => ProviderKind::Spotify,
+            Source::Youtube(_) => ProviderKind::Youtube,
+        }
+    }
+
     pub fn source(&self) -> &Source {
         &self.source
     }
+
+    pub fn created(&self) -> Option<DateTime<Utc>> {
+        self.created
+    }
 }
 
-#[derive(Debug, Clone)]
+impl Display for ProviderKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ProviderKind::Youtube => "youtube",
+            ProviderKind::Local => "local",
+            ProviderKind::Stream => "stream",
+            ProviderKind::Spotify => "spotify",
+        };
+
+        f.write_str(s)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Source {
     Local(PathBuf),
     Url(Url),
@@ -41,11 +94,35 @@ pub enum Source {
     Youtube(String),
 }
 
+impl Source {
+    /// Builds the source from the columns of a `track_provider` row, of
+    /// which exactly one is set.
+    fn from_row(
+        local_path: Option<String>,
+        url: Option<String>,
+        spotify_id: Option<String>,
+        youtube_id: Option<String>,
+    ) -> Self {
+        if let Some(local_path) = local_path {
+            Source::Local(local_path.into())
+        } else if let Some(url) = url {
+            Source::Url(url.parse().expect("invalid URL in track_provider.url"))
+        } else if let Some(spotify_id) = spotify_id {
+            Source::Spotify(spotify_id)
+        } else if let Some(youtube_id) = youtube_id {
+            Source::Youtube(youtube_id)
+        } else {
+            unimplemented!()
+        }
+    }
+}
+
 impl Track {
     pub fn new() -> Self {
         Track {
             object: object::Track::new(),
             providers: Vec::new(),
+            base: None,
         }
     }
 
@@ -56,6 +133,97 @@ impl Track {
         Ok(track)
     }
     
+    /// Loads the tracks for those of the given YouTube videos which have
+    /// been imported already, keyed by video ID. This takes three queries no
+    /// matter how many videos there are, where loading each track on its own
+    /// took two per track, so 101 instead of 3 for a playlist with 50
+    /// imported videos.
+    pub async fn load_by_youtube_ids(
+        ids: &[String],
+        db: &mut PgConnection,
+    ) -> sqlx::Result<HashMap<String, Self>> {
+        // language=SQL
+        let rows = sqlx::query!(
+            "SELECT youtube_id, track FROM track_provider WHERE youtube_id = ANY($1)",
+            ids
+        )
+        .fetch_all(&mut *db)
+        .await?;
+
+        let ids: Vec<_> = rows.iter().map(|row| row.track).collect();
+        let loaded = Track::load_many(&ids, db).await?;
+
+        let tracks = rows
+            .into_iter()
+            .filter_map(|row| Some((row.youtube_id?, loaded.get(&row.track)?.clone())))
+            .collect();
+
+        Ok(tracks)
+    }
+
+    /// Loads the tracks with the given IDs in the same order, like
+    /// [`load_many`](Track::load_many) does. IDs of tracks which don't exist
+    /// are left out.
+    pub async fn load_batch(ids: &[Uuid], db: &mut PgConnection) -> sqlx::Result<Vec<Self>> {
+        let tracks = Track::load_many(ids, db).await?;
+
+        Ok(ids.iter().filter_map(|id| tracks.get(id).cloned()).collect())
+    }
+
+    /// Loads the tracks with the given IDs, keyed by ID. This takes two
+    /// queries no matter how many tracks there are. IDs of tracks which don't
+    /// exist are left out.
+    pub async fn load_many(
+        ids: &[Uuid],
+        db: &mut PgConnection,
+    ) -> sqlx::Result<HashMap<Uuid, Self>> {
+        let mut tracks: HashMap<_, _> = object::Track::load_many(ids, &mut *db)
+            .await?
+            .into_iter()
+            .map(|object| {
+                let id = object.id().unwrap();
+                let track = Track {
+                    object,
+                    providers: Vec::new(),
+                    base: None,
+                };
+                (id, track)
+            })
+            .collect();
+
+        // language=SQL
+        let rows = sqlx::query!(
+            "SELECT id, track, local_path, url, spotify_id, youtube_id, created \
+             FROM track_provider \
+             WHERE track = ANY($1) \
+             ORDER BY created NULLS FIRST",
+            ids
+        )
+        .fetch_all(&mut *db)
+        .await?;
+
+        for row in rows {
+            if let Some(track) = tracks.get_mut(&row.track) {
+                track.providers.push(Provider {
+                    id: row.id,
+                    source: Source::from_row(
+                        row.local_path,
+                        row.url,
+                        row.spotify_id,
+                        row.youtube_id,
+                    ),
+                    created: row.created,
+                });
+            }
+        }
+
+        for track in tracks.values_mut() {
+            track.update_base();
+        }
+
+        Ok(tracks)
+    }
+
     pub async fn load_by_code(code: &str, db: &mut PgConnection) -> sqlx::Result<Self> {
         let mut track = Track::new();
         track.object = object::Track::load_by_code(code, db).await?;
@@ -75,14 +243,44 @@ impl Track {
         self.object.title()
     }
 
-    pub fn add_provider(&mut self, source: Source) {
+    pub fn set_gain_db(&mut self, gain_db: Option<f64>) {
+        self.object.set_gain_db(gain_db);
+    }
+
+    /// Adds a provider to play the track from, after the existing ones.
+    /// Returns the ID of the new provider.
+    pub fn add_provider(&mut self, source: Source) -> Uuid {
+        self.object.mark_changed();
         let id = Uuid::new_v4();
-        self.providers.push(TrackProvider { id, source });
+        self.providers.push(Provider {
+            id,
+            source,
+            created: Some(Utc::now()),
+        });
+        id
+    }
+
+    /// Removes the provider with the given ID, returning it if it existed.
+    pub fn remove_provider(&mut self, id: Uuid) -> Option<Provider> {
+        let idx = self.providers.iter().position(|p| p.id == id)?;
+        self.object.mark_changed();
+        Some(self.providers.remove(idx))
     }
 
-    pub fn providers(&self) -> &[TrackProvider] {
+    /// Returns the providers of this track, in the order they are tried in
+    /// when playing it.
+    pub fn providers(&self) -> &[Provider] {
         &self.providers
     }
+
+    /// Returns the ID of the YouTube video the track can be played from, if
+    /// it has a YouTube provider.
+    pub fn youtube_provider_id(&self) -> Option<&str> {
+        self.providers.iter().find_map(|p| match &p.source {
+            Source::Youtube(id) => Some(id.as_str()),
+            _ => None,
+        })
+    }
 }
 
 impl Track {
@@ -101,9 +299,10 @@ impl Track {
         self.providers.clear();
         // language=SQL
         let mut rows = sqlx::query!(
-            "SELECT id, local_path, url, spotify_id, youtube_id \
+            "SELECT id, local_path, url, spotify_id, youtube_id, created \
              FROM track_provider \
-             WHERE track = $1",
+             WHERE track = $1 \
+             ORDER BY created NULLS FIRST",
             id
         )
         .fetch(&mut *db);
@@ -111,27 +310,88 @@ impl Track {
         while let Some(row) = rows.next().await {
             let row = row?;
 
-            let source = if let Some(local_path) = row.local_path {
-                Source::Local(local_path.into())
-            } else if let Some(url) = row.url {
-                Source::Url(url.parse().expect("invalid URL in track_provider.url"))
-            } else if let Some(spotify_id) = row.spotify_id {
-                Source::Spotify(spotify_id)
-            } else if let Some(youtube_id) = row.youtube_id {
-                Source::Youtube(youtube_id)
-            } else {
-                unimplemented!()
-            };
-
-            self.providers.push(TrackProvider { id: row.id, source });
+            self.providers.push(Provider {
+                id: row.id,
+                source: Source::from_row(row.local_path, row.url, row.spotify_id, row.youtube_id),
+                created: row.created,
+            });
         }
 
+        self.update_base();
+
         Ok(())
     }
 
+    fn update_base(&mut self) {
+        self.base = Some(Box::new(Base {
+            object: self.object.clone(),
+            providers: self.sources(),
+        }));
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        self.providers.iter().map(|p| p.source.clone()).collect()
+    }
+
+    /// See [`objgen::Entity::refresh_and_merge`]. The providers are merged as
+    /// a whole.
+    pub async fn refresh_and_merge(
+        &mut self,
+        db: &mut PgConnection,
+    ) -> objgen::Result<MergeReport> {
+        let id = match self.object.id() {
+            None => return Ok(MergeReport::default()),
+            Some(id) => id,
+        };
+
+        let mut remote = Track::load(id, db).await?;
+        let remote_base = remote.base.take().expect("loaded track has no base");
+        // without a base, treat everything as changed locally
+        let base = self.base.as_deref().unwrap_or(&remote_base);
+
+        let mut m = Merger::new();
+        let object = self.object.merge(&base.object, &remote_base.object, &mut m);
+        let sources = self.sources();
+        let merged_sources = m.field(
+            "providers",
+            &base.providers,
+            &sources,
+            &remote_base.providers,
+        );
+        let report = m.finish()?;
+
+        self.object = object;
+
+        if merged_sources != sources {
+            self.providers = remote.providers;
+        }
+
+        self.base = Some(remote_base);
+
+        Ok(report)
+    }
+
     pub async fn save(&mut self, db: &mut PgConnection) -> objgen::Result<()> {
         self.object.save(db).await?;
 
+        // the providers in the database are still the ones from when the
+        // track was loaded or last saved, which saves a few queries per track
+        // when saving large playlists
+        let providers_changed = self
+            .base
+            .as_ref()
+            .map_or(true, |base| base.providers != self.sources());
+
+        if providers_changed {
+            self.save_providers(db).await?;
+        }
+
+        self.update_base();
+
+        Ok(())
+    }
+
+    async fn save_providers(&self, db: &mut PgConnection) -> sqlx::Result<()> {
         // language=SQL
         sqlx::query!(
             "DELETE FROM track_provider WHERE track = $1",
@@ -150,14 +410,15 @@ impl Track {
 
             // language=SQL
             sqlx::query!(
-                "INSERT INTO track_provider (id, track, local_path, url, spotify_id, youtube_id) \
-                 VALUES ($1, $2, $3, $4, $5, $6)",
+                "INSERT INTO track_provider (id, track, local_path, url, spotify_id, youtube_id, created) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)",
                 p.id,
                 self.object.id(),
                 local_path,
                 url,
                 spotify_id,
-                youtube_id
+                youtube_id,
+                p.created
             )
             .execute(&mut *db)
             .await?;
@@ -171,6 +432,27 @@ impl Track {
     }
 }
 
+#[async_trait]
+impl objgen::Entity for Track {
+    type Object = object::Track;
+
+    async fn reload(&mut self, db: &mut PgConnection) -> sqlx::Result<()> {
+        Track::reload(self, db).await
+    }
+
+    async fn save(&mut self, db: &mut PgConnection) -> objgen::Result<()> {
+        Track::save(self, db).await
+    }
+
+    async fn refresh_and_merge(&mut self, db: &mut PgConnection) -> objgen::Result<MergeReport> {
+        Track::refresh_and_merge(self, db).await
+    }
+
+    fn object(&self) -> &object::Track {
+        &self.object
+    }
+}
+
 impl Display for Track {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         Display::fmt(&self.object, f)
@@ -182,3 +464,39 @@ impl HtmlDisplay for Track {
         HtmlDisplay::fmt(&self.object, f)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Connection, PgConnection};
+
+    use super::{Source, Track};
+    use crate::db::test_util::queries;
+
+    #[tokio::test]
+    #[ignore = "needs the database configured in srvrc"]
+    async fn test_load_by_youtube_ids() {
+        let mut conn = PgConnection::connect(env!("DATABASE_URL")).await.unwrap();
+        // rolled back when dropped, so nothing ends up in the database
+        let mut tx = conn.begin().await.unwrap();
+
+        let mut ids = Vec::new();
+
+        for i in 0..50 {
+            let id = format!("r2djtest{:03}", i);
+            let mut track = Track::new();
+            track.set_title(Some(format!("Track {}", i)));
+            track.add_provider(Source::Youtube(id.clone()));
+            track.save(&mut tx).await.unwrap();
+            ids.push(id);
+        }
+
+        ids.push("r2djtestnew".to_string());
+
+        let before = queries();
+        let tracks = Track::load_by_youtube_ids(&ids, &mut tx).await.unwrap();
+
+        assert_eq!(3, queries() - before);
+        assert_eq!(50, tracks.len());
+        assert_eq!(Some("Track 7"), tracks["r2djtest007"].title());
+    }
+}
@@ -1,8 +1,11 @@
+use std::time::Duration;
+
 use sqlx::PgConnection;
 use url::Url;
 use youtube_dl::{SingleVideo, YoutubeDlOutput};
 
-use crate::entity::import::ImportError;
+use crate::entity::import::{self, ImportError};
+use crate::spotify::{self, Candidate, SpotifyClient};
 
 use super::{Source, Track};
 
@@ -28,7 +31,7 @@ impl Track {
 
         let url = Url::parse_with_params("https://www.youtube.com/watch", [("v", id)])?;
 
-        let output = youtube_dl::YoutubeDl::new(url.into_string()).run()?;
+        let output = import::run_youtube_dl(&youtube_dl::YoutubeDl::new(url.into_string())).await?;
 
         let output = match output {
             YoutubeDlOutput::Playlist(_) => unreachable!(),
@@ -39,6 +42,70 @@ impl Track {
         Ok(track)
     }
 
+    /// Updates the track's title from its YouTube video, in case the video
+    /// was renamed since it was imported, and saves the track.
+    pub async fn reimport_from_youtube(
+        &mut self,
+        db: &mut PgConnection,
+    ) -> Result<(), ImportError> {
+        let id = self
+            .youtube_provider_id()
+            .ok_or(ImportError::NoYoutubeProvider)?;
+
+        let url = Url::parse_with_params("https://www.youtube.com/watch", [("v", id)])?;
+
+        let output = import::run_youtube_dl(&youtube_dl::YoutubeDl::new(url.into_string())).await?;
+
+        let output = match output {
+            YoutubeDlOutput::Playlist(_) => unreachable!(),
+            YoutubeDlOutput::SingleVideo(v) => v,
+        };
+
+        self.set_title(Some(output.title));
+        self.save(db).await?;
+
+        Ok(())
+    }
+
+    pub async fn load_by_spotify_id(id: &str, db: &mut PgConnection) -> sqlx::Result<Self> {
+        // language=SQL
+        let r = sqlx::query!("SELECT track FROM track_provider WHERE spotify_id = $1", id)
+            .fetch_one(&mut *db)
+            .await?
+            .track;
+        Track::load(r, &mut *db).await
+    }
+
+    /// Imports a track from Spotify. Since Spotify can't be played from,
+    /// YouTube is searched for the track and the video closest in duration
+    /// is added as a provider too. Returns the matched video, or `None` if
+    /// the track was already in the database.
+    pub async fn import_by_spotify_id(
+        id: &str,
+        spotify: &SpotifyClient,
+        db: &mut PgConnection,
+    ) -> Result<(Self, Option<Candidate>), ImportError> {
+        match Track::load_by_spotify_id(id, db).await {
+            Ok(v) => return Ok((v, None)),
+            Err(sqlx::Error::RowNotFound) => {}
+            Err(e) => return Err(e.into()),
+        };
+
+        let info = spotify.track_info(id).await?;
+        let query = format!("{} {}", info.artists.join(" "), info.title);
+        let candidates = search_youtube(&query).await?;
+
+        let video = spotify::best_match(&candidates, info.duration)
+            .cloned()
+            .ok_or(ImportError::NoMatch(query))?;
+
+        let mut track = Track::new();
+        track.set_title(Some(info.title));
+        track.add_provider(Source::Youtube(video.id.clone()));
+        track.add_provider(Source::Spotify(id.to_string()));
+        Ok((track, Some(video)))
+    }
+
     pub async fn import_from_youtube(
         metadata: &SingleVideo,
         db: Option<&mut PgConnection>,
@@ -57,3 +124,31 @@ impl Track {
         Ok(track)
     }
 }
+
+/// How many YouTube search results to consider when matching a track.
+const SEARCH_RESULTS: usize = 5;
+
+async fn search_youtube(query: &str) -> Result<Vec<Candidate>, ImportError> {
+    let ytdl = youtube_dl::YoutubeDl::new(format!("ytsearch{}:{}", SEARCH_RESULTS, query));
+    let output = import::run_youtube_dl(&ytdl).await?;
+
+    let videos = match output {
+        YoutubeDlOutput::Playlist(pl) => pl.entries.unwrap_or_default(),
+        YoutubeDlOutput::SingleVideo(_) => unreachable!(),
+    };
+
+    let candidates = videos
+        .into_iter()
+        .map(|v| Candidate {
+            duration: v
+                .duration
+                .as_ref()
+                .and_then(|d| d.as_f64())
+                .map(Duration::from_secs_f64),
+            id: v.id,
+            title: v.title,
+        })
+        .collect();
+
+    Ok(candidates)
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use super::Track;
+
+impl Track {
+    /// Tags the track with `tag`, creating the tag if it doesn't exist yet.
+    /// Returns false if the track already had the tag. The track has to be
+    /// saved already.
+    pub async fn add_tag(&self, tag: &str, db: &mut PgConnection) -> sqlx::Result<bool> {
+        let id = self.saved_id();
+
+        // language=SQL
+        sqlx::query!(
+            "INSERT INTO tag (id, name) VALUES ($1, $2) ON CONFLICT (name) DO NOTHING",
+            Uuid::new_v4(),
+            tag
+        )
+        .execute(&mut *db)
+        .await?;
+
+        // language=SQL
+        let result = sqlx::query!(
+            "INSERT INTO track_tag (track, tag) \
+             SELECT $1, id FROM tag WHERE name = $2 \
+             ON CONFLICT DO NOTHING",
+            id,
+            tag
+        )
+        .execute(&mut *db)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Removes `tag` from the track. Returns false if the track didn't have
+    /// the tag. The tag itself stays, even if no track has it anymore.
+    pub async fn remove_tag(&self, tag: &str, db: &mut PgConnection) -> sqlx::Result<bool> {
+        let id = self.saved_id();
+
+        // language=SQL
+        let result = sqlx::query!(
+            "DELETE FROM track_tag USING tag \
+             WHERE track_tag.tag = tag.id AND track_tag.track = $1 AND tag.name = $2",
+            id,
+            tag
+        )
+        .execute(&mut *db)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Returns the names of the track's tags, sorted by name.
+    pub async fn tags(&self, db: &mut PgConnection) -> sqlx::Result<Vec<String>> {
+        let id = match self.object.id() {
+            None => return Ok(Vec::new()),
+            Some(v) => v,
+        };
+
+        let mut tags = Track::load_tags(&[id], db).await?;
+        Ok(tags.remove(&id).unwrap_or_default())
+    }
+
+    /// Loads the names of the tags of the tracks with the given IDs, sorted
+    /// by name and keyed by track ID. Tracks without tags are left out.
+    pub async fn load_tags(
+        ids: &[Uuid],
+        db: &mut PgConnection,
+    ) -> sqlx::Result<HashMap<Uuid, Vec<String>>> {
+        // language=SQL
+        let rows = sqlx::query!(
+            "SELECT track_tag.track, tag.name FROM track_tag \
+             JOIN tag ON tag.id = track_tag.tag \
+             WHERE track_tag.track = ANY($1) \
+             ORDER BY tag.name",
+            ids
+        )
+        .fetch_all(db)
+        .await?;
+
+        let mut tags: HashMap<_, Vec<_>> = HashMap::new();
+
+        for row in rows {
+            tags.entry(row.track).or_default().push(row.name);
+        }
+
+        Ok(tags)
+    }
+
+    fn saved_id(&self) -> Uuid {
+        self.object
+            .id()
+            .expect("track needs to be saved before changing its tags")
+    }
+}
@@ -0,0 +1,25 @@
+use sqlx::PgPool;
+
+// defines `embedded()`, generated by build.rs from the migrations directory
+include!(concat!(env!("OUT_DIR"), "/migrations.rs"));
+
+/// Applies the migrations this binary was built with that haven't been
+/// applied to the database yet.
+pub async fn apply_pending(db: &PgPool) -> miglib::Result<usize> {
+    let mut conn = db.acquire().await?;
+    miglib::apply_pending(&mut conn, &embedded()).await
+}
+
+/// Returns how many of the migrations this binary was built with haven't
+/// been applied to the database yet. Applied migrations this binary doesn't
+/// know about, from a newer version, are ignored.
+pub async fn check_pending(db: &PgPool) -> miglib::Result<usize> {
+    let mut conn = db.acquire().await?;
+    miglib::init(&mut conn).await?;
+    let applied = miglib::applied(&mut conn).await?;
+
+    Ok(embedded()
+        .iter()
+        .filter(|m| !applied.contains(&m.id()))
+        .count())
+}
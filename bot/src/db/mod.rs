@@ -1,5 +1,9 @@
 #[macro_use]
-mod objgen;
+pub mod objgen;
 
 pub mod entity;
+pub mod migrations;
 pub mod object;
+pub mod query;
+#[cfg(test)]
+pub mod test_util;
@@ -1,10 +1,12 @@
 use std::fmt::{Display, Formatter};
 
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use sqlx::postgres::{PgArguments, PgRow};
-use sqlx::{Arguments, FromRow, PgConnection, Row};
+use sqlx::{Arguments, Connection, FromRow, PgConnection, Row};
 use uuid::Uuid;
 
-use crate::db::objgen::{self, ObjectHeader};
+use crate::db::objgen::{self, Detach, Merger, ObjectHeader};
 use crate::fmt::HtmlDisplay;
 
 #[derive(Clone, Default, Debug)]
@@ -14,14 +16,43 @@ pub struct Playlist {
     title: String,
     spotify_id: Option<String>,
     youtube_id: Option<String>,
+    nesting_mode: NestingMode,
 }
 
+/// How the entries of a playlist are played when it is nested in another
+/// playlist.
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub enum NestingMode {
+    /// The tracks are mixed in with the parent playlist's entries.
     Flatten,
+    /// The playlist counts as a single entry of the parent playlist, which
+    /// plays one of its tracks each time it is chosen.
     RoundRobin,
 }
 
+impl NestingMode {
+    pub fn from_db(value: i16) -> Option<Self> {
+        match value {
+            0 => Some(NestingMode::Flatten),
+            1 => Some(NestingMode::RoundRobin),
+            _ => None,
+        }
+    }
+
+    pub fn to_db(self) -> i16 {
+        match self {
+            NestingMode::Flatten => 0,
+            NestingMode::RoundRobin => 1,
+        }
+    }
+}
+
+impl Default for NestingMode {
+    fn default() -> Self {
+        NestingMode::Flatten
+    }
+}
+
 impl_detach!(Playlist);
 
 impl Playlist {
@@ -67,12 +98,53 @@ impl Playlist {
         self.youtube_id.as_deref()
     }
 
-    pub fn set_nesting_mode(&mut self, _nesting_mode: NestingMode) {
-        todo!()
+    pub fn set_nesting_mode(&mut self, nesting_mode: NestingMode) {
+        self.header.mark_changed();
+        self.nesting_mode = nesting_mode;
     }
 
     pub fn nesting_mode(&self) -> NestingMode {
-        NestingMode::Flatten // TODO
+        self.nesting_mode
+    }
+
+    /// Merges the changes made to this playlist since it was `base` with
+    /// the ones made in the database since then, which resulted in `remote`.
+    pub fn merge(&self, base: &Self, remote: &Self, m: &mut Merger) -> Self {
+        let (spotify_id, youtube_id) = m.field(
+            "external source",
+            &(base.spotify_id.clone(), base.youtube_id.clone()),
+            &(self.spotify_id.clone(), self.youtube_id.clone()),
+            &(remote.spotify_id.clone(), remote.youtube_id.clone()),
+        );
+
+        let mut header = remote.header.clone();
+        header.mark_changed();
+
+        Playlist {
+            header,
+            code: m.field("code", &base.code, &self.code, &remote.code),
+            title: m.field("title", &base.title, &self.title, &remote.title),
+            spotify_id,
+            youtube_id,
+            nesting_mode: m.field(
+                "nesting mode",
+                &base.nesting_mode,
+                &self.nesting_mode,
+                &remote.nesting_mode,
+            ),
+        }
+    }
+
+    /// Like [`Detach::detach`], but also clears the code and the external
+    /// source, so that the copy can be saved alongside the original. A new
+    /// code is generated when saving.
+    pub fn detach_unlinked(&self) -> Self {
+        Playlist {
+            code: None,
+            spotify_id: None,
+            youtube_id: None,
+            ..self.detach()
+        }
     }
 }
 
@@ -88,6 +160,17 @@ impl Playlist {
             .await
     }
 
+    /// Loads the playlists with the given IDs with a single query. IDs which
+    /// don't exist are left out, and the result is in no particular order.
+    pub async fn load_many(ids: &[Uuid], db: &mut PgConnection) -> sqlx::Result<Vec<Self>> {
+        let mut args = PgArguments::default();
+        args.add(ids);
+        // language=SQL
+        sqlx::query_as_with("SELECT * FROM playlist WHERE id = ANY($1)", args)
+            .fetch_all(db)
+            .await
+    }
+
     pub async fn load_by_code(code: &str, db: &mut PgConnection) -> sqlx::Result<Self> {
         let mut args = PgArguments::default();
         args.add(code);
@@ -100,10 +183,21 @@ impl Playlist {
         .await
     }
 
+    /// Like [`Playlist::load_by_code`], but also finds the playlist if it has been
+    /// deleted.
+    pub async fn load_by_code_any(code: &str, db: &mut PgConnection) -> sqlx::Result<Self> {
+        let mut args = PgArguments::default();
+        args.add(code);
+        // language=SQL
+        sqlx::query_as_with("SELECT * FROM playlist WHERE code = $1", args)
+            .fetch_one(db)
+            .await
+    }
+
     pub async fn load_by_youtube_id(id: &str, db: &mut PgConnection) -> sqlx::Result<Self> {
         // language=SQL
         let row = sqlx::query!(
-            "SELECT id, code, title, created, modified \
+            "SELECT id, code, title, nesting_mode, created, modified \
              FROM playlist \
              WHERE youtube_id = $1 AND deleted = false",
             id,
@@ -117,9 +211,34 @@ impl Playlist {
             title: row.title,
             spotify_id: None,
             youtube_id: Some(id.to_string()),
+            nesting_mode: nesting_mode(row.nesting_mode)?,
         })
     }
 
+    /// Finds the playlists whose title contains the words in `query`, best
+    /// matches first.
+    pub async fn search(
+        query: &str,
+        limit: i64,
+        offset: i64,
+        db: &mut PgConnection,
+    ) -> sqlx::Result<Vec<Self>> {
+        let mut args = PgArguments::default();
+        args.add(query);
+        args.add(limit);
+        args.add(offset);
+        // language=SQL
+        sqlx::query_as_with(
+            "SELECT * FROM playlist \
+             WHERE deleted = FALSE AND to_tsvector('simple', title) @@ plainto_tsquery('simple', $1) \
+             ORDER BY ts_rank(to_tsvector('simple', title), plainto_tsquery('simple', $1)) DESC, code \
+             LIMIT $2 OFFSET $3",
+            args,
+        )
+        .fetch_all(db)
+        .await
+    }
+
     pub async fn save(&mut self, db: &mut PgConnection) -> objgen::Result<()> {
         // using unchecked queries because it wants non-Option spotify_id/youtube_id
 
@@ -129,36 +248,61 @@ impl Playlist {
                 // language=SQL
                 let code = match &self.code {
                     None => {
-                        sqlx::query_unchecked!(
-                            "INSERT INTO playlist (id, code, title, spotify_id, youtube_id, created, deleted) \
-                             VALUES ($1, DEFAULT, $2, $3, $4, $5, $6) \
-                             RETURNING code",
-                            save.id(),
-                            &self.title,
-                            &self.spotify_id,
-                            &self.youtube_id,
-                            save.now(),
-                            save.deleted(),
-                        )
-                        .fetch_one(&mut *db)
-                        .await?
-                        .code
+                        let mut attempt = 1;
+
+                        // the sequence generating the code can run into one
+                        // that was set explicitly, the next attempt gets the
+                        // next number since sequences aren't rolled back
+                        loop {
+                            let mut tx = db.begin().await?;
+
+                            let result = sqlx::query_unchecked!(
+                                "INSERT INTO playlist (id, code, title, spotify_id, youtube_id, nesting_mode, created, deleted) \
+                                 VALUES ($1, DEFAULT, $2, $3, $4, $5, $6, $7) \
+                                 RETURNING code",
+                                save.id(),
+                                &self.title,
+                                &self.spotify_id,
+                                &self.youtube_id,
+                                self.nesting_mode.to_db(),
+                                save.now(),
+                                save.deleted(),
+                            )
+                            .fetch_one(&mut *tx)
+                            .await;
+
+                            match result {
+                                Ok(row) => {
+                                    tx.commit().await?;
+                                    break row.code;
+                                }
+                                Err(e)
+                                    if attempt < objgen::CODE_ATTEMPTS
+                                        && objgen::is_code_taken(&e, "playlist") =>
+                                {
+                                    attempt += 1;
+                                }
+                                Err(e) => return Err(e.into()),
+                            }
+                        }
                     }
                     Some(code) => {
                         sqlx::query_unchecked!(
-                            "INSERT INTO playlist (id, code, title, spotify_id, youtube_id, created, deleted) \
-                             VALUES ($1, $2, $3, $4, $5, $6, $7) \
+                            "INSERT INTO playlist (id, code, title, spotify_id, youtube_id, nesting_mode, created, deleted) \
+                             VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
                              RETURNING code",
                             save.id(),
                             code,
                             &self.title,
                             &self.spotify_id,
                             &self.youtube_id,
+                            self.nesting_mode.to_db(),
                             save.now(),
                             save.deleted(),
                         )
                         .fetch_one(&mut *db)
-                        .await?
+                        .await
+                        .map_err(objgen::Error::code_taken("playlist", code))?
                         .code
                     }
                 };
@@ -185,21 +329,31 @@ impl Playlist {
                     return Err(objgen::Error::Deleted);
                 }
 
-                sqlx::query_unchecked!(
+                let code = self.code.as_deref().expect("code must be set");
+
+                // checking deleted again in case it was deleted since the
+                // query above
+                let result = sqlx::query_unchecked!(
                     // language=SQL
                     "UPDATE playlist \
-                     SET code = $2, title = $3, spotify_id = $4, youtube_id = $5, modified = $6, deleted = $7 \
-                     WHERE id = $1",
+                     SET code = $2, title = $3, spotify_id = $4, youtube_id = $5, nesting_mode = $6, modified = $7, deleted = $8 \
+                     WHERE id = $1 AND deleted = FALSE",
                     save.id(),
-                    self.code.as_deref().expect("code must be set"),
+                    code,
                     &self.title,
                     &self.spotify_id,
                     &self.youtube_id,
+                    self.nesting_mode.to_db(),
                     save.now(),
                     save.deleted(),
                 )
                 .execute(&mut *db)
-                .await?;
+                .await
+                .map_err(objgen::Error::code_taken("playlist", code))?;
+
+                if result.rows_affected() == 0 {
+                    return Err(objgen::Error::Deleted);
+                }
             }
 
             save.succeed();
@@ -212,6 +366,61 @@ impl Playlist {
         self.header.mark_deleted();
         self.save(db).await
     }
+
+    /// Undoes [`Playlist::delete`]. Only clears the deleted flag, other unsaved
+    /// changes are not saved.
+    pub async fn restore(&mut self, db: &mut PgConnection) -> objgen::Result<()> {
+        let id = match self.header.id() {
+            None => return Ok(()),
+            Some(id) => id,
+        };
+
+        let now = Utc::now();
+
+        // language=SQL
+        sqlx::query!(
+            "UPDATE playlist SET deleted = false, modified = $2 WHERE id = $1",
+            id,
+            now
+        )
+        .execute(db)
+        .await?;
+
+        self.header.restored(now);
+
+        Ok(())
+    }
+
+    pub fn deleted(&self) -> bool {
+        self.header.deleted()
+    }
+}
+
+#[async_trait]
+impl objgen::Object for Playlist {
+    async fn load(id: Uuid, db: &mut PgConnection) -> sqlx::Result<Self> {
+        Playlist::load(id, db).await
+    }
+
+    async fn save(&mut self, db: &mut PgConnection) -> objgen::Result<()> {
+        Playlist::save(self, db).await
+    }
+
+    fn id(&self) -> Option<Uuid> {
+        self.header.id()
+    }
+
+    fn persistent(&self) -> bool {
+        self.header.persistent()
+    }
+
+    fn created_at(&self) -> Option<DateTime<Utc>> {
+        self.header.created_at()
+    }
+
+    fn modified_at(&self) -> Option<DateTime<Utc>> {
+        self.header.modified_at()
+    }
 }
 
 impl<'r> FromRow<'r, PgRow> for Playlist {
@@ -221,6 +430,7 @@ impl<'r> FromRow<'r, PgRow> for Playlist {
         let title = row.try_get("title")?;
         let spotify_id = row.try_get("spotify_id")?;
         let youtube_id = row.try_get("youtube_id")?;
+        let nesting_mode = nesting_mode(row.try_get("nesting_mode")?)?;
 
         Ok(Playlist {
             header,
@@ -228,10 +438,16 @@ impl<'r> FromRow<'r, PgRow> for Playlist {
             title,
             spotify_id,
             youtube_id,
+            nesting_mode,
         })
     }
 }
 
+fn nesting_mode(value: i16) -> sqlx::Result<NestingMode> {
+    NestingMode::from_db(value)
+        .ok_or_else(|| sqlx::Error::Decode(format!("invalid nesting mode {}", value).into()))
+}
+
 impl Display for Playlist {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         write!(f, "{} {}", self.code.as_deref().unwrap_or(""), self.title)
@@ -1,12 +1,13 @@
 use std::fmt::{Display, Formatter};
 
-use chrono::NaiveDate;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
 use sqlx::postgres::{PgArguments, PgRow};
-use sqlx::{Arguments, FromRow, PgConnection, Row};
+use sqlx::{Arguments, Connection, FromRow, PgConnection, Row};
 use uuid::Uuid;
 
 use crate::db::objgen;
-use crate::db::objgen::ObjectHeader;
+use crate::db::objgen::{Merger, ObjectHeader};
 use crate::fmt::HtmlDisplay;
 
 #[derive(Clone, Debug, Default)]
@@ -16,8 +17,12 @@ pub struct Track {
     title: Option<String>,
     genre: Option<Uuid>,
     release_date: Option<NaiveDate>,
+    gain_db: Option<f64>,
 }
 
+/// Tracks can't be made louder or quieter than this, in dB.
+pub const MAX_GAIN_DB: f64 = 20.0;
+
 impl_detach!(Track);
 
 impl Track {
@@ -29,6 +34,10 @@ impl Track {
         self.code = Some(code.into());
     }
 
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+
     pub fn set_title(&mut self, title: Option<String>) {
         self.header.mark_changed();
         self.title = title;
@@ -55,6 +64,38 @@ impl Track {
     pub fn release_date(&self) -> Option<NaiveDate> {
         self.release_date
     }
+
+    /// Sets the gain applied when playing the track. Values outside of
+    /// ±[`MAX_GAIN_DB`] are clamped.
+    pub fn set_gain_db(&mut self, gain_db: Option<f64>) {
+        self.header.mark_changed();
+        self.gain_db = gain_db.map(|g| g.clamp(-MAX_GAIN_DB, MAX_GAIN_DB));
+    }
+
+    pub fn gain_db(&self) -> Option<f64> {
+        self.gain_db
+    }
+
+    /// Merges the changes made to this track since it was `base` with the
+    /// ones made in the database since then, which resulted in `remote`.
+    pub fn merge(&self, base: &Self, remote: &Self, m: &mut Merger) -> Self {
+        let mut header = remote.header.clone();
+        header.mark_changed();
+
+        Track {
+            header,
+            code: m.field("code", &base.code, &self.code, &remote.code),
+            title: m.field("title", &base.title, &self.title, &remote.title),
+            genre: m.field("genre", &base.genre, &self.genre, &remote.genre),
+            release_date: m.field(
+                "release date",
+                &base.release_date,
+                &self.release_date,
+                &remote.release_date,
+            ),
+            gain_db: m.field("gain", &base.gain_db, &self.gain_db, &remote.gain_db),
+        }
+    }
 }
 
 impl Track {
@@ -69,6 +110,17 @@ impl Track {
             .await
     }
 
+    /// Loads the tracks with the given IDs with a single query. IDs which
+    /// don't exist are left out, and the result is in no particular order.
+    pub async fn load_many(ids: &[Uuid], db: &mut PgConnection) -> sqlx::Result<Vec<Self>> {
+        let mut args = PgArguments::default();
+        args.add(ids);
+        // language=SQL
+        sqlx::query_as_with("SELECT * FROM track WHERE id = ANY($1)", args)
+            .fetch_all(db)
+            .await
+    }
+
     pub async fn load_by_code(code: &str, db: &mut PgConnection) -> sqlx::Result<Self> {
         let mut args = PgArguments::default();
         args.add(code);
@@ -81,42 +133,126 @@ impl Track {
         .await
     }
 
+    /// Like [`Track::load_by_code`], but also finds the track if it has been
+    /// deleted.
+    pub async fn load_by_code_any(code: &str, db: &mut PgConnection) -> sqlx::Result<Self> {
+        let mut args = PgArguments::default();
+        args.add(code);
+        // language=SQL
+        sqlx::query_as_with("SELECT * FROM track WHERE code = $1", args)
+            .fetch_one(db)
+            .await
+    }
+
+    /// Finds the tracks whose title contains the words in `query`, best
+    /// matches first. With a `tag`, only tracks with that tag are found, and
+    /// `query` may be empty to find all of them.
+    pub async fn search(
+        query: &str,
+        tag: Option<&str>,
+        limit: i64,
+        offset: i64,
+        db: &mut PgConnection,
+    ) -> sqlx::Result<Vec<Self>> {
+        let mut args = PgArguments::default();
+        args.add(limit);
+        args.add(offset);
+
+        // language=SQL
+        let mut sql = "SELECT track.* FROM track".to_string();
+
+        if tag.is_some() {
+            sql.push_str(
+                " INNER JOIN track_tag ON track_tag.track = track.id \
+                 INNER JOIN tag ON tag.id = track_tag.tag",
+            );
+        }
+
+        sql.push_str(" WHERE track.deleted = FALSE");
+
+        if let Some(tag) = tag {
+            args.add(tag);
+            sql.push_str(" AND tag.name = $3");
+        }
+
+        if !query.is_empty() || tag.is_none() {
+            args.add(query);
+            let n = if tag.is_some() { 4 } else { 3 };
+            sql.push_str(&format!(
+                " AND to_tsvector('simple', coalesce(title, '')) @@ plainto_tsquery('simple', ${n}) \
+                 ORDER BY ts_rank(to_tsvector('simple', coalesce(title, '')), plainto_tsquery('simple', ${n})) DESC, code",
+                n = n
+            ));
+        } else {
+            sql.push_str(" ORDER BY code");
+        }
+
+        sql.push_str(" LIMIT $1 OFFSET $2");
+
+        sqlx::query_as_with(&sql, args).fetch_all(db).await
+    }
+
     pub async fn save(&mut self, db: &mut PgConnection) -> objgen::Result<()> {
         if let Some(save) = self.header.save() {
             if save.is_new() {
                 // language=SQL
                 let code = match &self.code {
                     None => {
-                        sqlx::query_unchecked!(
-                            "INSERT INTO track (id, code, title, genre, release_date, created, deleted) \
-                             VALUES ($1, DEFAULT, $2, $3, $4, $5, $6) \
-                             RETURNING code",
-                            save.id(),
-                            &self.title,
-                            &self.genre,
-                            &self.release_date,
-                            save.now(),
-                            save.deleted(),
-                        )
-                        .fetch_one(&mut *db)
-                        .await?
-                        .code
+                        let mut attempt = 1;
+
+                        // the sequence generating the code can run into one
+                        // that was set explicitly, the next attempt gets the
+                        // next number since sequences aren't rolled back
+                        loop {
+                            let mut tx = db.begin().await?;
+
+                            let result = sqlx::query_unchecked!(
+                                "INSERT INTO track (id, code, title, genre, release_date, gain_db, created, deleted) \
+                                 VALUES ($1, DEFAULT, $2, $3, $4, $5, $6, $7) \
+                                 RETURNING code",
+                                save.id(),
+                                &self.title,
+                                &self.genre,
+                                &self.release_date,
+                                &self.gain_db,
+                                save.now(),
+                                save.deleted(),
+                            )
+                            .fetch_one(&mut *tx)
+                            .await;
+
+                            match result {
+                                Ok(row) => {
+                                    tx.commit().await?;
+                                    break row.code;
+                                }
+                                Err(e)
+                                    if attempt < objgen::CODE_ATTEMPTS
+                                        && objgen::is_code_taken(&e, "track") =>
+                                {
+                                    attempt += 1;
+                                }
+                                Err(e) => return Err(e.into()),
+                            }
+                        }
                     }
                     Some(code) => {
                         sqlx::query_unchecked!(
-                            "INSERT INTO track (id, code, title, genre, release_date, created, deleted) \
-                             VALUES ($1, $2, $3, $4, $5, $6, $7) \
+                            "INSERT INTO track (id, code, title, genre, release_date, gain_db, created, deleted) \
+                             VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
                              RETURNING code",
                             save.id(),
                             code,
                             &self.title,
                             &self.genre,
                             &self.release_date,
+                            &self.gain_db,
                             save.now(),
                             save.deleted(),
                         )
                         .fetch_one(&mut *db)
-                        .await?
+                        .await
+                        .map_err(objgen::Error::code_taken("track", code))?
                         .code
                     }
                 };
@@ -143,20 +279,31 @@ impl Track {
                     return Err(objgen::Error::Deleted);
                 }
 
-                sqlx::query_unchecked!(
+                let code = self.code.as_deref().expect("code must be set");
+
+                // checking deleted again in case it was deleted since the
+                // query above
+                let result = sqlx::query_unchecked!(
                     // language=SQL
                     "UPDATE track \
-                     SET code = $2, title = $3, genre = $4, release_date = $5, modified = $6 \
-                     WHERE id = $1",
+                     SET code = $2, title = $3, genre = $4, release_date = $5, gain_db = $6, modified = $7, deleted = $8 \
+                     WHERE id = $1 AND deleted = FALSE",
                     save.id(),
-                    self.code.as_deref().expect("code must be set"),
+                    code,
                     &self.title,
                     &self.genre,
                     &self.release_date,
+                    &self.gain_db,
                     save.now(),
+                    save.deleted(),
                 )
                 .execute(&mut *db)
-                .await?;
+                .await
+                .map_err(objgen::Error::code_taken("track", code))?;
+
+                if result.rows_affected() == 0 {
+                    return Err(objgen::Error::Deleted);
+                }
             };
 
             save.succeed();
@@ -169,6 +316,61 @@ impl Track {
         self.header.mark_deleted();
         self.save(db).await
     }
+
+    /// Undoes [`Track::delete`]. Only clears the deleted flag, other unsaved
+    /// changes are not saved.
+    pub async fn restore(&mut self, db: &mut PgConnection) -> objgen::Result<()> {
+        let id = match self.header.id() {
+            None => return Ok(()),
+            Some(id) => id,
+        };
+
+        let now = Utc::now();
+
+        // language=SQL
+        sqlx::query!(
+            "UPDATE track SET deleted = false, modified = $2 WHERE id = $1",
+            id,
+            now
+        )
+        .execute(db)
+        .await?;
+
+        self.header.restored(now);
+
+        Ok(())
+    }
+
+    pub fn deleted(&self) -> bool {
+        self.header.deleted()
+    }
+}
+
+#[async_trait]
+impl objgen::Object for Track {
+    async fn load(id: Uuid, db: &mut PgConnection) -> sqlx::Result<Self> {
+        Track::load(id, db).await
+    }
+
+    async fn save(&mut self, db: &mut PgConnection) -> objgen::Result<()> {
+        Track::save(self, db).await
+    }
+
+    fn id(&self) -> Option<Uuid> {
+        self.header.id()
+    }
+
+    fn persistent(&self) -> bool {
+        self.header.persistent()
+    }
+
+    fn created_at(&self) -> Option<DateTime<Utc>> {
+        self.header.created_at()
+    }
+
+    fn modified_at(&self) -> Option<DateTime<Utc>> {
+        self.header.modified_at()
+    }
 }
 
 impl<'r> FromRow<'r, PgRow> for Track {
@@ -178,6 +380,7 @@ impl<'r> FromRow<'r, PgRow> for Track {
         let title = row.try_get("title")?;
         let genre = row.try_get("genre")?;
         let release_date = row.try_get("release_date")?;
+        let gain_db = row.try_get("gain_db")?;
 
         Ok(Track {
             header,
@@ -185,6 +388,7 @@ impl<'r> FromRow<'r, PgRow> for Track {
             title,
             genre,
             release_date,
+            gain_db,
         })
     }
 }
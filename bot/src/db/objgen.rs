@@ -1,12 +1,18 @@
+use std::fmt::{Display, Formatter};
+
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use sqlx::postgres::{PgQueryResult, PgRow};
-use sqlx::{Executor, FromRow, PgPool, Postgres, Row};
+use sqlx::postgres::PgRow;
+use sqlx::{FromRow, PgConnection, Row};
 use thiserror::Error;
 use uuid::Uuid;
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// How often inserting a new object is tried when the code generated by the
+/// database for it is taken already.
+pub const CODE_ATTEMPTS: usize = 3;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("the table was changed by someone else while editing, at {0}")]
@@ -15,6 +21,35 @@ pub enum Error {
     Deleted,
     #[error("{0}")]
     Sqlx(#[from] sqlx::Error),
+    #[error("conflicting changes to {0}")]
+    Conflict(Conflict),
+    #[error("code '{0}' is already in use")]
+    CodeTaken(String),
+}
+
+impl Error {
+    /// Converts a violation of the unique constraint on the code column of
+    /// `table` into [`Error::CodeTaken`], leaving other errors untouched.
+    pub fn code_taken<'a>(table: &'a str, code: &'a str) -> impl FnOnce(sqlx::Error) -> Error + 'a {
+        move |e| {
+            if is_code_taken(&e, table) {
+                Error::CodeTaken(code.to_string())
+            } else {
+                e.into()
+            }
+        }
+    }
+}
+
+/// Returns whether `e` is a violation of the unique constraint on the code
+/// column of `table`.
+pub fn is_code_taken(e: &sqlx::Error, table: &str) -> bool {
+    match e {
+        sqlx::Error::Database(e) => e
+            .constraint()
+            .map_or(false, |c| c == format!("{}_code_key", table)),
+        _ => false,
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, Ord, PartialOrd)]
@@ -71,6 +106,12 @@ impl ObjectHeader {
         self.deleted = true;
     }
 
+    /// Records that the deleted flag was cleared in the database at `now`.
+    pub fn restored(&mut self, now: DateTime<Utc>) {
+        self.deleted = false;
+        self.modified_at = Some(now);
+    }
+
     pub fn save(&mut self) -> Option<Save> {
         if self.id.is_some() && !self.modified {
             None
@@ -141,13 +182,12 @@ pub trait Detach: Sized {
     fn detach(&self) -> Self;
 }
 
+/// A single database row.
 #[async_trait]
 pub trait Object: Sized {
-    async fn load(id: Uuid, db: &PgPool) -> sqlx::Result<Self>;
+    async fn load(id: Uuid, db: &mut PgConnection) -> sqlx::Result<Self>;
 
-    async fn save<'a, E>(&mut self, db: E) -> Result<PgQueryResult>
-    where
-        E: Executor<'a, Database = Postgres> + Copy;
+    async fn save(&mut self, db: &mut PgConnection) -> Result<()>;
 
     fn id(&self) -> Option<Uuid>;
 
@@ -158,17 +198,106 @@ pub trait Object: Sized {
     fn modified_at(&self) -> Option<DateTime<Utc>>;
 }
 
+/// An [`Object`] together with the data from other tables that belongs to
+/// it.
 #[async_trait]
 pub trait Entity {
     type Object: Object;
 
-    async fn reload(&mut self, db: &PgPool) -> sqlx::Result<()>;
+    async fn reload(&mut self, db: &mut PgConnection) -> sqlx::Result<()>;
 
-    async fn save(&mut self, db: &PgPool) -> Result<PgQueryResult>;
+    /// Loads the current state from the database and applies the local
+    /// changes on top of it, for when saving failed with
+    /// [`Error::OutdatedState`]. Fails with [`Error::Conflict`] and leaves
+    /// the entity as is if a field was changed both locally and in the
+    /// database.
+    async fn refresh_and_merge(&mut self, db: &mut PgConnection) -> Result<MergeReport>;
+
+    async fn save(&mut self, db: &mut PgConnection) -> Result<()>;
 
     fn object(&self) -> &Self::Object;
 }
 
+/// What happened to each field when merging local changes with the state in
+/// the database.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MergeReport {
+    /// Fields changed locally, which were applied on top.
+    pub replayed: Vec<&'static str>,
+    /// Fields changed in the database, which were taken over.
+    pub updated: Vec<&'static str>,
+}
+
+/// Fields that were changed both locally and in the database, to different
+/// values.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Conflict {
+    pub fields: Vec<&'static str>,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.fields.join(", "))
+    }
+}
+
+/// Three-way merge of an object's fields, comparing the local and database
+/// state against the state both started from.
+#[derive(Debug, Default)]
+pub struct Merger {
+    report: MergeReport,
+    conflicts: Vec<&'static str>,
+}
+
+impl Merger {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the merged value of a field. On a conflict, the local value is
+    /// returned, but [`finish`](Self::finish) will fail.
+    pub fn field<T>(&mut self, name: &'static str, base: &T, local: &T, remote: &T) -> T
+    where
+        T: PartialEq + Clone,
+    {
+        match (local != base, remote != base) {
+            (true, false) => {
+                self.report.replayed.push(name);
+                local.clone()
+            }
+            (false, true) => {
+                self.report.updated.push(name);
+                remote.clone()
+            }
+            (true, true) if local != remote => {
+                self.conflicts.push(name);
+                local.clone()
+            }
+            _ => local.clone(),
+        }
+    }
+
+    pub fn finish(self) -> Result<MergeReport> {
+        if self.conflicts.is_empty() {
+            Ok(self.report)
+        } else {
+            Err(Error::Conflict(Conflict {
+                fields: self.conflicts,
+            }))
+        }
+    }
+}
+
+/// Saves all of `objects`, stopping at the first one that fails. Objects
+/// that haven't changed since loading are skipped by their `save` anyway.
+pub async fn save_all<T: Object>(objects: &mut [T], db: &mut PgConnection) -> Result<()> {
+    for object in objects {
+        object.save(db).await?;
+    }
+
+    Ok(())
+}
+
 macro_rules! impl_detach {
     ($name:ident) => {
         impl $crate::db::objgen::Detach for $name {
@@ -203,6 +332,13 @@ macro_rules! impl_object {
         pub fn modified_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
             self.header.modified_at()
         }
+
+        /// Marks the object as changed even though none of its own fields
+        /// were, for when data belonging to it in other tables changes.
+        #[allow(unused)]
+        pub fn mark_changed(&mut self) {
+            self.header.mark_changed();
+        }
     };
 }
 
@@ -231,3 +367,135 @@ macro_rules! check_out_of_date {
         }
     };
 }
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Connection, PgConnection};
+    use uuid::Uuid;
+
+    use crate::db::object::{Playlist, Track};
+
+    use super::{save_all, Error, Merger, ObjectHeader};
+
+    #[test]
+    fn test_merge() {
+        let mut m = Merger::new();
+        assert_eq!("local", m.field("title", &"base", &"local", &"base"));
+        assert_eq!(2, m.field("entries", &1, &1, &2));
+        assert_eq!(3, m.field("code", &1, &3, &3));
+        assert_eq!(1, m.field("genre", &1, &1, &1));
+
+        let report = m.finish().unwrap();
+        assert_eq!(vec!["title"], report.replayed);
+        assert_eq!(vec!["entries"], report.updated);
+
+        let mut m = Merger::new();
+        m.field("title", &"base", &"local", &"remote");
+        m.field("entries", &1, &2, &1);
+
+        match m.finish() {
+            Err(Error::Conflict(c)) => assert_eq!(vec!["title"], c.fields),
+            _ => panic!("expected a conflict"),
+        }
+    }
+
+    #[test]
+    fn test_header_deleted() {
+        let mut header = ObjectHeader::from_loaded(Uuid::new_v4(), None, None, false);
+        assert!(header.persistent());
+        assert!(header.save().is_none());
+
+        header.mark_deleted();
+        assert!(header.deleted());
+        assert!(!header.persistent());
+
+        let save = header.save().unwrap();
+        assert!(!save.is_new());
+        assert!(save.deleted());
+        save.succeed();
+
+        assert!(header.persistent());
+        assert!(header.deleted());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the database configured in srvrc"]
+    async fn test_deleted_objects() {
+        let mut conn = PgConnection::connect(env!("DATABASE_URL")).await.unwrap();
+        let mut tx = conn.begin().await.unwrap();
+
+        let mut track = Track::new();
+        track.save(&mut tx).await.unwrap();
+        track.delete(&mut tx).await.unwrap();
+
+        let code = track.code().unwrap().to_string();
+        let loaded = Track::load_by_code(&code, &mut tx).await;
+        assert!(matches!(loaded, Err(sqlx::Error::RowNotFound)));
+
+        track.set_title(Some("Deleted".to_string()));
+        assert!(matches!(track.save(&mut tx).await, Err(Error::Deleted)));
+
+        let mut playlist = Playlist::new();
+        playlist.save(&mut tx).await.unwrap();
+        playlist.delete(&mut tx).await.unwrap();
+
+        let code = playlist.code().unwrap().to_string();
+        let loaded = Playlist::load_by_code(&code, &mut tx).await;
+        assert!(matches!(loaded, Err(sqlx::Error::RowNotFound)));
+
+        playlist.set_title("Deleted");
+        assert!(matches!(playlist.save(&mut tx).await, Err(Error::Deleted)));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the database configured in srvrc"]
+    async fn test_save_all() {
+        let mut conn = PgConnection::connect(env!("DATABASE_URL")).await.unwrap();
+        // rolled back when dropped, so nothing ends up in the database
+        let mut tx = conn.begin().await.unwrap();
+
+        let mut tracks = vec![Track::new(), Track::new()];
+        tracks[1].set_title(Some("Track".to_string()));
+        save_all(&mut tracks, &mut tx).await.unwrap();
+        assert!(tracks.iter().all(|t| t.persistent()));
+
+        let mut playlists = vec![Playlist::new(), Playlist::new()];
+        playlists[1].set_title("Playlist");
+        save_all(&mut playlists, &mut tx).await.unwrap();
+        assert!(playlists.iter().all(|p| p.persistent()));
+
+        // unchanged objects are left alone
+        let modified_at = playlists[1].modified_at();
+        save_all(&mut playlists, &mut tx).await.unwrap();
+        assert_eq!(modified_at, playlists[1].modified_at());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the database configured in srvrc"]
+    async fn test_code_taken() {
+        let mut conn = PgConnection::connect(env!("DATABASE_URL")).await.unwrap();
+        let mut tx = conn.begin().await.unwrap();
+
+        let mut first = Track::new();
+        first.save(&mut tx).await.unwrap();
+        let code: u64 = first.code().unwrap().parse().unwrap();
+
+        // take the code the sequence generates next
+        let mut taken = Track::new();
+        taken.set_code(format!("{:08}", code + 1));
+        taken.save(&mut tx).await.unwrap();
+
+        let mut generated = Track::new();
+        generated.save(&mut tx).await.unwrap();
+        assert_eq!(Some(&*format!("{:08}", code + 2)), generated.code());
+
+        // this fails the transaction, so it has to come last
+        let mut duplicate = Track::new();
+        duplicate.set_code(first.code().unwrap());
+
+        match duplicate.save(&mut tx).await {
+            Err(Error::CodeTaken(c)) => assert_eq!(first.code().unwrap(), c),
+            r => panic!("expected the code to be taken, got {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use sqlx::postgres::PgArguments;
+use sqlx::Arguments;
+
+/// How similar a title has to be to the search terms for fuzzy matching to
+/// find it. `pg_trgm`'s own default of 0.6 misses too many misspelled titles.
+pub const WORD_SIMILARITY_THRESHOLD: f32 = 0.4;
+
+/// Builds queries for rows of a table with `code` and `title` columns, such
+/// as `track` and `playlist`, from the filters given in commands.
+///
+/// Fuzzy matching uses `pg_trgm`'s word similarity, so misspelled words and
+/// words in a different order still match. Rows below
+/// [`WORD_SIMILARITY_THRESHOLD`] are left out.
+#[derive(Debug, Clone)]
+pub struct TitleQuery<'a> {
+    table: &'static str,
+    deleted: bool,
+    codes: Vec<&'a str>,
+    titles: Vec<&'a str>,
+    fuzzy: bool,
+    limit: Option<u32>,
+}
+
+impl<'a> TitleQuery<'a> {
+    pub fn new(table: &'static str) -> Self {
+        TitleQuery {
+            table,
+            deleted: false,
+            codes: Vec::new(),
+            titles: Vec::new(),
+            fuzzy: false,
+            limit: None,
+        }
+    }
+
+    /// Only finds deleted rows instead of only rows that aren't deleted.
+    pub fn deleted(mut self, deleted: bool) -> Self {
+        self.deleted = deleted;
+        self
+    }
+
+    /// Only finds rows whose code contains all of `codes`.
+    pub fn codes(mut self, codes: impl IntoIterator<Item = &'a str>) -> Self {
+        self.codes.extend(codes);
+        self
+    }
+
+    /// Only finds rows whose title contains all of `titles`, or is similar
+    /// to them if fuzzy matching is enabled.
+    pub fn titles(mut self, titles: impl IntoIterator<Item = &'a str>) -> Self {
+        self.titles.extend(titles);
+        self
+    }
+
+    /// Matches titles by similarity and orders the results by it, best
+    /// matches first. Otherwise, titles need to match exactly and the results
+    /// are ordered by code.
+    pub fn fuzzy(mut self, fuzzy: bool) -> Self {
+        self.fuzzy = fuzzy;
+        self
+    }
+
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Returns the SQL and the values for its parameters, in order.
+    pub fn build(&self) -> (String, Vec<String>) {
+        let mut sql = format!(
+            "SELECT * FROM {} WHERE deleted = {}",
+            self.table, self.deleted
+        );
+        let mut binds = Vec::new();
+
+        for code in &self.codes {
+            binds.push(format!("%{}%", code));
+            sql.push_str(&format!(" AND code LIKE ${}", binds.len()));
+        }
+
+        let mut order = "code".to_string();
+
+        if self.fuzzy {
+            if !self.titles.is_empty() {
+                binds.push(self.titles.join(" "));
+                let n = binds.len();
+                sql.push_str(&format!(
+                    " AND word_similarity(${}, title) >= {}",
+                    n, WORD_SIMILARITY_THRESHOLD
+                ));
+                order = format!("word_similarity(${}, title) DESC, code", n);
+            }
+        } else {
+            for title in &self.titles {
+                binds.push(format!("%{}%", title));
+                sql.push_str(&format!(" AND title LIKE ${}", binds.len()));
+            }
+        }
+
+        sql.push_str(&format!(" ORDER BY {}", order));
+
+        if let Some(limit) = self.limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+
+        (sql, binds)
+    }
+
+    pub fn build_args(&self) -> (String, PgArguments) {
+        let (sql, binds) = self.build();
+        (sql, to_args(binds))
+    }
+}
+
+/// Returns a query for the tracks and playlists whose title is similar to
+/// `terms`, best matches first. The rows have the columns `kind` (either
+/// `track` or `playlist`), `code`, `title` and `score`.
+pub fn fuzzy_title_query(terms: &str, limit: u32) -> (String, Vec<String>) {
+    let sql = format!(
+        "SELECT 'track' AS kind, code, title, word_similarity($1, title) AS score \
+         FROM track WHERE deleted = false AND word_similarity($1, title) >= {threshold} \
+         UNION ALL \
+         SELECT 'playlist' AS kind, code, title, word_similarity($1, title) AS score \
+         FROM playlist WHERE deleted = false AND word_similarity($1, title) >= {threshold} \
+         ORDER BY score DESC, code LIMIT {limit}",
+        threshold = WORD_SIMILARITY_THRESHOLD,
+        limit = limit
+    );
+
+    (sql, vec![terms.to_string()])
+}
+
+pub fn to_args(binds: Vec<String>) -> PgArguments {
+    let mut args = PgArguments::default();
+
+    for bind in binds {
+        args.add(bind);
+    }
+
+    args
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fuzzy_title_query, TitleQuery};
+
+    #[test]
+    fn test_filter() {
+        let (sql, binds) = TitleQuery::new("track")
+            .codes(["001"])
+            .titles(["night", "drive"])
+            .build();
+
+        assert_eq!(
+            "SELECT * FROM track WHERE deleted = false \
+             AND code LIKE $1 AND title LIKE $2 AND title LIKE $3 \
+             ORDER BY code",
+            sql
+        );
+        assert_eq!(vec!["%001%", "%night%", "%drive%"], binds);
+    }
+
+    #[test]
+    fn test_fuzzy() {
+        let (sql, binds) = TitleQuery::new("playlist")
+            .deleted(true)
+            .codes(["7"])
+            .titles(["nite", "drvie"])
+            .fuzzy(true)
+            .limit(10)
+            .build();
+
+        assert_eq!(
+            "SELECT * FROM playlist WHERE deleted = true \
+             AND code LIKE $1 AND word_similarity($2, title) >= 0.4 \
+             ORDER BY word_similarity($2, title) DESC, code LIMIT 10",
+            sql
+        );
+        assert_eq!(vec!["%7%", "nite drvie"], binds);
+    }
+
+    #[test]
+    fn test_fuzzy_without_titles() {
+        let (sql, binds) = TitleQuery::new("track").fuzzy(true).build();
+
+        assert_eq!(
+            "SELECT * FROM track WHERE deleted = false ORDER BY code",
+            sql
+        );
+        assert!(binds.is_empty());
+    }
+
+    #[test]
+    fn test_fuzzy_title_query() {
+        let (sql, binds) = fuzzy_title_query("bohemain", 10);
+
+        assert!(sql.ends_with("ORDER BY score DESC, code LIMIT 10"));
+        assert_eq!(2, sql.matches("word_similarity($1, title) >= 0.4").count());
+        assert_eq!(vec!["bohemain"], binds);
+    }
+}
@@ -0,0 +1,40 @@
+use std::cell::Cell;
+use std::sync::Once;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+thread_local! {
+    static QUERIES: Cell<usize> = Cell::new(0);
+}
+
+/// Counts the statements sqlx logs on the current thread, which it does
+/// once for every query sent to the database.
+struct QueryCounter;
+
+impl Log for QueryCounter {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if record.target() == "sqlx::query" {
+            QUERIES.with(|q| q.set(q.get() + 1));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Returns how many queries were sent on the current thread so far. The
+/// first call starts counting, so call it once before the queries that
+/// should be counted.
+pub fn queries() -> usize {
+    static INIT: Once = Once::new();
+
+    INIT.call_once(|| {
+        log::set_logger(&QueryCounter).expect("a logger is set already");
+        log::set_max_level(LevelFilter::Trace);
+    });
+
+    QUERIES.with(|q| q.get())
+}
@@ -0,0 +1,64 @@
+use thiserror::Error;
+
+use msgtools::proxy;
+
+use crate::db::objgen;
+use crate::entity::import::ImportError;
+use crate::player::media::MediaError;
+
+pub type Result<T = (), E = Error> = std::result::Result<T, E>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("proxy call failed: {0}")]
+    ProxyError(#[from] proxy::Error),
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("failed to save object: {0}")]
+    Save(#[from] objgen::Error),
+    #[error("failed to import: {0}")]
+    Import(#[from] ImportError),
+    #[error("media cache error: {0}")]
+    Media(#[from] MediaError),
+    #[error("permission denied")]
+    PermissionDenied,
+    /// An error caused by the user's input. The message is HTML and is shown
+    /// to the user as is.
+    #[error("{0}")]
+    User(String),
+}
+
+impl Error {
+    /// Creates a user-facing error with the given HTML message.
+    pub fn user(message: impl Into<String>) -> Self {
+        Error::User(message.into())
+    }
+
+    /// Returns whether this error is the result of something the user did
+    /// and can be shown to them directly. Other errors are internal and only
+    /// get logged.
+    pub fn is_user_facing(&self) -> bool {
+        matches!(
+            self,
+            Error::PermissionDenied
+                | Error::User(_)
+                | Error::Import(_)
+                | Error::Save(objgen::Error::CodeTaken(_))
+        )
+    }
+
+    /// Converts a "row not found" error from loading `what` by its code into
+    /// a user-facing error, leaving other errors untouched.
+    pub fn not_found(what: &str, code: &str) -> impl FnOnce(sqlx::Error) -> Error {
+        let message = format!(
+            "{} <code>{}</code> not found",
+            what,
+            html_escape::encode_text(code)
+        );
+
+        move |e| match e {
+            sqlx::Error::RowNotFound => Error::User(message),
+            e => e.into(),
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::sync::{Arc, RwLock};
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use simplelog::{TermLogger, TerminalMode};
+use thiserror::Error;
+
+/// Per-module log levels, in the style of `env_logger`. A module's level also
+/// applies to all of its submodules unless they have a level of their own.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    pub fn new(default: LevelFilter) -> Self {
+        LogFilter {
+            default,
+            modules: Vec::new(),
+        }
+    }
+
+    pub fn default_level(&self) -> LevelFilter {
+        self.default
+    }
+
+    pub fn set_default_level(&mut self, level: LevelFilter) {
+        self.default = level;
+    }
+
+    pub fn set_level(&mut self, module: &str, level: LevelFilter) {
+        match self.modules.iter_mut().find(|(m, _)| m == module) {
+            None => self.modules.push((module.to_string(), level)),
+            Some((_, l)) => *l = level,
+        }
+    }
+
+    /// Parses a comma separated list of `module=level` entries, such as
+    /// `mumble=debug,sqlx=warn`, and applies them on top of this filter.
+    pub fn parse_modules(&mut self, spec: &str) -> Result<(), ParseFilterError> {
+        for entry in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (module, level) = entry
+                .split_once('=')
+                .ok_or_else(|| ParseFilterError::NoLevel(entry.to_string()))?;
+            let level = level
+                .parse()
+                .map_err(|_| ParseFilterError::InvalidLevel(level.to_string()))?;
+
+            self.set_level(module, level);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the module entries in the format accepted by
+    /// [`parse_modules`](Self::parse_modules).
+    pub fn modules_spec(&self) -> String {
+        self.modules
+            .iter()
+            .map(|(m, l)| format!("{}={}", m, level_str(*l)))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(m, _)| {
+                target
+                    .strip_prefix(m.as_str())
+                    .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(m, _)| m.len())
+            .map_or(self.default, |(_, l)| *l)
+    }
+
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, l)| *l)
+            .fold(self.default, LevelFilter::max)
+    }
+}
+
+/// Handle to the installed logger, used to change log levels at runtime.
+#[derive(Debug, Clone)]
+pub struct LogHandle {
+    filter: Arc<RwLock<LogFilter>>,
+}
+
+impl LogHandle {
+    pub fn set_level(&self, module: &str, level: LevelFilter) {
+        let mut filter = self.filter.write().unwrap();
+        filter.set_level(module, level);
+        log::set_max_level(filter.max_level());
+    }
+
+    pub fn set_default_level(&self, level: LevelFilter) {
+        let mut filter = self.filter.write().unwrap();
+        filter.set_default_level(level);
+        log::set_max_level(filter.max_level());
+    }
+}
+
+struct FilteredLogger {
+    filter: Arc<RwLock<LogFilter>>,
+    inner: Box<TermLogger>,
+}
+
+impl Log for FilteredLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.read().unwrap().level_for(metadata.target())
+            && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Installs the global logger, writing to the terminal everything that
+/// passes `filter`.
+pub fn init(filter: LogFilter) -> Result<LogHandle, SetLoggerError> {
+    log::set_max_level(filter.max_level());
+
+    let filter = Arc::new(RwLock::new(filter));
+    let inner = TermLogger::new(
+        LevelFilter::Trace,
+        simplelog::Config::default(),
+        TerminalMode::default(),
+    );
+
+    log::set_boxed_logger(Box::new(FilteredLogger {
+        filter: filter.clone(),
+        inner,
+    }))?;
+
+    Ok(LogHandle { filter })
+}
+
+/// Formats a level the way it is written in the config.
+pub fn level_str(level: LevelFilter) -> String {
+    level.to_string().to_lowercase()
+}
+
+#[derive(Debug, Error)]
+pub enum ParseFilterError {
+    #[error("missing level in '{0}', expected module=level")]
+    NoLevel(String),
+    #[error("invalid log level '{0}'")]
+    InvalidLevel(String),
+}
+
+#[cfg(test)]
+mod test {
+    use log::LevelFilter;
+
+    use super::LogFilter;
+
+    #[test]
+    fn test_level_for() {
+        let mut filter = LogFilter::new(LevelFilter::Info);
+        filter
+            .parse_modules("mumble=debug, sqlx=warn,mumble::tasks=off")
+            .unwrap();
+
+        assert_eq!(LevelFilter::Info, filter.level_for("r2dj::player"));
+        assert_eq!(LevelFilter::Debug, filter.level_for("mumble"));
+        assert_eq!(LevelFilter::Debug, filter.level_for("mumble::connect"));
+        assert_eq!(LevelFilter::Off, filter.level_for("mumble::tasks::encoder"));
+        assert_eq!(LevelFilter::Info, filter.level_for("mumble_protocol"));
+        assert_eq!(LevelFilter::Warn, filter.level_for("sqlx::query"));
+        assert_eq!(LevelFilter::Debug, filter.max_level());
+    }
+
+    #[test]
+    fn test_parse_modules() {
+        let mut filter = LogFilter::new(LevelFilter::Info);
+        assert!(filter.parse_modules("mumble").is_err());
+        assert!(filter.parse_modules("mumble=loud").is_err());
+
+        filter.parse_modules("mumble=debug,sqlx=warn").unwrap();
+        filter.set_level("sqlx", LevelFilter::Error);
+        assert_eq!("mumble=debug,sqlx=error", filter.modules_spec());
+    }
+}
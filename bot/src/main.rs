@@ -1,46 +1,80 @@
+use std::borrow::Cow;
 use std::cmp::min;
 use std::fmt::{Display, Formatter};
-use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use clap::{App, Arg};
 use futures::channel::oneshot;
 use futures::{FutureExt, StreamExt};
-use log::{debug, info, warn, LevelFilter};
-use simplelog::{Config, TerminalMode};
+use log::{debug, error, info, warn};
 use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
 use sqlx::{ConnectOptions, PgPool};
-use thiserror::Error;
 use tokio::time::interval;
 
 use audiopipe::Core;
-use msgtools::proxy;
 use mumble::{MumbleClient, MumbleConfig};
 use player2x::ffplayer::PlayerEvent;
 
+use crate::auth::AuthService;
+use crate::config::Config;
 use crate::db::entity;
+pub use crate::error::{Error, Result};
+use crate::fmt::HtmlDisplayExt;
+use crate::logging::LogHandle;
+use crate::player::media::MediaCache;
+use crate::player::playlists_index::PlaylistIndex;
 use crate::player::{Event as RoomEvent, Room};
+use crate::spotify::SpotifyClient;
+use crate::template::{Placeholder, Template};
+use crate::web::WebState;
 
 const CRATE_NAME: &str = env!("CARGO_PKG_NAME");
 const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+const STATUS_RETRY_DELAY: Duration = Duration::from_secs(5);
+const STATUS_LINE_TIMEOUT: Duration = Duration::from_secs(30);
+const PLAYLIST_INDEX_REFRESH: Duration = Duration::from_secs(300);
+
+mod auth;
 mod commands;
 mod config;
 mod db;
+mod error;
 mod player;
 mod spotify;
 mod fmt;
+mod logging;
+mod template;
+mod web;
 
 #[tokio::main]
 async fn main() {
-    let config = load_config();
+    let matches = App::new(CRATE_NAME)
+        .version(CRATE_VERSION)
+        .arg(
+            Arg::new("check-config")
+                .long("check-config")
+                .about("Validate the configuration, print it and exit"),
+        )
+        .get_matches();
+
+    let config = match Config::load("srvrc") {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    simplelog::TermLogger::init(
-        LevelFilter::Debug,
-        Config::default(),
-        TerminalMode::default(),
-    )
-    .unwrap();
+    if matches.is_present("check-config") {
+        println!("{}", config);
+        return;
+    }
+
+    let config = Arc::new(config);
+
+    let log = logging::init(config.log_filter.clone()).unwrap();
 
     info!("Starting {} {}", CRATE_NAME, CRATE_VERSION);
 
@@ -50,7 +84,7 @@ async fn main() {
         .unwrap()
         .application_name(CRATE_NAME);
 
-    co.log_statements(LevelFilter::Trace);
+    co.log_statements(config.db_log_statements);
 
     let pool = PgPoolOptions::new()
         .max_connections(config.db_pool_size)
@@ -60,32 +94,122 @@ async fn main() {
         .await
         .unwrap();
 
-    let db = pool.acquire().await.unwrap();
+    if config.auto_migrate {
+        match db::migrations::apply_pending(&pool).await {
+            Ok(0) => {}
+            Ok(count) => info!("applied {} database migrations", count),
+            Err(e) => {
+                error!("failed to migrate database: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        match db::migrations::check_pending(&pool).await {
+            Ok(0) => {}
+            Ok(count) if config.abort_on_pending_migrations => {
+                error!("{} migrations pending, run migtool apply", count);
+                std::process::exit(1);
+            }
+            Ok(count) => warn!("{} migrations pending, run migtool apply", count),
+            Err(e) if config.abort_on_pending_migrations => {
+                error!("failed to check for pending migrations: {}", e);
+                std::process::exit(1);
+            }
+            Err(e) => warn!("failed to check for pending migrations: {}", e),
+        }
+    }
+
+    let mut db = pool.acquire().await.unwrap();
+
+    let playlists = match PlaylistIndex::load(&mut db).await {
+        Ok(index) => index,
+        Err(e) => {
+            warn!("failed to load playlist index: {}", e);
+            PlaylistIndex::default()
+        }
+    };
+
+    let auth = AuthService::new(pool.clone());
+
+    match auth.delete_expired_sessions().await {
+        Ok(count) => debug!("removed {} expired sessions", count),
+        Err(e) => warn!("failed to remove expired sessions: {}", e),
+    }
+
+    let spotify = match &config.spotify {
+        None => None,
+        Some((username, password)) => match SpotifyClient::connect(username, password).await {
+            Ok(client) => Some(client),
+            Err(e) => {
+                warn!("{}, Spotify links will not work", e);
+                None
+            }
+        },
+    };
 
     let mumble_config = MumbleConfig {
         username: config.name.clone(),
+        audio_frame_ms: config.audio_frame,
+        send_burst: config.audio_send_burst,
     };
 
-    let ac = Arc::new(Core::new(48000));
+    let ac = Arc::new(Core::with_buffers(48000, config.audio_buffers(48000)));
 
     let client = mumble::MumbleClient::connect(
         &config.mumble_domain,
         config.mumble_port,
-        config.mumble_cert,
+        config.mumble_cert.as_ref(),
         mumble_config,
         &ac,
     )
     .await
     .unwrap();
 
+    if let Some(name) = &config.channel {
+        match client.state().await.unwrap().channel_by_name(name) {
+            None => warn!("channel '{}' not found, staying in the default channel", name),
+            Some(channel) => client.join_channel(channel.to_ref()).await.unwrap(),
+        }
+    }
+
     let mut r = client.event_subscriber().await.unwrap();
 
-    let room = Room::new(client.audio_input().await.unwrap(), ac);
+    let queue = match Room::load_queue(&pool, config.room_id).await {
+        Ok(queue) => queue,
+        Err(e) => {
+            warn!("failed to restore queue: {}", e);
+            Vec::new()
+        }
+    };
+
+    let room = Arc::new(Room::new(
+        config.room_id,
+        client.audio_input().await.unwrap(),
+        ac.clone(),
+        pool.clone(),
+        MediaCache::new(pool.clone(), config.cache_size_bytes()),
+        queue,
+        config.max_track_failures,
+    ));
     let mut room_events = room.subscribe();
 
-    let mut prev_rst = None;
+    let web_state = WebState {
+        room: room.clone(),
+        auth: auth.clone(),
+        db: pool.clone(),
+    };
+    let web_bind = config.web_bind;
+
+    tokio::spawn(async move {
+        if let Err(e) = web::serve(web_bind, web_state).await {
+            error!("web interface stopped: {}", e);
+        }
+    });
+
+    let mut status = StatusUpdater::new(config.clone());
     let mut rst = RoomStatus::default();
     let mut update_timer = interval(Duration::from_secs(5));
+    let mut index_timer = interval(PLAYLIST_INDEX_REFRESH);
 
     let (shutdown_tx, shutdown_rx) = oneshot::channel();
     let mut shutdown_rx = shutdown_rx.into_stream();
@@ -93,11 +217,17 @@ async fn main() {
     let mut bot = Bot {
         client,
         room,
+        audio: ac,
         db: pool.clone(),
+        auth,
+        spotify,
+        playlists,
+        config,
+        log,
         shutdown_fuse: Some(shutdown_tx),
     };
 
-    update_status(&bot.client, &mut prev_rst, &rst).await;
+    status.update(&bot.client, &rst).await;
 
     loop {
         tokio::select! {
@@ -105,7 +235,13 @@ async fn main() {
                 break;
             }
             _ = update_timer.tick() => {
-                update_status(&bot.client, &mut prev_rst, &rst).await;
+                rst.expire_status_line();
+                status.update(&bot.client, &rst).await;
+            }
+            _ = index_timer.tick() => {
+                if let Err(e) = bot.playlists.refresh(&mut db).await {
+                    warn!("failed to refresh playlist index: {}", e);
+                }
             }
             ev = r.recv() => {
                 let ev = match ev {
@@ -137,15 +273,27 @@ async fn main() {
                 match ev {
                     RoomEvent::PlayerEvent(p) => {
                         match p {
+                            PlayerEvent::Buffering { pos, .. } => {
+                                rst.playing_since = None;
+                                rst.position = pos;
+                                rst.set_status_line("buffering…");
+                                status.update(&bot.client, &rst).await;
+                            },
                             PlayerEvent::Playing { now, pos } => {
                                 rst.playing_since = Some(now);
                                 rst.position = pos;
-                                update_status(&bot.client, &mut prev_rst, &rst).await;
+                                rst.status_line = None;
+                                status.update(&bot.client, &rst).await;
                             },
                             PlayerEvent::Paused { pos, .. } => {
                                 rst.playing_since = None;
                                 rst.position = pos;
-                                update_status(&bot.client, &mut prev_rst, &rst).await;
+                                status.update(&bot.client, &rst).await;
+                            },
+                            PlayerEvent::CrossfadePoint { .. } => {},
+                            PlayerEvent::Error { message, .. } => {
+                                rst.set_status_line(format!("error: {}", message));
+                                status.update(&bot.client, &rst).await;
                             },
                         }
                     }
@@ -153,27 +301,71 @@ async fn main() {
                         rst.title = t.object().title().unwrap_or("Unnamed Track").to_string();
                         rst.total_duration = len;
                         rst.position = Duration::ZERO;
-                        update_status(&bot.client, &mut prev_rst, &rst).await;
+                        status.update(&bot.client, &rst).await;
+                    }
+                    RoomEvent::TrackFailed { track, error } => {
+                        let text = format!(
+                            "failed to play {}: {}",
+                            track.html(),
+                            html_escape::encode_text(&error.to_string()),
+                        );
+                        let _ = bot.client.announce(text, bot.config.announce_linked).await;
+
+                        rst.set_status_line(format!("error: {}", error));
+                        status.update(&bot.client, &rst).await;
                     }
                     RoomEvent::TrackCleared => {
                         rst.title = "(none)".to_string();
                         rst.total_duration = Duration::ZERO;
                         rst.position = Duration::ZERO;
-                        update_status(&bot.client, &mut prev_rst, &rst).await;
+                        status.update(&bot.client, &rst).await;
+                    }
+                    RoomEvent::PlaybackHalted { reason } => {
+                        let text = format!(
+                            "playback stopped: {}",
+                            html_escape::encode_text(&reason)
+                        );
+                        let _ = bot.client.announce(text, bot.config.announce_linked).await;
+
+                        rst.title = "(none)".to_string();
+                        rst.total_duration = Duration::ZERO;
+                        rst.position = Duration::ZERO;
+                        rst.set_status_line(format!("stopped: {}", reason));
+                        status.update(&bot.client, &rst).await;
+                    }
+                    RoomEvent::PlaylistFinished => {
+                        let _ = bot
+                            .client
+                            .announce("playlist finished", bot.config.announce_linked)
+                            .await;
+
+                        rst.title = "(none)".to_string();
+                        rst.total_duration = Duration::ZERO;
+                        rst.position = Duration::ZERO;
+                        status.update(&bot.client, &rst).await;
                     }
                 }
             }
         }
     }
 
-    let _ = bot.client.message_my_channel("quitting!").await;
+    let _ = bot
+        .client
+        .announce("quitting!", bot.config.announce_linked)
+        .await;
     let _ = bot.client.close().await;
 }
 
 pub struct Bot {
     client: MumbleClient,
-    room: Room,
+    room: Arc<Room>,
+    audio: Arc<Core>,
     db: PgPool,
+    auth: AuthService,
+    spotify: Option<SpotifyClient>,
+    playlists: PlaylistIndex,
+    config: Arc<Config>,
+    log: LogHandle,
     shutdown_fuse: Option<oneshot::Sender<()>>,
 }
 
@@ -185,12 +377,36 @@ struct RoomStatus {
     position: Duration,
     playing_since: Option<Instant>,
     total_duration: Duration,
+    status_line: Option<StatusLine>,
+}
+
+/// A transient message shown below the track info, such as an error.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct StatusLine {
+    text: String,
+    since: Instant,
 }
 
 impl RoomStatus {
     pub fn should_update(&self, other: &RoomStatus) -> bool {
         self.playing_since.is_some() || self != other
     }
+
+    pub fn set_status_line(&mut self, text: impl Into<String>) {
+        self.status_line = Some(StatusLine {
+            text: text.into(),
+            since: Instant::now(),
+        });
+    }
+
+    /// Clears the status line if it has been shown for long enough.
+    pub fn expire_status_line(&mut self) {
+        if let Some(line) = &self.status_line {
+            if line.since.elapsed() >= STATUS_LINE_TIMEOUT {
+                self.status_line = None;
+            }
+        }
+    }
 }
 
 impl Default for RoomStatus {
@@ -202,46 +418,210 @@ impl Default for RoomStatus {
             position: Default::default(),
             playing_since: None,
             total_duration: Default::default(),
+            status_line: None,
         }
     }
 }
 
-async fn update_status(client: &MumbleClient, prev_st: &mut Option<RoomStatus>, st: &RoomStatus) {
-    let should_update = match prev_st {
-        None => true,
-        Some(prev_st) => st.should_update(prev_st),
-    };
+/// Keeps the bot's comment in sync with the room status. If setting the
+/// comment fails, the update is retried later with an increasing delay.
+#[derive(Debug)]
+struct StatusUpdater {
+    config: Arc<Config>,
+    prev: Option<RoomStatus>,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
 
-    if should_update {
-        let state_ch = match st.playing_since {
-            None => "⏸︎",
-            Some(_) => "⏵︎",
-        };
+impl StatusUpdater {
+    fn new(config: Arc<Config>) -> Self {
+        StatusUpdater {
+            config,
+            prev: None,
+            failures: 0,
+            retry_at: None,
+        }
+    }
 
-        let current_position = match st.playing_since {
-            None => st.position,
-            Some(then) => {
-                let diff = Instant::now().duration_since(then);
-                min(st.position + diff, st.total_duration)
+    async fn update(&mut self, client: &MumbleClient, st: &RoomStatus) {
+        if let Some(retry_at) = self.retry_at {
+            if Instant::now() < retry_at {
+                return;
             }
+        }
+
+        let should_update = match &self.prev {
+            None => true,
+            Some(prev) => st.should_update(prev),
         };
 
-        let str = format!(
-            "{}<br>{}<br>{}<br>[{}] [{} / {}]<hr>{} {}",
-            st.title,
-            st.album_title,
-            st.artist,
-            state_ch,
-            FmtDuration(current_position),
-            FmtDuration(st.total_duration),
-            CRATE_NAME,
-            CRATE_VERSION,
-        );
-
-        client.set_comment(str).await.unwrap();
+        if should_update {
+            if let Err(e) = set_status(client, &self.config, st).await {
+                self.failures += 1;
+                let delay = STATUS_RETRY_DELAY * (1 << min(self.failures - 1, 6));
+                self.retry_at = Some(Instant::now() + delay);
+
+                warn!(
+                    "failed to update status ({} times in a row), retrying in {}: {}",
+                    self.failures,
+                    FmtDuration(delay),
+                    e
+                );
+
+                // prev is left alone, so the next call tries again
+                return;
+            }
+
+            self.failures = 0;
+            self.retry_at = None;
+        }
+
+        self.prev = Some(st.clone());
+    }
+}
+
+async fn set_status(client: &MumbleClient, config: &Config, st: &RoomStatus) -> Result {
+    let html = client.allow_html_messages().await? != Some(false);
+
+    let template = if html {
+        &config.status_template
+    } else {
+        &config.status_template_plain
+    };
+
+    let (playing_icon, paused_icon) = &config.status_icons;
+
+    let state = match st.playing_since {
+        None => paused_icon,
+        Some(_) => playing_icon,
+    };
+
+    let current_position = match st.playing_since {
+        None => st.position,
+        Some(then) => {
+            let diff = Instant::now().duration_since(then);
+            min(st.position + diff, st.total_duration)
+        }
+    };
+
+    let escape = |s: &str| {
+        if html {
+            html_escape::encode_text(s).into_owned()
+        } else {
+            s.to_string()
+        }
+    };
+
+    let status_line = match (&st.status_line, html) {
+        (None, _) => String::new(),
+        (Some(line), true) => format!("<br><i>{}</i>", escape(&line.text)),
+        (Some(line), false) => format!("\n{}", line.text),
+    };
+
+    let render = |title: &str, album_title: &str, artist: &str| {
+        template.render(|p| match p {
+            Placeholder::Title => escape(title),
+            Placeholder::Album => escape(album_title),
+            Placeholder::Artist => escape(artist),
+            Placeholder::Position => FmtDuration(current_position).to_string(),
+            Placeholder::Duration => FmtDuration(st.total_duration).to_string(),
+            Placeholder::State => escape(state),
+            Placeholder::Status => status_line.clone(),
+            Placeholder::Version => format!("{} {}", CRATE_NAME, CRATE_VERSION),
+        })
+    };
+
+    let str = match client.max_message_length().await? {
+        None => render(&st.title, &st.album_title, &st.artist),
+        Some(max_len) => fit_status(template, max_len as usize, html, st, render),
+    };
+
+    client.set_comment(str).await?;
+
+    Ok(())
+}
+
+/// Renders the status with `render` so that it is at most `max_len` bytes
+/// long. The track info is shortened first, and if the rest of the template
+/// doesn't fit by itself, the whole text is cut off.
+fn fit_status<F>(
+    template: &Template,
+    max_len: usize,
+    html: bool,
+    st: &RoomStatus,
+    render: F,
+) -> String
+where
+    F: Fn(&str, &str, &str) -> String,
+{
+    let str = render(&st.title, &st.album_title, &st.artist);
+
+    if str.len() <= max_len {
+        return str;
     }
 
-    *prev_st = Some(st.clone());
+    // shorten the track info equally so that the rest still fits
+    let fixed_len = render("", "", "").len();
+    let fields = template.count(Placeholder::Title)
+        + template.count(Placeholder::Album)
+        + template.count(Placeholder::Artist);
+    let field_len = max_len.saturating_sub(fixed_len) / fields.max(1);
+
+    let str = render(
+        &ellipsize(&st.title, field_len),
+        &ellipsize(&st.album_title, field_len),
+        &ellipsize(&st.artist, field_len),
+    );
+
+    if html {
+        ellipsize_html(&str, max_len).into_owned()
+    } else {
+        ellipsize(&str, max_len).into_owned()
+    }
+}
+
+const ELLIPSIS: &str = "…";
+
+/// Shortens `s` to at most `max_len` bytes, marking it with an ellipsis if
+/// anything was cut off.
+fn ellipsize(s: &str, max_len: usize) -> Cow<str> {
+    if s.len() <= max_len {
+        return s.into();
+    }
+
+    let end = cut_point(s, max_len);
+    format!("{}{}", &s[..end], ELLIPSIS).into()
+}
+
+/// Like [`ellipsize`], but for HTML, where it doesn't leave half a tag or
+/// entity at the end.
+fn ellipsize_html(s: &str, max_len: usize) -> Cow<str> {
+    if s.len() <= max_len {
+        return s.into();
+    }
+
+    let mut end = cut_point(s, max_len);
+    let kept = &s[..end];
+    let tag = kept.rfind('<').filter(|&i| !kept[i..].contains('>'));
+    let entity = kept.rfind('&').filter(|&i| !kept[i..].contains(';'));
+
+    if let Some(start) = tag.into_iter().chain(entity).min() {
+        end = start;
+    }
+
+    format!("{}{}", &s[..end], ELLIPSIS).into()
+}
+
+/// Returns where to cut off `s` so that it fits into `max_len` bytes along
+/// with the ellipsis.
+fn cut_point(s: &str, max_len: usize) -> usize {
+    let mut end = max_len.saturating_sub(ELLIPSIS.len());
+
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    end
 }
 
 struct FmtDuration(Duration);
@@ -257,101 +637,50 @@ impl Display for FmtDuration {
     }
 }
 
-pub struct LaunchConfig {
-    pub data_dir: PathBuf,
-    pub db_url: String,
-    pub db_pool_size: u32,
-    pub db_pool_size_min: u32,
-
-    // temporary
-    pub mumble_domain: String,
-    pub mumble_port: u16,
-    pub mumble_cert: Option<String>,
-    pub name: String,
-}
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
 
-fn load_config() -> LaunchConfig {
-    use cmdparser::CommandDispatcher;
-    use cmdparser::ExecSource;
-    use cmdparser::SimpleExecutor;
-
-    let mut data_dir = None;
-    let mut db_url = None;
-    let mut db_pool_size = None;
-    let mut db_pool_size_min = None;
-    let mut mumble = None;
-    let mut mumble_cert = None;
-    let mut name = None;
-
-    let mut cd = CommandDispatcher::new(SimpleExecutor::new(|cmd, args| match cmd {
-        "data_dir" => data_dir = Some(args[0].to_string()),
-        "db_url" => db_url = Some(args[0].to_string()),
-        "db_pool_size" => {
-            db_pool_size = Some(
-                args[0]
-                    .parse::<u32>()
-                    .expect("db_pool_size must be a positive integer"),
-            )
-        }
-        "db_pool_size_scale" => {
-            db_pool_size = Some(
-                args[0]
-                    .parse::<u32>()
-                    .expect("db_pool_size must be a positive integer")
-                    * num_cpus::get() as u32,
-            )
-        }
-        "db_pool_size_min" => {
-            db_pool_size_min = Some(
-                args[0]
-                    .parse::<u32>()
-                    .expect("db_pool_size_min must be a positive integer"),
-            )
-        }
-        "db_pool_size_min_scale" => {
-            db_pool_size_min = Some(
-                args[0]
-                    .parse::<u32>()
-                    .expect("db_pool_size_min must be a positive integer")
-                    * num_cpus::get() as u32,
-            )
-        }
-        "mumble" => {
-            mumble = Some((
-                args[0].to_string(),
-                args[1]
-                    .parse::<u16>()
-                    .expect("mumble second param must be port"),
-            ))
+    use super::{ellipsize_html, fit_status, RoomStatus};
+    use crate::template::{Placeholder, Template};
+
+    fn status(title: &str) -> RoomStatus {
+        RoomStatus {
+            title: title.to_string(),
+            album_title: String::new(),
+            artist: String::new(),
+            position: Duration::ZERO,
+            playing_since: None,
+            total_duration: Duration::ZERO,
+            status_line: None,
         }
-        "mumble_cert" => mumble_cert = Some(args[0].to_string()),
-        "name" => name = Some(args[0].to_string()),
-        _ => eprintln!("Ignoring invalid bootstrap command '{}'!", cmd),
-    }));
-    cd.scheduler()
-        .exec_path("srvrc", ExecSource::Event)
-        .expect("Failed to load srvrc");
-    cd.resume_until_empty();
-
-    let db_pool_size = db_pool_size.unwrap_or_else(|| num_cpus::get() as u32);
-    let (mumble_domain, mumble_port) = mumble.expect("mumble connection not set!");
-
-    LaunchConfig {
-        data_dir: data_dir.expect("data_dir not set!").into(),
-        db_url: db_url.expect("db_url not set!"),
-        db_pool_size,
-        db_pool_size_min: db_pool_size_min.unwrap_or(db_pool_size),
-        mumble_domain,
-        mumble_port,
-        mumble_cert,
-        name: name.unwrap_or_else(|| "r2dj".to_string()),
     }
-}
 
-pub type Result<T = (), E = Error> = std::result::Result<T, E>;
+    #[test]
+    fn test_fit_status() {
+        let template: Template = "{title} | now playing on the radio, tune in!"
+            .parse()
+            .unwrap();
+        let render = |title: &str, _: &str, _: &str| {
+            template.render(|p| match p {
+                Placeholder::Title => title,
+                _ => "",
+            })
+        };
+
+        let text = fit_status(&template, 100, false, &status("Drive"), render);
+        assert_eq!("Drive | now playing on the radio, tune in!", text);
+
+        // the template alone is longer than the limit
+        let text = fit_status(&template, 20, false, &status("Drive"), render);
+        assert_eq!("… | now playing…", text);
+        assert!(text.len() <= 20);
+    }
 
-#[derive(Error, Debug, Clone)]
-pub enum Error {
-    #[error("proxy call failed: {0}")]
-    ProxyError(#[from] proxy::Error),
+    #[test]
+    fn test_ellipsize_html() {
+        assert_eq!("Drive…", ellipsize_html("Drive<br><i>on air</i>", 11));
+        assert_eq!("Drive<br>…", ellipsize_html("Drive<br><i>on air</i>", 13));
+        assert_eq!("Drive …", ellipsize_html("Drive &amp; Go", 12));
+    }
 }
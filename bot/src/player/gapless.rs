@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use audiopipe::AudioSource;
+use player2x::ffplayer::Player;
+
+use crate::db::entity::Track;
+
+/// Plays tracks back to back without a gap between them. Once the current
+/// track has `crossfade_duration` left, the player sends
+/// [`PlayerEvent::CrossfadePoint`](player2x::ffplayer::PlayerEvent::CrossfadePoint),
+/// and the room loads the next track into the second player so that its
+/// audio is buffered by the time the current one ends.
+pub struct GaplessPlayer {
+    /// The player of the track that is playing right now.
+    player_a: Option<Player<AudioSource>>,
+    /// The player of the track after it, if it was loaded already.
+    player_b: Option<(Track, Player<AudioSource>)>,
+    crossfade_duration: Duration,
+}
+
+impl GaplessPlayer {
+    pub fn new(crossfade_duration: Duration) -> Self {
+        GaplessPlayer {
+            player_a: None,
+            player_b: None,
+            crossfade_duration,
+        }
+    }
+
+    /// Returns the player of the track that is playing.
+    pub fn current(&self) -> Option<&Player<AudioSource>> {
+        self.player_a.as_ref()
+    }
+
+    /// Stops the current player and starts `player` in its place, set up to
+    /// announce when the next track should be loaded.
+    pub async fn play(&mut self, player: Player<AudioSource>) {
+        player
+            .set_crossfade_point(Some(self.crossfade_duration))
+            .await;
+
+        self.stop().await;
+        player.play().await;
+        self.player_a = Some(player);
+    }
+
+    /// Stops the current player. The next track, if loaded, stays.
+    pub async fn stop(&mut self) {
+        if let Some(player) = self.player_a.take() {
+            // TODO: remove audio output from ac
+            player.pause().await;
+        }
+    }
+
+    /// Returns whether the next track is loaded already.
+    pub fn has_next(&self) -> bool {
+        self.player_b.is_some()
+    }
+
+    /// Keeps `player` for `track` to be played once the current one stops.
+    /// Its audio should be preloaded with [`Player::preload`] already.
+    pub fn set_next(&mut self, track: Track, player: Player<AudioSource>) {
+        self.player_b = Some((track, player));
+    }
+
+    /// Forgets the loaded next track, for example because the playlist
+    /// changed.
+    pub fn clear_next(&mut self) {
+        self.player_b = None;
+    }
+
+    /// Removes the loaded next track to start playing it, if there is one.
+    /// Its buffered audio starts right away, so when this is called as soon
+    /// as the current track stopped, there is no gap between the two.
+    pub fn take_next(&mut self) -> Option<(Track, Player<AudioSource>)> {
+        self.player_b.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use audiopipe::{AudioSource, Core};
+    use player2x::ffplayer::{Player, PlayerEvent};
+    use player2x::mock::{MockProber, MockTranscoder};
+
+    use super::GaplessPlayer;
+    use crate::db::entity::Track;
+
+    fn player(core: &Core, length: Duration) -> Player<AudioSource> {
+        let frames = (length.as_secs_f64() * 48000.0) as usize;
+
+        Player::with_backend(
+            "mock.wav",
+            core.add_input(),
+            &MockProber::new(length),
+            Arc::new(MockTranscoder::new(vec![[0, 0]; frames])),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_switch() {
+        let core = Core::new(48000);
+        let mut gapless = GaplessPlayer::new(Duration::from_secs(10));
+
+        let first = player(&core, Duration::from_millis(1));
+        let mut events = first.event_listener();
+        gapless.play(first).await;
+
+        let next = player(&core, Duration::from_secs(1));
+        next.preload().await.unwrap();
+        gapless.set_next(Track::new(), next);
+
+        loop {
+            if let PlayerEvent::Paused { stopped: true, .. } = events.recv().await.unwrap() {
+                break;
+            }
+        }
+
+        // what the room does once the current track is over
+        gapless.stop().await;
+        let (_, next) = gapless.take_next().unwrap();
+        gapless.play(next).await;
+
+        assert!(!gapless.has_next());
+        assert!(gapless.current().unwrap().is_playing().await);
+    }
+}
@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use log::error;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::db::entity::Track;
+
+/// Records which tracks a room played in the `play_history` table, for
+/// statistics like [`Playlist::play_counts`].
+///
+/// [`Playlist::play_counts`]: crate::db::entity::Playlist::play_counts
+pub struct PlayHistory {
+    db: PgPool,
+    room_id: Uuid,
+}
+
+impl PlayHistory {
+    pub fn new(db: PgPool, room_id: Uuid) -> Self {
+        PlayHistory { db, room_id }
+    }
+
+    /// Records that `track`, which is `length` long, started playing. The
+    /// write happens in the background. Tracks which haven't been saved to
+    /// the database are skipped.
+    pub fn record(&self, track: &Track, length: Duration) {
+        let track_id = match track.object().id() {
+            None => return,
+            Some(id) => id,
+        };
+
+        let db = self.db.clone();
+        let room_id = self.room_id;
+
+        tokio::spawn(async move {
+            let result = sqlx::query!(
+                // language=SQL
+                "INSERT INTO play_history (id, room_id, track_id, duration_secs) \
+                 VALUES ($1, $2, $3, $4)",
+                Uuid::new_v4(),
+                room_id,
+                track_id,
+                length.as_secs_f64(),
+            )
+            .execute(&db)
+            .await;
+
+            if let Err(e) = result {
+                error!("failed to record play history: {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,150 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Creates a slot for background tasks where only the most recently started
+/// one matters. Starting a new task aborts the previous one, and results of
+/// superseded tasks are never returned from the receiver.
+pub fn latest_task<T>() -> (LatestTask<T>, LatestReceiver<T>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let state = Arc::new(Mutex::new(State {
+        generation: 0,
+        running: false,
+    }));
+
+    (
+        LatestTask {
+            state: state.clone(),
+            task: None,
+            tx,
+        },
+        LatestReceiver { state, rx },
+    )
+}
+
+struct State {
+    generation: u64,
+    running: bool,
+}
+
+pub struct LatestTask<T> {
+    state: Arc<Mutex<State>>,
+    task: Option<JoinHandle<()>>,
+    tx: mpsc::UnboundedSender<(u64, T)>,
+}
+
+pub struct LatestReceiver<T> {
+    state: Arc<Mutex<State>>,
+    rx: mpsc::UnboundedReceiver<(u64, T)>,
+}
+
+impl<T> LatestTask<T>
+where
+    T: Send + 'static,
+{
+    pub fn start<F>(&mut self, fut: F)
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            state.generation += 1;
+            state.running = true;
+            state.generation
+        };
+
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+
+        let tx = self.tx.clone();
+
+        self.task = Some(tokio::spawn(async move {
+            let _ = tx.send((generation, fut.await));
+        }));
+    }
+}
+
+impl<T> LatestTask<T> {
+    pub fn cancel(&mut self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.generation += 1;
+            state.running = false;
+        }
+
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+
+    /// Returns whether a task has been started whose result has not been
+    /// received yet.
+    pub fn is_running(&self) -> bool {
+        self.state.lock().unwrap().running
+    }
+}
+
+impl<T> LatestReceiver<T> {
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            let (generation, value) = self.rx.recv().await?;
+            let mut state = self.state.lock().unwrap();
+
+            if generation == state.generation {
+                state.running = false;
+                return Some(value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time::{sleep, timeout};
+
+    use super::latest_task;
+
+    #[tokio::test]
+    async fn test_only_latest() {
+        let (mut task, mut rx) = latest_task();
+
+        for i in 0..5 {
+            task.start(async move {
+                sleep(Duration::from_millis(20)).await;
+                i
+            });
+        }
+
+        assert!(task.is_running());
+        assert_eq!(Some(4), rx.recv().await);
+        assert!(!task.is_running());
+        assert!(timeout(Duration::from_millis(50), rx.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_finished_but_superseded() {
+        let (mut task, mut rx) = latest_task();
+
+        task.start(async { 1 });
+        sleep(Duration::from_millis(20)).await;
+        task.start(async { 2 });
+
+        assert_eq!(Some(2), rx.recv().await);
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let (mut task, mut rx) = latest_task();
+
+        task.start(async { 1 });
+        task.cancel();
+
+        assert!(!task.is_running());
+        assert!(timeout(Duration::from_millis(50), rx.recv()).await.is_err());
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use thiserror::Error;
+
+/// Where media downloaded from non-local providers is stored.
+pub const CACHE_DIR: &str = "media/cached";
+
+/// Keeps track of the checksums of cached media files, so that files with
+/// identical content only take up space once. Duplicates are replaced by
+/// hard links to the same file, which means a cached file's storage is only
+/// freed when its last link is removed, see [`MediaCache::remove`].
+///
+/// Once the cache grows beyond its maximum size, the files used least
+/// recently are removed, see [`MediaCache::evict`]. They are downloaded
+/// again the next time they are played.
+#[derive(Debug, Clone)]
+pub struct MediaCache {
+    db: PgPool,
+    max_size: u64,
+}
+
+/// A file in the cache directory, as looked at by [`MediaCache::evict`].
+#[derive(Debug, Clone)]
+struct CachedFile {
+    path: PathBuf,
+    size: u64,
+    /// Device and inode number, which are the same for all links to a file.
+    inode: (u64, u64),
+    accessed: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct DedupeStats {
+    /// Number of files that were checked.
+    pub files: usize,
+    /// Number of files that were replaced by a link to an identical file.
+    pub merged: usize,
+    /// Bytes freed by merging files.
+    pub reclaimed: u64,
+}
+
+impl MediaCache {
+    /// Creates a cache that holds at most `max_size` bytes of media.
+    pub fn new(db: PgPool, max_size: u64) -> Self {
+        MediaCache { db, max_size }
+    }
+
+    /// Records the checksum of a cached file. If another file with the same
+    /// content is already known, `path` is replaced with a hard link to it.
+    /// Returns the number of bytes freed by doing so.
+    pub async fn register(&self, path: &Path) -> Result<u64, MediaError> {
+        let key = path.to_string_lossy();
+
+        // language=SQL
+        let known = sqlx::query!("SELECT path FROM track_media WHERE path = $1", &*key)
+            .fetch_optional(&self.db)
+            .await?;
+
+        if known.is_some() {
+            return Ok(0);
+        }
+
+        let (checksum, size) = blocking({
+            let path = path.to_path_buf();
+            move || checksum(&path)
+        })
+        .await?;
+
+        let duplicates = sqlx::query!(
+            // language=SQL
+            "SELECT path FROM track_media WHERE checksum = $1 AND path != $2",
+            &checksum[..],
+            &*key
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        let mut freed = 0;
+
+        for row in duplicates {
+            let original = PathBuf::from(&row.path);
+
+            if !original.is_file() {
+                // language=SQL
+                sqlx::query!("DELETE FROM track_media WHERE path = $1", row.path)
+                    .execute(&self.db)
+                    .await?;
+                continue;
+            }
+
+            freed = blocking({
+                let path = path.to_path_buf();
+                move || link_to(&original, &path)
+            })
+            .await?;
+
+            break;
+        }
+
+        sqlx::query!(
+            // language=SQL
+            "INSERT INTO track_media (path, checksum, size) VALUES ($1, $2, $3) \
+             ON CONFLICT (path) DO UPDATE SET checksum = $2, size = $3",
+            &*key,
+            &checksum[..],
+            size as i64
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(freed)
+    }
+
+    /// Removes a cached file. Returns the number of bytes freed, which is
+    /// zero if other links to the same content remain.
+    pub async fn remove(&self, path: &Path) -> Result<u64, MediaError> {
+        let key = path.to_string_lossy();
+
+        // language=SQL
+        sqlx::query!("DELETE FROM track_media WHERE path = $1", &*key)
+            .execute(&self.db)
+            .await?;
+
+        let freed = blocking({
+            let path = path.to_path_buf();
+            move || {
+                let meta = fs::metadata(&path)?;
+                fs::remove_file(&path)?;
+                Ok(if meta.nlink() == 1 { meta.len() } else { 0 })
+            }
+        })
+        .await?;
+
+        Ok(freed)
+    }
+
+    /// Removes the least recently used files until the cache fits into its
+    /// maximum size again. `keep` is never removed, since it is about to be
+    /// played. Returns the number of bytes freed.
+    pub async fn evict(&self, keep: &Path) -> Result<u64, MediaError> {
+        let files = blocking(|| {
+            let mut paths = Vec::new();
+            collect_files(Path::new(CACHE_DIR), &mut paths)?;
+
+            let mut files = Vec::with_capacity(paths.len());
+
+            for path in paths {
+                let meta = fs::metadata(&path)?;
+
+                files.push(CachedFile {
+                    path,
+                    size: meta.len(),
+                    inode: (meta.dev(), meta.ino()),
+                    // not every file system keeps access times up to date
+                    accessed: meta.accessed().or_else(|_| meta.modified())?,
+                });
+            }
+
+            Ok(files)
+        })
+        .await?;
+
+        let mut freed = 0;
+
+        for path in evicted(&files, self.max_size, keep) {
+            freed += self.remove(path).await?;
+        }
+
+        Ok(freed)
+    }
+
+    /// Checks every file in the cache directory and merges the ones that have
+    /// identical content.
+    pub async fn dedupe(&self) -> Result<DedupeStats, MediaError> {
+        let files = blocking(|| {
+            let mut files = Vec::new();
+            collect_files(Path::new(CACHE_DIR), &mut files)?;
+            Ok(files)
+        })
+        .await?;
+
+        let mut stats = DedupeStats::default();
+
+        for file in files {
+            stats.files += 1;
+
+            let freed = self.register(&file).await?;
+
+            if freed > 0 {
+                stats.merged += 1;
+                stats.reclaimed += freed;
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+/// Picks the files to remove so that `files` take up at most `max_size`
+/// bytes, least recently accessed first. Links to the same content are
+/// counted once and removed together, since removing only some of them
+/// doesn't free anything. The content `keep` links to is never picked.
+fn evicted<'a>(files: &'a [CachedFile], max_size: u64, keep: &Path) -> Vec<&'a Path> {
+    let mut by_inode: HashMap<_, (u64, SystemTime, Vec<&Path>)> = HashMap::new();
+
+    for file in files {
+        let (_, accessed, paths) =
+            by_inode
+                .entry(file.inode)
+                .or_insert((file.size, file.accessed, Vec::new()));
+        *accessed = (*accessed).max(file.accessed);
+        paths.push(&file.path);
+    }
+
+    let mut total: u64 = by_inode.values().map(|(size, _, _)| size).sum();
+
+    let mut candidates: Vec<_> = by_inode
+        .into_values()
+        .filter(|(_, _, paths)| !paths.contains(&keep))
+        .collect();
+    candidates.sort_by_key(|(_, accessed, _)| *accessed);
+
+    let mut evicted = Vec::new();
+
+    for (size, _, paths) in candidates {
+        if total <= max_size {
+            break;
+        }
+
+        total -= size;
+        evicted.extend(paths);
+    }
+
+    evicted
+}
+
+async fn blocking<F, T>(op: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(op)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+}
+
+fn checksum(path: &Path) -> io::Result<(Vec<u8>, u64)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 65536];
+    let mut size = 0;
+
+    loop {
+        let len = file.read(&mut buf)?;
+
+        if len == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..len]);
+        size += len as u64;
+    }
+
+    Ok((hasher.finalize().to_vec(), size))
+}
+
+/// Replaces `path` with a hard link to `original`. Returns the number of
+/// bytes freed.
+fn link_to(original: &Path, path: &Path) -> io::Result<u64> {
+    let meta = fs::metadata(path)?;
+    let original_meta = fs::metadata(original)?;
+
+    if meta.dev() == original_meta.dev() && meta.ino() == original_meta.ino() {
+        return Ok(0);
+    }
+
+    // link to a temporary name first so that path never disappears
+    let tmp = path.with_extension("link");
+    let _ = fs::remove_file(&tmp);
+    fs::hard_link(original, &tmp)?;
+    fs::rename(&tmp, path)?;
+
+    Ok(if meta.nlink() == 1 { meta.len() } else { 0 })
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.extension().map_or(false, |ext| ext == "flac") {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum MediaError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    use super::{checksum, evicted, link_to, CachedFile};
+
+    fn file(path: &str, size: u64, inode: u64, age_secs: u64) -> CachedFile {
+        CachedFile {
+            path: PathBuf::from(path),
+            size,
+            inode: (1, inode),
+            accessed: SystemTime::UNIX_EPOCH + Duration::from_secs(1000 - age_secs),
+        }
+    }
+
+    #[test]
+    fn test_evicted() {
+        let files = [
+            file("old.flac", 40, 1, 300),
+            file("new.flac", 40, 2, 10),
+            file("playing.flac", 40, 3, 500),
+            // a link to new.flac, which doesn't take up any more space
+            file("new-link.flac", 40, 2, 200),
+            file("older.flac", 40, 4, 400),
+        ];
+        let keep = Path::new("playing.flac");
+
+        // 160 bytes in total, fits
+        assert!(evicted(&files, 160, keep).is_empty());
+
+        // the oldest one is being played, so the next oldest ones go
+        assert_eq!(vec![Path::new("older.flac")], evicted(&files, 150, keep));
+        assert_eq!(
+            vec![Path::new("older.flac"), Path::new("old.flac")],
+            evicted(&files, 80, keep)
+        );
+
+        // never more than needed, and never the one being played
+        let mut all = evicted(&files, 0, keep);
+        all.sort();
+        assert_eq!(
+            vec![
+                Path::new("new-link.flac"),
+                Path::new("new.flac"),
+                Path::new("old.flac"),
+                Path::new("older.flac")
+            ],
+            all
+        );
+    }
+
+    #[test]
+    fn test_link_duplicate() {
+        let dir = std::env::temp_dir().join(format!("r2dj-media-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let a = dir.join("a.flac");
+        let b = dir.join("b.flac");
+        fs::write(&a, b"some audio").unwrap();
+        fs::write(&b, b"some audio").unwrap();
+
+        assert_eq!(checksum(&a).unwrap(), checksum(&b).unwrap());
+        assert_eq!(10, link_to(&a, &b).unwrap());
+        assert_eq!(
+            fs::metadata(&a).unwrap().ino(),
+            fs::metadata(&b).unwrap().ino()
+        );
+        assert_eq!(2, fs::metadata(&a).unwrap().nlink());
+
+        // already linked, nothing to do
+        assert_eq!(0, link_to(&a, &b).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,38 +1,97 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 
 use futures::StreamExt;
-use log::{error, warn};
+use log::{debug, error, warn};
 use petgraph::graph::NodeIndex;
 use pin_project_lite::pin_project;
-use tokio::sync::broadcast;
+use sqlx::PgPool;
+use thiserror::Error;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc};
 use tokio::time::Duration;
 use uuid::Uuid;
 
 use audiopipe::{AudioSource, Core};
 use msgtools::{proxy, Ac};
+use player2x::backend::{Ffmpeg, Ffprobe, Prober, Transcoder};
 use player2x::ffplayer::{Player, PlayerEvent};
-use playlistv2::treepath::TreePathBuf;
+use playlistv2::treepath::{TreePath, TreePathBuf};
 pub use playlistv2::*;
 
+use crate::db::entity::import::ImportError;
+use crate::db::entity::playlist::Content;
 use crate::db::entity::{Playlist, Track};
+use crate::db::object::playlist::NestingMode;
+use crate::db::objgen;
+use crate::player::gapless::GaplessPlayer;
+use crate::player::history::PlayHistory;
+use crate::player::latest::{LatestReceiver, LatestTask};
+use crate::player::media::MediaCache;
+pub use crate::player::queue::QueueEntry;
+use crate::player::queue::QueueStore;
+use crate::player::radio::RadioSource;
+use crate::player::track::TrackError;
 
+mod gapless;
+mod history;
+mod latest;
+pub mod media;
 // mod playlist;
+pub mod playlists_index;
 mod playlistv2;
+mod queue;
+pub mod radio;
 mod track;
 
+/// How many tracks the radio adds to the queue at most each time it runs
+/// out of tracks.
+const RADIO_REFILL: usize = 5;
+
+/// How many of the last played tracks the radio won't add again.
+const RADIO_HISTORY: usize = 50;
+
+/// How long before the end of a track the next one starts loading, so that
+/// it can play right after without a gap.
+const PRELOAD_AHEAD: Duration = Duration::from_secs(10);
+
+/// The highest volume a room can be set to, i.e. twice as loud as the
+/// tracks themselves.
+const MAX_VOLUME: f32 = 2.0;
+
+/// The playlist slot a room starts out with.
+pub const DEFAULT_SLOT: &str = "default";
+
 proxy! {
-    pub proxy Room1 {
+    pub proxy Room1(cap = 64): trait + batch {
         pub async fn play();
         pub async fn pause();
         pub async fn next();
         pub async fn toggle_random() -> bool;
-        pub async fn add_to_queue(track: Track);
+        pub async fn set_volume(volume: f32);
+        pub async fn adjust_volume(delta: f32) -> f32;
+        pub async fn volume() -> f32;
+        pub async fn set_muted(muted: bool);
+        pub async fn muted() -> bool;
+        pub async fn set_mode(mode: PlayMode);
+        pub async fn set_radio(source: Option<Arc<dyn RadioSource>>);
+        pub async fn add_to_queue(track: Track, requested_by: Option<String>);
+        pub async fn play_now(track: Track) -> Result<(), PlayNowError>;
+        pub async fn remove_from_queue(index: usize) -> bool;
+        pub async fn move_in_queue(from: usize, to: usize) -> bool;
+        pub async fn queue_contents() -> Vec<Track>;
         pub async fn set_playlist(playlist: Ac<Playlist>);
         pub async fn playlist() -> Ac<Playlist>;
-        pub async fn add_playlist(playlist: Ac<Playlist>, path: TreePathBuf) -> bool;
+        pub async fn add_playlist(playlist: Ac<Playlist>, path: TreePathBuf) -> Result<(), AddPlaylistError>;
+        pub async fn set_nesting_mode(path: TreePathBuf, mode: NestingMode) -> Result<Playlist, NestingModeError>;
+        pub async fn load_slot(slot: String, playlist: Ac<Playlist>);
+        pub async fn switch_slot(slot: String) -> bool;
+        pub async fn slots() -> Vec<SlotInfo>;
+        pub async fn player_node() -> Option<NodeIndex>;
+        pub async fn register_client(name: String, event_tx: mpsc::Sender<Event>);
     }
 }
 
@@ -43,25 +102,155 @@ pub struct Room {
 }
 
 struct RoomService {
-    player: Option<Player<AudioSource>>,
+    db: PgPool,
+    player: GaplessPlayer,
     player_receiver: Option<broadcast::Receiver<PlayerEvent>>,
     audio_out: NodeIndex,
     ac: Arc<Core>,
+    media: MediaCache,
+    backend: PlayerBackend,
     event_tx: broadcast::Sender<Event>,
     mode: PlayMode,
+    /// The playlist of the active slot.
     playlist: PlaylistTracker,
+    /// The name of the active slot.
+    slot: String,
+    /// The playlists of the other slots, which keep their position and
+    /// shuffle order while they're not active.
+    slots: BTreeMap<String, PlaylistTracker>,
+    queue: VecDeque<QueueEntry>,
+    queue_store: QueueStore,
+    play_history: PlayHistory,
     track_state: Option<TrackState>,
     clients: Vec<Client>,
+    loader: LatestTask<LoadResult>,
+    /// Loads the track after the current one ahead of time, see
+    /// [`RoomService::preload_next`].
+    next_loader: LatestTask<LoadResult>,
+    /// Where the preloaded track comes from. It is only taken from there
+    /// once it starts playing, so that it isn't lost if it never does.
+    next_source: Option<NextSource>,
+    /// How many tracks in a row failed to load.
+    failures: usize,
+    /// How many tracks in a row may fail to load before the room gives up
+    /// and stops playback instead of skipping to the next one.
+    max_failures: usize,
+    /// Volume of the room, applied on top of each track's own gain.
+    volume: f32,
+    /// Whether the room is muted. The volume stays as it was, so that
+    /// unmuting goes back to it.
+    muted: bool,
+    /// Gain of the track that is currently playing, without the volume.
+    track_gain: f32,
+    /// Where to get more tracks from when the playlist ends, if anywhere.
+    radio: Option<Arc<dyn RadioSource>>,
+    radio_loader: LatestTask<RadioResult>,
+    /// IDs of the most recently played tracks, newest last.
+    history: VecDeque<Uuid>,
+    /// The track that was played last, which the radio picks tracks for.
+    last_track: Option<Track>,
+    /// The track requested with [`Room1::play_now`] that is being loaded.
+    play_now: Option<PendingPlay>,
+}
+
+struct PendingPlay {
+    track: Track,
+    callback: proxy::Callback<Result<(), PlayNowError>>,
+}
+
+/// Why a track requested with [`Room1::play_now`] didn't start playing.
+#[derive(Debug, Clone, Error)]
+pub enum PlayNowError {
+    #[error("another track was started before it")]
+    Superseded,
+    #[error("{0}")]
+    Failed(Arc<TrackError>),
 }
 
+/// Why a playlist couldn't be added with [`Room1::add_playlist`].
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+pub enum AddPlaylistError {
+    #[error("no such entry")]
+    NotFound,
+    #[error("not a playlist")]
+    NotAPlaylist,
+}
+
+/// Why the nesting mode of a playlist couldn't be changed with
+/// [`Room1::set_nesting_mode`].
+#[derive(Debug, Clone, Error)]
+pub enum NestingModeError {
+    #[error("not a playlist")]
+    NotAPlaylist,
+    /// The mode was changed, but couldn't be saved.
+    #[error("{0}")]
+    Save(Arc<objgen::Error>),
+}
+
+type LoadResult = (Track, Result<Player<AudioSource>, TrackError>);
+type RadioResult = Result<Vec<Track>, ImportError>;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum PlayMode {
+    /// Stops at the end of the playlist.
     Once,
+    /// Starts over at the end of the playlist.
     Repeat,
     RepeatOne,
 }
 
-pub enum Client {
-    MumbleClient,
+/// A playlist slot of a room, see [`Room1::slots`].
+#[derive(Debug, Clone)]
+pub struct SlotInfo {
+    pub name: String,
+    pub playlist: Ac<Playlist>,
+    pub active: bool,
+}
+
+/// Something other than the room's subscribers that wants to know about
+/// its events, registered with [`Room1::register_client`].
+struct Client {
+    name: String,
+    event_tx: mpsc::Sender<Event>,
+}
+
+/// Where the track that comes next is taken from, see [`peek_next`].
+#[derive(Debug, Clone, PartialEq)]
+enum NextSource {
+    /// The front of the queue.
+    Queue,
+    /// The track at this path in the playlist.
+    Playlist(TreePathBuf),
+}
+
+impl NextSource {
+    /// Takes the track out of the queue or marks it as played in the
+    /// playlist, as [`RoomService::next`] would have done.
+    fn take(self, queue: &mut VecDeque<QueueEntry>, playlist: &mut PlaylistTracker) {
+        match self {
+            NextSource::Queue => {
+                queue.pop_front();
+            }
+            NextSource::Playlist(path) => playlist.mark_played(&path),
+        }
+    }
+}
+
+/// Returns the track that comes after the current one and where it comes
+/// from, without taking it. Unlike [`RoomService::next`], this doesn't go
+/// back to the start of the playlist once it ends.
+fn peek_next(
+    queue: &VecDeque<QueueEntry>,
+    playlist: &PlaylistTracker,
+) -> Option<(Track, NextSource)> {
+    if let Some(entry) = queue.front() {
+        return Some((entry.track().clone(), NextSource::Queue));
+    }
+
+    let path = playlist.peek().ok()?;
+    let track = playlist.playlist().get_track(&path)?.clone();
+
+    Some((track, NextSource::Playlist(path)))
 }
 
 struct TrackState {
@@ -69,35 +258,113 @@ struct TrackState {
     offset: Duration,
 }
 
+/// How a room probes and decodes the media files of its tracks.
+#[derive(Clone)]
+pub struct PlayerBackend {
+    pub prober: Arc<dyn Prober>,
+    pub transcoder: Arc<dyn Transcoder>,
+}
+
+impl Default for PlayerBackend {
+    /// Runs ffprobe and ffmpeg.
+    fn default() -> Self {
+        PlayerBackend {
+            prober: Arc::new(Ffprobe),
+            transcoder: Arc::new(Ffmpeg),
+        }
+    }
+}
+
 impl Room {
-    pub fn new(audio_out: NodeIndex, ac: Arc<Core>) -> Self {
+    /// Creates a new room. `queue` is the previously saved queue to continue
+    /// with, see [`Room::load_queue`]. Once `max_failures` tracks in a row
+    /// failed to load, playback stops with [`Event::PlaybackHalted`].
+    pub fn new(
+        id: Uuid,
+        audio_out: NodeIndex,
+        ac: Arc<Core>,
+        db: PgPool,
+        media: MediaCache,
+        queue: Vec<QueueEntry>,
+        max_failures: usize,
+    ) -> Self {
+        Room::with_backend(
+            id,
+            audio_out,
+            ac,
+            db,
+            media,
+            queue,
+            max_failures,
+            PlayerBackend::default(),
+        )
+    }
+
+    /// Like [`Room::new`], but plays tracks with `backend` instead of
+    /// ffprobe and ffmpeg.
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_backend(
+        id: Uuid,
+        audio_out: NodeIndex,
+        ac: Arc<Core>,
+        db: PgPool,
+        media: MediaCache,
+        queue: Vec<QueueEntry>,
+        max_failures: usize,
+        backend: PlayerBackend,
+    ) -> Self {
         let (event_tx, _) = broadcast::channel(20);
+        let (loader, loads) = latest::latest_task();
+        let (next_loader, next_loads) = latest::latest_task();
+        let (radio_loader, radio_loads) = latest::latest_task();
 
         let rd = RoomService {
-            player: None,
+            db: db.clone(),
+            player: GaplessPlayer::new(PRELOAD_AHEAD),
             player_receiver: None,
             audio_out,
             ac,
+            media,
+            backend,
             event_tx: event_tx.clone(),
             mode: PlayMode::Repeat,
             playlist: PlaylistTracker::new(Ac::new(Playlist::new())),
+            slot: DEFAULT_SLOT.to_string(),
+            slots: BTreeMap::new(),
+            queue: queue.into(),
+            queue_store: QueueStore::new(db.clone(), id),
+            play_history: PlayHistory::new(db, id),
             track_state: None,
             clients: vec![],
+            loader,
+            next_loader,
+            next_source: None,
+            failures: 0,
+            max_failures,
+            volume: 1.0,
+            muted: false,
+            track_gain: 1.0,
+            radio: None,
+            radio_loader,
+            history: VecDeque::new(),
+            last_track: None,
+            play_now: None,
         };
 
         let (tx, rx) = Room1::channel();
 
-        tokio::spawn(run_room(rd, rx));
+        tokio::spawn(run_room(rd, rx, loads, next_loads, radio_loads));
 
-        let r = Room {
-            id: Uuid::new_v4(),
-            tx,
-            event_tx,
-        };
+        let r = Room { id, tx, event_tx };
 
         r
     }
 
+    /// Loads the saved queue of the room with the given ID.
+    pub async fn load_queue(db: &PgPool, id: Uuid) -> sqlx::Result<Vec<QueueEntry>> {
+        QueueStore::load(db, id).await
+    }
+
     pub fn proxy(&self) -> &Room1 {
         &self.tx
     }
@@ -108,39 +375,760 @@ impl Room {
 }
 
 impl RoomService {
-    fn next(&mut self) -> Option<Track> {
-        // TODO song queuing
-        self.playlist.next().map(|x| x.clone()).ok()
+    /// Sends `ev` to the subscribers and the registered clients.
+    fn send_event(&mut self, ev: Event) {
+        self.broadcast_event_to_clients(&ev);
+        let _ = self.event_tx.send(ev);
+    }
+
+    /// Sends `ev` to the registered clients. Clients whose receiver was
+    /// dropped are removed, a client whose queue is full misses the event.
+    fn broadcast_event_to_clients(&mut self, ev: &Event) {
+        self.clients
+            .retain(|client| match client.event_tx.try_send(ev.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "event queue of client {} is full, dropping event",
+                        client.name
+                    );
+                    true
+                }
+                Err(TrySendError::Closed(_)) => {
+                    debug!("removing client {}", client.name);
+                    false
+                }
+            });
+    }
+
+    /// Adds a client to send events to, replacing the one with the same
+    /// name if there is one.
+    fn register_client(&mut self, name: String, event_tx: mpsc::Sender<Event>) {
+        self.clients.retain(|client| client.name != name);
+        self.clients.push(Client { name, event_tx });
+    }
+
+    /// Adds `playlist` to the playlist at `path` in the active playlist.
+    fn add_playlist(
+        &mut self,
+        playlist: Playlist,
+        path: &TreePath,
+    ) -> Result<(), AddPlaylistError> {
+        if !path.is_empty() {
+            match self.playlist.playlist().get_entry(path) {
+                None => return Err(AddPlaylistError::NotFound),
+                Some(Content::Track(_)) => return Err(AddPlaylistError::NotAPlaylist),
+                Some(_) => {}
+            }
+        }
+
+        self.playlist
+            .add_playlist(playlist, path)
+            .map_err(|_| AddPlaylistError::NotFound)
+    }
+
+    /// Sets how the playlist at `path` in the active playlist is played
+    /// when it is nested, and saves it if it was saved before. Returns the
+    /// changed playlist.
+    async fn set_nesting_mode(
+        &mut self,
+        path: &TreePath,
+        mode: NestingMode,
+    ) -> Result<Playlist, NestingModeError> {
+        let db = &self.db;
+        let playlist = self
+            .playlist
+            .playlist_mut()
+            .get_playlist_mut(path)
+            .ok_or(NestingModeError::NotAPlaylist)?;
+
+        playlist.set_nesting_mode(mode);
+
+        // playlists that only exist in the room, such as ones created with
+        // new, are saved along with the rest of it, if ever
+        if playlist.object().id().is_some() {
+            let save = async {
+                let mut conn = db.acquire().await?;
+                playlist.save(&mut *conn).await
+            };
+
+            save.await
+                .map_err(|e| NestingModeError::Save(Arc::new(e)))?;
+        }
+
+        Ok(playlist.clone())
+    }
+
+    fn next(&mut self) -> Result<Track, GetTrackError> {
+        if let Some(entry) = self.queue.pop_front() {
+            self.save_queue();
+            return Ok(entry.into_track());
+        }
+
+        match self.playlist.next() {
+            Ok(track) => Ok(track.clone()),
+            // the radio continues after the playlist instead
+            Err(GetTrackError::End) if self.radio.is_some() => Err(GetTrackError::End),
+            Err(GetTrackError::End) => {
+                // start over either way, so that playing again after the
+                // playlist finished begins at the first track
+                self.playlist.restart();
+
+                match self.mode {
+                    PlayMode::Once => Err(GetTrackError::End),
+                    PlayMode::Repeat | PlayMode::RepeatOne => {
+                        self.playlist.next().map(|x| x.clone())
+                    }
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Makes `slot` the active slot. Returns false if there is no such slot.
+    fn switch_slot(&mut self, slot: String) -> bool {
+        if slot == self.slot {
+            return true;
+        }
+
+        let playlist = match self.slots.remove(&slot) {
+            None => return false,
+            Some(v) => v,
+        };
+
+        let old = std::mem::replace(&mut self.playlist, playlist);
+        let old_slot = std::mem::replace(&mut self.slot, slot);
+        self.slots.insert(old_slot, old);
+
+        true
+    }
+
+    fn slot_info(&self) -> Vec<SlotInfo> {
+        let active = SlotInfo {
+            name: self.slot.clone(),
+            playlist: self.playlist.playlist().clone(),
+            active: true,
+        };
+
+        let mut slots: Vec<_> = self
+            .slots
+            .iter()
+            .map(|(name, tracker)| SlotInfo {
+                name: name.clone(),
+                playlist: tracker.playlist().clone(),
+                active: false,
+            })
+            .chain(std::iter::once(active))
+            .collect();
+
+        slots.sort_by(|a, b| a.name.cmp(&b.name));
+        slots
+    }
+
+    /// Returns the gain for the current track, from its own gain and the
+    /// room's volume.
+    fn gain(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.track_gain * self.volume
+        }
+    }
+
+    /// Applies a changed volume to the track that is playing.
+    async fn update_gain(&self) {
+        if let Some(player) = self.player.current() {
+            player.set_gain(self.gain()).await;
+        }
+    }
+
+    fn save_queue(&self) {
+        self.queue_store.save(&self.queue);
     }
 
     async fn skip(&mut self) {
-        if let Some(player) = self.player.take() {
-            // TODO: remove audio output from ac
-            player.pause().await;
+        self.player.stop().await;
+        self.failures = 0;
+
+        if let Some((tr, player)) = self.player.take_next() {
+            self.loader.cancel();
+            self.take_preloaded();
+            self.start_track(tr, player).await;
+        } else if !self.next_loader.is_running() {
+            self.load_next();
+        }
+
+        // otherwise, the next track starts as soon as it finished loading
+    }
+
+    /// Forgets the next track if it was loaded already, because it isn't
+    /// the next one anymore.
+    fn discard_next(&mut self) {
+        self.player.clear_next();
+        self.next_loader.cancel();
+        self.next_source = None;
+    }
+
+    /// Takes the preloaded track out of the queue or the playlist, once it
+    /// starts playing or failed to load.
+    fn take_preloaded(&mut self) {
+        let source = match self.next_source.take() {
+            None => return,
+            Some(v) => v,
+        };
+
+        let from_queue = source == NextSource::Queue;
+        source.take(&mut self.queue, &mut self.playlist);
+
+        if from_queue {
+            self.save_queue();
+        }
+    }
+
+    /// Preloads the track that comes next again after the queue changed, in
+    /// case the one that was preloaded isn't the next one anymore.
+    fn reload_next(&mut self) {
+        if self.next_source.is_some() {
+            self.discard_next();
+            self.preload_next();
+        }
+    }
+
+    /// Starts loading the next track in the background. A load that is still
+    /// in progress is aborted, so that when skipping several times in a row,
+    /// only the last track actually gets prepared and played.
+    fn load_next(&mut self) {
+        let track = match self.next() {
+            Err(e) => {
+                self.loader.cancel();
+
+                if self.refill_radio() {
+                    // continues once the radio found something
+                    return;
+                }
+
+                let ev = match e {
+                    GetTrackError::End => Event::PlaylistFinished,
+                    GetTrackError::NoTracks => Event::TrackCleared,
+                };
+
+                self.send_event(ev);
+                return;
+            }
+            Ok(tr) => tr,
+        };
+
+        let load = self.load_task(track);
+        self.loader.start(load);
+    }
+
+    /// Starts loading the track after the current one in the background
+    /// while the current one is still playing, so that it can start right
+    /// when the current one ends.
+    fn preload_next(&mut self) {
+        if self.player.has_next() || self.next_loader.is_running() || self.loader.is_running() {
+            return;
+        }
+
+        // what happens at the end of the playlist is decided once the
+        // current track is over
+        if self.queue.is_empty() && self.playlist.at_end() {
+            return;
+        }
+
+        // the track stays where it is until it actually starts, so that
+        // skipping or changing the queue in the meantime doesn't lose it
+        let (track, source) = match peek_next(&self.queue, &self.playlist) {
+            None => return,
+            Some(v) => v,
+        };
+
+        let load = self.load_task(track);
+        self.next_source = Some(source);
+        self.next_loader.start(load);
+    }
+
+    fn load_task(&self, track: Track) -> impl Future<Output = LoadResult> {
+        let ac = self.ac.clone();
+        let audio_out = self.audio_out;
+        let media = self.media.clone();
+        let backend = self.backend.clone();
+
+        async move {
+            let result = load_track(&ac, audio_out, &media, &backend, &track).await;
+            (track, result)
         }
+    }
+
+    async fn start_track(&mut self, tr: Track, player: Player<AudioSource>) {
+        self.failures = 0;
+        self.player_receiver = Some(player.event_listener());
+        self.track_gain = track_gain(&tr);
+        player.set_gain(self.gain()).await;
 
-        let tr = self.next();
+        let length = player.length();
 
-        if let Some(tr) = tr {
-            let path = tr.providers().first().unwrap().media_path().await.unwrap();
-            let out = self.ac.add_input_to(Some(self.audio_out));
-            let player = Player::new(path, out).unwrap();
-            self.player_receiver = Some(player.event_listener());
+        self.player.play(player).await;
+        self.remember(&tr);
+        self.play_history.record(&tr, length);
 
-            player.play().await;
+        // look for more tracks while this one is playing, so that
+        // there's no gap once it's over
+        if self.queue.is_empty() && self.playlist.at_end() {
+            self.refill_radio();
+        }
 
-            let length = player.length();
+        self.send_event(Event::TrackChanged(tr.clone(), length));
+        self.resolve_play_now(&tr, Ok(()));
+    }
 
-            self.player = Some(player);
+    /// Tells the caller of [`Room1::play_now`] whether their track is the
+    /// one that `tr` is about. If it's another one, some other action
+    /// started that track first, and the request is superseded.
+    fn resolve_play_now(&mut self, tr: &Track, result: Result<(), PlayNowError>) {
+        let pending = match self.play_now.take() {
+            None => return,
+            Some(v) => v,
+        };
 
-            let _ = self.event_tx.send(Event::TrackChanged(tr, length));
+        let result = if pending.track.object().id() == tr.object().id() {
+            result
         } else {
-            let _ = self.event_tx.send(Event::TrackCleared);
+            Err(PlayNowError::Superseded)
+        };
+
+        let _ = pending.callback.send(result);
+    }
+
+    async fn finish_preload(&mut self, (tr, result): LoadResult) {
+        if self.player.current().is_none() {
+            // the current track ended before this one was ready
+            self.take_preloaded();
+            self.finish_load((tr, result)).await;
+            return;
+        }
+
+        match result {
+            Ok(player) => self.player.set_next(tr, player),
+            Err(e) => {
+                error!("failed to preload track {}: {}", tr, e);
+
+                // skip over it, it would just fail again
+                self.take_preloaded();
+
+                self.send_event(Event::TrackFailed {
+                    track: tr,
+                    error: Arc::new(e),
+                });
+
+                self.failures += 1;
+
+                if self.failures < self.max_failures {
+                    self.preload_next();
+                }
+            }
+        }
+    }
+
+    async fn finish_load(&mut self, (tr, result): LoadResult) {
+        match result {
+            Ok(player) => self.start_track(tr, player).await,
+            Err(e) => {
+                error!("failed to play track {}: {}", tr, e);
+
+                let error = Arc::new(e);
+                self.resolve_play_now(&tr, Err(PlayNowError::Failed(error.clone())));
+
+                self.send_event(Event::TrackFailed { track: tr, error });
+
+                self.failures += 1;
+
+                if self.failures >= self.max_failures {
+                    let reason = format!("{} tracks failed to play in a row", self.failures);
+                    warn!("{}, stopping", reason);
+                    self.discard_next();
+                    self.send_event(Event::PlaybackHalted { reason });
+                } else {
+                    self.load_next();
+                }
+            }
+        }
+    }
+
+    fn remember(&mut self, track: &Track) {
+        if let Some(id) = track.object().id() {
+            self.history.retain(|el| *el != id);
+            self.history.push_back(id);
+
+            if self.history.len() > RADIO_HISTORY {
+                self.history.pop_front();
+            }
+        }
+
+        self.last_track = Some(track.clone());
+    }
+
+    /// Starts looking for tracks to add to the queue in the background, if
+    /// the radio is on. Returns whether the radio is looking for tracks now.
+    fn refill_radio(&mut self) -> bool {
+        let (radio, seed) = match (&self.radio, &self.last_track) {
+            (Some(radio), Some(seed)) => (radio.clone(), seed.clone()),
+            _ => return false,
+        };
+
+        if !self.radio_loader.is_running() {
+            self.radio_loader
+                .start(async move { radio.related(&seed, RADIO_REFILL).await });
+        }
+
+        true
+    }
+
+    /// Adds the tracks the radio found to the queue, leaving out the ones
+    /// which have been played recently or are queued already.
+    fn finish_radio(&mut self, result: RadioResult) {
+        let tracks = match result {
+            Ok(tracks) => tracks,
+            Err(e) => {
+                warn!("radio failed to find tracks: {}", e);
+                Vec::new()
+            }
+        };
+
+        let mut added = 0;
+
+        for track in tracks {
+            let id = match track.object().id() {
+                None => continue,
+                Some(id) => id,
+            };
+
+            let queued = self
+                .queue
+                .iter()
+                .any(|entry| entry.track().object().id() == Some(id));
+
+            if queued || self.history.contains(&id) || added >= RADIO_REFILL {
+                continue;
+            }
+
+            self.queue.push_back(QueueEntry::new(track, None));
+            added += 1;
+        }
+
+        if added > 0 {
+            self.save_queue();
+        }
+
+        if self.player.current().is_none()
+            && !self.loader.is_running()
+            && !self.next_loader.is_running()
+        {
+            if added > 0 {
+                self.load_next();
+            } else {
+                self.send_event(Event::TrackCleared);
+            }
+        }
+    }
+}
+
+async fn load_track(
+    ac: &Core,
+    audio_out: NodeIndex,
+    cache: &MediaCache,
+    backend: &PlayerBackend,
+    track: &Track,
+) -> Result<Player<AudioSource>, TrackError> {
+    let path = track::resolve_media_path(track.providers(), cache).await?;
+
+    prepare_player(ac, audio_out, track, |out| {
+        Player::with_backend(path, out, &*backend.prober, backend.transcoder.clone())
+    })
+    .await
+}
+
+/// Creates the input node for `track` in `audio_out`, opens the player on
+/// it with `open` and preloads the start of the track.
+async fn prepare_player<F>(
+    ac: &Core,
+    audio_out: NodeIndex,
+    track: &Track,
+    open: F,
+) -> Result<Player<AudioSource>, TrackError>
+where
+    F: FnOnce(AudioSource) -> player2x::ffplayer::Result<Player<AudioSource>>,
+{
+    let out = ac.add_input_to(Some(audio_out));
+    let player = PendingPlayer {
+        ac,
+        player: Some(open(out)?),
+    };
+
+    if let Err(e) = player.get().preload().await {
+        warn!("failed to preload {}: {}", track, e);
+    }
+
+    Ok(player.finish())
+}
+
+/// A player that is still being prepared. If the load is aborted because
+/// the track was skipped, its input node is taken out of the graph right
+/// away instead of staying connected until the graph notices that the
+/// player is gone.
+struct PendingPlayer<'a> {
+    ac: &'a Core,
+    player: Option<Player<AudioSource>>,
+}
+
+impl PendingPlayer<'_> {
+    fn get(&self) -> &Player<AudioSource> {
+        self.player.as_ref().unwrap()
+    }
+
+    fn finish(mut self) -> Player<AudioSource> {
+        self.player.take().unwrap()
+    }
+}
+
+impl Drop for PendingPlayer<'_> {
+    fn drop(&mut self) {
+        // the node index is only valid while the player still exists
+        if let Some(player) = self.player.take() {
+            self.ac.disconnect_node(player.node());
+        }
+    }
+}
+
+/// Converts the gain set for a track from dB to the factor its samples are
+/// multiplied with.
+fn track_gain(track: &Track) -> f32 {
+    let gain_db = track.object().gain_db().unwrap_or(0.0);
+    10f64.powf(gain_db / 20.0) as f32
+}
+
+/// Handles a single message sent through the room's [`Room1`] proxy.
+async fn handle_message(data: &mut RoomService, msg: Room1Message) {
+    match msg {
+        Room1Message::Play { callback } => {
+            match data.player.current() {
+                None if data.loader.is_running()
+                    || data.next_loader.is_running()
+                    || data.radio_loader.is_running() => {}
+                None => data.skip().await,
+                Some(pl) => pl.play().await,
+            }
+
+            let _ = callback.send(());
+        }
+        Room1Message::Pause { callback } => {
+            if let Some(player) = data.player.current() {
+                player.pause().await;
+            }
+
+            let _ = callback.send(());
+        }
+        Room1Message::Next { callback } => {
+            data.skip().await;
+            let _ = callback.send(());
+        }
+        Room1Message::ToggleRandom { callback } => {
+            let new_random = !data.playlist.random();
+            data.playlist.set_random(new_random);
+            let _ = callback.send(new_random);
+        }
+        Room1Message::SetVolume { volume, callback } => {
+            data.volume = volume;
+            data.muted = false;
+            data.update_gain().await;
+            let _ = callback.send(());
+        }
+        Room1Message::AdjustVolume { delta, callback } => {
+            data.volume = (data.volume + delta).clamp(0.0, MAX_VOLUME);
+            data.muted = false;
+            data.update_gain().await;
+            let _ = callback.send(data.volume);
+        }
+        Room1Message::Volume { callback } => {
+            let _ = callback.send(data.volume);
+        }
+        Room1Message::SetMuted { muted, callback } => {
+            data.muted = muted;
+            data.update_gain().await;
+            let _ = callback.send(());
+        }
+        Room1Message::Muted { callback } => {
+            let _ = callback.send(data.muted);
+        }
+        Room1Message::SetMode { mode, callback } => {
+            data.mode = mode;
+            let _ = callback.send(());
+        }
+        Room1Message::SetRadio { source, callback } => {
+            if source.is_none() {
+                data.radio_loader.cancel();
+            }
+
+            data.radio = source;
+            let _ = callback.send(());
+        }
+        Room1Message::AddToQueue {
+            track,
+            requested_by,
+            callback,
+        } => {
+            let was_empty = data.queue.is_empty();
+            data.queue.push_back(QueueEntry::new(track, requested_by));
+            data.save_queue();
+
+            // it comes before the rest of the playlist
+            if was_empty {
+                data.reload_next();
+            }
+
+            let _ = callback.send(());
+        }
+        Room1Message::PlayNow { track, callback } => {
+            if let Some(pending) = data.play_now.take() {
+                let _ = pending.callback.send(Err(PlayNowError::Superseded));
+            }
+
+            // the track that was loaded to play next is still in the queue
+            // or the playlist, and comes after this one now
+            data.discard_next();
+            data.queue.push_front(QueueEntry::new(track.clone(), None));
+            data.save_queue();
+            data.play_now = Some(PendingPlay { track, callback });
+            data.skip().await;
+        }
+        Room1Message::RemoveFromQueue { index, callback } => {
+            let removed = data.queue.remove(index).is_some();
+
+            if removed {
+                data.save_queue();
+
+                if index == 0 {
+                    data.reload_next();
+                }
+            }
+
+            let _ = callback.send(removed);
+        }
+        Room1Message::MoveInQueue { from, to, callback } => {
+            let moved = from < data.queue.len() && to < data.queue.len();
+
+            if moved {
+                let entry = data.queue.remove(from).unwrap();
+                data.queue.insert(to, entry);
+                data.save_queue();
+
+                if from == 0 || to == 0 {
+                    data.reload_next();
+                }
+            }
+
+            let _ = callback.send(moved);
+        }
+        Room1Message::QueueContents { callback } => {
+            let tracks = data
+                .queue
+                .iter()
+                .map(|entry| entry.track().clone())
+                .collect();
+            let _ = callback.send(tracks);
+        }
+        Room1Message::SetPlaylist { playlist, callback } => {
+            data.playlist = PlaylistTracker::new(playlist);
+            data.discard_next();
+            data.skip().await;
+            let _ = callback.send(());
+        }
+        Room1Message::Playlist { callback } => {
+            let _ = callback.send(data.playlist.playlist().clone());
+        }
+        Room1Message::AddPlaylist {
+            playlist,
+            path,
+            callback,
+        } => {
+            let result = data.add_playlist(playlist.into_inner(), &path);
+            let _ = callback.send(result);
+        }
+        Room1Message::SetNestingMode {
+            path,
+            mode,
+            callback,
+        } => {
+            let result = data.set_nesting_mode(&path, mode).await;
+            let _ = callback.send(result);
+        }
+        Room1Message::LoadSlot {
+            slot,
+            playlist,
+            callback,
+        } => {
+            if slot == data.slot {
+                data.playlist = PlaylistTracker::new(playlist);
+                data.discard_next();
+                data.skip().await;
+            } else {
+                data.slots.insert(slot, PlaylistTracker::new(playlist));
+            }
+
+            let _ = callback.send(());
+        }
+        Room1Message::SwitchSlot { slot, callback } => {
+            let changed = slot != data.slot;
+            let switched = data.switch_slot(slot);
+
+            // switching to the active slot keeps playing the current track
+            if switched && changed {
+                data.discard_next();
+                data.skip().await;
+            }
+
+            let _ = callback.send(switched);
+        }
+        Room1Message::Slots { callback } => {
+            let _ = callback.send(data.slot_info());
+        }
+        Room1Message::PlayerNode { callback } => {
+            let _ = callback.send(data.player.current().map(|pl| pl.node()));
+        }
+        Room1Message::RegisterClient {
+            name,
+            event_tx,
+            callback,
+        } => {
+            data.register_client(name, event_tx);
+            let _ = callback.send(());
+        }
+        Room1Message::Batch(messages) => {
+            // all of them before anything else, which is the point of
+            // sending them in one batch
+            for msg in messages {
+                handle_message_boxed(data, msg).await;
+            }
         }
     }
 }
 
-async fn run_room(mut data: RoomService, mut rx: Room1Receiver) {
+/// [`handle_message`] for the messages in a batch, which may be batches
+/// themselves.
+fn handle_message_boxed(
+    data: &mut RoomService,
+    msg: Room1Message,
+) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+    Box::pin(handle_message(data, msg))
+}
+
+async fn run_room(
+    mut data: RoomService,
+    mut rx: Room1Receiver,
+    mut loads: LatestReceiver<LoadResult>,
+    mut next_loads: LatestReceiver<LoadResult>,
+    mut radio_loads: LatestReceiver<RadioResult>,
+) {
     loop {
         let mut player_receiver = data.player_receiver.take();
         let player_fut = FutureOption::new(player_receiver.as_mut().map(|el| el.recv()));
@@ -152,62 +1140,33 @@ async fn run_room(mut data: RoomService, mut rx: Room1Receiver) {
                     Some(msg) => msg,
                 };
 
-                match msg {
-                    Room1Message::Play { callback } => {
-                        match &data.player {
-                            None => data.skip().await,
-                            Some(pl) => pl.play().await,
-                        }
-
-                        let _ = callback.send(());
-                    }
-                    Room1Message::Pause { callback } => {
-                        if let Some(player) = &data.player {
-                            player.pause().await;
-                        }
-
-                        let _ = callback.send(());
-                    }
-                    Room1Message::Next { callback } => {
-                        data.skip().await;
-                        let _ = callback.send(());
-                    }
-                    Room1Message::ToggleRandom { callback } => {
-                        let new_random = !data.playlist.random();
-                        data.playlist.set_random(new_random);
-                        let _ = callback.send(new_random);
-                    }
-                    Room1Message::AddToQueue { track, callback } => {
-                        warn!("AddToQueue unimplemented");
-                        let _ = callback.send(());
-                    }
-                    Room1Message::SetPlaylist { playlist, callback } => {
-                        data.playlist = PlaylistTracker::new(playlist);
-                        data.skip().await;
-                        let _ = callback.send(());
-                    }
-                    Room1Message::Playlist { callback } => {
-                        let _ = callback.send(data.playlist.playlist().clone());
-                    }
-                    Room1Message::AddPlaylist { playlist, path, callback } => {
-                        let success = data.playlist.add_playlist(playlist.into_inner(), path).is_ok();
-                        let _ = callback.send(success);
-                    }
-                }
+                handle_message(&mut data, msg).await;
+            }
+            Some(loaded) = loads.recv() => {
+                data.finish_load(loaded).await;
+            }
+            Some(loaded) = next_loads.recv() => {
+                data.finish_preload(loaded).await;
+            }
+            Some(found) = radio_loads.recv() => {
+                data.finish_radio(found);
             }
             ev = player_fut => {
                 match ev {
                     Ok(ev) => {
                         match ev {
+                            PlayerEvent::Buffering { .. } => {}
                             PlayerEvent::Playing { .. } => {}
                             PlayerEvent::Paused { stopped, .. } => {
                                 if stopped {
                                     data.skip().await;
                                 }
                             }
+                            PlayerEvent::CrossfadePoint { .. } => data.preload_next(),
+                            PlayerEvent::Error { .. } => {}
                         }
 
-                        let _ = data.event_tx.send(Event::PlayerEvent(ev));
+                        data.send_event(Event::PlayerEvent(ev));
                     }
                     Err(broadcast::error::RecvError::Closed) => {
                         // not sure this can happen, but I guess we should play
@@ -231,7 +1190,19 @@ async fn run_room(mut data: RoomService, mut rx: Room1Receiver) {
 pub enum Event {
     PlayerEvent(PlayerEvent),
     TrackChanged(Track, Duration),
+    TrackFailed {
+        track: Track,
+        error: Arc<TrackError>,
+    },
     TrackCleared,
+    /// Playback stopped because of a problem that playing other tracks
+    /// likely won't fix, such as youtube-dl not working.
+    PlaybackHalted {
+        reason: String,
+    },
+    /// The last track of the playlist finished playing and the room is in
+    /// [`PlayMode::Once`].
+    PlaylistFinished,
 }
 
 pin_project! {
@@ -261,3 +1232,200 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::future::Future;
+    use std::io;
+    use std::path::Path;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use sqlx::PgPool;
+    use tokio::io::{AsyncRead, ReadBuf};
+    use tokio::sync::mpsc;
+    use tokio::time::{sleep, timeout, Sleep};
+    use uuid::Uuid;
+
+    use audiopipe::Core;
+    use msgtools::Ac;
+    use player2x::backend::{Decoded, Transcoder};
+    use player2x::ffmpeg::FfmpegConfig;
+    use player2x::mock::{MockProber, MockTranscoder};
+
+    use super::{peek_next, Event, NextSource, PlayerBackend, PlaylistTracker, QueueEntry, Room};
+    use crate::db::entity::track::Source;
+    use crate::db::entity::{Playlist, Track};
+    use crate::player::media::MediaCache;
+
+    /// How long each track is, shorter than [`super::PRELOAD_AHEAD`] so that
+    /// the next one is preloaded as soon as a track starts.
+    const LENGTH: Duration = Duration::from_secs(5);
+
+    /// How long it takes to start decoding a track.
+    const LOAD_TIME: Duration = Duration::from_millis(100);
+
+    /// Decodes silence, but only after [`LOAD_TIME`], like ffmpeg on a file
+    /// that takes a while to download.
+    struct SlowTranscoder(MockTranscoder);
+
+    impl Transcoder for SlowTranscoder {
+        fn decode(&self, path: &Path, config: FfmpegConfig) -> io::Result<Decoded> {
+            Ok(Box::pin(Delayed {
+                delay: Box::pin(sleep(LOAD_TIME)),
+                inner: self.0.decode(path, config)?,
+            }))
+        }
+    }
+
+    struct Delayed {
+        delay: Pin<Box<Sleep>>,
+        inner: Decoded,
+    }
+
+    impl AsyncRead for Delayed {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            futures::ready!(self.delay.as_mut().poll(cx));
+            self.inner.as_mut().poll_read(cx, buf)
+        }
+    }
+
+    /// Starts a room playing the mock tracks and registers a client on it.
+    async fn room() -> (Room, mpsc::Receiver<Event>) {
+        let core = Core::new(48000);
+        let audio_out = core.add_output().node();
+
+        // the tracks are local files, so the cache never uses the database
+        let db = PgPool::connect_lazy("postgres://localhost/r2dj").unwrap();
+        let backend = PlayerBackend {
+            prober: Arc::new(MockProber::new(LENGTH)),
+            transcoder: Arc::new(SlowTranscoder(MockTranscoder::silence(LENGTH))),
+        };
+
+        let room = Room::with_backend(
+            Uuid::new_v4(),
+            audio_out,
+            Arc::new(core),
+            db.clone(),
+            MediaCache::new(db, u64::MAX),
+            Vec::new(),
+            3,
+            backend,
+        );
+
+        let (tx, rx) = mpsc::channel(100);
+        room.proxy()
+            .register_client("test".to_string(), tx)
+            .await
+            .unwrap();
+
+        (room, rx)
+    }
+
+    fn track(title: &str) -> Track {
+        let mut track = Track::new();
+        track.set_title(Some(title.to_string()));
+        track.add_provider(Source::Local(format!("{}.flac", title).into()));
+        track
+    }
+
+    fn titles(tracks: &[Track]) -> Vec<&str> {
+        tracks.iter().map(|t| t.title().unwrap()).collect()
+    }
+
+    /// Waits for the next [`Event::TrackChanged`] and returns the title of
+    /// the track, or `None` if there is none within `wait`.
+    async fn track_changed(rx: &mut mpsc::Receiver<Event>, wait: Duration) -> Option<String> {
+        let changed = async {
+            while let Some(ev) = rx.recv().await {
+                if let Event::TrackChanged(track, _) = ev {
+                    return track.title().map(str::to_string);
+                }
+            }
+
+            None
+        };
+
+        timeout(wait, changed).await.ok().flatten()
+    }
+
+    #[test]
+    fn test_peek_next() {
+        let mut pl = Playlist::new();
+        pl.push_track(Track::new());
+
+        let mut playlist = PlaylistTracker::new(Ac::new(pl));
+        playlist.set_random(false);
+
+        let mut queue = VecDeque::new();
+        queue.push_back(QueueEntry::new(Track::new(), None));
+
+        // the queue comes first, and its track stays in it until taken, in
+        // case the preloaded track is discarded
+        let (_, source) = peek_next(&queue, &playlist).unwrap();
+        assert_eq!(NextSource::Queue, source);
+        assert_eq!(1, queue.len());
+
+        source.take(&mut queue, &mut playlist);
+        assert!(queue.is_empty());
+
+        let (_, source) = peek_next(&queue, &playlist).unwrap();
+        assert!(matches!(source, NextSource::Playlist(_)));
+        assert!(!playlist.at_end());
+
+        source.take(&mut queue, &mut playlist);
+        assert!(playlist.at_end());
+        assert!(peek_next(&queue, &playlist).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rapid_skip() {
+        let (room, mut rx) = room().await;
+        let proxy = room.proxy();
+
+        for title in ["a", "b", "c", "d", "e"] {
+            proxy.add_to_queue(track(title), None).await.unwrap();
+        }
+
+        // every skip aborts the load of the previous track before it is done
+        proxy.play().await.unwrap();
+
+        for _ in 0..4 {
+            proxy.next().await.unwrap();
+        }
+
+        let wait = LOAD_TIME * 5;
+        assert_eq!(Some("e"), track_changed(&mut rx, wait).await.as_deref());
+        assert_eq!(None, track_changed(&mut rx, wait).await);
+        assert!(proxy.queue_contents().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_preload_from_queue() {
+        let (room, mut rx) = room().await;
+        let proxy = room.proxy();
+
+        proxy.add_to_queue(track("b"), None).await.unwrap();
+        proxy.add_to_queue(track("d"), None).await.unwrap();
+        proxy.play().await.unwrap();
+
+        let wait = LOAD_TIME * 5;
+        assert_eq!(Some("b"), track_changed(&mut rx, wait).await.as_deref());
+
+        // d is preloaded by now, but stays in the queue until it plays
+        sleep(wait).await;
+        let queue = proxy.queue_contents().await.unwrap();
+        assert_eq!(vec!["d"], titles(&queue));
+
+        proxy.next().await.unwrap();
+        assert_eq!(Some("d"), track_changed(&mut rx, wait).await.as_deref());
+        assert!(proxy.queue_contents().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::fmt::Formatter;
+
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::fmt::HtmlDisplay;
+
+/// Codes and titles of all playlists, so that looking up playlists doesn't
+/// need a database query. Call [`PlaylistIndex::refresh`] to pick up changes.
+#[derive(Debug, Default)]
+pub struct PlaylistIndex {
+    by_code: HashMap<String, Uuid>,
+    by_title: HashMap<String, Vec<Uuid>>,
+    entries: HashMap<Uuid, IndexEntry>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct IndexEntry {
+    id: Uuid,
+    code: String,
+    title: String,
+}
+
+impl PlaylistIndex {
+    pub async fn load(db: &mut PgConnection) -> sqlx::Result<Self> {
+        let mut index = PlaylistIndex::default();
+        index.refresh(db).await?;
+        Ok(index)
+    }
+
+    /// Replaces the contents of the index with the current state of the
+    /// database.
+    pub async fn refresh(&mut self, db: &mut PgConnection) -> sqlx::Result<()> {
+        // language=SQL
+        let rows = sqlx::query!("SELECT id, code, title FROM playlist WHERE deleted = false")
+            .fetch_all(db)
+            .await?;
+
+        self.set_entries(rows.into_iter().map(|row| IndexEntry {
+            id: row.id,
+            code: row.code,
+            title: row.title,
+        }));
+
+        Ok(())
+    }
+
+    fn set_entries<I>(&mut self, entries: I)
+    where
+        I: IntoIterator<Item = IndexEntry>,
+    {
+        self.by_code.clear();
+        self.by_title.clear();
+        self.entries.clear();
+
+        for entry in entries {
+            self.by_code.insert(entry.code.clone(), entry.id);
+            self.by_title
+                .entry(entry.title.clone())
+                .or_default()
+                .push(entry.id);
+            self.entries.insert(entry.id, entry);
+        }
+    }
+
+    pub fn by_code(&self, code: &str) -> Option<&IndexEntry> {
+        self.by_code.get(code).map(|id| &self.entries[id])
+    }
+
+    pub fn by_title(&self, title: &str) -> Vec<&IndexEntry> {
+        self.by_title
+            .get(title)
+            .into_iter()
+            .flatten()
+            .map(|id| &self.entries[id])
+            .collect()
+    }
+
+    /// Returns the playlists whose code contains all of `codes` and whose
+    /// title contains all of `titles`, ordered by code.
+    pub fn query<'a, C, T>(&self, codes: C, titles: T) -> Vec<&IndexEntry>
+    where
+        C: IntoIterator<Item = &'a str>,
+        T: IntoIterator<Item = &'a str>,
+    {
+        let codes: Vec<_> = codes.into_iter().collect();
+        let titles: Vec<_> = titles.into_iter().collect();
+
+        let mut result: Vec<_> = self
+            .entries
+            .values()
+            .filter(|e| codes.iter().all(|c| e.code.contains(c)))
+            .filter(|e| titles.iter().all(|t| e.title.contains(t)))
+            .collect();
+
+        result.sort_by(|a, b| a.code.cmp(&b.code));
+        result
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+impl IndexEntry {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+}
+
+impl HtmlDisplay for IndexEntry {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "<code>{}</code> {}", self.code, self.title)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use super::{IndexEntry, PlaylistIndex};
+
+    #[test]
+    fn test_query() {
+        let entry = |code: &str, title: &str| IndexEntry {
+            id: Uuid::new_v4(),
+            code: code.to_string(),
+            title: title.to_string(),
+        };
+
+        let mut index = PlaylistIndex::default();
+        index.set_entries(vec![
+            entry("00003", "Chill"),
+            entry("00001", "Rock"),
+            entry("00002", "Chill"),
+            entry("games", "Game Music"),
+        ]);
+
+        assert_eq!(4, index.len());
+        assert_eq!(Some("Rock"), index.by_code("00001").map(|e| e.title()));
+        assert_eq!(2, index.by_title("Chill").len());
+
+        let codes = |list: Vec<&IndexEntry>| {
+            list.iter()
+                .map(|e| e.code().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["00002", "00003"], codes(index.query([], ["Chill"])));
+        assert_eq!(
+            vec!["00001", "00002", "00003"],
+            codes(index.query(["000"], []))
+        );
+        assert_eq!(vec!["games"], codes(index.query(["m"], ["Music"])));
+
+        index.set_entries(vec![entry("00001", "Rock")]);
+        assert_eq!(None, index.by_code("games"));
+        assert!(index.by_title("Chill").is_empty());
+    }
+}
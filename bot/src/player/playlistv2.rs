@@ -10,6 +10,7 @@ use crate::db::entity::{Playlist, Track};
 use crate::db::object::playlist::NestingMode;
 use crate::player::playlistv2::treepath::{TreePath, TreePathBuf};
 
+mod serde_support;
 pub mod treepath;
 
 #[derive(Debug, Clone)]
@@ -49,48 +50,79 @@ impl PlaylistTracker {
     }
 
     pub fn next(&mut self) -> Result<&Track, GetTrackError> {
+        let path = self.peek()?;
+        self.mark_played(&path);
+
+        self.playlist.get_track(&path).ok_or(GetTrackError::End)
+    }
+
+    /// Chooses the track that comes next and returns its path, without
+    /// taking it yet. In random mode, the choice is made anew each time, so
+    /// the returned path has to be passed to
+    /// [`mark_played`](Self::mark_played) to actually take that track.
+    pub fn peek(&self) -> Result<TreePathBuf, GetTrackError> {
         let mut available = Vec::new();
         self.collect_choices(&TreePathBuf::root(), &self.playlist, &mut available);
 
         if available.is_empty() {
-            Err(GetTrackError::NoTracks)
-        } else {
-            let last_played = self
-                .trackers
-                .get(&TreePathBuf::root())
-                .map(|x| &**x)
-                .unwrap_or(&[]);
-
-            let next_idx = if self.random {
-                if available.is_empty() {
-                    None
-                } else {
-                    let indices: Vec<_> = last_played
-                        .iter()
-                        .filter_map(|(_, el)| available.iter().position(|v| el == v))
-                        .collect();
-
-                    let next = select_next_random(available.len(), &indices);
-                    Some(&available[next])
-                }
-            } else {
-                match last_played
-                    .last()
-                    .filter(|(iteration, _)| *iteration == self.iteration)
-                    .and_then(|(_, path)| available.iter().position(|el| el == path))
-                {
-                    None => Some(&available[0]),
-                    Some(idx) => available.get(idx + 1),
-                }
-            };
+            return Err(GetTrackError::NoTracks);
+        }
 
-            if let Some(next_idx) = next_idx {
-                self.insert_last_played(&TreePathBuf::root(), &next_idx);
+        let last_played = self
+            .trackers
+            .get(&TreePathBuf::root())
+            .map(|x| &**x)
+            .unwrap_or(&[]);
+
+        let next_idx = if self.random {
+            let indices: Vec<_> = last_played
+                .iter()
+                .filter_map(|(_, el)| available.iter().position(|v| el == v))
+                .collect();
+
+            let next = select_next_random(available.len(), &indices);
+            Some(&available[next])
+        } else {
+            match last_played
+                .last()
+                .filter(|(iteration, _)| *iteration == self.iteration)
+                .and_then(|(_, path)| available.iter().position(|el| el == path))
+            {
+                None => Some(&available[0]),
+                Some(idx) => available.get(idx + 1),
             }
+        };
 
-            next_idx
-                .and_then(move |x| self.playlist.get_track(x))
-                .ok_or(GetTrackError::End)
+        next_idx.cloned().ok_or(GetTrackError::End)
+    }
+
+    /// Takes the track at `path`, as returned by [`peek`](Self::peek), so
+    /// that the playlist continues after it.
+    pub fn mark_played(&mut self, path: &TreePath) {
+        self.insert_last_played(&TreePathBuf::root(), path);
+    }
+
+    /// Returns whether the playlist ends after the track last returned by
+    /// [`next`](Self::next), so that the next call fails with
+    /// [`GetTrackError::End`]. Random mode never ends.
+    pub fn at_end(&self) -> bool {
+        if self.random {
+            return false;
+        }
+
+        let mut available = Vec::new();
+        self.collect_choices(&TreePathBuf::root(), &self.playlist, &mut available);
+
+        let last = self
+            .trackers
+            .get(&TreePathBuf::root())
+            .and_then(|x| x.last())
+            .filter(|(iteration, _)| *iteration == self.iteration)
+            .and_then(|(_, path)| available.iter().position(|el| el == path));
+
+        match last {
+            None => available.is_empty(),
+            Some(idx) => idx + 1 >= available.len(),
         }
     }
 
@@ -102,12 +134,14 @@ impl PlaylistTracker {
                 Content::Track(_) => {
                     out.push(new_path);
                 }
-                Content::Playlist(pl1) => match pl.object().nesting_mode() {
+                // the nested playlist's own mode decides whether its tracks
+                // are chosen from individually or it takes turns as a whole
+                Content::Playlist(pl1) => match pl1.object().nesting_mode() {
                     NestingMode::Flatten => {
                         self.collect_choices(&new_path, pl1, out);
                     }
                     NestingMode::RoundRobin => {
-                        if !self.is_empty_(pl) {
+                        if !self.is_empty_(pl1) {
                             out.push(new_path);
                         }
                     }
@@ -229,6 +263,12 @@ impl PlaylistTracker {
     pub fn playlist(&self) -> &Ac<Playlist> {
         &self.playlist
     }
+
+    /// Returns the playlist for changing it in place. Which tracks were
+    /// played already is kept.
+    pub fn playlist_mut(&mut self) -> &mut Playlist {
+        &mut self.playlist
+    }
 }
 
 struct TrackIterator<'a> {
@@ -281,3 +321,54 @@ fn select_next_random(len: usize, last: &[usize]) -> usize {
         last[idx]
     }
 }
+
+#[cfg(test)]
+mod test {
+    use msgtools::Ac;
+
+    use crate::db::entity::{Playlist, Track};
+
+    use super::{GetTrackError, PlaylistTracker};
+
+    #[test]
+    fn test_at_end() {
+        let mut pl = Playlist::new();
+        pl.push_track(Track::new());
+        pl.push_track(Track::new());
+
+        let mut tracker = PlaylistTracker::new(Ac::new(pl));
+        tracker.set_random(false);
+        assert!(!tracker.at_end());
+
+        tracker.next().unwrap();
+        assert!(!tracker.at_end());
+        tracker.next().unwrap();
+        assert!(tracker.at_end());
+        assert_eq!(Err(GetTrackError::End), tracker.next().map(|_| ()));
+
+        tracker.restart();
+        assert!(!tracker.at_end());
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut pl = Playlist::new();
+        pl.push_track(Track::new());
+        pl.push_track(Track::new());
+
+        let mut tracker = PlaylistTracker::new(Ac::new(pl));
+        tracker.set_random(false);
+
+        // peeking doesn't take the track
+        let first = tracker.peek().unwrap();
+        assert_eq!(first, tracker.peek().unwrap());
+
+        tracker.mark_played(&first);
+        let second = tracker.peek().unwrap();
+        assert_ne!(first, second);
+
+        tracker.mark_played(&second);
+        assert!(tracker.at_end());
+        assert_eq!(Err(GetTrackError::End), tracker.peek());
+    }
+}
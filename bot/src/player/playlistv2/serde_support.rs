@@ -0,0 +1,63 @@
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::player::playlistv2::treepath::{TreePath, TreePathBuf};
+
+// tree paths are serialized as their display string, like "1-0-4", so they
+// look the same in JSON as they do in commands
+
+impl Serialize for TreePath {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl Serialize for TreePathBuf {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TreePathBuf {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::player::playlistv2::treepath::{TreePath, TreePathBuf};
+
+    #[test]
+    fn test_serialize() {
+        let path = TreePathBuf::from(&[1, 0, 4][..]);
+        assert_eq!("\"1-0-4\"", serde_json::to_string(&path).unwrap());
+        assert_eq!("\"1-0-4\"", serde_json::to_string(&*path).unwrap());
+        assert_eq!(
+            "\"-\"",
+            serde_json::to_string(&TreePathBuf::root()).unwrap()
+        );
+        assert_eq!("\"0\"", serde_json::to_string(TreePath::new(&[0])).unwrap());
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let path: TreePathBuf = serde_json::from_str("\"1-0-4\"").unwrap();
+        assert_eq!(TreePathBuf::from(&[1, 0, 4][..]), path);
+
+        let root: TreePathBuf = serde_json::from_str("\"-\"").unwrap();
+        assert_eq!(TreePathBuf::root(), root);
+
+        assert!(serde_json::from_str::<TreePathBuf>("\"1-x\"").is_err());
+    }
+}
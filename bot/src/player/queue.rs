@@ -0,0 +1,185 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use sqlx::PgPool;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::db::entity::Track;
+
+/// How long to wait after the queue changed before writing it to the
+/// database. Further changes during that time are written together.
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone)]
+pub struct QueueEntry {
+    track: Track,
+    requested_by: Option<String>,
+    added_at: DateTime<Utc>,
+}
+
+impl QueueEntry {
+    pub fn new(track: Track, requested_by: Option<String>) -> Self {
+        QueueEntry {
+            track,
+            requested_by,
+            added_at: Utc::now(),
+        }
+    }
+
+    pub fn track(&self) -> &Track {
+        &self.track
+    }
+
+    pub fn requested_by(&self) -> Option<&str> {
+        self.requested_by.as_deref()
+    }
+
+    pub fn added_at(&self) -> DateTime<Utc> {
+        self.added_at
+    }
+
+    pub fn into_track(self) -> Track {
+        self.track
+    }
+}
+
+#[derive(Debug, Clone)]
+struct QueueRow {
+    track_id: Uuid,
+    requested_by: Option<String>,
+    added_at: DateTime<Utc>,
+}
+
+/// Keeps the `room_queue` table in sync with a room's queue. Saving only
+/// records the latest state, the actual write happens in the background
+/// after [`SAVE_DELAY`].
+pub struct QueueStore {
+    tx: watch::Sender<Vec<QueueRow>>,
+}
+
+impl QueueStore {
+    pub fn new(db: PgPool, room_id: Uuid) -> Self {
+        let (tx, rx) = watch::channel(Vec::new());
+        tokio::spawn(run_writer(db, room_id, rx));
+        QueueStore { tx }
+    }
+
+    /// Schedules the queue to be written to the database, replacing whatever
+    /// was previously stored for the room. Tracks which haven't been saved
+    /// to the database yet are skipped.
+    pub fn save<'a, I>(&self, entries: I)
+    where
+        I: IntoIterator<Item = &'a QueueEntry>,
+    {
+        let rows = entries
+            .into_iter()
+            .filter_map(|entry| {
+                Some(QueueRow {
+                    track_id: entry.track.object().id()?,
+                    requested_by: entry.requested_by.clone(),
+                    added_at: entry.added_at,
+                })
+            })
+            .collect();
+
+        let _ = self.tx.send(rows);
+    }
+
+    /// Loads the stored queue of the room. Entries referring to tracks that
+    /// have been deleted since are dropped.
+    pub async fn load(db: &PgPool, room_id: Uuid) -> sqlx::Result<Vec<QueueEntry>> {
+        let mut conn = db.acquire().await?;
+
+        let rows = sqlx::query!(
+            // language=SQL
+            "SELECT rq.position, rq.track_id, rq.requested_by, rq.added_at, t.deleted \
+             FROM room_queue rq \
+             JOIN track t ON t.id = rq.track_id \
+             WHERE rq.room_id = $1 \
+             ORDER BY rq.position",
+            room_id
+        )
+        .fetch_all(&mut conn)
+        .await?;
+
+        let ids: Vec<_> = rows.iter().map(|row| row.track_id).collect();
+        let tracks = Track::load_many(&ids, &mut conn).await?;
+
+        let mut entries = Vec::with_capacity(rows.len());
+        let mut stale = Vec::new();
+
+        for row in rows {
+            if row.deleted {
+                warn!(
+                    "dropping deleted track {} at position {} from the queue",
+                    row.track_id, row.position
+                );
+                stale.push(row.position);
+                continue;
+            }
+
+            entries.push(QueueEntry {
+                track: tracks
+                    .get(&row.track_id)
+                    .cloned()
+                    .ok_or(sqlx::Error::RowNotFound)?,
+                requested_by: row.requested_by,
+                added_at: row.added_at,
+            });
+        }
+
+        if !stale.is_empty() {
+            sqlx::query!(
+                // language=SQL
+                "DELETE FROM room_queue WHERE room_id = $1 AND position = ANY($2)",
+                room_id,
+                &stale
+            )
+            .execute(&mut conn)
+            .await?;
+        }
+
+        Ok(entries)
+    }
+}
+
+async fn run_writer(db: PgPool, room_id: Uuid, mut rx: watch::Receiver<Vec<QueueRow>>) {
+    // exits once the room is gone, but still writes the last change
+    while rx.changed().await.is_ok() {
+        tokio::time::sleep(SAVE_DELAY).await;
+
+        let rows = rx.borrow().clone();
+
+        if let Err(e) = write_queue(&db, room_id, &rows).await {
+            error!("failed to save queue: {}", e);
+        }
+    }
+}
+
+async fn write_queue(db: &PgPool, room_id: Uuid, rows: &[QueueRow]) -> sqlx::Result<()> {
+    let mut tx = db.begin().await?;
+
+    // language=SQL
+    sqlx::query!("DELETE FROM room_queue WHERE room_id = $1", room_id)
+        .execute(&mut tx)
+        .await?;
+
+    for (position, row) in rows.iter().enumerate() {
+        sqlx::query!(
+            // language=SQL
+            "INSERT INTO room_queue (room_id, position, track_id, requested_by, added_at) \
+             VALUES ($1, $2, $3, $4, $5)",
+            room_id,
+            position as i32,
+            row.track_id,
+            row.requested_by.as_deref(),
+            row.added_at,
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
@@ -0,0 +1,103 @@
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use log::warn;
+use sqlx::PgPool;
+use url::Url;
+use youtube_dl::YoutubeDlOutput;
+
+use crate::commands::autoplay::find_similar_tracks;
+use crate::db::entity::import::{self, ImportError};
+use crate::db::entity::track::Source;
+use crate::db::entity::Track;
+
+/// Finds tracks to keep playing with once a room runs out of them.
+#[async_trait]
+pub trait RadioSource: Debug + Send + Sync {
+    /// Returns up to `limit` tracks that go well after `seed`. The tracks
+    /// are saved to the database.
+    async fn related(&self, seed: &Track, limit: usize) -> Result<Vec<Track>, ImportError>;
+}
+
+/// Picks tracks from the database which share the genre or an artist with
+/// the seed.
+#[derive(Debug, Clone)]
+pub struct DbRadio {
+    db: PgPool,
+}
+
+impl DbRadio {
+    pub fn new(db: PgPool) -> Self {
+        DbRadio { db }
+    }
+}
+
+#[async_trait]
+impl RadioSource for DbRadio {
+    async fn related(&self, seed: &Track, limit: usize) -> Result<Vec<Track>, ImportError> {
+        let mut db = self.db.acquire().await?;
+        let mut tracks = find_similar_tracks(seed, &mut *db).await?;
+        tracks.truncate(limit);
+        Ok(tracks)
+    }
+}
+
+/// Imports tracks from the mix YouTube generates for the seed's video.
+#[derive(Debug, Clone)]
+pub struct YoutubeRadio {
+    db: PgPool,
+}
+
+impl YoutubeRadio {
+    pub fn new(db: PgPool) -> Self {
+        YoutubeRadio { db }
+    }
+}
+
+#[async_trait]
+impl RadioSource for YoutubeRadio {
+    async fn related(&self, seed: &Track, limit: usize) -> Result<Vec<Track>, ImportError> {
+        let id = match seed.providers().iter().find_map(|p| match p.source() {
+            Source::Youtube(id) => Some(id),
+            _ => None,
+        }) {
+            None => return Ok(Vec::new()),
+            Some(id) => id,
+        };
+
+        let list = format!("RD{}", id);
+        let url = Url::parse_with_params(
+            "https://www.youtube.com/watch",
+            [("v", id.as_str()), ("list", list.as_str())],
+        )?;
+
+        let mut ytdl = youtube_dl::YoutubeDl::new(url.into_string());
+        ytdl.flat_playlist(true);
+
+        let videos = match import::run_youtube_dl(&ytdl).await? {
+            YoutubeDlOutput::Playlist(pl) => pl.entries.unwrap_or_default(),
+            YoutubeDlOutput::SingleVideo(_) => Vec::new(),
+        };
+
+        let mut db = self.db.acquire().await?;
+        let mut tracks = Vec::new();
+
+        // the mix starts with the seed itself
+        for video in videos.iter().filter(|v| &v.id != id) {
+            if tracks.len() >= limit {
+                break;
+            }
+
+            let mut track = Track::import_from_youtube(video, Some(&mut *db)).await?;
+
+            if let Err(e) = track.save(&mut *db).await {
+                warn!("failed to save {}, skipping: {}", video.id, e);
+                continue;
+            }
+
+            tracks.push(track);
+        }
+
+        Ok(tracks)
+    }
+}
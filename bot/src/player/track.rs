@@ -3,32 +3,86 @@ use std::io;
 use std::path::{Path, PathBuf};
 use std::process::ExitStatus;
 
-use crate::db::entity::track::{Source, TrackProvider};
+use async_trait::async_trait;
+use log::warn;
 use thiserror::Error;
 use tokio::process::Command;
 use url::Url;
 use uuid::Uuid;
 
-impl TrackProvider {
-    pub async fn media_path(&self) -> Result<Cow<'_, Path>, GetFileError> {
-        match &self.source() {
-            Source::Local(pb) => Ok(pb.into()),
-            Source::Url(url) => media_path_url(&self.id(), url).await.map(|v| v.into()),
-            Source::Spotify(id) => {
-                todo!()
+use crate::db::entity::track::{Provider, Source};
+use crate::player::media::{MediaCache, CACHE_DIR};
+
+impl Provider {
+    /// Returns the media file to play this provider from, downloading it
+    /// into the cache first if needed. Downloaded files are registered with
+    /// `cache`.
+    pub async fn media_path(&self, cache: &MediaCache) -> Result<Cow<'_, Path>, GetFileError> {
+        let path = match self.source() {
+            Source::Local(pb) => return Ok(pb.into()),
+            Source::Url(url) => media_path_url(&self.id(), url).await?,
+            Source::Spotify(_) => return Err(GetFileError::Unsupported("spotify")),
+            Source::Youtube(id) => {
+                media_path_url(
+                    &self.id(),
+                    &Url::parse(&format!("https://www.youtube.com/watch?v={}", id)).unwrap(),
+                )
+                .await?
             }
-            Source::Youtube(id) => media_path_url(
-                &self.id(),
-                &Url::parse(&format!("https://www.youtube.com/watch?v={}", id)).unwrap(),
-            )
-            .await
-            .map(|v| v.into()),
+        };
+
+        // the file is there either way, so this isn't worth failing over
+        if let Err(e) = cache.register(&path).await {
+            warn!("failed to register {} in cache: {}", path.display(), e);
+        }
+
+        if let Err(e) = cache.evict(&path).await {
+            warn!("failed to clean up the media cache: {}", e);
+        }
+
+        Ok(path.into())
+    }
+}
+
+/// Something that can produce a local media file to play.
+#[async_trait]
+pub trait MediaProvider {
+    async fn fetch_media(&self, cache: &MediaCache) -> Result<PathBuf, GetFileError>;
+}
+
+#[async_trait]
+impl MediaProvider for Provider {
+    async fn fetch_media(&self, cache: &MediaCache) -> Result<PathBuf, GetFileError> {
+        self.media_path(cache).await.map(Cow::into_owned)
+    }
+}
+
+/// Tries each provider in order and returns the first media file that could
+/// be fetched. If all of them fail, the error of the last one is returned.
+pub async fn resolve_media_path<P>(
+    providers: &[P],
+    cache: &MediaCache,
+) -> Result<PathBuf, TrackError>
+where
+    P: MediaProvider + Sync,
+{
+    let mut last_error = None;
+
+    for provider in providers {
+        match provider.fetch_media(cache).await {
+            Ok(path) => return Ok(path),
+            Err(e) => last_error = Some(e),
         }
     }
+
+    match last_error {
+        None => Err(TrackError::NoProviders),
+        Some(e) => Err(TrackError::Media(e)),
+    }
 }
 
 async fn media_path_url(id: &Uuid, url: &Url) -> Result<PathBuf, GetFileError> {
-    let mut path = PathBuf::from("media/cached");
+    let mut path = PathBuf::from(CACHE_DIR);
     let mut buffer = Uuid::encode_buffer();
     let id = id.to_simple_ref().encode_upper(&mut buffer);
     path.push(&id[..2]);
@@ -48,6 +102,18 @@ pub enum GetFileError {
     Io(#[from] io::Error),
     #[error("youtube-dl error {0}")]
     ExitStatus(ExitStatus),
+    #[error("playing from {0} providers is not supported")]
+    Unsupported(&'static str),
+}
+
+#[derive(Debug, Error)]
+pub enum TrackError {
+    #[error("track has no providers")]
+    NoProviders,
+    #[error("failed to get media file: {0}")]
+    Media(#[from] GetFileError),
+    #[error("failed to start player: {0}")]
+    Player(#[from] player2x::ffplayer::Error),
 }
 
 async fn youtube_dl<P>(url: &Url, output: P) -> Result<(), GetFileError>
@@ -58,9 +124,70 @@ where
     let mut cmd = Command::new("youtube-dl");
     cmd.arg("-x").arg("--audio-format").arg("flac");
     cmd.arg("-o").arg(output.as_ref()).arg(url.as_str());
+    // the download gets aborted if the track is skipped while loading
+    cmd.kill_on_drop(true);
     match cmd.status().await {
         Ok(st) if st.success() => Ok(()),
         Ok(st) => Err(GetFileError::ExitStatus(st)),
         Err(e) => Err(e.into()),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::path::PathBuf;
+
+    use async_trait::async_trait;
+    use sqlx::PgPool;
+
+    use super::{resolve_media_path, GetFileError, MediaProvider, TrackError};
+    use crate::player::media::MediaCache;
+
+    enum MockProvider {
+        Ok(&'static str),
+        Err,
+    }
+
+    #[async_trait]
+    impl MediaProvider for MockProvider {
+        async fn fetch_media(&self, _cache: &MediaCache) -> Result<PathBuf, GetFileError> {
+            match self {
+                MockProvider::Ok(path) => Ok(PathBuf::from(path)),
+                MockProvider::Err => Err(io::Error::new(io::ErrorKind::NotFound, "gone").into()),
+            }
+        }
+    }
+
+    fn cache() -> MediaCache {
+        // never used by the mock providers, so it doesn't need a database
+        MediaCache::new(
+            PgPool::connect_lazy("postgres://localhost/r2dj").unwrap(),
+            u64::MAX,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_fallback() {
+        let providers = [MockProvider::Err, MockProvider::Ok("b.flac")];
+        let path = resolve_media_path(&providers, &cache()).await.unwrap();
+        assert_eq!(PathBuf::from("b.flac"), path);
+    }
+
+    #[tokio::test]
+    async fn test_all_failed() {
+        let providers = [MockProvider::Err, MockProvider::Err];
+        let result = resolve_media_path(&providers, &cache()).await;
+        assert!(matches!(
+            result,
+            Err(TrackError::Media(GetFileError::Io(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_no_providers() {
+        let providers: [MockProvider; 0] = [];
+        let result = resolve_media_path(&providers, &cache()).await;
+        assert!(matches!(result, Err(TrackError::NoProviders)));
+    }
+}
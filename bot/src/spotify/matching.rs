@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+/// Differences in duration up to this are not counted, since sites round
+/// durations differently and videos often have a bit of silence added.
+const DURATION_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// A search result that could be used to play a track.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Candidate {
+    pub id: String,
+    pub title: String,
+    pub duration: Option<Duration>,
+}
+
+impl Candidate {
+    /// How far this candidate's duration is off from `duration`, if it is
+    /// known.
+    pub fn duration_diff(&self, duration: Duration) -> Option<Duration> {
+        self.duration.map(|d| {
+            if d > duration {
+                d - duration
+            } else {
+                duration - d
+            }
+        })
+    }
+}
+
+/// Picks the candidate whose duration is closest to `duration`. Candidates
+/// are expected in the order the search returned them, which decides between
+/// candidates that are equally close. Candidates with unknown duration are
+/// only picked if no candidate has a known duration.
+pub fn best_match(candidates: &[Candidate], duration: Duration) -> Option<&Candidate> {
+    candidates
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, c)| {
+            let diff = c.duration_diff(duration)?;
+            Some((diff.saturating_sub(DURATION_TOLERANCE), idx, c))
+        })
+        .min_by_key(|&(diff, idx, _)| (diff, idx))
+        .map(|(_, _, c)| c)
+        .or_else(|| candidates.first())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{best_match, Candidate};
+
+    fn candidates(list: &[(&str, Option<u64>)]) -> Vec<Candidate> {
+        list.iter()
+            .map(|&(id, secs)| Candidate {
+                id: id.to_string(),
+                title: format!("video {}", id),
+                duration: secs.map(Duration::from_secs),
+            })
+            .collect()
+    }
+
+    fn best(list: &[(&str, Option<u64>)], secs: u64) -> Option<String> {
+        best_match(&candidates(list), Duration::from_secs(secs)).map(|c| c.id.clone())
+    }
+
+    #[test]
+    fn test_closest_duration() {
+        // music video with a long intro, lyrics video, live version
+        let list = [
+            ("mv", Some(272)),
+            ("lyrics", Some(214)),
+            ("live", Some(250)),
+        ];
+
+        assert_eq!(Some("lyrics".to_string()), best(&list, 213));
+    }
+
+    #[test]
+    fn test_tolerance_keeps_search_order() {
+        let list = [("a", Some(215)), ("b", Some(213)), ("c", Some(400))];
+
+        // both a and b are within tolerance, so the first one wins
+        assert_eq!(Some("a".to_string()), best(&list, 213));
+        assert_eq!(Some("b".to_string()), best(&list, 210));
+    }
+
+    #[test]
+    fn test_unknown_duration() {
+        let list = [("a", None), ("b", Some(600))];
+        assert_eq!(Some("b".to_string()), best(&list, 180));
+
+        let list = [("a", None), ("b", None)];
+        assert_eq!(Some("a".to_string()), best(&list, 180));
+
+        assert_eq!(None, best(&[], 180));
+    }
+}
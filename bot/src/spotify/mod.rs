@@ -1 +1,115 @@
-use librespot;
+use std::time::Duration;
+
+use futures::future::try_join_all;
+use librespot::core::authentication::Credentials;
+use librespot::core::config::SessionConfig;
+use librespot::core::session::{Session, SessionError};
+use librespot::core::spotify_id::SpotifyId;
+use librespot::metadata::{Artist, Metadata, Track};
+use thiserror::Error;
+use url::Url;
+
+pub use matching::{best_match, Candidate};
+
+mod matching;
+
+/// A connection to Spotify, used to look up track metadata.
+#[derive(Clone)]
+pub struct SpotifyClient {
+    session: Session,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackInfo {
+    pub title: String,
+    pub artists: Vec<String>,
+    pub duration: Duration,
+}
+
+impl SpotifyClient {
+    pub async fn connect(username: &str, password: &str) -> Result<Self, SpotifyError> {
+        let credentials = Credentials::with_password(username, password);
+        let session = Session::connect(SessionConfig::default(), credentials, None).await?;
+
+        Ok(SpotifyClient { session })
+    }
+
+    /// Fetches title, artists and duration of the track with the given
+    /// base62 ID.
+    pub async fn track_info(&self, id: &str) -> Result<TrackInfo, SpotifyError> {
+        let id = SpotifyId::from_base62(id).map_err(|_| SpotifyError::InvalidId(id.to_string()))?;
+
+        let track = Track::get(&self.session, id)
+            .await
+            .map_err(|_| SpotifyError::Metadata)?;
+
+        let artists = try_join_all(
+            track
+                .artists
+                .iter()
+                .map(|&artist| Artist::get(&self.session, artist)),
+        )
+        .await
+        .map_err(|_| SpotifyError::Metadata)?;
+
+        Ok(TrackInfo {
+            title: track.name,
+            artists: artists.into_iter().map(|a| a.name).collect(),
+            duration: Duration::from_millis(track.duration.max(0) as u64),
+        })
+    }
+}
+
+/// Extracts the track ID from a Spotify track link, either of the form
+/// `https://open.spotify.com/track/<id>` or `spotify:track:<id>`.
+pub fn parse_track_url(url: &Url) -> Option<&str> {
+    let id = match url.scheme() {
+        "spotify" => url.path().strip_prefix("track:")?,
+        "http" | "https" if url.domain() == Some("open.spotify.com") => {
+            url.path().strip_prefix("/track/")?
+        }
+        _ => return None,
+    };
+
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+
+    Some(id)
+}
+
+#[derive(Debug, Error)]
+pub enum SpotifyError {
+    #[error("failed to connect to Spotify: {0}")]
+    Session(#[from] SessionError),
+    #[error("invalid Spotify ID: {0}")]
+    InvalidId(String),
+    #[error("failed to fetch metadata from Spotify")]
+    Metadata,
+}
+
+#[cfg(test)]
+mod test {
+    use url::Url;
+
+    use super::parse_track_url;
+
+    #[test]
+    fn test_parse_track_url() {
+        let parse = |s: &str| parse_track_url(&Url::parse(s).unwrap()).map(str::to_string);
+
+        assert_eq!(
+            Some("4uLU6hMCjMI75M1A2tKUQC".to_string()),
+            parse("https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC?si=abc")
+        );
+        assert_eq!(
+            Some("4uLU6hMCjMI75M1A2tKUQC".to_string()),
+            parse("spotify:track:4uLU6hMCjMI75M1A2tKUQC")
+        );
+        assert_eq!(
+            None,
+            parse("https://open.spotify.com/album/4uLU6hMCjMI75M1A2tKUQC")
+        );
+        assert_eq!(None, parse("https://www.youtube.com/watch?v=dQw4w9WgXcQ"));
+    }
+}
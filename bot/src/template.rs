@@ -0,0 +1,201 @@
+use std::fmt::{Display, Formatter, Write};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// A text with placeholders such as `{title}`, used for the bot's status
+/// comment. Placeholders are checked when parsing, so rendering can't fail.
+/// Literal braces are written as `{{` and `}}`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Part {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Placeholder {
+    Title,
+    Album,
+    Artist,
+    /// The playback position.
+    Position,
+    /// The length of the track.
+    Duration,
+    /// Whether the track is playing or paused.
+    State,
+    /// A transient message such as an error, on its own line, or nothing if
+    /// there is none.
+    Status,
+    /// The name and version of the bot.
+    Version,
+}
+
+impl Placeholder {
+    const ALL: &'static [Placeholder] = &[
+        Placeholder::Title,
+        Placeholder::Album,
+        Placeholder::Artist,
+        Placeholder::Position,
+        Placeholder::Duration,
+        Placeholder::State,
+        Placeholder::Status,
+        Placeholder::Version,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Placeholder::Title => "title",
+            Placeholder::Album => "album",
+            Placeholder::Artist => "artist",
+            Placeholder::Position => "position",
+            Placeholder::Duration => "duration",
+            Placeholder::State => "state",
+            Placeholder::Status => "status",
+            Placeholder::Version => "version",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Placeholder::ALL.iter().copied().find(|p| p.name() == name)
+    }
+}
+
+impl Template {
+    /// Replaces each placeholder with the value returned by `value` for it.
+    pub fn render<F, S>(&self, mut value: F) -> String
+    where
+        F: FnMut(Placeholder) -> S,
+        S: Display,
+    {
+        let mut s = String::new();
+
+        for part in self.parts.iter() {
+            match part {
+                Part::Text(text) => s.push_str(text),
+                Part::Placeholder(p) => write!(s, "{}", value(*p)).unwrap(),
+            }
+        }
+
+        s
+    }
+
+    /// Returns how often `placeholder` appears in the template.
+    pub fn count(&self, placeholder: Placeholder) -> usize {
+        self.parts
+            .iter()
+            .filter(|part| **part == Part::Placeholder(placeholder))
+            .count()
+    }
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let (name, rest) = chars
+                        .as_str()
+                        .split_once('}')
+                        .ok_or(TemplateError::Unclosed)?;
+                    let p = Placeholder::from_name(name)
+                        .ok_or_else(|| TemplateError::UnknownPlaceholder(name.to_string()))?;
+
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+
+                    parts.push(Part::Placeholder(p));
+                    chars = rest.chars();
+                }
+                '}' => return Err(TemplateError::Unmatched),
+                c => text.push(c),
+            }
+        }
+
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        Ok(Template { parts })
+    }
+}
+
+impl Display for Template {
+    /// Formats the template in the syntax it is parsed from.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for part in self.parts.iter() {
+            match part {
+                Part::Text(text) => f.write_str(&text.replace('{', "{{").replace('}', "}}"))?,
+                Part::Placeholder(p) => write!(f, "{{{}}}", p.name())?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TemplateError {
+    #[error("unknown placeholder '{{{0}}}'")]
+    UnknownPlaceholder(String),
+    #[error("unclosed '{{'")]
+    Unclosed,
+    #[error("unmatched '}}', use '}}}}' for a literal one")]
+    Unmatched,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Placeholder, Template, TemplateError};
+
+    #[test]
+    fn test_render() {
+        let template: Template = "{title} by {artist} {{{state}}}".parse().unwrap();
+
+        let text = template.render(|p| match p {
+            Placeholder::Title => "Drive",
+            Placeholder::Artist => "Someone",
+            _ => p.name(),
+        });
+
+        assert_eq!("Drive by Someone {state}", text);
+        assert_eq!(1, template.count(Placeholder::Title));
+        assert_eq!(0, template.count(Placeholder::Album));
+        assert_eq!("{title} by {artist} {{{state}}}", template.to_string());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(matches!(
+            "{titel}".parse::<Template>(),
+            Err(TemplateError::UnknownPlaceholder(name)) if name == "titel"
+        ));
+        assert!(matches!(
+            "{title".parse::<Template>(),
+            Err(TemplateError::Unclosed)
+        ));
+        assert!(matches!(
+            "title}".parse::<Template>(),
+            Err(TemplateError::Unmatched)
+        ));
+    }
+}
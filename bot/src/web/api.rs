@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+use hyper::{Body, Request, Response};
+use serde::Serialize;
+use sqlx::postgres::PgArguments;
+use sqlx::{Arguments, PgConnection};
+use uuid::Uuid;
+
+use crate::web::{json, session, WebState};
+use crate::Result;
+
+#[derive(Debug, Clone, Serialize)]
+struct QueueItem {
+    code: Option<String>,
+    title: Option<String>,
+    /// The names of the track's artists, comma separated.
+    artist: Option<String>,
+    /// The length of the track, known once it was played.
+    duration_secs: Option<f64>,
+}
+
+/// `GET /queue`: the tracks in the room's queue, the next one first.
+pub async fn queue(state: &WebState, req: &Request<Body>) -> Result<Response<Body>> {
+    session(state, req).await?;
+
+    let tracks = state.room.proxy().queue_contents().await?;
+    let ids: Vec<_> = tracks.iter().filter_map(|t| t.object().id()).collect();
+
+    let mut db = state.db.acquire().await?;
+    let details = track_details(&ids, &mut *db).await?;
+
+    let items: Vec<_> = tracks
+        .iter()
+        .map(|t| {
+            let (artist, duration_secs) = t
+                .object()
+                .id()
+                .and_then(|id| details.get(&id).cloned())
+                .unwrap_or_default();
+
+            QueueItem {
+                code: t.object().code().map(str::to_string),
+                title: t.title().map(str::to_string),
+                artist,
+                duration_secs,
+            }
+        })
+        .collect();
+
+    Ok(json(&items))
+}
+
+/// Loads the artists and the length of each of the tracks with the given
+/// IDs, which the track entity doesn't have.
+async fn track_details(
+    ids: &[Uuid],
+    db: &mut PgConnection,
+) -> sqlx::Result<HashMap<Uuid, (Option<String>, Option<f64>)>> {
+    let mut args = PgArguments::default();
+    args.add(ids);
+
+    let rows: Vec<(Uuid, Option<String>, Option<f64>)> = sqlx::query_as_with(
+        // language=SQL
+        "SELECT t.id, \
+                (SELECT string_agg(a.name, ', ' ORDER BY a.name) \
+                 FROM track_artist ta JOIN artist a ON a.id = ta.artist \
+                 WHERE ta.track = t.id), \
+                (SELECT h.duration_secs FROM play_history h \
+                 WHERE h.track_id = t.id ORDER BY h.played_at DESC LIMIT 1) \
+         FROM track t WHERE t.id = ANY($1)",
+        args,
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(id, artist, duration)| (id, (artist, duration)))
+        .collect())
+}
@@ -0,0 +1,128 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::header::{HeaderMap, CONTENT_TYPE, COOKIE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::auth::{AuthService, Session};
+use crate::player::Room;
+use crate::{Error, Result};
+
+mod api;
+
+/// The cookie holding the ID of the user's session, see
+/// [`AuthService::create_session`]. This is the session cookie the API
+/// requires. It is an opaque ID that is looked up in the database instead of
+/// a JWT, so that logging out or deleting a session takes effect right away.
+const SESSION_COOKIE: &str = "r2dj_session";
+
+/// What the request handlers have access to.
+#[derive(Clone)]
+pub struct WebState {
+    pub room: Arc<Room>,
+    pub auth: AuthService,
+    pub db: PgPool,
+}
+
+/// Serves the web interface on `addr`. Only returns if the server fails.
+pub async fn serve(addr: SocketAddr, state: WebState) -> hyper::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    info!("web interface listening on {}", addr);
+
+    server.await
+}
+
+async fn handle(state: WebState, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let result = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/queue") => api::queue(&state, &req).await,
+        _ => Ok(status(StatusCode::NOT_FOUND)),
+    };
+
+    let response = match result {
+        Ok(response) => response,
+        Err(Error::PermissionDenied) => status(StatusCode::UNAUTHORIZED),
+        Err(e) => {
+            error!("{} {} failed: {}", req.method(), req.uri().path(), e);
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    };
+
+    Ok(response)
+}
+
+/// Returns the session of the user who sent `req`. Fails with
+/// [`Error::PermissionDenied`] if they aren't logged in.
+async fn session(state: &WebState, req: &Request<Body>) -> Result<Session> {
+    let id = session_id(req.headers()).ok_or(Error::PermissionDenied)?;
+
+    state
+        .auth
+        .validate_session(id)
+        .await?
+        .ok_or(Error::PermissionDenied)
+}
+
+/// Reads the session ID from the cookies in `headers`.
+fn session_id(headers: &HeaderMap) -> Option<Uuid> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .and_then(|(_, value)| Uuid::parse_str(value).ok())
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Body> {
+    // the response types only contain strings and numbers
+    let body = serde_json::to_vec(value).expect("failed to serialize response");
+
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.into())
+        .unwrap()
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::header::{HeaderMap, HeaderValue, COOKIE};
+    use uuid::Uuid;
+
+    use super::session_id;
+
+    #[test]
+    fn test_session_id() {
+        let id = Uuid::new_v4();
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, session_id(&headers));
+
+        headers.append(COOKIE, HeaderValue::from_static("theme=dark"));
+        let cookie = format!("lang=en; r2dj_session={}", id);
+        headers.append(COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        assert_eq!(Some(id), session_id(&headers));
+
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, HeaderValue::from_static("r2dj_session=garbage"));
+        assert_eq!(None, session_id(&headers));
+    }
+}
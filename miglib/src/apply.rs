@@ -0,0 +1,436 @@
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::TryStreamExt;
+use log::{debug, info, trace, warn};
+use sqlx::{Connection, Executor, PgConnection};
+use uuid::Uuid;
+
+use crate::{lock, unlock, Error, Migration, Result};
+
+const INIT_SQL: &str = include_str!("init.sql");
+
+/// Changes to the table created by [`INIT_SQL`], in the order they were made.
+/// Since there is nothing keeping track of which of these have been run, each
+/// of them must do nothing if it has been run before.
+const META_MIGRATIONS: &[&str] = &[include_str!("checksum.sql"), include_str!("baselined.sql")];
+
+/// Creates the table that keeps track of applied migrations, if it doesn't
+/// exist yet, and brings it up to date.
+pub async fn init(db: &mut PgConnection) -> Result {
+    for sql in std::iter::once(&INIT_SQL).chain(META_MIGRATIONS) {
+        trace!("=> {}", sql);
+        db.execute(*sql).await?;
+    }
+
+    Ok(())
+}
+
+/// Returns the IDs of the migrations applied to the database, in the order
+/// they were applied in.
+pub async fn applied(db: &mut PgConnection) -> Result<Vec<Uuid>> {
+    // language=SQL
+    let applied = sqlx::query_scalar("SELECT id FROM __migtool_meta ORDER BY (run_at, id) ASC")
+        .fetch_all(db)
+        .await?;
+
+    Ok(applied)
+}
+
+/// A migration that has been applied to the database, see [`applied_at`].
+#[derive(Debug, Clone)]
+pub struct AppliedMigration {
+    pub id: Uuid,
+    pub run_at: Option<DateTime<Utc>>,
+    /// The checksum of the migration's apply.sql when it was applied, see
+    /// [`Migration::checksum`]. Not known for migrations applied before
+    /// checksums were recorded.
+    pub checksum: Option<Vec<u8>>,
+    /// Whether the migration was only recorded as applied with [`baseline`]
+    /// and its SQL was never run.
+    pub baselined: bool,
+}
+
+impl AppliedMigration {
+    /// Returns whether the apply.sql of `migration`, which has to be the one
+    /// that was applied, has changed since. If the checksum isn't known,
+    /// there is no way to tell, so this returns false.
+    pub fn is_modified(&self, migration: &Migration) -> bool {
+        match &self.checksum {
+            None => false,
+            Some(checksum) => *checksum != migration.checksum(),
+        }
+    }
+}
+
+/// Like [`applied`], but also returns when and how each migration was
+/// applied and its checksum.
+pub async fn applied_at(db: &mut PgConnection) -> Result<Vec<AppliedMigration>> {
+    // language=SQL
+    let applied: Vec<(Uuid, Option<NaiveDateTime>, Option<Vec<u8>>, bool)> = sqlx::query_as(
+        "SELECT id, run_at, checksum, baselined FROM __migtool_meta ORDER BY (run_at, id) ASC",
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(applied
+        .into_iter()
+        .map(|(id, run_at, checksum, baselined)| AppliedMigration {
+            id,
+            // run_at is stored as UTC without a time zone
+            run_at: run_at.map(|t| DateTime::from_utc(t, Utc)),
+            checksum,
+            baselined,
+        })
+        .collect())
+}
+
+/// Whether a migration has been applied, see [`status`].
+#[derive(Debug, Clone)]
+pub enum MigrationStatus<'a> {
+    Applied(&'a Migration, Option<DateTime<Utc>>),
+    /// The migration has been applied, but its apply.sql has changed since.
+    Modified(&'a Migration, Option<DateTime<Utc>>),
+    /// The migration has been recorded as applied with [`baseline`], without
+    /// running it.
+    Baselined(&'a Migration, Option<DateTime<Utc>>),
+    Pending(&'a Migration),
+    /// The migration with this ID has been applied to the database, but
+    /// isn't one of the available migrations.
+    Missing(Uuid, Option<DateTime<Utc>>),
+}
+
+/// Returns the status of each migration in `available`, in the same order,
+/// followed by the applied migrations that aren't available.
+pub fn status<'a>(
+    available: &'a [Migration],
+    applied: &[AppliedMigration],
+) -> Vec<MigrationStatus<'a>> {
+    let mut status: Vec<_> = available
+        .iter()
+        .map(|m| match applied.iter().find(|a| a.id == m.id()) {
+            None => MigrationStatus::Pending(m),
+            Some(a) if a.is_modified(m) => MigrationStatus::Modified(m, a.run_at),
+            Some(a) if a.baselined => MigrationStatus::Baselined(m, a.run_at),
+            Some(a) => MigrationStatus::Applied(m, a.run_at),
+        })
+        .collect();
+
+    status.extend(
+        applied
+            .iter()
+            .filter(|a| !available.iter().any(|m| m.id() == a.id))
+            .map(|a| MigrationStatus::Missing(a.id, a.run_at)),
+    );
+
+    status
+}
+
+/// Returns the migrations in `available` which have been applied, but whose
+/// apply.sql has changed since.
+pub fn modified<'a>(
+    available: &'a [Migration],
+    applied: &[AppliedMigration],
+) -> Vec<&'a Migration> {
+    available
+        .iter()
+        .filter(|m| applied.iter().any(|a| a.id == m.id() && a.is_modified(m)))
+        .collect()
+}
+
+/// Returns the migrations in `available` which haven't been applied yet.
+///
+/// Fails if a migration has been applied that isn't in `available`, since
+/// then the database is in a state that the available migrations might not
+/// apply to.
+pub fn pending<'a>(available: &'a [Migration], applied: &[Uuid]) -> Result<Vec<&'a Migration>> {
+    if let Some(&unknown) = applied
+        .iter()
+        .find(|&&id| !available.iter().any(|m| m.id() == id))
+    {
+        return Err(Error::UnknownMigration(unknown));
+    }
+
+    Ok(available
+        .iter()
+        .filter(|m| !applied.contains(&m.id()))
+        .collect())
+}
+
+/// What running a migration did, see [`run`].
+#[derive(Debug, Clone, Default)]
+pub struct RunStats {
+    /// How long it took to run the migration and record it.
+    pub duration: Duration,
+    /// How many statements the migration's SQL consists of.
+    pub statements: usize,
+    /// How many rows the statements inserted, changed or deleted together.
+    pub rows_affected: u64,
+}
+
+/// Applies or unapplies `migration` and records that in the database. Runs in
+/// a transaction of its own, so a migration that fails leaves no changes
+/// behind. Returns how long that took and what the migration changed.
+pub async fn run(db: &mut PgConnection, migration: &Migration, unapply: bool) -> Result<RunStats> {
+    let fail = |e| Error::Migration(migration.display_name().into_owned(), e);
+
+    let src = if !unapply {
+        migration.apply_sql()
+    } else {
+        migration.unapply_sql()
+    };
+
+    let start = Instant::now();
+    let mut stats = RunStats::default();
+    let mut ta = db.begin().await?;
+
+    debug!("=> {}", src.replace('\n', "\n.. "));
+
+    {
+        // each statement reports its result once it is done
+        let mut results = ta.fetch_many(src);
+        let mut last = start;
+
+        while let Some(step) = results.try_next().await.map_err(fail)? {
+            if let Some(done) = step.left() {
+                let now = Instant::now();
+                stats.statements += 1;
+                stats.rows_affected += done.rows_affected();
+                trace!(
+                    "   statement {} done in {:.1?}, {} rows affected",
+                    stats.statements,
+                    now - last,
+                    done.rows_affected()
+                );
+                last = now;
+            }
+        }
+    }
+
+    // language=SQL
+    let meta = if !unapply {
+        "INSERT INTO __migtool_meta (id, checksum) VALUES ($1, $2)"
+    } else {
+        "DELETE FROM __migtool_meta WHERE id = $1"
+    };
+
+    trace!("=> {}", meta);
+    let mut query = sqlx::query(meta).bind(migration.id());
+
+    if !unapply {
+        query = query.bind(migration.checksum().to_vec());
+    }
+
+    query.execute(&mut ta).await.map_err(fail)?;
+
+    ta.commit().await?;
+
+    stats.duration = start.elapsed();
+
+    Ok(stats)
+}
+
+/// Records `migration` as applied without running it, for databases that
+/// already are in the state it leads to, for example because they were
+/// created before there were migrations.
+pub async fn baseline(db: &mut PgConnection, migration: &Migration) -> Result {
+    // language=SQL
+    let meta = "INSERT INTO __migtool_meta (id, checksum, baselined) VALUES ($1, $2, true)";
+
+    trace!("=> {}", meta);
+    sqlx::query(meta)
+        .bind(migration.id())
+        .bind(migration.checksum().to_vec())
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Returns the SQL that records in the database that `migration` has been
+/// applied or unapplied, the same way [`run`] does, for running it some other
+/// way.
+pub fn record_sql(migration: &Migration, unapply: bool) -> String {
+    let id = migration.id().to_hyphenated();
+
+    if !unapply {
+        let checksum: String = migration
+            .checksum()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        format!(
+            "INSERT INTO __migtool_meta (id, checksum) VALUES ('{}', '\\x{}');",
+            id, checksum
+        )
+    } else {
+        format!("DELETE FROM __migtool_meta WHERE id = '{}';", id)
+    }
+}
+
+/// Applies all migrations in `available` that haven't been applied to the
+/// database yet, see [`pending`]. Either all of them are applied or none.
+/// Waits for other processes running migrations to finish first, see
+/// [`lock`]. Returns how many were applied.
+pub async fn apply_pending(db: &mut PgConnection, available: &[Migration]) -> Result<usize> {
+    lock(db, None).await?;
+    let result = apply_pending_locked(db, available).await;
+    unlock(db).await?;
+    result
+}
+
+async fn apply_pending_locked(db: &mut PgConnection, available: &[Migration]) -> Result<usize> {
+    let mut ta = db.begin().await?;
+
+    init(&mut ta).await?;
+    let applied = applied_at(&mut ta).await?;
+
+    for migration in modified(available, &applied) {
+        warn!(
+            "Migration {} has been modified since it was applied",
+            migration.display_name()
+        );
+    }
+
+    let ids: Vec<_> = applied.iter().map(|a| a.id).collect();
+    let pending = pending(available, &ids)?;
+
+    for &migration in pending.iter() {
+        info!("Applying migration {}", migration.display_name());
+        let stats = run(&mut ta, migration, false).await?;
+        debug!(
+            "Applied migration {} in {:.1?}, {} rows affected",
+            migration.display_name(),
+            stats.duration,
+            stats.rows_affected
+        );
+    }
+
+    ta.commit().await?;
+
+    Ok(pending.len())
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use super::{modified, pending, record_sql, status, AppliedMigration, MigrationStatus};
+    use crate::{Error, Migration};
+
+    fn migrations() -> Vec<Migration> {
+        vec![
+            Migration::embedded("20210327155155-initial", 1, 1616860315, None, "", ""),
+            Migration::embedded("20210901210618-second", 2, 1630530378, None, "", ""),
+            Migration::embedded("20210901223937-third", 3, 1630535977, None, "", ""),
+        ]
+    }
+
+    #[test]
+    fn test_pending() {
+        let available = migrations();
+
+        let ids = |v: Vec<&Migration>| v.iter().map(|m| m.id()).collect::<Vec<_>>();
+
+        let all = pending(&available, &[]).unwrap();
+        assert_eq!(
+            vec![Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3)],
+            ids(all)
+        );
+
+        let rest = pending(&available, &[Uuid::from_u128(1)]).unwrap();
+        assert_eq!(vec![Uuid::from_u128(2), Uuid::from_u128(3)], ids(rest));
+
+        let applied: Vec<_> = available.iter().map(|m| m.id()).collect();
+        assert!(pending(&available, &applied).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unknown_applied() {
+        let available = migrations();
+        let applied = [Uuid::from_u128(1), Uuid::from_u128(4)];
+
+        assert!(matches!(
+            pending(&available, &applied),
+            Err(Error::UnknownMigration(id)) if id == Uuid::from_u128(4)
+        ));
+    }
+
+    fn applied(id: u128, checksum: Option<Vec<u8>>) -> AppliedMigration {
+        AppliedMigration {
+            id: Uuid::from_u128(id),
+            run_at: None,
+            checksum,
+            baselined: false,
+        }
+    }
+
+    #[test]
+    fn test_status() {
+        let available = migrations();
+        let applied = [applied(1, None), applied(4, None)];
+
+        let status = status(&available, &applied);
+        assert_eq!(4, status.len());
+        assert!(
+            matches!(status[0], MigrationStatus::Applied(m, _) if m.id() == Uuid::from_u128(1))
+        );
+        assert!(matches!(status[1], MigrationStatus::Pending(m) if m.id() == Uuid::from_u128(2)));
+        assert!(matches!(status[2], MigrationStatus::Pending(m) if m.id() == Uuid::from_u128(3)));
+        assert!(matches!(status[3], MigrationStatus::Missing(id, _) if id == Uuid::from_u128(4)));
+    }
+
+    #[test]
+    fn test_record_sql() {
+        let m = &migrations()[0];
+
+        assert_eq!(
+            "INSERT INTO __migtool_meta (id, checksum) VALUES \
+             ('00000000-0000-0000-0000-000000000001', \
+             '\\xe3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855');",
+            record_sql(m, false)
+        );
+        assert_eq!(
+            "DELETE FROM __migtool_meta WHERE id = '00000000-0000-0000-0000-000000000001';",
+            record_sql(m, true)
+        );
+    }
+
+    #[test]
+    fn test_modified() {
+        let available = migrations();
+        let unchanged = available[0].checksum().to_vec();
+        let applied = [
+            applied(1, Some(unchanged)),
+            applied(2, Some(vec![0; 32])),
+            applied(3, None),
+        ];
+
+        let modified = modified(&available, &applied);
+        assert_eq!(1, modified.len());
+        assert_eq!(Uuid::from_u128(2), modified[0].id());
+
+        let status = status(&available, &applied);
+        assert!(matches!(status[0], MigrationStatus::Applied(..)));
+        assert!(matches!(status[1], MigrationStatus::Modified(..)));
+        assert!(matches!(status[2], MigrationStatus::Applied(..)));
+    }
+
+    #[test]
+    fn test_baselined() {
+        let available = migrations();
+        let applied = [
+            AppliedMigration {
+                baselined: true,
+                ..applied(1, None)
+            },
+            applied(2, None),
+        ];
+
+        let status = status(&available, &applied);
+        assert!(matches!(status[0], MigrationStatus::Baselined(..)));
+        assert!(matches!(status[1], MigrationStatus::Applied(..)));
+        assert!(matches!(status[2], MigrationStatus::Pending(..)));
+    }
+}
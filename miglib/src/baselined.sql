@@ -0,0 +1,2 @@
+ALTER TABLE __migtool_meta
+    ADD COLUMN IF NOT EXISTS baselined boolean NOT NULL DEFAULT false;
\ No newline at end of file
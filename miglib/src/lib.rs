@@ -0,0 +1,41 @@
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+use uuid::Uuid;
+
+pub use crate::apply::{
+    applied, applied_at, apply_pending, baseline, init, modified, pending, record_sql, run, status,
+    AppliedMigration, MigrationStatus, RunStats,
+};
+pub use crate::lock::{lock, unlock};
+pub use crate::migration::{load_dir, load_migration, sort, Migration};
+
+mod apply;
+mod lock;
+mod migration;
+
+pub type Result<T = (), E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("failed to read {}: {1}", .0.display())]
+    Io(PathBuf, #[source] io::Error),
+    #[error("invalid migration properties in {}: {1}", .0.display())]
+    Props(PathBuf, String),
+    #[error("{0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error(
+        "migration {} has been applied to the database, but is not known to this version",
+        .0.to_simple()
+    )]
+    UnknownMigration(Uuid),
+    #[error("migration {0} depends on {}, which doesn't exist", .1.to_simple())]
+    UnknownDependency(String, Uuid),
+    #[error("migrations can't be ordered, their dependencies form a cycle: {}", .0.join(", "))]
+    DependencyCycle(Vec<String>),
+    #[error("failed to run migration {0}: {1}")]
+    Migration(String, #[source] sqlx::Error),
+    #[error("another migration is in progress")]
+    Locked,
+}
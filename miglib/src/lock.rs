@@ -0,0 +1,134 @@
+use std::time::Duration;
+
+use log::debug;
+use sqlx::PgConnection;
+use tokio::time::{sleep, Instant};
+
+use crate::{Error, Result};
+
+/// Key of the advisory lock held while migrations run, "migtool" in ASCII.
+const LOCK_KEY: i64 = 0x006d_6967_746f_6f6c;
+
+/// How often to try again while waiting for the lock.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Takes the lock that keeps several processes from running migrations at
+/// the same time. Waits for as long as `wait` for another process to
+/// release it, or forever if it is `None`.
+///
+/// The lock belongs to the connection, not a transaction, so it has to be
+/// taken before starting one. It is released with [`unlock`] or when the
+/// connection closes.
+pub async fn lock(db: &mut PgConnection, wait: Option<Duration>) -> Result {
+    let wait = match wait {
+        None => {
+            // language=SQL
+            sqlx::query("SELECT pg_advisory_lock($1)")
+                .bind(LOCK_KEY)
+                .execute(db)
+                .await?;
+            return Ok(());
+        }
+        Some(v) => v,
+    };
+
+    let deadline = Instant::now() + wait;
+
+    loop {
+        // language=SQL
+        let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_lock($1)")
+            .bind(LOCK_KEY)
+            .fetch_one(&mut *db)
+            .await?;
+
+        if locked {
+            return Ok(());
+        }
+
+        if Instant::now() + RETRY_INTERVAL > deadline {
+            return Err(Error::Locked);
+        }
+
+        debug!("Waiting for another migration run to finish");
+        sleep(RETRY_INTERVAL).await;
+    }
+}
+
+/// Releases the lock taken with [`lock`].
+pub async fn unlock(db: &mut PgConnection) -> Result {
+    // language=SQL
+    sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(LOCK_KEY)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use sqlx::{Connection, PgConnection};
+
+    use super::{lock, unlock};
+    use crate::{Error, Migration};
+
+    async fn connect() -> PgConnection {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL not set");
+        PgConnection::connect(&url).await.unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database in DATABASE_URL"]
+    async fn test_lock() {
+        let mut a = connect().await;
+        let mut b = connect().await;
+
+        lock(&mut a, None).await.unwrap();
+        assert!(matches!(
+            lock(&mut b, Some(Duration::from_millis(300))).await,
+            Err(Error::Locked)
+        ));
+
+        unlock(&mut a).await.unwrap();
+        lock(&mut b, Some(Duration::ZERO)).await.unwrap();
+        unlock(&mut b).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database in DATABASE_URL"]
+    async fn test_concurrent_apply() {
+        let mut a = connect().await;
+        let mut b = connect().await;
+
+        let migrations = [Migration::embedded(
+            "20211220000000-concurrent-apply-test",
+            0x6c6f_636b_7465_7374,
+            1639958400,
+            None,
+            "CREATE TABLE __migtool_lock_test (id INTEGER);",
+            "DROP TABLE __migtool_lock_test;",
+        )];
+
+        // both wait for the lock, so the second one sees the migration
+        // already applied by the first one
+        let (ra, rb) = tokio::join!(
+            crate::apply_pending(&mut a, &migrations),
+            crate::apply_pending(&mut b, &migrations)
+        );
+
+        let mut applied = [ra.unwrap(), rb.unwrap()];
+        applied.sort_unstable();
+        assert_eq!([0, 1], applied);
+
+        // language=SQL
+        let rows: i64 = sqlx::query_scalar("SELECT count(*) FROM __migtool_meta WHERE id = $1")
+            .bind(migrations[0].id())
+            .fetch_one(&mut a)
+            .await
+            .unwrap();
+        assert_eq!(1, rows);
+
+        crate::run(&mut a, &migrations[0], true).await.unwrap();
+    }
+}
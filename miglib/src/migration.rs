@@ -0,0 +1,303 @@
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use cmdparser::{CommandDispatcher, ExecSource, SimpleExecutor};
+use petgraph::algo::tarjan_scc;
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::Direction;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{Error, Result};
+
+#[derive(Debug, Clone)]
+pub struct Migration {
+    root: PathBuf,
+    id: Uuid,
+    date: DateTime<Utc>,
+    name: Option<String>,
+    depends: Vec<Uuid>,
+    apply_sql: Cow<'static, str>,
+    unapply_sql: Cow<'static, str>,
+}
+
+impl Migration {
+    /// Creates a migration from data compiled into the binary. `root` is the
+    /// name of the directory it was loaded from and `date` is a Unix
+    /// timestamp. Its dependencies aren't kept, embedded migrations are
+    /// expected to be in the order [`load_dir`] returned them in already.
+    pub fn embedded(
+        root: &'static str,
+        id: u128,
+        date: i64,
+        name: Option<&'static str>,
+        apply_sql: &'static str,
+        unapply_sql: &'static str,
+    ) -> Self {
+        Migration {
+            root: PathBuf::from(root),
+            id: Uuid::from_u128(id),
+            date: from_timestamp(date),
+            name: name.map(str::to_string),
+            depends: Vec::new(),
+            apply_sql: Cow::Borrowed(apply_sql),
+            unapply_sql: Cow::Borrowed(unapply_sql),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn date(&self) -> DateTime<Utc> {
+        self.date
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the friendly name of the migration, or the directory name if
+    /// it doesn't have one.
+    pub fn display_name(&self) -> Cow<str> {
+        self.name
+            .as_deref()
+            .map(Cow::Borrowed)
+            .unwrap_or_else(|| self.root.to_string_lossy())
+    }
+
+    /// Returns the IDs of the migrations which have to be applied before
+    /// this one, regardless of their date. These are declared in `_props`
+    /// with `depends`, which takes several IDs, or `requires`, which takes
+    /// one.
+    pub fn depends(&self) -> &[Uuid] {
+        &self.depends
+    }
+
+    pub fn apply_sql(&self) -> &str {
+        &self.apply_sql
+    }
+
+    pub fn unapply_sql(&self) -> &str {
+        &self.unapply_sql
+    }
+
+    /// Returns the SHA-256 checksum of [`apply_sql`](Self::apply_sql), which
+    /// is recorded when the migration is applied to detect later changes.
+    pub fn checksum(&self) -> [u8; 32] {
+        Sha256::digest(self.apply_sql.as_bytes()).into()
+    }
+}
+
+/// Loads all migrations from `dir`, sorted by date and dependencies, see
+/// [`sort`].
+pub fn load_dir(dir: &Path) -> Result<Vec<Migration>> {
+    let mut available = Vec::new();
+
+    for entry in fs::read_dir(dir).map_err(|e| Error::Io(dir.to_path_buf(), e))? {
+        let path = entry.map_err(|e| Error::Io(dir.to_path_buf(), e))?.path();
+
+        if path.is_dir() {
+            available.push(load_migration(path)?);
+        }
+    }
+
+    sort(available)
+}
+
+/// Sorts migrations by date, except that each one comes after the ones it
+/// depends on. Fails if a dependency is not in `migrations` or if
+/// dependencies form a cycle.
+pub fn sort(mut migrations: Vec<Migration>) -> Result<Vec<Migration>> {
+    migrations.sort_by(|a, b| a.date.cmp(&b.date));
+
+    // node i is migrations[i], with an edge to each migration depending on it
+    let mut graph = DiGraph::<(), ()>::with_capacity(migrations.len(), 0);
+
+    for _ in migrations.iter() {
+        graph.add_node(());
+    }
+
+    for (idx, m) in migrations.iter().enumerate() {
+        for &dep in m.depends.iter() {
+            let dep_idx = migrations
+                .iter()
+                .position(|o| o.id == dep)
+                .ok_or_else(|| Error::UnknownDependency(m.display_name().to_string(), dep))?;
+            graph.add_edge(NodeIndex::new(dep_idx), NodeIndex::new(idx), ());
+        }
+    }
+
+    if let Some(cycle) = tarjan_scc(&graph)
+        .into_iter()
+        .find(|scc| scc.len() > 1 || graph.contains_edge(scc[0], scc[0]))
+    {
+        let names = cycle
+            .iter()
+            .map(|n| migrations[n.index()].display_name().to_string())
+            .collect();
+        return Err(Error::DependencyCycle(names));
+    }
+
+    let mut waiting_for: Vec<usize> = graph
+        .node_indices()
+        .map(|n| graph.neighbors_directed(n, Direction::Incoming).count())
+        .collect();
+    let mut order = Vec::with_capacity(migrations.len());
+
+    while order.len() < migrations.len() {
+        // the oldest migration whose dependencies have all been placed,
+        // which exists since there are no cycles
+        let next = (0..migrations.len())
+            .find(|&i| waiting_for[i] == 0 && !order.contains(&i))
+            .unwrap();
+
+        order.push(next);
+
+        for dependent in graph.neighbors(NodeIndex::new(next)) {
+            waiting_for[dependent.index()] -= 1;
+        }
+    }
+
+    let mut migrations: Vec<_> = migrations.into_iter().map(Some).collect();
+
+    Ok(order
+        .into_iter()
+        .map(|i| migrations[i].take().unwrap())
+        .collect())
+}
+
+/// Loads the migration in the directory `root`.
+pub fn load_migration(root: PathBuf) -> Result<Migration> {
+    let props = root.join("_props");
+
+    let mut id = None;
+    let mut date = None;
+    let mut name = None;
+    let mut depends = Vec::new();
+    let mut error = None;
+
+    let mut cd = CommandDispatcher::new(SimpleExecutor::new(|cmd, args| match cmd {
+        "id" => match Uuid::parse_str(args[0]) {
+            Ok(v) => id = Some(v),
+            Err(e) => error = Some(format!("invalid id: {}", e)),
+        },
+        "date" => match args[0].parse() {
+            Ok(v) => date = Some(from_timestamp(v)),
+            Err(e) => error = Some(format!("invalid date: {}", e)),
+        },
+        "name" => name = Some(args[0].to_string()),
+        "depends" if args.is_empty() => error = Some("depends needs at least one id".to_string()),
+        "depends" => {
+            for arg in args {
+                match Uuid::parse_str(arg) {
+                    Ok(v) => depends.push(v),
+                    Err(e) => error = Some(format!("invalid dependency id: {}", e)),
+                }
+            }
+        }
+        // like depends, but with one id per line
+        "requires" if args.len() != 1 => error = Some("requires needs exactly one id".to_string()),
+        "requires" => match Uuid::parse_str(args[0]) {
+            Ok(v) => depends.push(v),
+            Err(e) => error = Some(format!("invalid dependency id: {}", e)),
+        },
+        _ => {}
+    }));
+    cd.scheduler()
+        .exec_path(&props, ExecSource::Other)
+        .map_err(|e| Error::Props(props.clone(), e.to_string()))?;
+    cd.resume_until_empty();
+    drop(cd);
+
+    if let Some(error) = error {
+        return Err(Error::Props(props, error));
+    }
+
+    let id = id.ok_or_else(|| Error::Props(props.clone(), "id not specified".to_string()))?;
+    let date = date.ok_or_else(|| Error::Props(props.clone(), "date not specified".to_string()))?;
+
+    let read = |file: &str| {
+        let path = root.join(file);
+        fs::read_to_string(&path).map_err(|e| Error::Io(path, e))
+    };
+
+    let apply_sql = read("apply.sql")?;
+    let unapply_sql = read("unapply.sql")?;
+
+    Ok(Migration {
+        root,
+        id,
+        date,
+        name,
+        depends,
+        apply_sql: Cow::Owned(apply_sql),
+        unapply_sql: Cow::Owned(unapply_sql),
+    })
+}
+
+fn from_timestamp(secs: i64) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&NaiveDateTime::from_timestamp(secs, 0))
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use super::{sort, Migration};
+    use crate::Error;
+
+    fn migration(id: u128, date: i64, depends: &[u128]) -> Migration {
+        let mut m = Migration::embedded("m", id, date, None, "", "");
+        m.depends = depends.iter().copied().map(Uuid::from_u128).collect();
+        m
+    }
+
+    fn ids(migrations: &[Migration]) -> Vec<u128> {
+        migrations.iter().map(|m| m.id().as_u128()).collect()
+    }
+
+    #[test]
+    fn test_sort() {
+        let sorted = sort(vec![
+            migration(3, 30, &[]),
+            migration(1, 10, &[]),
+            migration(2, 20, &[]),
+        ])
+        .unwrap();
+        assert_eq!(vec![1, 2, 3], ids(&sorted));
+
+        // 2 was created on another branch before 3, which it depends on
+        let sorted = sort(vec![
+            migration(1, 10, &[]),
+            migration(2, 20, &[3]),
+            migration(3, 30, &[1]),
+            migration(4, 40, &[]),
+        ])
+        .unwrap();
+        assert_eq!(vec![1, 3, 2, 4], ids(&sorted));
+    }
+
+    #[test]
+    fn test_sort_invalid() {
+        // 3 can't be ordered either, but isn't part of the cycle
+        assert!(matches!(
+            sort(vec![migration(1, 10, &[2]), migration(2, 20, &[1]), migration(3, 30, &[1])]),
+            Err(Error::DependencyCycle(names)) if names.len() == 2
+        ));
+        assert!(matches!(
+            sort(vec![migration(1, 10, &[1])]),
+            Err(Error::DependencyCycle(names)) if names.len() == 1
+        ));
+        assert!(matches!(
+            sort(vec![migration(1, 10, &[5])]),
+            Err(Error::UnknownDependency(_, id)) if id == Uuid::from_u128(5)
+        ));
+    }
+}
@@ -0,0 +1,10 @@
+CREATE TABLE session
+(
+    id                 uuid        NOT NULL,
+    registered_user_id bigint      NOT NULL,
+    created            timestamptz NOT NULL DEFAULT now(),
+    expires            timestamptz NOT NULL,
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX session_expires_idx ON session (expires);
@@ -0,0 +1,10 @@
+CREATE TABLE room_queue
+(
+    room_id      uuid        NOT NULL,
+    position     integer     NOT NULL,
+    track_id     uuid        NOT NULL,
+    requested_by text,
+    added_at     timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (room_id, position),
+    FOREIGN KEY (track_id) REFERENCES track (id) ON DELETE CASCADE
+);
\ No newline at end of file
@@ -0,0 +1 @@
+DROP TABLE room_queue;
\ No newline at end of file
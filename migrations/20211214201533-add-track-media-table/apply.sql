@@ -0,0 +1,9 @@
+CREATE TABLE track_media
+(
+    path     text   NOT NULL,
+    checksum bytea  NOT NULL,
+    size     bigint NOT NULL,
+    PRIMARY KEY (path)
+);
+
+CREATE INDEX track_media_checksum_idx ON track_media (checksum);
\ No newline at end of file
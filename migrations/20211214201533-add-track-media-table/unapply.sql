@@ -0,0 +1 @@
+DROP TABLE track_media;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- 0 = flatten, 1 = round robin
+ALTER TABLE playlist
+    ADD COLUMN nesting_mode smallint NOT NULL DEFAULT 0;
\ No newline at end of file
@@ -0,0 +1,2 @@
+ALTER TABLE playlist
+    DROP COLUMN nesting_mode;
\ No newline at end of file
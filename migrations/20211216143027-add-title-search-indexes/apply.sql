@@ -0,0 +1,5 @@
+CREATE INDEX track_title_search_idx ON track
+    USING gin (to_tsvector('simple', coalesce(title, '')));
+
+CREATE INDEX playlist_title_search_idx ON playlist
+    USING gin (to_tsvector('simple', title));
\ No newline at end of file
@@ -0,0 +1,3 @@
+DROP INDEX playlist_title_search_idx;
+
+DROP INDEX track_title_search_idx;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- in dB, applied when playing the track
+ALTER TABLE track
+    ADD COLUMN gain_db double precision;
\ No newline at end of file
@@ -0,0 +1,2 @@
+ALTER TABLE track
+    DROP COLUMN gain_db;
\ No newline at end of file
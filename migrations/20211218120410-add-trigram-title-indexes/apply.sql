@@ -0,0 +1,7 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX track_title_trgm_idx ON track
+    USING gin (title gin_trgm_ops);
+
+CREATE INDEX playlist_title_trgm_idx ON playlist
+    USING gin (title gin_trgm_ops);
\ No newline at end of file
@@ -0,0 +1,5 @@
+DROP INDEX playlist_title_trgm_idx;
+
+DROP INDEX track_title_trgm_idx;
+
+-- pg_trgm is left installed, it might have been there before this migration
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- providers that existed before this are left without a date
+ALTER TABLE track_provider
+    ADD COLUMN created timestamptz;
\ No newline at end of file
@@ -0,0 +1,2 @@
+ALTER TABLE track_provider
+    DROP COLUMN created;
\ No newline at end of file
@@ -0,0 +1,12 @@
+CREATE TABLE play_history
+(
+    id            uuid             NOT NULL,
+    room_id       uuid             NOT NULL,
+    track_id      uuid             NOT NULL,
+    played_at     timestamptz      NOT NULL DEFAULT now(),
+    duration_secs double precision NOT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (track_id) REFERENCES track (id) ON DELETE CASCADE
+);
+
+CREATE INDEX play_history_track_id_idx ON play_history (track_id);
\ No newline at end of file
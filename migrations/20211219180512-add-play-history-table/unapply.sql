@@ -0,0 +1 @@
+DROP TABLE play_history;
\ No newline at end of file
@@ -0,0 +1,18 @@
+CREATE TABLE tag
+(
+    id   uuid NOT NULL,
+    name text NOT NULL,
+    PRIMARY KEY (id),
+    UNIQUE (name)
+);
+
+CREATE TABLE track_tag
+(
+    track uuid NOT NULL,
+    tag   uuid NOT NULL,
+    PRIMARY KEY (track, tag),
+    FOREIGN KEY (track) REFERENCES track (id) ON DELETE CASCADE,
+    FOREIGN KEY (tag) REFERENCES tag (id) ON DELETE CASCADE
+);
+
+CREATE INDEX track_tag_tag_idx ON track_tag (tag);
\ No newline at end of file
@@ -0,0 +1,2 @@
+DROP TABLE track_tag;
+DROP TABLE tag;
\ No newline at end of file
@@ -1,72 +1,381 @@
-use std::borrow::Cow;
+use std::cmp::min;
 use std::ffi::OsStr;
-use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
 
 use anyhow::bail;
-use chrono::{TimeZone, Utc};
-use cmdparser::{CommandDispatcher, ExecSource, SimpleExecutor};
-use sqlx::postgres::PgRow;
-use sqlx::prelude::*;
-use sqlx::types::chrono::{DateTime, NaiveDateTime};
-use sqlx::{Execute, PgConnection, Postgres, Transaction};
-use std::cmp::min;
-use tokio::fs;
-use tokio::io;
-use tokio::stream::StreamExt;
+use log::{log_enabled, Level};
+use miglib::{AppliedMigration, Migration, RunStats};
 use uuid::Uuid;
 
+use crate::db::{self, Db};
+
+/// Which of the migrations that can be applied or unapplied to run.
 pub enum ApplyBehavior<'a> {
     All,
     Count(usize),
+    /// Everything before the migration in this directory. When unapplying,
+    /// that is everything applied after it, so it stays applied.
     Until(&'a str),
+    /// Like `Until`, but including the migration in this directory.
+    Through(&'a str),
 }
 
 pub async fn apply_migration(
     db_url: &str,
-    v: u64,
     b: &ApplyBehavior<'_>,
     dir: &Path,
     unapply: bool,
     pretend: bool,
+    plan: Option<&Path>,
+    strict: bool,
+    force: bool,
+    wait: Duration,
 ) -> anyhow::Result<()> {
-    let db: PgConnection = PgConnection::connect(db_url).await.unwrap();
-
-    let mut available: Vec<Migration> = fs::read_dir(dir)
-        .await?
-        .filter_map(|entry| {
-            let entry = entry.unwrap();
-            let p = entry.path();
-            if p.is_dir() {
-                let mig = load_migration(p).unwrap();
-                Some(mig)
-            } else {
-                None
-            }
-        })
-        .collect()
-        .await;
+    let mut db = db::connect(db_url).await?;
+
+    let available = miglib::load_dir(dir)?;
+
+    lock(&mut *db, wait).await?;
+    db.begin().await?;
+
+    let applied = applied(&mut *db, &available, strict).await?;
+    let queue = resolve_queue(&available, &ids(&applied), unapply);
+
+    let queue = select(&queue, &available, b, unapply)?;
+
+    if unapply {
+        check_baselined(queue, &applied, force)?;
+    }
+
+    if let Some(plan) = plan {
+        // nothing that was run to find out what to apply is kept
+        db.rollback().await?;
+        write_plan(plan, queue, unapply, &*db)?;
+        db.unlock().await?;
+        return Ok(());
+    }
+
+    let ran = run_queue(&mut *db, queue, unapply).await?;
+    print_summary(&ran);
+
+    if !pretend {
+        db.commit().await?;
+    } else {
+        db.rollback().await?;
+    }
+
+    db.unlock().await?;
+
+    Ok(())
+}
+
+/// Unapplies the last `count` applied migrations and applies them again, all
+/// in one transaction.
+pub async fn redo_migrations(
+    db_url: &str,
+    count: usize,
+    dir: &Path,
+    pretend: bool,
+    strict: bool,
+    force: bool,
+    wait: Duration,
+) -> anyhow::Result<()> {
+    let mut db = db::connect(db_url).await?;
+
+    let available = miglib::load_dir(dir)?;
+
+    lock(&mut *db, wait).await?;
+    db.begin().await?;
+
+    let applied = applied(&mut *db, &available, strict).await?;
+    let queue = resolve_queue(&available, &ids(&applied), true);
+
+    if queue.len() < count {
+        bail!(
+            "Can not redo {} migrations, only {} can be unapplied!",
+            count,
+            queue.len()
+        );
+    }
+
+    let queue = &queue[..count];
+
+    check_baselined(queue, &applied, force)?;
+
+    // unapply.sql has to exist, but might not have been filled in
+    if let Some(m) = queue.iter().find(|m| m.unapply_sql().trim().is_empty()) {
+        bail!(
+            "Migration {} has an empty unapply.sql, can not redo it!",
+            m.display_name()
+        );
+    }
+
+    let mut ran = run_queue(&mut *db, queue, true).await?;
+
+    let reapply: Vec<_> = queue.iter().rev().copied().collect();
+    ran.extend(run_queue(&mut *db, &reapply, false).await?);
+    print_summary(&ran);
+
+    if !pretend {
+        db.commit().await?;
+    } else {
+        db.rollback().await?;
+    }
+
+    db.unlock().await?;
+
+    Ok(())
+}
+
+/// Records the pending migrations selected by `b` as applied without running
+/// them.
+pub async fn baseline_migrations(
+    db_url: &str,
+    b: &ApplyBehavior<'_>,
+    dir: &Path,
+    pretend: bool,
+    strict: bool,
+    wait: Duration,
+) -> anyhow::Result<()> {
+    let mut db = db::connect(db_url).await?;
+
+    let available = miglib::load_dir(dir)?;
+
+    lock(&mut *db, wait).await?;
+    db.begin().await?;
+
+    let applied = applied(&mut *db, &available, strict).await?;
+    let queue = resolve_queue(&available, &ids(&applied), false);
+
+    let queue = select(&queue, &available, b, false)?;
+
+    for &item in queue {
+        println!("Baselining migration {}", item.display_name());
+        db.baseline(item).await?;
+    }
 
-    available.sort_unstable_by(|a, b| a.date.cmp(&b.date));
+    if !pretend {
+        db.commit().await?;
+    } else {
+        db.rollback().await?;
+    }
+
+    db.unlock().await?;
+
+    Ok(())
+}
+
+/// Takes the migration lock, so that only one process runs migrations at a
+/// time. It is released with the connection if a run fails.
+async fn lock(db: &mut dyn Db, wait: Duration) -> anyhow::Result<()> {
+    match db.lock(wait).await {
+        Err(miglib::Error::Locked) if wait.is_zero() => {
+            bail!("Another migration is in progress! Use --wait to wait for it to finish.")
+        }
+        Err(miglib::Error::Locked) => {
+            bail!(
+                "Another migration is still in progress after waiting {}s!",
+                wait.as_secs()
+            )
+        }
+        r => Ok(r?),
+    }
+}
+
+/// Brings the migration table up to date and returns the applied
+/// migrations, after checking them against `available`.
+async fn applied(
+    db: &mut dyn Db,
+    available: &[Migration],
+    strict: bool,
+) -> anyhow::Result<Vec<AppliedMigration>> {
+    db.init().await?;
+    let applied = db.applied_at().await?;
+    check_modified(available, &applied, strict)?;
+
+    Ok(applied)
+}
 
-    let root_ta = db.begin().await?;
+fn ids(applied: &[AppliedMigration]) -> Vec<Uuid> {
+    applied.iter().map(|a| a.id).collect()
+}
+
+/// Returns the part of `queue`, as returned by [`resolve_queue`], selected
+/// by `b`.
+fn select<'a, 'b>(
+    queue: &'b [&'a Migration],
+    available: &[Migration],
+    b: &ApplyBehavior<'_>,
+    unapply: bool,
+) -> anyhow::Result<&'b [&'a Migration]> {
+    let (dir, inclusive) = match *b {
+        ApplyBehavior::All => return Ok(queue),
+        ApplyBehavior::Count(c) => return Ok(&queue[..min(c, queue.len())]),
+        ApplyBehavior::Until(dir) => (dir, false),
+        ApplyBehavior::Through(dir) => (dir, true),
+    };
 
-    let mut ta = root_ta.begin().await?;
-    do_exec(&mut ta, include_str!("init.sql"), v >= 2).await?;
-    let mut root_ta = ta.commit().await?;
+    let is_dir = |m: &Migration| OsStr::new(dir) == m.root().as_os_str();
 
-    let applied: Vec<Uuid> = sqlx::query("SELECT id FROM __migtool_meta ORDER BY (run_at, id) ASC")
-        .map(|row: PgRow| row.get::<Uuid, _>(0))
-        .fetch(&mut root_ta)
-        .fold(Ok(Vec::new()), |acc, a| match (acc, a) {
-            (Ok(mut acc), Ok(a)) => {
-                acc.push(a);
-                Ok(acc)
+    match queue.iter().position(|m| is_dir(m)) {
+        Some(idx) if inclusive => Ok(&queue[..idx + 1]),
+        Some(idx) => Ok(&queue[..idx]),
+        None if !available.iter().any(is_dir) => bail!("Migration {} not available!", dir),
+        None if !unapply => bail!("Migration {} is already applied!", dir),
+        None => bail!("Migration {} is not applied!", dir),
+    }
+}
+
+/// Fails if a migration in `queue` was baselined, since its unapply.sql
+/// would undo changes that weren't made by applying it, unless `force` is
+/// set.
+fn check_baselined(
+    queue: &[&Migration],
+    applied: &[AppliedMigration],
+    force: bool,
+) -> anyhow::Result<()> {
+    let baselined = queue
+        .iter()
+        .find(|m| applied.iter().any(|a| a.id == m.id() && a.baselined));
+
+    match baselined {
+        Some(m) if !force => bail!(
+            "Migration {} was baselined, not applied! Use --force to unapply it anyway.",
+            m.display_name()
+        ),
+        Some(m) => {
+            eprintln!(
+                "warning: Unapplying baselined migration {}",
+                m.display_name()
+            );
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// A migration that was applied or unapplied by [`run_queue`].
+struct Ran<'a> {
+    migration: &'a Migration,
+    unapply: bool,
+    stats: RunStats,
+}
+
+async fn run_queue<'a>(
+    db: &mut dyn Db,
+    queue: &[&'a Migration],
+    unapply: bool,
+) -> anyhow::Result<Vec<Ran<'a>>> {
+    let mut ran = Vec::new();
+
+    for &item in queue {
+        let name = item.display_name();
+        if !unapply {
+            print!("Applying migration {} ... ", name);
+        } else {
+            print!("Unapplying migration {} ... ", name);
+        }
+
+        // the statements are logged in between at higher verbosity
+        if log_enabled!(Level::Debug) {
+            println!();
+        } else {
+            io::stdout().flush()?;
+        }
+
+        let stats = match db.run(item, unapply).await {
+            Ok(stats) => stats,
+            Err(e) => {
+                println!("failed");
+                bail!("Failed to run migration: {}", e);
             }
-            (Ok(_), Err(a)) => Err(a),
-            (x @ Err(_), _) => x,
-        })
-        .await?;
+        };
+
+        println!(
+            "done in {:.1}s ({} statements, {} rows affected)",
+            stats.duration.as_secs_f64(),
+            stats.statements,
+            format_count(stats.rows_affected)
+        );
+
+        ran.push(Ran {
+            migration: item,
+            unapply,
+            stats,
+        });
+    }
+
+    Ok(ran)
+}
+
+/// Prints a table of the migrations in `ran` and what running them took.
+fn print_summary(ran: &[Ran]) {
+    if ran.is_empty() {
+        return;
+    }
+
+    let name_width = ran
+        .iter()
+        .map(|r| r.migration.display_name().len())
+        .max()
+        .unwrap_or(0);
+
+    println!();
+    println!(
+        "{:9} {:name_width$} {:>8} {:>10} {:>6}",
+        "",
+        "MIGRATION",
+        "TIME",
+        "STATEMENTS",
+        "ROWS",
+        name_width = name_width
+    );
+
+    for r in ran {
+        println!(
+            "{:9} {:name_width$} {:>7.1}s {:>10} {:>6}",
+            if !r.unapply { "applied" } else { "unapplied" },
+            r.migration.display_name(),
+            r.stats.duration.as_secs_f64(),
+            r.stats.statements,
+            format_count(r.stats.rows_affected),
+            name_width = name_width
+        );
+    }
 
+    let unapplied = ran.iter().filter(|r| r.unapply).count();
+    let total: Duration = ran.iter().map(|r| r.stats.duration).sum();
+    let rows: u64 = ran.iter().map(|r| r.stats.rows_affected).sum();
+
+    println!();
+    println!(
+        "{} applied, {} unapplied in {:.1}s, {} rows affected",
+        ran.len() - unapplied,
+        unapplied,
+        total.as_secs_f64(),
+        format_count(rows)
+    );
+}
+
+/// Shortens large row counts, so that 120345 becomes 120k.
+fn format_count(n: u64) -> String {
+    match n {
+        0..=9_999 => n.to_string(),
+        10_000..=9_999_999 => format!("{}k", n / 1_000),
+        _ => format!("{}M", n / 1_000_000),
+    }
+}
+
+/// Returns the migrations that can be applied, or unapplied in the order
+/// they need to be unapplied in, given the migrations that are `applied`.
+fn resolve_queue<'a>(
+    available: &'a [Migration],
+    applied: &[Uuid],
+    unapply: bool,
+) -> Vec<&'a Migration> {
     let mut queue = Vec::new();
 
     {
@@ -92,7 +401,7 @@ pub async fn apply_migration(
                 }
                 break;
             }
-            if available[i_avail].uuid != applied[i_applied] {
+            if available[i_avail].id() != applied[i_applied] {
                 eprintln!("warning: No migration definition or unexpected order for migration {}! Can not unapply.", applied[i_applied].to_simple());
                 if unapply {
                     queue.clear();
@@ -111,156 +420,138 @@ pub async fn apply_migration(
         queue.reverse();
     }
 
-    let queue = match b {
-        ApplyBehavior::Count(c) => &queue[..min(*c, queue.len())],
-        ApplyBehavior::Until(e) => {
-            let idx = queue
-                .iter()
-                .enumerate()
-                .filter(|(_, m)| OsStr::new(e) == m.root.as_os_str())
-                .map(|(idx, _)| idx)
-                .next();
-            match idx {
-                None => bail!("Migration {} not available!", e),
-                Some(idx) => &queue[..idx],
-            }
-        }
-        ApplyBehavior::All => &queue,
+    queue
+}
+
+/// Writes the SQL for running the migrations in `queue` on `db` to `path`, or
+/// to stdout if `path` is `-`, in a single transaction like a real run.
+fn write_plan(path: &Path, queue: &[&Migration], unapply: bool, db: &dyn Db) -> anyhow::Result<()> {
+    let mut out: Box<dyn Write> = if path == Path::new("-") {
+        Box::new(io::stdout())
+    } else {
+        Box::new(File::create(path)?)
     };
 
-    for &item in queue {
-        let name = item
-            .name
-            .as_deref()
-            .map(Cow::Borrowed)
-            .unwrap_or_else(|| item.root.to_string_lossy());
-        if !unapply {
-            println!("Applying migration {}", name);
+    writeln!(out, "BEGIN;")?;
+
+    for &m in queue {
+        let src = if !unapply {
+            m.apply_sql()
         } else {
-            println!("Unapplying migration {}", name);
-        }
-        match run_migration(item, root_ta, unapply, v).await {
-            Err(e) => {
-                bail!("Failed to run migration: {}", e);
-            }
-            Ok(a) => root_ta = a,
-        }
-    }
+            m.unapply_sql()
+        };
 
-    if !pretend {
-        root_ta.commit().await?;
+        writeln!(out)?;
+        writeln!(
+            out,
+            "-- {} migration {}",
+            if !unapply { "Apply" } else { "Unapply" },
+            m.display_name()
+        )?;
+        writeln!(out, "-- id: {}", m.id().to_simple())?;
+        writeln!(out, "{}", src.trim_end())?;
+        writeln!(out, "{}", db.record_sql(m, unapply))?;
     }
 
+    writeln!(out)?;
+    writeln!(out, "COMMIT;")?;
+
     Ok(())
 }
 
-async fn run_migration(
-    migration: &Migration,
-    db: Transaction<PgConnection>,
-    unapply: bool,
-    v: u64,
-) -> anyhow::Result<Transaction<PgConnection>> {
-    let src = if !unapply {
-        migration.apply_source().await?
-    } else {
-        migration.unapply_source().await?
-    };
+/// Warns about applied migrations that have been modified since, or fails if
+/// `strict` is set.
+pub fn check_modified(
+    available: &[Migration],
+    applied: &[AppliedMigration],
+    strict: bool,
+) -> anyhow::Result<()> {
+    let modified = miglib::modified(available, applied);
 
-    let name = migration
-        .name
-        .as_ref()
-        .map(|s| s.as_str().into())
-        .unwrap_or_else(|| migration.root.to_string_lossy());
-
-    let mut ta = db.begin().await?;
-    do_exec(&mut ta, src.as_str(), v >= 1).await?;
-    if !unapply {
-        do_exec(
-            &mut ta,
-            sqlx::query("INSERT INTO __migtool_meta (id) VALUES ($1)").bind(&migration.uuid),
-            v >= 2,
-        )
-        .await?;
-    } else {
-        do_exec(
-            &mut ta,
-            sqlx::query("DELETE FROM __migtool_meta WHERE id = $1").bind(&migration.uuid),
-            v >= 2,
-        )
-        .await?;
+    for m in modified.iter() {
+        eprintln!(
+            "warning: Migration {} has been modified since it was applied!",
+            m.display_name()
+        );
+    }
+
+    if strict && !modified.is_empty() {
+        bail!("{} applied migrations have been modified", modified.len());
     }
-    let db = ta.commit().await?;
 
-    Ok(db)
+    Ok(())
 }
 
-async fn do_exec(
-    mut db: impl Executor<Database = Postgres>,
-    q: impl Execute<'_, Postgres>,
-    verbose: bool,
-) -> sqlx::Result<u64> {
-    if verbose {
-        println!("=> {}", q.query_string().replace('\n', "\n.. "));
+#[cfg(test)]
+mod test {
+    use miglib::Migration;
+
+    use super::{format_count, select, ApplyBehavior};
+
+    fn migrations() -> Vec<Migration> {
+        vec![
+            Migration::embedded("20210327155155-initial", 1, 1616860315, None, "", ""),
+            Migration::embedded("20210901210618-second", 2, 1630530378, None, "", ""),
+            Migration::embedded("20210901223937-third", 3, 1630535977, None, "", ""),
+        ]
     }
-    let f = db.execute(q);
-    match f.await as sqlx::Result<u64> {
-        Ok(rows) => {
-            if verbose {
-                println!("{} rows affected.\n", rows);
-            }
-            Ok(rows)
-        }
-        Err(e) => {
-            eprintln!("{}", e);
-            Err(e)
-        }
+
+    fn dirs(queue: &[&Migration]) -> Vec<String> {
+        queue
+            .iter()
+            .map(|m| m.root().to_string_lossy().into_owned())
+            .collect()
     }
-}
 
-#[derive(Debug)]
-struct Migration {
-    root: PathBuf,
-    uuid: Uuid,
-    date: DateTime<Utc>,
-    name: Option<String>,
-}
+    #[test]
+    fn test_select_apply() {
+        let available = migrations();
+        let queue: Vec<_> = available.iter().collect();
+        let sel = |b| select(&queue, &available, &b, false).map(dirs);
 
-impl Migration {
-    async fn apply_source(&self) -> io::Result<String> {
-        let pb = self.root.join("apply.sql");
-        fs::read_to_string(pb).await
+        assert_eq!(3, sel(ApplyBehavior::All).unwrap().len());
+        assert_eq!(1, sel(ApplyBehavior::Count(1)).unwrap().len());
+        assert_eq!(3, sel(ApplyBehavior::Count(5)).unwrap().len());
+        assert_eq!(
+            vec!["20210327155155-initial"],
+            sel(ApplyBehavior::Until("20210901210618-second")).unwrap()
+        );
+        assert_eq!(
+            vec!["20210327155155-initial", "20210901210618-second"],
+            sel(ApplyBehavior::Through("20210901210618-second")).unwrap()
+        );
+        assert!(sel(ApplyBehavior::Until("20211231000000-missing")).is_err());
     }
 
-    async fn unapply_source(&self) -> io::Result<String> {
-        let pb = self.root.join("unapply.sql");
-        fs::read_to_string(pb).await
+    #[test]
+    fn test_format_count() {
+        assert_eq!("0", format_count(0));
+        assert_eq!("9999", format_count(9_999));
+        assert_eq!("120k", format_count(120_345));
+        assert_eq!("14M", format_count(14_000_001));
     }
-}
 
-fn load_migration(path: impl AsRef<Path>) -> anyhow::Result<Migration> {
-    let mut uuid = None;
-    let mut date = None;
-    let mut name = None;
-
-    let mut cd = CommandDispatcher::new(SimpleExecutor::new(|cmd, args| match cmd {
-        "id" => uuid = Some(Uuid::parse_str(args[0]).expect("Invalid uuid")),
-        "date" => {
-            date = Some(Utc::from_utc_datetime(
-                &Utc,
-                &NaiveDateTime::from_timestamp(args[0].parse().expect("Invalid timestamp"), 0),
-            ))
-        }
-        "name" => name = Some(args[0].to_string()),
-        _ => {}
-    }));
-    cd.scheduler()
-        .exec_path(path.as_ref().join("_props"), ExecSource::Other)?;
-    cd.resume_until_empty();
-
-    Ok(Migration {
-        root: path.as_ref().to_path_buf(),
-        uuid: uuid.expect("uuid not specified"),
-        date: date.expect("date not specified"),
-        name,
-    })
+    #[test]
+    fn test_select_unapply() {
+        let available = migrations();
+        // the first two are applied, newest first
+        let queue = vec![&available[1], &available[0]];
+        let sel = |b| select(&queue, &available, &b, true).map(dirs);
+
+        assert_eq!(
+            vec!["20210901210618-second"],
+            sel(ApplyBehavior::Until("20210327155155-initial")).unwrap()
+        );
+        assert_eq!(
+            vec!["20210901210618-second", "20210327155155-initial"],
+            sel(ApplyBehavior::Through("20210327155155-initial")).unwrap()
+        );
+
+        // exists, but can't be unapplied since it isn't applied
+        let e = sel(ApplyBehavior::Through("20210901223937-third")).unwrap_err();
+        assert_eq!(
+            "Migration 20210901223937-third is not applied!",
+            e.to_string()
+        );
+    }
 }
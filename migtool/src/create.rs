@@ -2,12 +2,19 @@ use std::fs;
 use std::fs::File;
 use std::io::ErrorKind;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use sqlx::types::chrono::Utc;
+use chrono::Utc;
 use uuid::Uuid;
 
-pub fn create_migration(name: &str, dir: &Path) -> anyhow::Result<()> {
+/// Creates a new migration in `dir`, which depends on the migrations in the
+/// directories `depends_on`.
+pub fn create_migration(name: &str, dir: &Path, depends_on: &[&Path]) -> anyhow::Result<()> {
+    let depends = depends_on
+        .iter()
+        .map(|&path| miglib::load_migration(PathBuf::from(path)).map(|m| m.id().to_simple()))
+        .collect::<Result<Vec<_>, _>>()?;
+
     let now = Utc::now();
     let dirname = format!(
         "{}-{}",
@@ -36,6 +43,11 @@ pub fn create_migration(name: &str, dir: &Path) -> anyhow::Result<()> {
     writeln!(props, "name {}", cmdparser::escape(name))?;
     writeln!(props, "date {}", now.timestamp())?;
 
+    if !depends.is_empty() {
+        let ids: Vec<_> = depends.iter().map(|id| id.to_string()).collect();
+        writeln!(props, "depends {}", ids.join(" "))?;
+    }
+
     writeln!(
         apply,
         "-- Write SQL here that applies the changes to the database you want, starting"
@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use anyhow::bail;
+use async_trait::async_trait;
+use miglib::{AppliedMigration, Migration, RunStats};
+use sqlx::{Connection, Database, PgConnection, Postgres, TransactionManager};
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+/// A connection to the database migrations are applied to.
+///
+/// Transactions started with [`begin`](Db::begin) nest: starting one while
+/// another is open creates a savepoint, which is how each migration gets a
+/// transaction of its own inside the one around a whole run.
+#[async_trait]
+pub trait Db: Send {
+    /// Takes the lock that keeps several processes from running migrations
+    /// at the same time, waiting for as long as `wait` for it. Has to be
+    /// called outside of a transaction.
+    async fn lock(&mut self, wait: Duration) -> miglib::Result;
+
+    /// Releases the lock taken with [`lock`](Db::lock).
+    async fn unlock(&mut self) -> miglib::Result;
+
+    async fn begin(&mut self) -> sqlx::Result<()>;
+
+    async fn commit(&mut self) -> sqlx::Result<()>;
+
+    async fn rollback(&mut self) -> sqlx::Result<()>;
+
+    /// Creates the table that keeps track of applied migrations, or brings
+    /// it up to date, see [`miglib::init`].
+    async fn init(&mut self) -> miglib::Result;
+
+    /// Returns the applied migrations in the order they were applied in,
+    /// see [`miglib::applied_at`].
+    async fn applied_at(&mut self) -> miglib::Result<Vec<AppliedMigration>>;
+
+    /// Applies or unapplies `migration` and records that, returning how long
+    /// it took and what it changed, see [`miglib::run`].
+    async fn run(&mut self, migration: &Migration, unapply: bool) -> miglib::Result<RunStats>;
+
+    /// Records `migration` as applied without running it, see
+    /// [`miglib::baseline`].
+    async fn baseline(&mut self, migration: &Migration) -> miglib::Result;
+
+    /// Returns the SQL that records `migration` as applied or unapplied, see
+    /// [`miglib::record_sql`].
+    fn record_sql(&self, migration: &Migration, unapply: bool) -> String;
+}
+
+/// Connects to the database at `db_url`. URLs starting with `sqlite:` open
+/// an SQLite database if migtool was built with the `sqlite` feature,
+/// everything else is passed to Postgres.
+pub async fn connect(db_url: &str) -> anyhow::Result<Box<dyn Db>> {
+    if db_url.starts_with("sqlite:") {
+        #[cfg(feature = "sqlite")]
+        return Ok(Box::new(sqlite::SqliteDb::connect(db_url).await?));
+
+        #[cfg(not(feature = "sqlite"))]
+        bail!("migtool was built without SQLite support, enable the sqlite feature to use it!");
+    }
+
+    if !db_url.starts_with("postgres:") && !db_url.starts_with("postgresql:") {
+        bail!("Unsupported database URL {}!", db_url);
+    }
+
+    Ok(Box::new(PgDb {
+        conn: PgConnection::connect(db_url).await?,
+    }))
+}
+
+/// A Postgres database, which is what [`miglib`] works with.
+struct PgDb {
+    conn: PgConnection,
+}
+
+#[async_trait]
+impl Db for PgDb {
+    async fn lock(&mut self, wait: Duration) -> miglib::Result {
+        miglib::lock(&mut self.conn, Some(wait)).await
+    }
+
+    async fn unlock(&mut self) -> miglib::Result {
+        miglib::unlock(&mut self.conn).await
+    }
+
+    // the transaction has to outlive the borrow of the connection, so it is
+    // managed the way sqlx's Transaction does it internally, which also
+    // keeps the transactions miglib starts nested in it
+    async fn begin(&mut self) -> sqlx::Result<()> {
+        <Postgres as Database>::TransactionManager::begin(&mut self.conn).await
+    }
+
+    async fn commit(&mut self) -> sqlx::Result<()> {
+        <Postgres as Database>::TransactionManager::commit(&mut self.conn).await
+    }
+
+    async fn rollback(&mut self) -> sqlx::Result<()> {
+        <Postgres as Database>::TransactionManager::rollback(&mut self.conn).await
+    }
+
+    async fn init(&mut self) -> miglib::Result {
+        miglib::init(&mut self.conn).await
+    }
+
+    async fn applied_at(&mut self) -> miglib::Result<Vec<AppliedMigration>> {
+        miglib::applied_at(&mut self.conn).await
+    }
+
+    async fn run(&mut self, migration: &Migration, unapply: bool) -> miglib::Result<RunStats> {
+        miglib::run(&mut self.conn, migration, unapply).await
+    }
+
+    async fn baseline(&mut self, migration: &Migration) -> miglib::Result {
+        miglib::baseline(&mut self.conn, migration).await
+    }
+
+    fn record_sql(&self, migration: &Migration, unapply: bool) -> String {
+        miglib::record_sql(migration, unapply)
+    }
+}
@@ -0,0 +1,288 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::TryStreamExt;
+use log::{debug, trace};
+use miglib::{AppliedMigration, Migration, RunStats};
+use sqlx::{Connection, Database, Executor, Sqlite, SqliteConnection, TransactionManager};
+use uuid::Uuid;
+
+use super::Db;
+
+/// The same table as the one for Postgres, with the IDs stored as text since
+/// SQLite has no UUID type. Created with all columns that were added to the
+/// Postgres one since, so there is nothing to bring up to date.
+const INIT_SQL: &str = include_str!("sqlite_init.sql");
+
+/// How `run_at` is stored, in UTC.
+const RUN_AT_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// An SQLite database, which migtool keeps track of the same way as a
+/// Postgres one.
+pub struct SqliteDb {
+    conn: SqliteConnection,
+}
+
+impl SqliteDb {
+    pub async fn connect(db_url: &str) -> sqlx::Result<Self> {
+        Ok(SqliteDb {
+            conn: SqliteConnection::connect(db_url).await?,
+        })
+    }
+}
+
+#[async_trait]
+impl Db for SqliteDb {
+    // SQLite has no advisory locks, but only lets one connection write at a
+    // time, so a second run fails once it tries to change the database
+    async fn lock(&mut self, _wait: Duration) -> miglib::Result {
+        Ok(())
+    }
+
+    async fn unlock(&mut self) -> miglib::Result {
+        Ok(())
+    }
+
+    async fn begin(&mut self) -> sqlx::Result<()> {
+        <Sqlite as Database>::TransactionManager::begin(&mut self.conn).await
+    }
+
+    async fn commit(&mut self) -> sqlx::Result<()> {
+        <Sqlite as Database>::TransactionManager::commit(&mut self.conn).await
+    }
+
+    async fn rollback(&mut self) -> sqlx::Result<()> {
+        <Sqlite as Database>::TransactionManager::rollback(&mut self.conn).await
+    }
+
+    async fn init(&mut self) -> miglib::Result {
+        trace!("=> {}", INIT_SQL);
+        self.conn.execute(INIT_SQL).await?;
+        Ok(())
+    }
+
+    async fn applied_at(&mut self) -> miglib::Result<Vec<AppliedMigration>> {
+        // rowid keeps the order of migrations applied in the same second
+        // language=SQL
+        let applied: Vec<(String, Option<String>, Option<Vec<u8>>, bool)> = sqlx::query_as(
+            "SELECT id, run_at, checksum, baselined FROM __migtool_meta ORDER BY rowid ASC",
+        )
+        .fetch_all(&mut self.conn)
+        .await?;
+
+        applied
+            .into_iter()
+            .map(|(id, run_at, checksum, baselined)| {
+                let id = Uuid::parse_str(&id)
+                    .map_err(|e| miglib::Error::Sqlx(sqlx::Error::Decode(Box::new(e))))?;
+                let run_at = run_at
+                    .and_then(|t| NaiveDateTime::parse_from_str(&t, RUN_AT_FORMAT).ok())
+                    .map(|t| DateTime::from_utc(t, Utc));
+
+                Ok(AppliedMigration {
+                    id,
+                    run_at,
+                    checksum,
+                    baselined,
+                })
+            })
+            .collect()
+    }
+
+    async fn run(&mut self, migration: &Migration, unapply: bool) -> miglib::Result<RunStats> {
+        let fail = |e| miglib::Error::Migration(migration.display_name().into_owned(), e);
+
+        let src = if !unapply {
+            migration.apply_sql()
+        } else {
+            migration.unapply_sql()
+        };
+
+        let start = Instant::now();
+        let mut stats = RunStats::default();
+        let mut ta = self.conn.begin().await?;
+
+        debug!("=> {}", src.replace('\n', "\n.. "));
+
+        {
+            let mut results = ta.fetch_many(src);
+            let mut last = start;
+
+            while let Some(step) = results.try_next().await.map_err(fail)? {
+                if let Some(done) = step.left() {
+                    let now = Instant::now();
+                    stats.statements += 1;
+                    stats.rows_affected += done.rows_affected();
+                    trace!(
+                        "   statement {} done in {:.1?}, {} rows affected",
+                        stats.statements,
+                        now - last,
+                        done.rows_affected()
+                    );
+                    last = now;
+                }
+            }
+        }
+
+        // language=SQL
+        let meta = if !unapply {
+            "INSERT INTO __migtool_meta (id, checksum) VALUES (?1, ?2)"
+        } else {
+            "DELETE FROM __migtool_meta WHERE id = ?1"
+        };
+
+        trace!("=> {}", meta);
+        let mut query = sqlx::query(meta).bind(migration.id().to_hyphenated().to_string());
+
+        if !unapply {
+            query = query.bind(migration.checksum().to_vec());
+        }
+
+        query.execute(&mut ta).await.map_err(fail)?;
+
+        ta.commit().await?;
+
+        stats.duration = start.elapsed();
+
+        Ok(stats)
+    }
+
+    async fn baseline(&mut self, migration: &Migration) -> miglib::Result {
+        // language=SQL
+        let meta = "INSERT INTO __migtool_meta (id, checksum, baselined) VALUES (?1, ?2, 1)";
+
+        trace!("=> {}", meta);
+        sqlx::query(meta)
+            .bind(migration.id().to_hyphenated().to_string())
+            .bind(migration.checksum().to_vec())
+            .execute(&mut self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    fn record_sql(&self, migration: &Migration, unapply: bool) -> String {
+        let id = migration.id().to_hyphenated();
+
+        if !unapply {
+            let checksum: String = migration
+                .checksum()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+
+            format!(
+                "INSERT INTO __migtool_meta (id, checksum) VALUES ('{}', X'{}');",
+                id, checksum
+            )
+        } else {
+            format!("DELETE FROM __migtool_meta WHERE id = '{}';", id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use miglib::Migration;
+    use uuid::Uuid;
+
+    use super::SqliteDb;
+    use crate::db::Db;
+
+    fn migrations() -> Vec<Migration> {
+        vec![
+            Migration::embedded(
+                "20210327155155-initial",
+                1,
+                1616860315,
+                None,
+                "CREATE TABLE track (id integer PRIMARY KEY, title text);",
+                "DROP TABLE track;",
+            ),
+            Migration::embedded(
+                "20210901210618-index-title",
+                2,
+                1630530378,
+                None,
+                "CREATE INDEX track_title ON track (title);",
+                "DROP INDEX track_title;",
+            ),
+        ]
+    }
+
+    async fn ids(db: &mut SqliteDb) -> Vec<Uuid> {
+        db.applied_at()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|a| a.id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_apply_unapply() {
+        let available = migrations();
+        let mut db = SqliteDb::connect("sqlite::memory:").await.unwrap();
+
+        db.lock(Default::default()).await.unwrap();
+        db.begin().await.unwrap();
+        db.init().await.unwrap();
+
+        for m in available.iter() {
+            let stats = db.run(m, false).await.unwrap();
+            assert_eq!(1, stats.statements);
+        }
+
+        db.commit().await.unwrap();
+        db.unlock().await.unwrap();
+
+        assert_eq!(
+            vec![Uuid::from_u128(1), Uuid::from_u128(2)],
+            ids(&mut db).await
+        );
+        sqlx::query("INSERT INTO track (title) VALUES ('Drive')")
+            .execute(&mut db.conn)
+            .await
+            .unwrap();
+
+        let applied = db.applied_at().await.unwrap();
+        assert!(!applied[0].is_modified(&available[0]));
+        assert!(!applied[0].baselined);
+
+        // a rolled back run leaves nothing behind
+        db.begin().await.unwrap();
+        db.run(&available[1], true).await.unwrap();
+        db.rollback().await.unwrap();
+        assert_eq!(2, ids(&mut db).await.len());
+
+        for m in available.iter().rev() {
+            db.run(m, true).await.unwrap();
+        }
+
+        assert!(ids(&mut db).await.is_empty());
+        assert!(sqlx::query("SELECT * FROM track")
+            .execute(&mut db.conn)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_migration() {
+        let available = migrations();
+        let mut db = SqliteDb::connect("sqlite::memory:").await.unwrap();
+        db.init().await.unwrap();
+
+        // the table it changes doesn't exist yet
+        assert!(matches!(
+            db.run(&available[1], false).await,
+            Err(miglib::Error::Migration(..))
+        ));
+        assert!(ids(&mut db).await.is_empty());
+
+        db.baseline(&available[0]).await.unwrap();
+        let applied = db.applied_at().await.unwrap();
+        assert!(applied[0].baselined);
+        assert!(applied[0].run_at.is_some());
+    }
+}
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS __migtool_meta
+(
+    id        text,
+    run_at    text    DEFAULT (strftime('%Y-%m-%d %H:%M:%S', 'now')),
+    checksum  blob,
+    baselined integer NOT NULL DEFAULT 0,
+
+    PRIMARY KEY (id)
+);
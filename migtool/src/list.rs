@@ -0,0 +1,18 @@
+use std::path::Path;
+
+pub fn list_migrations(dir: &Path) -> anyhow::Result<()> {
+    let migrations = miglib::load_dir(dir)?;
+
+    println!("{:<32}  {:<19}  NAME", "ID", "DATE");
+
+    for m in migrations.iter() {
+        println!(
+            "{}  {}  {}",
+            m.id().to_simple(),
+            m.date().format("%Y-%m-%d %H:%M:%S"),
+            m.display_name()
+        );
+    }
+
+    Ok(())
+}
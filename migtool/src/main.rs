@@ -1,11 +1,16 @@
 use std::path::Path;
+use std::time::Duration;
 
 use crate::apply::ApplyBehavior;
-use clap::{app_from_crate, App, Arg};
+use clap::{app_from_crate, App, Arg, ArgMatches};
 use cmdparser::{CommandDispatcher, ExecSource, SimpleExecutor};
+use simplelog::{LevelFilter, SimpleLogger};
 
 mod apply;
 mod create;
+mod db;
+mod list;
+mod status;
 
 fn main() -> anyhow::Result<()> {
     let matches =
@@ -13,8 +18,21 @@ fn main() -> anyhow::Result<()> {
             .subcommand(
                 App::new("create")
                     .about("Create a new migration")
-                    .arg(Arg::new("name").value_name("NAME").required(true)),
+                    .arg(Arg::new("name").value_name("NAME").required(true))
+                    .arg(
+                        Arg::new("depends-on")
+                            .long("depends-on")
+                            .value_name("DIRECTORY")
+                            .multiple_occurrences(true)
+                            .about("Always apply the migration in DIRECTORY before the new one, even if it is newer"),
+                    ),
             )
+            .subcommand(
+                App::new("list").about("List available migrations without connecting to database"),
+            )
+            .subcommand(App::new("status").about(
+                "Show which migrations are applied and which are pending. Exits with status 1 if any are pending",
+            ))
             .subcommand(
                 App::new("apply")
                     .about("Apply and unapply migrations")
@@ -23,7 +41,15 @@ fn main() -> anyhow::Result<()> {
                             .short('u')
                             .long("until")
                             .value_name("DIRECTORY")
-                            .about("Apply until a certain migration")
+                            .about("Apply the migrations before DIRECTORY, or unapply the ones after it")
+                            .conflicts_with_all(&["all", "through"]),
+                    )
+                    .arg(
+                        Arg::new("through")
+                            .short('t')
+                            .long("through")
+                            .value_name("DIRECTORY")
+                            .about("Like --until, but also apply or unapply DIRECTORY itself")
                             .conflicts_with("all"),
                     )
                     .arg(
@@ -35,6 +61,66 @@ fn main() -> anyhow::Result<()> {
                     .arg(Arg::new("all").short('a').long("all").about(
                         "Apply until the newest state or unapply until before the first state",
                     ))
+                    .arg(
+                        Arg::new("pretend")
+                            .short('p')
+                            .long("pretend")
+                            .about("Do not actually modify the database"),
+                    )
+                    .arg(
+                        Arg::new("force")
+                            .short('f')
+                            .long("force")
+                            .about("Unapply migrations even if they were baselined"),
+                    )
+                    .arg(
+                        Arg::new("plan")
+                            .long("plan")
+                            .value_name("FILE")
+                            .about("Write the SQL that would be run to FILE, or to standard output if FILE is -, instead of running it")
+                            .conflicts_with("pretend"),
+                    ),
+            )
+            .subcommand(
+                App::new("redo")
+                    .about("Unapply the last applied migrations and apply them again")
+                    .arg(
+                        Arg::new("count")
+                            .value_name("N")
+                            .default_value("1")
+                            .about("How many migrations to redo"),
+                    )
+                    .arg(
+                        Arg::new("pretend")
+                            .short('p')
+                            .long("pretend")
+                            .about("Do not actually modify the database"),
+                    )
+                    .arg(
+                        Arg::new("force")
+                            .short('f')
+                            .long("force")
+                            .about("Redo migrations even if they were baselined"),
+                    ),
+            )
+            .subcommand(
+                App::new("baseline")
+                    .about("Record pending migrations as applied without running them, for databases that already contain their changes")
+                    .arg(
+                        Arg::new("until")
+                            .short('u')
+                            .long("until")
+                            .value_name("DIRECTORY")
+                            .about("Only record the migrations before DIRECTORY")
+                            .conflicts_with("through"),
+                    )
+                    .arg(
+                        Arg::new("through")
+                            .short('t')
+                            .long("through")
+                            .value_name("DIRECTORY")
+                            .about("Only record the migrations up to and including DIRECTORY"),
+                    )
                     .arg(
                         Arg::new("pretend")
                             .short('p')
@@ -60,6 +146,20 @@ fn main() -> anyhow::Result<()> {
                     .about("Path to server configuration file containing database URL")
                     .global(true),
             )
+            .arg(
+                Arg::new("strict")
+                    .long("strict")
+                    .about("Fail instead of warning if an applied migration has been modified")
+                    .global(true),
+            )
+            .arg(
+                Arg::new("wait")
+                    .long("wait")
+                    .value_name("SECS")
+                    .default_value("0")
+                    .about("How long to wait for another migration run to finish")
+                    .global(true),
+            )
             .arg(
                 Arg::new("verbose")
                     .short('v')
@@ -73,7 +173,32 @@ fn main() -> anyhow::Result<()> {
         Some(("create", args)) => {
             let name = args.value_of("name").unwrap();
             let dir = args.value_of_os("migration-dir").unwrap();
-            create::create_migration(name, Path::new(dir))?;
+            let depends_on: Vec<_> = args
+                .values_of_os("depends-on")
+                .into_iter()
+                .flatten()
+                .map(Path::new)
+                .collect();
+            create::create_migration(name, Path::new(dir), &depends_on)?;
+        }
+        Some(("list", args)) => {
+            let dir = args.value_of_os("migration-dir").unwrap();
+            list::list_migrations(Path::new(dir))?
+        }
+        Some(("status", args)) => {
+            let rc = args.value_of_os("rc").unwrap();
+            let dir = args.value_of_os("migration-dir").unwrap();
+            let strict = args.is_present("strict");
+
+            let db_url = read_config(rc);
+
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let up_to_date =
+                runtime.block_on(status::print_status(&db_url, Path::new(dir), strict))?;
+
+            if !up_to_date {
+                std::process::exit(1);
+            }
         }
         Some(("apply", args)) => {
             let rc = args.value_of_os("rc").unwrap();
@@ -81,26 +206,80 @@ fn main() -> anyhow::Result<()> {
             let dir = args.value_of_os("migration-dir").unwrap();
             let unapply = args.is_present("unapply");
             let all = args.is_present("all");
-            let until = args.value_of("until");
             let pretend = args.is_present("pretend");
+            let plan = args.value_of_os("plan").map(Path::new);
+            let strict = args.is_present("strict");
+            let force = args.is_present("force");
+            let wait = Duration::from_secs(args.value_of("wait").unwrap().parse()?);
+
+            SimpleLogger::init(log_level(verbosity), simplelog::Config::default())?;
 
             let db_url = read_config(rc);
             let b = if all {
                 ApplyBehavior::All
-            } else if let Some(until) = until {
-                ApplyBehavior::Until(until)
             } else {
-                ApplyBehavior::Count(1)
+                selected_range(args).unwrap_or(ApplyBehavior::Count(1))
             };
 
-            let mut runtime = tokio::runtime::Runtime::new().unwrap();
+            let runtime = tokio::runtime::Runtime::new().unwrap();
             runtime.block_on(apply::apply_migration(
                 &db_url,
-                verbosity,
                 &b,
                 Path::new(dir),
                 unapply,
                 pretend,
+                plan,
+                strict,
+                force,
+                wait,
+            ))?
+        }
+        Some(("redo", args)) => {
+            let rc = args.value_of_os("rc").unwrap();
+            let verbosity = args.occurrences_of("verbose");
+            let dir = args.value_of_os("migration-dir").unwrap();
+            let count: usize = args.value_of("count").unwrap().parse()?;
+            let pretend = args.is_present("pretend");
+            let strict = args.is_present("strict");
+            let force = args.is_present("force");
+            let wait = Duration::from_secs(args.value_of("wait").unwrap().parse()?);
+
+            SimpleLogger::init(log_level(verbosity), simplelog::Config::default())?;
+
+            let db_url = read_config(rc);
+
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(apply::redo_migrations(
+                &db_url,
+                count,
+                Path::new(dir),
+                pretend,
+                strict,
+                force,
+                wait,
+            ))?
+        }
+        Some(("baseline", args)) => {
+            let rc = args.value_of_os("rc").unwrap();
+            let verbosity = args.occurrences_of("verbose");
+            let dir = args.value_of_os("migration-dir").unwrap();
+            let b = selected_range(args).unwrap_or(ApplyBehavior::All);
+            let pretend = args.is_present("pretend");
+            let strict = args.is_present("strict");
+            let wait = Duration::from_secs(args.value_of("wait").unwrap().parse()?);
+
+            SimpleLogger::init(log_level(verbosity), simplelog::Config::default())?;
+
+            let db_url = read_config(rc);
+
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(apply::baseline_migrations(
+                &db_url,
+                &b,
+                Path::new(dir),
+                pretend,
+                strict,
+                wait,
             ))?
         }
         _ => {}
@@ -109,6 +288,26 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Returns the migrations selected with `--until` or `--through`, if either
+/// was given.
+fn selected_range(args: &ArgMatches) -> Option<ApplyBehavior<'_>> {
+    if let Some(until) = args.value_of("until") {
+        Some(ApplyBehavior::Until(until))
+    } else {
+        args.value_of("through").map(ApplyBehavior::Through)
+    }
+}
+
+/// Returns the log level for the number of times `-v` was passed. `-v` shows
+/// the migrations' SQL, `-vv` everything else that's run.
+fn log_level(verbosity: u64) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
 #[allow(clippy::single_match)]
 fn read_config(path: impl AsRef<Path>) -> String {
     let mut db_url = None;
@@ -0,0 +1,118 @@
+use std::borrow::Cow;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use miglib::{Migration, MigrationStatus};
+
+use crate::apply::check_modified;
+use crate::db;
+
+/// Prints which migrations have been applied to the database and which are
+/// pending. Returns whether all of them have been applied. Fails after
+/// printing if `strict` is set and an applied migration has been modified.
+pub async fn print_status(db_url: &str, dir: &Path, strict: bool) -> anyhow::Result<bool> {
+    let mut db = db::connect(db_url).await?;
+
+    let available = miglib::load_dir(dir)?;
+
+    db.init().await?;
+    let applied = db.applied_at().await?;
+    let status = miglib::status(&available, &applied);
+
+    let width = available
+        .iter()
+        .map(|m| dir_name(m).len())
+        .max()
+        .unwrap_or(0)
+        .max("DIRECTORY".len());
+
+    println!(
+        "{:>3}  {:<width$}  {:<19}  NAME",
+        "#",
+        "DIRECTORY",
+        "APPLIED",
+        width = width
+    );
+
+    let mut pending = 0;
+
+    for (idx, s) in status.iter().enumerate() {
+        let (order, dir, applied, name) = match s {
+            MigrationStatus::Applied(m, run_at) => (
+                (idx + 1).to_string(),
+                dir_name(m),
+                format_run_at(*run_at),
+                m.name().unwrap_or("").to_string(),
+            ),
+            MigrationStatus::Modified(m, run_at) => (
+                (idx + 1).to_string(),
+                dir_name(m),
+                format_run_at(*run_at),
+                match m.name() {
+                    None => "(modified)".to_string(),
+                    Some(name) => format!("{} (modified)", name),
+                },
+            ),
+            MigrationStatus::Baselined(m, run_at) => (
+                (idx + 1).to_string(),
+                dir_name(m),
+                format_run_at(*run_at),
+                match m.name() {
+                    None => "(baselined)".to_string(),
+                    Some(name) => format!("{} (baselined)", name),
+                },
+            ),
+            MigrationStatus::Pending(m) => {
+                pending += 1;
+
+                (
+                    (idx + 1).to_string(),
+                    dir_name(m),
+                    "pending".to_string(),
+                    m.name().unwrap_or("").to_string(),
+                )
+            }
+            // marked with '!' since the database might not match what the
+            // available migrations expect
+            MigrationStatus::Missing(id, run_at) => (
+                "!".to_string(),
+                "(missing)".into(),
+                format_run_at(*run_at),
+                format!("unknown migration {}", id.to_simple()),
+            ),
+        };
+
+        println!(
+            "{:>3}  {:<width$}  {:<19}  {}",
+            order,
+            dir,
+            applied,
+            name,
+            width = width
+        );
+    }
+
+    if pending > 0 {
+        println!("{} pending migrations", pending);
+    }
+
+    if strict {
+        check_modified(&available, &applied, true)?;
+    }
+
+    Ok(pending == 0)
+}
+
+fn dir_name(m: &Migration) -> Cow<str> {
+    let root = m.root();
+    root.file_name()
+        .unwrap_or(root.as_os_str())
+        .to_string_lossy()
+}
+
+fn format_run_at(run_at: Option<DateTime<Utc>>) -> String {
+    match run_at {
+        None => "unknown".to_string(),
+        Some(t) => t.format("%Y-%m-%d %H:%M:%S").to_string(),
+    }
+}
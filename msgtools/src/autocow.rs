@@ -3,6 +3,13 @@ use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 
 /// A transparent copy-on-write smart pointer.
+///
+/// Cloning an `Ac` is cheap, the clones share the value until one of them is
+/// mutated through [`DerefMut`]. That handle then gets a copy of its own, so
+/// a change made through one handle is never visible through any other:
+/// every handle behaves like an independent value. A task that hands out
+/// its state, such as a room's playlist, keeps changing its own copy while
+/// whoever received it keeps seeing the state at the time it was handed out.
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub struct Ac<T: ?Sized> {
     inner: Arc<T>,
@@ -26,6 +33,29 @@ impl<T: ?Sized> Ac<T> {
     pub fn to_arc(&self) -> &Arc<T> {
         &self.inner
     }
+
+    /// Returns a read-only view of the current value, which is as cheap as
+    /// cloning the handle. It won't see changes made through this handle
+    /// later, and since it can't be mutated, it never copies the value.
+    pub fn snapshot(&self) -> Snapshot<T> {
+        Snapshot {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Returns whether both handles currently share the same value, that
+    /// is, neither has been mutated since one was cloned from the other.
+    pub fn ptr_eq(this: &Ac<T>, other: &Ac<T>) -> bool {
+        Arc::ptr_eq(&this.inner, &other.inner)
+    }
+}
+
+impl<T> Ac<T> {
+    /// Returns the value if this is the only handle to it, or the handle
+    /// back otherwise.
+    pub fn try_unwrap(this: Ac<T>) -> Result<T, Ac<T>> {
+        Arc::try_unwrap(this.inner).map_err(Ac::from_arc)
+    }
 }
 
 impl<T: Clone> Ac<T> {
@@ -35,6 +65,12 @@ impl<T: Clone> Ac<T> {
             Err(arc) => (*arc).clone(),
         }
     }
+
+    /// Returns a handle to a copy of the value right away, instead of when it
+    /// is first mutated. Mutating the copy doesn't have to copy it again.
+    pub fn detach(&self) -> Ac<T> {
+        Ac::new((*self.inner).clone())
+    }
 }
 
 impl<T: ?Sized> Deref for Ac<T> {
@@ -69,6 +105,41 @@ impl<T: Debug + ?Sized> Debug for Ac<T> {
     }
 }
 
+/// A read-only view of the value of an [`Ac`] at the time it was taken, see
+/// [`Ac::snapshot`].
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct Snapshot<T: ?Sized> {
+    inner: Arc<T>,
+}
+
+impl<T: ?Sized> Snapshot<T> {
+    /// Returns a handle to the value, which copies it once it is mutated like
+    /// any other.
+    pub fn to_ac(&self) -> Ac<T> {
+        Ac::from_arc(self.inner.clone())
+    }
+}
+
+impl<T: ?Sized> Deref for Snapshot<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T: Display + ?Sized> Display for Snapshot<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: Debug + ?Sized> Debug for Snapshot<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
 impl<T> From<T> for Ac<T> {
     fn from(s: T) -> Self {
         Ac::new(s)
@@ -101,4 +172,43 @@ mod test {
         assert_eq!("abc", &*s);
         assert_eq!("abcdef", &*copy);
     }
+
+    #[test]
+    fn test_snapshot() {
+        let mut s: Ac<String> = "a".into();
+        let snapshot = s.snapshot();
+        assert!(Ac::ptr_eq(&s, &snapshot.to_ac()));
+
+        s.push_str("bc");
+        assert_eq!("a", &*snapshot);
+
+        // mutating a handle made from the snapshot doesn't change either
+        let mut copy = snapshot.to_ac();
+        copy.push('d');
+        assert_eq!("a", &*snapshot);
+        assert_eq!("abc", &*s);
+        assert_eq!("ad", &*copy);
+    }
+
+    #[test]
+    fn test_detach() {
+        let s: Ac<String> = "a".into();
+        let mut detached = s.detach();
+        assert!(!Ac::ptr_eq(&s, &detached));
+
+        let ptr = detached.to_arc().as_ptr();
+        detached.push('b');
+        assert_eq!(ptr, detached.to_arc().as_ptr());
+        assert_eq!("a", &*s);
+    }
+
+    #[test]
+    fn test_try_unwrap() {
+        let s: Ac<String> = "a".into();
+        let copy = s.clone();
+
+        let s = Ac::try_unwrap(s).unwrap_err();
+        drop(copy);
+        assert_eq!(Ok("a".to_string()), Ac::try_unwrap(s));
+    }
 }
@@ -1,9 +1,12 @@
 #[doc(hidden)]
+pub use ::async_trait;
+#[doc(hidden)]
 pub use ::futures;
 #[doc(hidden)]
 pub use ::paste;
 
-pub use autocow::Ac;
+pub use autocow::{Ac, Snapshot};
 
 pub mod autocow;
 pub mod proxy;
+pub mod sync_proxy;
@@ -1,36 +1,129 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
 use futures::channel::{mpsc, oneshot};
+use futures::future::{self, Either};
+use futures::pin_mut;
+use futures_timer::Delay;
 use pin_project_lite::pin_project;
 use thiserror::Error;
 
 #[macro_export]
 macro_rules! proxy {
     (
-        $v:vis proxy $name:ident {
-            $(
-                $fv:vis async fn $fn_name:ident ($($p:ident : $pty:ty),* $(,)?) $(-> $rty:ty)?;
-            )*
+        $v:vis proxy $name:ident $(( cap = $cap:expr ))? : trait + batch {
+            $($body:tt)*
+        }
+    ) => {
+        $crate::__proxy_parse! {
+            [$v $name [$($cap)?] [trait] [batch]] [] [] $($body)*
+        }
+    };
+    (
+        $v:vis proxy $name:ident $(( cap = $cap:expr ))? : batch {
+            $($body:tt)*
+        }
+    ) => {
+        $crate::__proxy_parse! {
+            [$v $name [$($cap)?] [] [batch]] [] [] $($body)*
+        }
+    };
+    (
+        $v:vis proxy $name:ident $(( cap = $cap:expr ))? $(: $tr:ident)? {
+            $($body:tt)*
+        }
+    ) => {
+        $crate::__proxy_parse! {
+            [$v $name [$($cap)?] [$($tr)?] []] [] [] $($body)*
+        }
+    };
+}
+
+/// Sorts the methods of a proxy into calls and notifications, which are
+/// declared with `async notify fn` and don't wait for a response, then
+/// generates the proxy.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __proxy_parse {
+    (
+        [$($head:tt)*] [$($calls:tt)*] [$($notifies:tt)*]
+        $fv:vis async notify fn $fn_name:ident ($($p:ident : $pty:ty),* $(,)?);
+        $($rest:tt)*
+    ) => {
+        $crate::__proxy_parse! {
+            [$($head)*] [$($calls)*] [$($notifies)* { $fv $fn_name ($($p : $pty),*) }]
+            $($rest)*
         }
+    };
+    (
+        [$($head:tt)*] [$($calls:tt)*] [$($notifies:tt)*]
+        $fv:vis async fn $fn_name:ident ($($p:ident : $pty:ty),* $(,)?) $(-> $rty:ty)?;
+        $($rest:tt)*
+    ) => {
+        $crate::__proxy_parse! {
+            [$($head)*] [$($calls)* { $fv $fn_name ($($p : $pty),*) $(-> $rty)? }] [$($notifies)*]
+            $($rest)*
+        }
+    };
+    (
+        [$v:vis $name:ident [$($cap:expr)?] [$($tr:ident)?] [$($batch:ident)?]]
+        [$({ $fv:vis $fn_name:ident ($($p:ident : $pty:ty),*) $(-> $rty:ty)? })*]
+        [$({ $nv:vis $n_name:ident ($($np:ident : $npty:ty),*) })*]
     ) => {
         $crate::paste::paste! {
             $v struct $name {
-                pipe: std::sync::Mutex<$crate::futures::channel::mpsc::Sender< [<$name Message>] >>
+                pipe: std::sync::Mutex<$crate::futures::channel::mpsc::Sender< [<$name Message>] >>,
+                capacity: usize,
+                pending: std::sync::atomic::AtomicUsize,
             }
 
             impl $name {
                 $v fn channel() -> ($name, [<$name Receiver>]) {
-                    let (tx, rx) = $crate::futures::channel::mpsc::channel(20);
+                    $name::channel_with_capacity($crate::__proxy_capacity!($($cap)?))
+                }
+
+                $v fn channel_with_capacity(capacity: usize) -> ($name, [<$name Receiver>]) {
+                    let (tx, rx) = $crate::futures::channel::mpsc::channel(capacity);
 
                     (
-                        $name { pipe: std::sync::Mutex::new(tx) },
+                        $name {
+                            pipe: std::sync::Mutex::new(tx),
+                            capacity,
+                            pending: std::sync::atomic::AtomicUsize::new(0),
+                        },
                         rx
                     )
                 }
+
+                /// Returns how many messages fit in the channel before
+                /// callers have to wait for the receiver.
+                #[allow(dead_code)]
+                $v fn capacity(&self) -> usize {
+                    self.capacity
+                }
+
+                /// Returns how many calls are waiting to be handled or for
+                /// their response.
+                #[allow(dead_code)]
+                $v fn pending(&self) -> usize {
+                    self.pending.load(std::sync::atomic::Ordering::Relaxed)
+                }
+
+                /// Returns whether there are as many pending calls as the
+                /// channel has room for, so that the next call might have to
+                /// wait for the receiver.
+                #[allow(dead_code)]
+                $v fn is_full(&self) -> bool {
+                    self.pending() >= self.capacity
+                }
             }
         }
 
         impl $name {
             $(
                 $fv async fn $fn_name (&self, $($p : $pty),* ) -> $crate::proxy::Result $(< $rty >)? {
+                    let _pending = $crate::proxy::Pending::new(&self.pending);
                     let (c, h) = $crate::futures::channel::oneshot::channel();
 
                     $crate::paste::paste! {
@@ -40,19 +133,230 @@ macro_rules! proxy {
                         };
                     }
 
-                    $crate::futures::SinkExt::send(&mut *self.pipe.lock().unwrap(), msg).await?;
+                    // not holding the lock while waiting, so that the
+                    // returned future is Send
+                    let mut pipe = self.pipe.lock().unwrap().clone();
+                    $crate::futures::SinkExt::send(&mut pipe, msg)
+                        .await
+                        .map_err(|e| $crate::proxy::Error::call(stringify!($name), stringify!($fn_name), e))?;
+
+                    h.await
+                        .map_err(|e| $crate::proxy::Error::call(stringify!($name), stringify!($fn_name), e))
+                }
+
+                $crate::paste::paste! {
+                    /// Queues the call without waiting, for callers that
+                    /// can't await. The response is dropped, so there is no
+                    /// way to find out whether or how the call was handled.
+                    #[allow(dead_code)]
+                    $fv fn [<try_ $fn_name>] (&self, $($p : $pty),* ) -> std::result::Result<(), $crate::proxy::TrySendError> {
+                        let (c, _) = $crate::futures::channel::oneshot::channel();
+
+                        let msg = [<$name Message>] :: [< $fn_name:camel >] {
+                            $($p,)*
+                            callback: c.into()
+                        };
+
+                        Ok(self.pipe.lock().unwrap().try_send(msg)?)
+                    }
+
+                    #[allow(dead_code)]
+                    $fv async fn [<$fn_name _timeout>] (&self, $($p : $pty,)* timeout: std::time::Duration) -> $crate::proxy::Result $(< $rty >)? {
+                        $crate::proxy::with_timeout(self.$fn_name($($p),*), timeout)
+                            .await
+                            .map_err(|e| $crate::proxy::Error::call(stringify!($name), stringify!($fn_name), e))
+                    }
+                }
+            )*
+
+            $(
+                $nv async fn $n_name (&self, $($np : $npty),* ) -> $crate::proxy::Result {
+                    let _pending = $crate::proxy::Pending::new(&self.pending);
+
+                    $crate::paste::paste! {
+                        let msg = [<$name Message>] :: [< $n_name:camel >] { $($np,)* };
+                    }
+
+                    let mut pipe = self.pipe.lock().unwrap().clone();
+                    $crate::futures::SinkExt::send(&mut pipe, msg)
+                        .await
+                        .map_err(|e| $crate::proxy::Error::call(stringify!($name), stringify!($n_name), e))
+                }
+
+                $crate::paste::paste! {
+                    /// Queues the notification without waiting, for callers
+                    /// that can't await.
+                    #[allow(dead_code)]
+                    $nv fn [<try_ $n_name>] (&self, $($np : $npty),* ) -> std::result::Result<(), $crate::proxy::TrySendError> {
+                        let msg = [<$name Message>] :: [< $n_name:camel >] { $($np,)* };
 
-                    Ok(h.await?)
+                        Ok(self.pipe.lock().unwrap().try_send(msg)?)
+                    }
                 }
             )*
         }
 
+        $crate::__proxy_trait! {
+            $($tr)?; $v $name {
+                $( $fn_name ($($p : $pty),*) $(-> $rty)?; )*
+                $( $n_name ($($np : $npty),*); )*
+            }
+        }
+
+        $crate::__proxy_batch! {
+            $($batch)?; $v $name {
+                $( $fv $fn_name ($($p : $pty),*) $(-> $rty)?; )*
+            } {
+                $( $nv $n_name ($($np : $npty),*); )*
+            }
+        }
+
         $crate::paste::paste! {
             type [<$name Receiver>] = $crate::futures::channel::mpsc::Receiver< [<$name Message>] >;
 
             #[derive(Debug)]
             $v enum [<$name Message>] {
-                $( [< $fn_name:camel >] { $($p : $pty,)* callback: $crate::proxy::Callback $( < $rty > )? } ),*
+                $( [< $fn_name:camel >] { $($p : $pty,)* callback: $crate::proxy::Callback $( < $rty > )? }, )*
+                $( [< $n_name:camel >] { $($np : $npty,)* }, )*
+                $( [< $batch:camel >] (Vec< [<$name Message>] >), )?
+            }
+        }
+    };
+}
+
+/// How many messages fit in the channel of a proxy that doesn't set its own
+/// capacity.
+pub const DEFAULT_CAPACITY: usize = 20;
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __proxy_capacity {
+    () => {
+        $crate::proxy::DEFAULT_CAPACITY
+    };
+    ($cap:expr) => {
+        $cap
+    };
+}
+
+/// Generates the `<Name>Like` trait for proxies declared with `: trait`, which
+/// has the same methods as the proxy and lets tests replace it with a mock.
+/// The methods fail by default as if the receiver dropped the call, so a mock
+/// only implements the ones the code under test uses.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __proxy_trait {
+    (; $($rest:tt)*) => {};
+    (
+        trait; $v:vis $name:ident {
+            $( $fn_name:ident ($($p:ident : $pty:ty),*) $(-> $rty:ty)?; )*
+        }
+    ) => {
+        $crate::paste::paste! {
+            #[$crate::async_trait::async_trait]
+            $v trait [<$name Like>]: Send + Sync {
+                $(
+                    async fn $fn_name (&self, $($p : $pty),* ) -> $crate::proxy::Result $(< $rty >)? {
+                        let _ = ($($p,)*);
+                        Err($crate::proxy::Error::call(
+                            stringify!($name),
+                            stringify!($fn_name),
+                            $crate::futures::channel::oneshot::Canceled,
+                        ))
+                    }
+                )*
+            }
+
+            #[$crate::async_trait::async_trait]
+            impl [<$name Like>] for $name {
+                $(
+                    async fn $fn_name (&self, $($p : $pty),* ) -> $crate::proxy::Result $(< $rty >)? {
+                        $name::$fn_name(self, $($p),*).await
+                    }
+                )*
+            }
+        }
+    };
+}
+
+/// Generates `<Name>::batch` for proxies declared with `: batch`, which
+/// queues several calls and sends them to the receiver as one
+/// `<Name>Message::Batch` message. The receiver handles all of them before
+/// it looks at anything else, so no other message can come in between.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __proxy_batch {
+    (; $($rest:tt)*) => {};
+    (
+        batch; $v:vis $name:ident {
+            $( $fv:vis $fn_name:ident ($($p:ident : $pty:ty),*) $(-> $rty:ty)?; )*
+        } {
+            $( $nv:vis $n_name:ident ($($np:ident : $npty:ty),*); )*
+        }
+    ) => {
+        $crate::paste::paste! {
+            /// Calls to be sent to the receiver in one message.
+            #[must_use = "the calls are only made once the batch is sent"]
+            $v struct [<$name Batch>]<'a> {
+                proxy: &'a $name,
+                messages: Vec< [<$name Message>] >,
+            }
+
+            impl $name {
+                /// Starts a batch of calls, which are handled one after
+                /// another without any other message in between.
+                $v fn batch(&self) -> [<$name Batch>]<'_> {
+                    [<$name Batch>] {
+                        proxy: self,
+                        messages: Vec::new(),
+                    }
+                }
+            }
+
+            #[allow(dead_code)]
+            impl [<$name Batch>]<'_> {
+                $(
+                    $fv fn $fn_name (&mut self, $($p : $pty),* ) -> $crate::proxy::Reply $(< $rty >)? {
+                        let (c, h) = $crate::futures::channel::oneshot::channel();
+
+                        self.messages.push([<$name Message>] :: [< $fn_name:camel >] {
+                            $($p,)*
+                            callback: c.into()
+                        });
+
+                        $crate::proxy::Reply::new(stringify!($name), stringify!($fn_name), h)
+                    }
+                )*
+
+                $(
+                    $nv fn $n_name (&mut self, $($np : $npty),* ) {
+                        self.messages.push([<$name Message>] :: [< $n_name:camel >] { $($np,)* });
+                    }
+                )*
+
+                /// Returns whether no calls have been added to the batch.
+                $v fn is_empty(&self) -> bool {
+                    self.messages.is_empty()
+                }
+
+                /// Sends the batch, after which the responses arrive through
+                /// the [`Reply`]s returned when adding the calls. An empty
+                /// batch isn't sent at all.
+                ///
+                /// [`Reply`]: $crate::proxy::Reply
+                $v async fn send(self) -> $crate::proxy::Result {
+                    if self.messages.is_empty() {
+                        return Ok(());
+                    }
+
+                    let _pending = $crate::proxy::Pending::new(&self.proxy.pending);
+                    let msg = [<$name Message>] :: Batch(self.messages);
+
+                    let mut pipe = self.proxy.pipe.lock().unwrap().clone();
+                    $crate::futures::SinkExt::send(&mut pipe, msg)
+                        .await
+                        .map_err(|e| $crate::proxy::Error::call(stringify!($name), "batch", e))
+                }
             }
         }
     };
@@ -60,6 +364,24 @@ macro_rules! proxy {
 
 pub type Result<T = (), E = Error> = std::result::Result<T, E>;
 
+/// Counts a call as pending for as long as it is alive, which includes the
+/// call being dropped before it finishes.
+#[doc(hidden)]
+pub struct Pending<'a>(&'a AtomicUsize);
+
+impl<'a> Pending<'a> {
+    pub fn new(count: &'a AtomicUsize) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Pending(count)
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 pin_project! {
     #[derive(Debug)]
     #[must_use = "this callback must be used to return a value to the caller"]
@@ -81,19 +403,148 @@ impl<T> From<oneshot::Sender<T>> for Callback<T> {
     }
 }
 
+/// The response to a call added to a batch, which arrives once the batch has
+/// been sent and the call handled.
+#[must_use = "the response is lost unless it is received"]
+#[derive(Debug)]
+pub struct Reply<T = ()> {
+    proxy: &'static str,
+    method: &'static str,
+    pipe: oneshot::Receiver<T>,
+}
+
+impl<T> Reply<T> {
+    #[doc(hidden)]
+    pub fn new(proxy: &'static str, method: &'static str, pipe: oneshot::Receiver<T>) -> Self {
+        Reply {
+            proxy,
+            method,
+            pipe,
+        }
+    }
+
+    /// Waits for the response, failing if the batch was never sent or the
+    /// receiver dropped the call.
+    pub async fn recv(self) -> Result<T> {
+        self.pipe
+            .await
+            .map_err(|e| Error::call(self.proxy, self.method, e))
+    }
+}
+
 #[derive(Error, Clone, Eq, PartialEq, Debug)]
 pub enum Error {
     #[error("{0}")]
     SendError(#[from] mpsc::SendError),
     #[error("{0}")]
     Canceled(#[from] oneshot::Canceled),
+    #[error("no response after {0:?}")]
+    Timeout(Duration),
+    /// One of the other errors, with the proxy and method it happened in.
+    #[error("{proxy}::{method}: {source}")]
+    Call {
+        proxy: &'static str,
+        method: &'static str,
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    /// Wraps `source` in an [`Error::Call`] for `method` of `proxy`, unless
+    /// it is one already.
+    pub fn call(proxy: &'static str, method: &'static str, source: impl Into<Error>) -> Self {
+        match source.into() {
+            e @ Error::Call { .. } => e,
+            source => Error::Call {
+                proxy,
+                method,
+                source: Box::new(source),
+            },
+        }
+    }
+
+    /// Returns the error without the proxy and method it happened in.
+    pub fn kind(&self) -> &Error {
+        match self {
+            Error::Call { source, .. } => source.kind(),
+            e => e,
+        }
+    }
+}
+
+/// Why a message couldn't be queued by one of the `try_` methods generated by
+/// [`proxy!`].
+#[derive(Error, Clone, Copy, Eq, PartialEq, Debug)]
+pub enum TrySendError {
+    #[error("the channel is full")]
+    Full,
+    #[error("the receiver was dropped")]
+    Closed,
+}
+
+impl<T> From<mpsc::TrySendError<T>> for TrySendError {
+    fn from(e: mpsc::TrySendError<T>) -> Self {
+        if e.is_full() {
+            TrySendError::Full
+        } else {
+            TrySendError::Closed
+        }
+    }
+}
+
+/// The error of a call to a proxy method returning a `Result`, with the
+/// error of the call itself and the one of the handler in one layer, see
+/// [`ResultExt::flatten_call`].
+#[derive(Error, Clone, Eq, PartialEq, Debug)]
+pub enum CallError<E> {
+    #[error("{0}")]
+    Proxy(#[from] Error),
+    #[error("{0}")]
+    Handler(E),
+}
+
+pub trait ResultExt<T, E> {
+    /// Turns the result of a proxy method returning a `Result`, which is
+    /// `proxy::Result<Result<T, E>>`, into a single `Result`.
+    fn flatten_call(self) -> std::result::Result<T, CallError<E>>;
+}
+
+impl<T, E> ResultExt<T, E> for Result<std::result::Result<T, E>> {
+    fn flatten_call(self) -> std::result::Result<T, CallError<E>> {
+        match self {
+            Ok(Ok(v)) => Ok(v),
+            Ok(Err(e)) => Err(CallError::Handler(e)),
+            Err(e) => Err(CallError::Proxy(e)),
+        }
+    }
+}
+
+/// Waits for the proxy call `call` for at most `timeout`, which includes
+/// waiting for room in the channel. Used by the `_timeout` variants of the
+/// methods generated by [`proxy!`].
+#[doc(hidden)]
+pub async fn with_timeout<T>(
+    call: impl Future<Output = Result<T>>,
+    timeout: Duration,
+) -> Result<T> {
+    pin_mut!(call);
+
+    match future::select(call, Delay::new(timeout)).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(Error::Timeout(timeout)),
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use futures::executor::LocalPool;
+    use std::time::Duration;
+
+    use futures::channel::oneshot;
+    use futures::executor::{block_on, LocalPool};
     use futures::task::{LocalSpawnExt, SpawnExt};
-    use futures::StreamExt;
+    use futures::{join, FutureExt, StreamExt};
+
+    use super::{CallError, Error, Result, ResultExt, TrySendError};
 
     proxy! {
         pub proxy Test {
@@ -140,4 +591,315 @@ mod test {
 
         pool.run();
     }
+
+    #[test]
+    fn test_ok() {
+        let (test, mut tr) = Test::channel();
+
+        let (result, _) = block_on(async {
+            join!(test.hello("2xsaiko".to_string()), async {
+                match tr.next().await {
+                    Some(TestMessage::Hello { name, callback }) => {
+                        let _ = callback.send(format!("Hello, {}!", name));
+                    }
+                    v => panic!("unexpected message: {:?}", v),
+                }
+            })
+        });
+
+        assert_eq!(Ok("Hello, 2xsaiko!".to_string()), result);
+    }
+
+    #[test]
+    fn test_receiver_dropped_before_send() {
+        let (test, tr) = Test::channel();
+        drop(tr);
+
+        match block_on(test.yeah()).as_ref().map_err(Error::kind) {
+            Err(Error::SendError(e)) => assert!(e.is_disconnected()),
+            v => panic!("expected SendError, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_receiver_dropped_after_send() {
+        let (test, tr) = Test::channel();
+
+        // the message is queued but never handled, dropping the receiver
+        // drops the callback along with it
+        let (result, _) = block_on(async { join!(test.yeah(), async { drop(tr) }) });
+
+        let e = result.unwrap_err();
+        assert_eq!(&Error::Canceled(oneshot::Canceled), e.kind());
+        assert_eq!("Test::yeah: oneshot canceled", e.to_string());
+    }
+
+    proxy! {
+        pub proxy Small(cap = 1): trait + batch {
+            pub async fn ping();
+            pub async notify fn poke(n: u32);
+            pub async fn half(n: u32) -> Result<u32, String>;
+        }
+    }
+
+    #[test]
+    fn test_capacity() {
+        let (test, _tr) = Test::channel();
+        assert_eq!(20, test.capacity());
+
+        let (small, mut sr) = Small::channel();
+        assert_eq!(1, small.capacity());
+        assert!(!small.is_full());
+
+        let (result, _) = block_on(async {
+            join!(small.ping(), async {
+                match sr.next().await {
+                    Some(SmallMessage::Ping { callback }) => {
+                        assert_eq!(1, small.pending());
+                        assert!(small.is_full());
+                        let _ = callback.send(());
+                    }
+                    v => panic!("unexpected message: {:?}", v),
+                }
+            })
+        });
+
+        assert_eq!(Ok(()), result);
+        assert_eq!(0, small.pending());
+        assert_eq!(5, Small::channel_with_capacity(5).0.capacity());
+    }
+
+    #[test]
+    fn test_notify() {
+        let (small, mut sr) = Small::channel();
+
+        // returns as soon as the message is in the channel
+        assert_eq!(Ok(()), block_on(small.poke(3)));
+        assert_eq!(0, small.pending());
+
+        match block_on(sr.next()) {
+            Some(SmallMessage::Poke { n }) => assert_eq!(3, n),
+            v => panic!("unexpected message: {:?}", v),
+        }
+
+        drop(sr);
+        let e = block_on(small.poke(4)).unwrap_err();
+        assert!(matches!(e.kind(), Error::SendError(_)));
+        assert!(e.to_string().starts_with("Small::poke: "));
+    }
+
+    #[test]
+    fn test_flatten_call() {
+        let (small, mut sr) = Small::channel();
+
+        let serve = async {
+            while let Some(msg) = sr.next().await {
+                if let SmallMessage::Half { n, callback } = msg {
+                    let _ = callback.send(match n % 2 {
+                        0 => Ok(n / 2),
+                        _ => Err(format!("{} is odd", n)),
+                    });
+                }
+            }
+        };
+
+        let call = async {
+            assert_eq!(Ok(2), small.half(4).await.flatten_call());
+            assert_eq!(
+                Err(CallError::Handler("3 is odd".to_string())),
+                small.half(3).await.flatten_call()
+            );
+            drop(small);
+        };
+
+        block_on(async { join!(serve, call) });
+
+        let (small, sr) = Small::channel();
+        drop(sr);
+        assert!(matches!(
+            block_on(small.half(2)).flatten_call(),
+            Err(CallError::Proxy(_))
+        ));
+    }
+
+    #[test]
+    fn test_try_call() {
+        let (small, mut sr) = Small::channel();
+
+        assert_eq!(Ok(()), small.try_ping());
+        assert_eq!(Ok(()), small.try_poke(1));
+        // one more than the capacity fits, since every sender has a slot of
+        // its own
+        assert_eq!(Err(TrySendError::Full), small.try_poke(2));
+
+        match block_on(sr.next()) {
+            Some(SmallMessage::Ping { callback }) => {
+                // nobody is waiting for the response
+                assert_eq!(Err(()), callback.send(()));
+            }
+            v => panic!("unexpected message: {:?}", v),
+        }
+
+        drop(sr);
+        assert_eq!(Err(TrySendError::Closed), small.try_poke(3));
+    }
+
+    #[test]
+    fn test_batch() {
+        let (small, mut sr) = Small::channel();
+
+        let mut batch = small.batch();
+        let half = batch.half(4);
+        batch.poke(1);
+        let ping = batch.ping();
+
+        // a single message, so it fits even though the capacity is 1
+        assert_eq!(Ok(()), block_on(batch.send()));
+
+        match block_on(sr.next()) {
+            Some(SmallMessage::Batch(messages)) => {
+                let mut messages = messages.into_iter();
+
+                match messages.next() {
+                    Some(SmallMessage::Half { n, callback }) => {
+                        let _ = callback.send(Ok(n / 2));
+                    }
+                    v => panic!("unexpected message: {:?}", v),
+                }
+
+                assert!(matches!(messages.next(), Some(SmallMessage::Poke { n: 1 })));
+                // dropping the last one without answering
+                assert!(matches!(messages.next(), Some(SmallMessage::Ping { .. })));
+                assert!(messages.next().is_none());
+            }
+            v => panic!("unexpected message: {:?}", v),
+        }
+
+        assert_eq!(Ok(Ok(2)), block_on(half.recv()));
+        assert_eq!(
+            "Small::ping: oneshot canceled",
+            block_on(ping.recv()).unwrap_err().to_string()
+        );
+
+        // empty batches aren't sent
+        assert_eq!(Ok(()), block_on(small.batch().send()));
+        assert!(sr.next().now_or_never().is_none());
+
+        drop(sr);
+        let mut batch = small.batch();
+        batch.poke(2);
+        let e = block_on(batch.send()).unwrap_err();
+        assert!(matches!(e.kind(), Error::SendError(_)));
+        assert!(e.to_string().starts_with("Small::batch: "));
+    }
+
+    #[test]
+    fn test_trait() {
+        async fn call(small: &impl SmallLike) -> Result<u32> {
+            small.ping().await?;
+            small.poke(1).await?;
+            Ok(small.half(4).await?.unwrap())
+        }
+
+        let (small, mut sr) = Small::channel();
+
+        let serve = async {
+            while let Some(msg) = sr.next().await {
+                match msg {
+                    SmallMessage::Ping { callback } => {
+                        let _ = callback.send(());
+                    }
+                    SmallMessage::Poke { n } => assert_eq!(1, n),
+                    SmallMessage::Half { n, callback } => {
+                        let _ = callback.send(Ok(n / 2));
+                    }
+                    v => panic!("unexpected message: {:?}", v),
+                }
+            }
+        };
+
+        let (result, _) = block_on(async {
+            join!(
+                async {
+                    let result = call(&small).await;
+                    drop(small);
+                    result
+                },
+                serve
+            )
+        });
+
+        assert_eq!(Ok(2), result);
+    }
+
+    #[test]
+    fn test_trait_default() {
+        struct MockSmall;
+
+        #[async_trait::async_trait]
+        impl SmallLike for MockSmall {
+            async fn half(&self, n: u32) -> Result<std::result::Result<u32, String>> {
+                Ok(Ok(n / 2))
+            }
+        }
+
+        assert_eq!(Ok(Ok(2)), block_on(MockSmall.half(4)));
+
+        let e = block_on(MockSmall.ping()).unwrap_err();
+        assert_eq!(&Error::Canceled(oneshot::Canceled), e.kind());
+        assert_eq!("Small::ping: oneshot canceled", e.to_string());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let (test, mut tr) = Test::channel();
+
+        let (result, _) = block_on(async {
+            join!(
+                test.hello_timeout("2xsaiko".to_string(), Duration::from_secs(10)),
+                async {
+                    match tr.next().await {
+                        Some(TestMessage::Hello { name, callback }) => {
+                            let _ = callback.send(format!("Hello, {}!", name));
+                        }
+                        v => panic!("unexpected message: {:?}", v),
+                    }
+                }
+            )
+        });
+
+        assert_eq!(Ok("Hello, 2xsaiko!".to_string()), result);
+    }
+
+    #[test]
+    fn test_timeout_no_response() {
+        let (test, mut tr) = Test::channel();
+        let timeout = Duration::from_millis(50);
+
+        // the handler holds on to the callback without ever answering
+        let (result, _msg) = block_on(async { join!(test.yeah_timeout(timeout), tr.next()) });
+
+        assert_eq!(
+            Some(&Error::Timeout(timeout)),
+            result.as_ref().err().map(Error::kind)
+        );
+        assert_eq!(
+            "Test::yeah: no response after 50ms",
+            result.unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_callback_dropped() {
+        let (test, mut tr) = Test::channel();
+
+        let (result, _) = block_on(async {
+            join!(test.yeah(), async {
+                let msg = tr.next().await;
+                assert!(matches!(msg, Some(TestMessage::Yeah { .. })));
+            })
+        });
+
+        assert!(matches!(result.unwrap_err().kind(), Error::Canceled(_)));
+    }
 }
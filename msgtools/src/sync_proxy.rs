@@ -0,0 +1,354 @@
+//! Blocking counterpart to [`proxy`](crate::proxy), for callers that are not
+//! running inside an async runtime.
+
+use std::sync::mpsc;
+
+use thiserror::Error;
+
+/// Like [`proxy!`](crate::proxy!), but the generated methods block until the
+/// message has been handled instead of being async, and messages are sent
+/// over a bounded [`std::sync::mpsc`] channel. The receiving end can still
+/// be served from an async task, for example with
+/// `tokio::task::spawn_blocking`.
+#[macro_export]
+macro_rules! sync_proxy {
+    (
+        $v:vis proxy $name:ident {
+            $(
+                $fv:vis fn $fn_name:ident ($($p:ident : $pty:ty),* $(,)?) $(-> $rty:ty)?;
+            )*
+        }
+    ) => {
+        $crate::paste::paste! {
+            $v struct $name {
+                pipe: std::sync::mpsc::SyncSender< [<$name Message>] >,
+            }
+
+            impl $name {
+                $v fn channel() -> ($name, [<$name Receiver>]) {
+                    let (tx, rx) = std::sync::mpsc::sync_channel($crate::proxy::DEFAULT_CAPACITY);
+
+                    ($name { pipe: tx }, rx)
+                }
+            }
+
+            impl $name {
+                $(
+                    $fv fn $fn_name (&self, $($p : $pty),* ) -> $crate::sync_proxy::Result $(< $rty >)? {
+                        let (c, h) = $crate::sync_proxy::oneshot::channel();
+
+                        let msg = [<$name Message>] :: [< $fn_name:camel >] {
+                            $($p,)*
+                            callback: c.into()
+                        };
+
+                        self.pipe.send(msg)?;
+
+                        Ok(h.recv()?)
+                    }
+                )*
+            }
+
+            type [<$name Receiver>] = std::sync::mpsc::Receiver< [<$name Message>] >;
+
+            #[derive(Debug)]
+            $v enum [<$name Message>] {
+                $( [< $fn_name:camel >] { $($p : $pty,)* callback: $crate::sync_proxy::Callback $( < $rty > )? } ),*
+            }
+        }
+    };
+}
+
+pub type Result<T = (), E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+#[must_use = "this callback must be used to return a value to the caller"]
+pub struct Callback<T = ()> {
+    pipe: oneshot::Sender<T>,
+}
+
+impl<T> Callback<T> {
+    pub fn send(self, t: T) -> Result<(), T> {
+        self.pipe.send(t)
+    }
+}
+
+impl<T> From<oneshot::Sender<T>> for Callback<T> {
+    fn from(pipe: oneshot::Sender<T>) -> Self {
+        Callback { pipe }
+    }
+}
+
+#[derive(Error, Clone, Eq, PartialEq, Debug)]
+pub enum Error {
+    #[error("the receiver was dropped")]
+    Disconnected,
+    #[error("{0}")]
+    Canceled(#[from] oneshot::Canceled),
+}
+
+impl<T> From<mpsc::SendError<T>> for Error {
+    fn from(_: mpsc::SendError<T>) -> Self {
+        Error::Disconnected
+    }
+}
+
+pub mod oneshot {
+    use std::fmt::{Debug, Formatter};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::time::Duration;
+
+    use thiserror::Error;
+
+    /// Creates a new blocking oneshot channel.
+    pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+        let inner = Arc::new(Inner {
+            state: Mutex::new(State {
+                value: None,
+                sender_dropped: false,
+                receiver_dropped: false,
+            }),
+            cond: Condvar::new(),
+        });
+
+        (
+            Sender {
+                inner: inner.clone(),
+            },
+            Receiver { inner },
+        )
+    }
+
+    struct Inner<T> {
+        state: Mutex<State<T>>,
+        cond: Condvar,
+    }
+
+    struct State<T> {
+        value: Option<T>,
+        sender_dropped: bool,
+        receiver_dropped: bool,
+    }
+
+    pub struct Sender<T> {
+        inner: Arc<Inner<T>>,
+    }
+
+    pub struct Receiver<T> {
+        inner: Arc<Inner<T>>,
+    }
+
+    #[derive(Error, Debug, Copy, Clone, Eq, PartialEq, Hash)]
+    #[error("oneshot canceled")]
+    pub struct Canceled;
+
+    impl<T> Sender<T> {
+        /// Completes the channel with `t`, waking up the receiver. If the
+        /// receiver has already been dropped, `t` is handed back.
+        pub fn send(self, t: T) -> Result<(), T> {
+            let mut state = self.inner.state.lock().unwrap();
+
+            if state.receiver_dropped {
+                return Err(t);
+            }
+
+            state.value = Some(t);
+            self.inner.cond.notify_all();
+
+            Ok(())
+        }
+
+        pub fn is_canceled(&self) -> bool {
+            self.inner.state.lock().unwrap().receiver_dropped
+        }
+    }
+
+    impl<T> Drop for Sender<T> {
+        fn drop(&mut self) {
+            let mut state = self.inner.state.lock().unwrap();
+            state.sender_dropped = true;
+            self.inner.cond.notify_all();
+        }
+    }
+
+    impl<T> Receiver<T> {
+        /// Blocks until a value is sent, or returns `Canceled` if the sender
+        /// was dropped without sending one.
+        pub fn recv(self) -> Result<T, Canceled> {
+            let mut state = self.inner.state.lock().unwrap();
+
+            loop {
+                if let Some(v) = state.value.take() {
+                    return Ok(v);
+                }
+
+                if state.sender_dropped {
+                    return Err(Canceled);
+                }
+
+                state = self.inner.cond.wait(state).unwrap();
+            }
+        }
+
+        /// Like [`recv`](Self::recv), but gives up after `timeout` and
+        /// returns `Ok(None)`. The receiver can be waited on again afterwards.
+        pub fn wait_for(&mut self, timeout: Duration) -> Result<Option<T>, Canceled> {
+            let state = self.inner.state.lock().unwrap();
+
+            let (mut state, _) = self
+                .inner
+                .cond
+                .wait_timeout_while(state, timeout, |st| {
+                    st.value.is_none() && !st.sender_dropped
+                })
+                .unwrap();
+
+            match state.value.take() {
+                Some(v) => Ok(Some(v)),
+                None if state.sender_dropped => Err(Canceled),
+                None => Ok(None),
+            }
+        }
+
+        /// Returns the value if one has been sent already, without blocking.
+        pub fn try_recv(&mut self) -> Result<Option<T>, Canceled> {
+            let mut state = self.inner.state.lock().unwrap();
+
+            match state.value.take() {
+                Some(v) => Ok(Some(v)),
+                None if state.sender_dropped => Err(Canceled),
+                None => Ok(None),
+            }
+        }
+    }
+
+    impl<T> Drop for Receiver<T> {
+        fn drop(&mut self) {
+            self.inner.state.lock().unwrap().receiver_dropped = true;
+        }
+    }
+
+    impl<T> Debug for Sender<T> {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Sender").finish_non_exhaustive()
+        }
+    }
+
+    impl<T> Debug for Receiver<T> {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Receiver").finish_non_exhaustive()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::oneshot::{self, Canceled};
+    use super::{Callback, Error};
+
+    sync_proxy! {
+        pub proxy Counter {
+            pub fn add(n: u32) -> u32;
+            pub fn reset();
+        }
+    }
+
+    fn run(rx: CounterReceiver) {
+        let mut count = 0;
+
+        for msg in rx {
+            match msg {
+                CounterMessage::Add { n, callback } => {
+                    count += n;
+                    let _ = callback.send(count);
+                }
+                CounterMessage::Reset { callback } => {
+                    count = 0;
+                    let _ = callback.send(());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_send() {
+        let (tx, rx) = oneshot::channel();
+        tx.send(5).unwrap();
+        assert_eq!(Ok(5), rx.recv());
+    }
+
+    #[test]
+    fn test_send_from_thread() {
+        let (tx, rx) = oneshot::channel::<String>();
+        let callback = Callback::from(tx);
+
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            callback.send("hello".to_string()).unwrap();
+        });
+
+        assert_eq!(Ok("hello".to_string()), rx.recv());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_canceled() {
+        let (tx, rx) = oneshot::channel::<()>();
+
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(tx);
+        });
+
+        assert_eq!(Err(Canceled), rx.recv());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_receiver_dropped() {
+        let (tx, rx) = oneshot::channel();
+        drop(rx);
+        assert!(tx.is_canceled());
+        assert_eq!(Err(1), tx.send(1));
+    }
+
+    #[test]
+    fn test_proxy() {
+        let (counter, rx) = Counter::channel();
+        let handle = thread::spawn(move || run(rx));
+
+        assert_eq!(Ok(2), counter.add(2));
+        assert_eq!(Ok(5), counter.add(3));
+        assert_eq!(Ok(()), counter.reset());
+        assert_eq!(Ok(1), counter.add(1));
+
+        // the service stops once all proxies are gone
+        drop(counter);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_proxy_disconnected() {
+        let (counter, rx) = Counter::channel();
+        drop(rx);
+        assert_eq!(Err(Error::Disconnected), counter.add(1));
+
+        // the message is received, but never answered
+        let (counter, rx) = Counter::channel();
+        let handle = thread::spawn(move || drop(rx.recv()));
+        assert_eq!(Err(Error::Canceled(Canceled)), counter.reset());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_wait_for() {
+        let (tx, mut rx) = oneshot::channel();
+        assert_eq!(Ok(None), rx.wait_for(Duration::from_millis(10)));
+        assert_eq!(Ok(None), rx.try_recv());
+        tx.send(1).unwrap();
+        assert_eq!(Ok(Some(1)), rx.wait_for(Duration::from_millis(10)));
+    }
+}
@@ -128,6 +128,14 @@ pub async fn handle_packet(
             server_state.remove_channel(p.get_channel_id());
             ResultAction::Continue(state)
         }
+        ControlPacket::ServerConfig(p) => {
+            server_state.update_server_config(*p);
+            ResultAction::Continue(state)
+        }
+        ControlPacket::CodecVersion(p) => {
+            server_state.update_codec_version(*p);
+            ResultAction::Continue(state)
+        }
         x => {
             debug!("Unhandled packet: {:?}", x);
 
@@ -1,9 +1,12 @@
+use bit_set::BitSet;
+
 use crate::server_state::{ChannelRef, UserRef};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Event {
     Message(Message),
     UserMoved(UserMoved),
+    ChannelUpdated(ChannelUpdated),
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -11,6 +14,9 @@ pub struct Message {
     pub actor: Option<UserRef>,
     pub receivers: Vec<UserRef>,
     pub channels: Vec<ChannelRef>,
+    /// Channels the message was sent to along with all of their
+    /// subchannels.
+    pub tree_channels: Vec<ChannelRef>,
     pub message: String,
     pub html_message: String,
 }
@@ -21,3 +27,11 @@ pub struct UserMoved {
     pub old_channel: ChannelRef,
     pub new_channel: ChannelRef,
 }
+
+/// A channel was changed. Only sent when its links change for now.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ChannelUpdated {
+    pub channel: ChannelRef,
+    /// The IDs of the channels it is linked to now.
+    pub links: BitSet,
+}
@@ -1,6 +1,7 @@
 #![feature(try_trait_v2)]
 
 use std::path::Path;
+use std::time::Duration;
 
 use futures::stream::StreamExt;
 use futures::SinkExt;
@@ -20,34 +21,60 @@ use msgtools::{proxy, Ac};
 
 use crate::connect::{HandshakeState, ResultAction};
 pub use crate::event::Event;
+pub use crate::loopback::LoopbackStats;
+pub use crate::registered::RegisteredUser;
 use crate::server_state::{Channel, ChannelRef, ServerState, User, UserRef};
+pub use crate::tasks::encoder::EncoderStats;
+pub use crate::whisper::WhisperMode;
 
 mod connect;
 pub mod event;
+pub mod loopback;
+mod registered;
 mod server_state;
 mod tasks;
+mod whisper;
 
 const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Debug, Clone)]
 pub struct MumbleConfig {
     pub username: String,
+    /// Length of the audio frames sent to the server, in milliseconds. Needs
+    /// to be a frame size Opus supports, i.e. 5, 10, 20, 40 or 60.
+    pub audio_frame_ms: u32,
+    /// How many audio frames to send to the server at once. Sending them in
+    /// bursts evens out delays on the way, but each frame waits for the
+    /// ones after it, so this adds up to `(send_burst - 1) * audio_frame_ms`
+    /// milliseconds of latency. 1 sends every frame as soon as it is
+    /// encoded.
+    pub send_burst: usize,
 }
 
 proxy! {
     pub proxy MumbleClient {
-        pub async fn broadcast_message_checked(channels: Vec<ChannelRef>, users: Vec<UserRef>, text: String) -> Result<(), MessageError>;
+        pub async fn broadcast_message_checked(channels: Vec<ChannelRef>, trees: Vec<ChannelRef>, users: Vec<UserRef>, text: String) -> Result<(), MessageError>;
         pub async fn set_comment(comment: String);
-        pub async fn my_user() -> Ac<User>;
+        pub async fn set_avatar(image: Vec<u8>) -> Result<(), MessageError>;
+        pub async fn join_channel(channel: ChannelRef);
+        pub async fn whisper_mode(mode: WhisperMode);
+        pub async fn my_user() -> Option<Ac<User>>;
         pub async fn my_user_ref() -> UserRef;
-        pub async fn my_channel() -> Ac<Channel>;
-        pub async fn my_channel_ref() -> ChannelRef;
+        pub async fn my_channel() -> Option<Ac<Channel>>;
+        pub async fn my_channel_ref() -> Option<ChannelRef>;
         pub async fn get_user(r: UserRef) -> Option<Ac<User>>;
         pub async fn state() -> Ac<ServerState>;
         pub async fn max_message_length() -> Option<u32>;
+        pub async fn max_image_message_length() -> Option<u32>;
         pub async fn allow_html_messages() -> Option<bool>;
         pub async fn audio_input() -> NodeIndex;
+        pub async fn audio_frame_length() -> Duration;
         pub async fn event_subscriber() -> broadcast::Receiver<Event>;
+        pub async fn request_user_list() -> Vec<RegisteredUser>;
+        pub async fn event_subscriber_with_snapshot() -> (Vec<User>, Vec<Channel>, broadcast::Receiver<Event>);
+        pub async fn encoder_stats() -> EncoderStats;
+        pub async fn start_loopback();
+        pub async fn stop_loopback() -> LoopbackStats;
         pub async fn close();
     }
 }
@@ -56,6 +83,8 @@ proxy! {
 pub enum MessageError {
     #[error("message too long: {0} > {1}")]
     MessageTooLong(usize, usize),
+    #[error("image too large: {0} > {1} bytes")]
+    ImageTooLarge(usize, usize),
 }
 
 impl MumbleClient {
@@ -123,6 +152,8 @@ impl MumbleClient {
             udp,
             peer_addr,
             ac.add_output(),
+            config.audio_frame_ms,
+            config.send_burst,
             server_state,
             UserRef::new(session_id),
         );
@@ -131,14 +162,17 @@ impl MumbleClient {
         Ok(client)
     }
 
+    /// Sends a message to `channels`, to `trees` and all of their
+    /// subchannels, and to `users`.
     pub async fn broadcast_message(
         &self,
         channels: Vec<ChannelRef>,
+        trees: Vec<ChannelRef>,
         users: Vec<UserRef>,
         text: String,
     ) -> proxy::Result {
         if let Err(e) = self
-            .broadcast_message_checked(channels, users, text)
+            .broadcast_message_checked(channels, trees, users, text)
             .await?
         {
             warn!("failed to send message: {}", e);
@@ -147,8 +181,51 @@ impl MumbleClient {
         Ok(())
     }
 
+    /// Sends a message to the channel the client is in. The message is
+    /// dropped if the server didn't tell the client which channel that is
+    /// yet.
     pub async fn message_my_channel(&self, text: &str) -> proxy::Result {
-        self.message_channel(self.my_channel_ref().await?, text)
+        match self.my_channel_ref().await? {
+            None => {
+                warn!("not sending message, own channel is not known yet");
+                Ok(())
+            }
+            Some(channel) => self.message_channel(channel, text).await,
+        }
+    }
+
+    /// Sends a message to the channel the client is in. If `linked` is set,
+    /// the channels linked to it get the message too, since users there hear
+    /// the client as well. Like [`MumbleClient::message_my_channel`], the
+    /// message is dropped if the channel isn't known yet.
+    pub async fn announce<S>(&self, text: S, linked: bool) -> proxy::Result
+    where
+        S: Into<String>,
+    {
+        let channel = match self.my_channel_ref().await? {
+            None => {
+                warn!("not sending announcement, own channel is not known yet");
+                return Ok(());
+            }
+            Some(channel) => channel,
+        };
+
+        if linked {
+            self.message_linked(channel, text).await
+        } else {
+            self.message_channel(channel, text).await
+        }
+    }
+
+    /// Sends a message to `channel` and all channels linked to it, directly
+    /// or through other linked channels.
+    pub async fn message_linked<S>(&self, channel: ChannelRef, text: S) -> proxy::Result
+    where
+        S: Into<String>,
+    {
+        let channels = self.state().await?.linked_channels(channel);
+
+        self.broadcast_message(channels, vec![], vec![], text.into())
             .await
     }
 
@@ -156,7 +233,7 @@ impl MumbleClient {
     where
         S: Into<String>,
     {
-        self.broadcast_message(vec![channel], vec![], text.into())
+        self.broadcast_message(vec![channel], vec![], vec![], text.into())
             .await
     }
 
@@ -164,10 +241,13 @@ impl MumbleClient {
     where
         S: Into<String>,
     {
-        self.broadcast_message(vec![], vec![user], text.into())
+        self.broadcast_message(vec![], vec![], vec![user], text.into())
             .await
     }
 
+    /// Replies to a message in the same scope it was sent in: the channels
+    /// and channel trees it was sent to, and its sender and the users it was
+    /// sent to privately.
     pub async fn respond<S>(&self, ev: &event::Message, text: S) -> proxy::Result
     where
         S: Into<String>,
@@ -180,6 +260,7 @@ impl MumbleClient {
 
         self.broadcast_message(
             ev.channels.iter().cloned().collect(),
+            ev.tree_channels.iter().cloned().collect(),
             users.into_iter().collect(),
             text.into(),
         )
@@ -0,0 +1,89 @@
+use audiopus::coder::Decoder;
+use audiopus::{Channels, SampleRate};
+use log::debug;
+use mumble_protocol::voice::VoicePacketPayload;
+
+/// The voice target that makes the server send audio back to the client that
+/// sent it, instead of to the other users in the channel.
+pub const LOOPBACK_TARGET: u8 = 31;
+
+/// Longest frame Opus can produce, 120 ms at 48 kHz.
+const MAX_FRAME_SAMPLES: usize = 5760;
+
+/// What happened to the audio sent between
+/// [`MumbleClient::start_loopback`](crate::MumbleClient::start_loopback) and
+/// [`MumbleClient::stop_loopback`](crate::MumbleClient::stop_loopback). While
+/// loopback is enabled, audio goes to [`LOOPBACK_TARGET`] and the server
+/// echoes it back, so other users don't hear the client.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LoopbackStats {
+    /// Packets produced by the encoder.
+    pub encoded: u64,
+    /// Packets successfully sent to the server.
+    pub sent: u64,
+    /// Packets the server sent back.
+    pub received: u64,
+    /// Returned packets that could be decoded.
+    pub decoded: u64,
+    /// Highest amplitude in the decoded audio, from 0 to 1.
+    pub peak: f32,
+}
+
+pub(crate) struct Loopback {
+    stats: LoopbackStats,
+    decoder: Decoder,
+    pcm_buf: Vec<i16>,
+}
+
+impl Loopback {
+    pub fn new() -> Self {
+        Loopback {
+            stats: LoopbackStats::default(),
+            decoder: Decoder::new(SampleRate::Hz48000, Channels::Mono).unwrap(),
+            pcm_buf: vec![0; MAX_FRAME_SAMPLES],
+        }
+    }
+
+    pub fn on_encoded(&mut self) {
+        self.stats.encoded += 1;
+    }
+
+    pub fn on_sent(&mut self) {
+        self.stats.sent += 1;
+    }
+
+    pub fn on_received(&mut self, payload: &VoicePacketPayload) {
+        self.stats.received += 1;
+
+        let data = match payload {
+            VoicePacketPayload::Opus(data, _) => data,
+            _ => {
+                debug!("received non-Opus loopback packet");
+                return;
+            }
+        };
+
+        match self
+            .decoder
+            .decode(Some(&data[..]), &mut self.pcm_buf, false)
+        {
+            Ok(len) => {
+                self.stats.decoded += 1;
+
+                let peak = self.pcm_buf[..len]
+                    .iter()
+                    .map(|&s| (s as f32 / i16::MAX as f32).abs())
+                    .fold(self.stats.peak, f32::max);
+
+                self.stats.peak = peak.min(1.0);
+            }
+            Err(e) => {
+                debug!("failed to decode loopback packet: {}", e);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> LoopbackStats {
+        self.stats
+    }
+}
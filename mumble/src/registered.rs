@@ -0,0 +1,43 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use mumble_protocol::control::msgs;
+
+use crate::server_state::ChannelRef;
+
+/// A user registered on the server, who isn't necessarily connected, see
+/// [`MumbleClient::request_user_list`](crate::MumbleClient::request_user_list).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RegisteredUser {
+    pub id: u32,
+    pub name: String,
+    /// The channel the user was in when they last disconnected.
+    pub last_channel: ChannelRef,
+    /// When the user was last connected, if the server sent it.
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+impl RegisteredUser {
+    pub(crate) fn from_msg(user: &msgs::UserList_User) -> Self {
+        let last_seen = if user.has_last_seen() {
+            parse_last_seen(user.get_last_seen())
+        } else {
+            None
+        };
+
+        RegisteredUser {
+            id: user.get_user_id(),
+            name: user.get_name().to_string(),
+            last_channel: ChannelRef::new(user.get_last_channel()),
+            last_seen,
+        }
+    }
+}
+
+/// Parses the time a user was last seen. Murmur sends it in ISO 8601 format
+/// in UTC, usually without a time zone.
+fn parse_last_seen(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(t) = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f") {
+        return Some(DateTime::from_utc(t, Utc));
+    }
+
+    s.parse().ok()
+}
@@ -1,12 +1,13 @@
 use std::collections::HashMap;
 
 use bit_set::BitSet;
+use log::warn;
 use mumble_protocol::control::msgs;
 use tokio::sync::broadcast;
 
 use msgtools::Ac;
 
-use crate::event::UserMoved;
+use crate::event::{ChannelUpdated, UserMoved};
 use crate::Event;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -42,6 +43,11 @@ pub struct ServerState {
     channels: HashMap<u32, Ac<Channel>>,
     users: HashMap<u32, Ac<User>>,
     max_message_length: Option<u32>,
+    max_image_message_length: Option<u32>,
+    allow_html: Option<bool>,
+    /// Whether the server wants clients to send Opus, from the last
+    /// CodecVersion message. Unknown until the server sends one.
+    opus: Option<bool>,
     event_subscriber: broadcast::Sender<Event>,
 }
 
@@ -139,6 +145,9 @@ impl ServerState {
             channels: Default::default(),
             users: Default::default(),
             max_message_length: None,
+            max_image_message_length: None,
+            allow_html: None,
+            opus: None,
             event_subscriber,
         }
     }
@@ -151,6 +160,71 @@ impl ServerState {
         self.channels.get(&id).cloned()
     }
 
+    /// Returns all users currently connected to the server.
+    pub fn users(&self) -> impl Iterator<Item = &Ac<User>> {
+        self.users.values()
+    }
+
+    /// Returns all channels on the server.
+    pub fn channels(&self) -> impl Iterator<Item = &Ac<Channel>> {
+        self.channels.values()
+    }
+
+    /// The sender events derived from state updates are sent to.
+    pub(crate) fn event_sender(&self) -> &broadcast::Sender<Event> {
+        &self.event_subscriber
+    }
+
+    /// Finds a channel by its name. Channel names are only unique among
+    /// siblings, so if there are several matches, any one of them is returned.
+    pub fn channel_by_name(&self, name: &str) -> Option<Ac<Channel>> {
+        self.channels.values().find(|c| c.name() == name).cloned()
+    }
+
+    /// Returns `channel` and all channels linked to it, directly or through
+    /// other linked channels. Users in any of these channels hear each other.
+    pub fn linked_channels(&self, channel: ChannelRef) -> Vec<ChannelRef> {
+        let mut seen = BitSet::new();
+        let mut result = vec![channel];
+        let mut next = 0;
+
+        seen.insert(channel.id as usize);
+
+        while let Some(&current) = result.get(next) {
+            next += 1;
+
+            let links = match self.channels.get(&current.id) {
+                None => continue,
+                Some(c) => &c.links,
+            };
+
+            for id in links.iter() {
+                if seen.insert(id) {
+                    result.push(ChannelRef::new(id as u32));
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Returns the users in `channel`. If `linked` is set, the users in the
+    /// channels linked to it are included as well, since they hear whatever
+    /// is played there.
+    pub fn users_in_channel(&self, channel: ChannelRef, linked: bool) -> Vec<Ac<User>> {
+        let channels = if linked {
+            self.linked_channels(channel)
+        } else {
+            vec![channel]
+        };
+
+        self.users
+            .values()
+            .filter(|u| channels.contains(&u.channel))
+            .cloned()
+            .collect()
+    }
+
     pub fn update_user(&mut self, mut state: msgs::UserState) {
         let session_id = state.get_session();
 
@@ -188,6 +262,20 @@ impl ServerState {
         self.max_message_length
     }
 
+    /// Returns the maximum size of messages containing images, which also
+    /// applies to avatars. `None` if the server doesn't limit it.
+    pub fn max_image_message_length(&self) -> Option<u32> {
+        self.max_image_message_length
+    }
+
+    pub fn allow_html(&self) -> Option<bool> {
+        self.allow_html
+    }
+
+    pub fn opus(&self) -> Option<bool> {
+        self.opus
+    }
+
     pub fn remove_user(&mut self, session_id: u32) {
         self.users.remove(&session_id);
     }
@@ -214,6 +302,8 @@ impl ServerState {
             channel.parent = ChannelRef::new(state.get_parent());
         }
 
+        let old_links = channel.links.clone();
+
         if !state.get_links().is_empty() {
             channel.links.clear();
             channel
@@ -228,6 +318,15 @@ impl ServerState {
             channel.links.remove(*el as usize);
         }
 
+        if channel.links != old_links {
+            let _ = self
+                .event_subscriber
+                .send(Event::ChannelUpdated(ChannelUpdated {
+                    channel: channel.to_ref(),
+                    links: channel.links.clone(),
+                }));
+        }
+
         if state.has_description() {
             channel.description = state.take_description();
         }
@@ -245,5 +344,126 @@ impl ServerState {
         if config.has_message_length() {
             self.max_message_length = Some(config.get_message_length());
         }
+
+        if config.has_image_message_length() {
+            // 0 means unlimited here
+            self.max_image_message_length =
+                Some(config.get_image_message_length()).filter(|&l| l != 0);
+        }
+
+        if config.has_allow_html() {
+            self.allow_html = Some(config.get_allow_html());
+        }
+    }
+
+    pub fn update_codec_version(&mut self, codec: msgs::CodecVersion) {
+        let opus = codec.get_opus();
+
+        // the server sends this again whenever a user joins, only warn once
+        if !opus && self.opus != Some(false) {
+            warn!(
+                "server wants clients to use CELT ({:#x}/{:#x}), but only Opus is supported, \
+                 users without Opus support won't hear anything",
+                codec.get_alpha(),
+                codec.get_beta()
+            );
+        }
+
+        self.opus = Some(opus);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mumble_protocol::control::msgs;
+    use tokio::sync::broadcast;
+
+    use super::{ChannelRef, ServerState};
+    use crate::event::ChannelUpdated;
+    use crate::Event;
+
+    fn link(st: &mut ServerState, id: u32, links: &[u32]) {
+        let mut state = msgs::ChannelState::new();
+        state.set_channel_id(id);
+        state.set_links(links.to_vec());
+        st.update_channel(state);
+    }
+
+    fn join(st: &mut ServerState, session: u32, channel: u32) {
+        let mut state = msgs::UserState::new();
+        state.set_session(session);
+        state.set_channel_id(channel);
+        st.update_user(state);
+    }
+
+    fn ids(channels: Vec<ChannelRef>) -> Vec<u32> {
+        let mut ids: Vec<_> = channels.iter().map(|c| c.id()).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn test_linked_channels() {
+        let (tx, _) = broadcast::channel(20);
+        let mut st = ServerState::new(tx);
+
+        // 0 - 1 - 2, 3 on its own
+        link(&mut st, 0, &[1]);
+        link(&mut st, 1, &[0, 2]);
+        link(&mut st, 2, &[1]);
+        link(&mut st, 3, &[]);
+
+        assert_eq!(vec![0, 1, 2], ids(st.linked_channels(ChannelRef::new(2))));
+        assert_eq!(vec![3], ids(st.linked_channels(ChannelRef::new(3))));
+        // not known yet
+        assert_eq!(vec![4], ids(st.linked_channels(ChannelRef::new(4))));
+
+        join(&mut st, 10, 0);
+        join(&mut st, 11, 2);
+        join(&mut st, 12, 3);
+
+        let users = |linked| {
+            let mut ids: Vec<_> = st
+                .users_in_channel(ChannelRef::new(0), linked)
+                .iter()
+                .map(|u| u.id())
+                .collect();
+            ids.sort_unstable();
+            ids
+        };
+
+        assert_eq!(vec![10], users(false));
+        assert_eq!(vec![10, 11], users(true));
+    }
+
+    #[test]
+    fn test_link_event() {
+        let (tx, mut rx) = broadcast::channel(20);
+        let mut st = ServerState::new(tx);
+
+        link(&mut st, 1, &[2]);
+
+        match rx.try_recv() {
+            Ok(Event::ChannelUpdated(ChannelUpdated { channel, links })) => {
+                assert_eq!(ChannelRef::new(1), channel);
+                assert_eq!(vec![2], links.iter().collect::<Vec<_>>());
+            }
+            v => panic!("unexpected event: {:?}", v),
+        }
+
+        // the same links again, nothing changed
+        link(&mut st, 1, &[2]);
+
+        let mut state = msgs::ChannelState::new();
+        state.set_channel_id(1);
+        state.set_links_remove(vec![2]);
+        st.update_channel(state);
+
+        match rx.try_recv() {
+            Ok(Event::ChannelUpdated(ChannelUpdated { links, .. })) => assert!(links.is_empty()),
+            v => panic!("unexpected event: {:?}", v),
+        }
+
+        assert!(rx.try_recv().is_err());
     }
 }
@@ -1,9 +1,8 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex as SyncMutex};
 use std::time::Duration;
 
 use audiopus::{Application, Channels, SampleRate};
 use bytes::Bytes;
-use dasp::sample::ToSample;
 use dasp::{Frame, Sample, Signal};
 use log::debug;
 use mumble_protocol::voice::VoicePacketPayload;
@@ -11,17 +10,30 @@ use tokio::select;
 use tokio::sync::{mpsc, Mutex};
 use tokio::time;
 
-pub(super) async fn encoder<S>(
+use audiopipe::OutputSignal;
+
+/// Counters describing how well the encoder keeps up.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct EncoderStats {
+    /// Voice packets handed on to be sent to the server.
+    pub packets_sent: u64,
+    /// Total size of the Opus data in those packets.
+    pub bytes_sent: u64,
+    /// Frames for which the audio graph hadn't produced enough audio yet, so
+    /// part of the frame was filled with silence.
+    pub underruns: u64,
+}
+
+pub(super) async fn encoder(
     voice_tx: mpsc::Sender<VoicePacketPayload>,
-    pipe: Arc<Mutex<S>>,
+    pipe: Arc<Mutex<OutputSignal>>,
+    stats: Arc<SyncMutex<EncoderStats>>,
+    frame_ms: u32,
     // mut stop_recv: watch::Receiver<()>,
-) where
-    S: Signal,
-    <S::Frame as Frame>::Sample: ToSample<i16>,
-{
+) {
     let mut pipe = pipe.lock().await;
 
-    let ms_buf_size = 10;
+    let ms_buf_size = frame_ms as usize;
     let sample_rate = SampleRate::Hz48000;
     let samples = sample_rate as usize * ms_buf_size / 1000;
 
@@ -44,6 +56,10 @@ pub(super) async fn encoder<S>(
 
             let mut is_empty = true;
 
+            if pipe.buffered() < pcm_buf.len() {
+                stats.lock().unwrap().underruns += 1;
+            }
+
             for (idx, frame) in pipe.by_ref().take(pcm_buf.len()).enumerate() {
                 // adjust volume
                 // let frame = frame.map(|s| s.to_sample() as i16).scale_amp(0.1);
@@ -62,12 +78,18 @@ pub(super) async fn encoder<S>(
             if !(is_empty && last_was_empty) {
                 let len = encoder.encode(&pcm_buf, &mut opus_buf).unwrap();
 
-                let _ = voice_tx
+                let result = voice_tx
                     .send(VoicePacketPayload::Opus(
                         Bytes::copy_from_slice(&opus_buf[..len]),
                         is_empty,
                     ))
                     .await;
+
+                if result.is_ok() {
+                    let mut stats = stats.lock().unwrap();
+                    stats.packets_sent += 1;
+                    stats.bytes_sent += len as u64;
+                }
             }
 
             last_was_empty = is_empty;
@@ -3,13 +3,13 @@ use std::fmt::Display;
 use std::io;
 use std::net::SocketAddr;
 use std::ops::{ControlFlow, Try};
-use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use futures::{Sink, SinkExt, Stream, StreamExt};
-use log::{debug, error};
+use log::{debug, error, warn};
 use mumble_protocol::control::{msgs, ControlPacket};
-use mumble_protocol::voice::VoicePacket;
+use mumble_protocol::voice::{VoicePacket, VoicePacketPayload};
 use mumble_protocol::{Clientbound, Serverbound};
 use petgraph::graph::NodeIndex;
 use tokio::select;
@@ -17,15 +17,31 @@ use tokio::sync::{broadcast, mpsc, Mutex as AsyncMutex};
 use tokio::time::interval;
 
 use audiopipe::OutputSignal;
-use encoder::encoder;
+use encoder::{encoder, EncoderStats};
+use send_queue::SendQueue;
+use msgtools::proxy::Callback;
 use msgtools::Ac;
 use html_parser::{Dom, Node};
 
 use crate::event::{Event, Message};
+use crate::loopback::{Loopback, LoopbackStats, LOOPBACK_TARGET};
+use crate::registered::RegisteredUser;
 use crate::server_state::{ChannelRef, ServerState, UserRef};
+use crate::whisper::WhisperMode;
 use crate::{MessageError, MumbleClientMessage, MumbleClientReceiver};
 
-mod encoder;
+pub(crate) mod encoder;
+mod send_queue;
+
+/// How long to hold back audio after connecting if the server hasn't said
+/// that it wants Opus. Some servers don't forward audio in a codec they
+/// haven't announced, so this waits for the CodecVersion message, but sends
+/// anyway if the server never sends one.
+const CODEC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The ACL permission needed to register users, see `ChanACL::Perm` in
+/// Murmur.
+const PERMISSION_REGISTER: u32 = 0x40000;
 
 pub struct State<T, U> {
     pipe: MumbleClientReceiver,
@@ -34,10 +50,20 @@ pub struct State<T, U> {
     peer: SocketAddr,
     server_state: Ac<ServerState>,
     event_chan: broadcast::Sender<Event>,
-    audio_seq: u64,
+    send_queue: SendQueue,
     output: Arc<AsyncMutex<OutputSignal>>,
     output_id: NodeIndex,
+    encoder_stats: Arc<Mutex<EncoderStats>>,
+    frame_ms: u32,
     me: UserRef,
+    loopback: Option<Loopback>,
+    whisper_mode: WhisperMode,
+    /// Callers waiting for the server's list of registered users. Dropped
+    /// without an answer if the server refuses to send it.
+    user_list_requests: Vec<Callback<Vec<RegisteredUser>>>,
+    /// Until when audio is held back while waiting for the server's codec,
+    /// see [`CODEC_TIMEOUT`].
+    codec_deadline: Instant,
 }
 
 impl<T, U> State<T, U> {
@@ -47,10 +73,12 @@ impl<T, U> State<T, U> {
         udp: U,
         peer: SocketAddr,
         output: OutputSignal,
+        frame_ms: u32,
+        send_burst: usize,
         server_state: Ac<ServerState>,
         me: UserRef,
     ) -> Self {
-        let (event_chan, _) = broadcast::channel(20);
+        let event_chan = server_state.event_sender().clone();
         let output_id = output.node();
         let output = Arc::new(AsyncMutex::new(output));
 
@@ -61,14 +89,26 @@ impl<T, U> State<T, U> {
             peer,
             server_state,
             event_chan,
-            audio_seq: 0,
+            send_queue: SendQueue::new(send_burst),
             output,
             output_id,
+            encoder_stats: Default::default(),
+            frame_ms,
             me,
+            loopback: None,
+            whisper_mode: WhisperMode::None,
+            user_list_requests: Vec::new(),
+            codec_deadline: Instant::now() + CODEC_TIMEOUT,
         }
     }
 }
 
+/// The channel the bot is in, or `None` if the server hasn't sent the bot's
+/// own user state yet.
+fn my_channel_ref(st: &ServerState, me: UserRef) -> Option<ChannelRef> {
+    me.get(st).map(|user| user.channel())
+}
+
 macro_rules! try_or_break {
     ($e:expr) => {
         match Try::branch($e) {
@@ -94,7 +134,12 @@ where
         let mut ping_timer = interval(Duration::from_secs(2));
         let mut close_callback = None;
 
-        tokio::spawn(encoder(voice_tx, self.output.clone()));
+        tokio::spawn(encoder(
+            voice_tx,
+            self.output.clone(),
+            self.encoder_stats.clone(),
+            self.frame_ms,
+        ));
 
         loop {
             select! {
@@ -110,7 +155,7 @@ where
                     };
 
                     match msg {
-                        MumbleClientMessage::BroadcastMessageChecked { channels, users, text, callback } => {
+                        MumbleClientMessage::BroadcastMessageChecked { channels, trees, users, text, callback } => {
                             // this is awful, I know
                             let mut too_long = false;
                             let mut max_len = 0;
@@ -129,6 +174,8 @@ where
                                 let mut m = msgs::TextMessage::new();
                                 m.mut_channel_id()
                                     .extend(channels.into_iter().map(|el| el.id()));
+                                m.mut_tree_id()
+                                    .extend(trees.into_iter().map(|el| el.id()));
                                 m.mut_session()
                                     .extend(users.into_iter().map(|el| el.session_id()));
                                 m.set_message(text.to_string());
@@ -139,22 +186,61 @@ where
                         MumbleClientMessage::SetComment { comment, callback } => {
                             let mut state = msgs::UserState::new();
                             state.set_comment(comment);
+                            // the caller finds out if the connection is gone,
+                            // since the callback is dropped then
+                            try_or_break!(self.tcp.send(state.into()).await);
+                            let _ = callback.send(());
+                        }
+                        MumbleClientMessage::SetAvatar { image, callback } => {
+                            // the server drops oversized avatars without telling us
+                            match self.server_state.max_image_message_length() {
+                                Some(l) if image.len() > l as usize => {
+                                    let _ = callback.send(Err(MessageError::ImageTooLarge(image.len(), l as usize)));
+                                }
+                                _ => {
+                                    let mut state = msgs::UserState::new();
+                                    state.set_texture(image);
+                                    try_or_break!(self.tcp.send(state.into()).await);
+                                    let _ = callback.send(Ok(()));
+                                }
+                            }
+                        }
+                        MumbleClientMessage::JoinChannel { channel, callback } => {
+                            let mut state = msgs::UserState::new();
+                            state.set_session(self.me.session_id());
+                            state.set_channel_id(channel.id());
                             try_or_break!(self.tcp.send(state.into()).await);
                             let _ = callback.send(());
                         }
+                        MumbleClientMessage::WhisperMode { mode, callback } => {
+                            // what's still queued was meant for the old target
+                            let frames = self.send_queue.flush();
+                            try_or_break!(self.send_audio(frames).await);
+
+                            if let Some(msg) = mode.voice_target_message() {
+                                try_or_break!(self.tcp.send(msg.into()).await);
+                            }
+
+                            self.whisper_mode = mode;
+                            // a new target starts a new audio stream
+                            self.send_queue.reset();
+                            let _ = callback.send(());
+                        }
+                        // the user's state might not have arrived yet, these
+                        // return None until it does
                         MumbleClientMessage::MyUser { callback } => {
-                            let _ = callback.send(self.me.get(&self.server_state).expect("failed to find my user"));
+                            let _ = callback.send(self.me.get(&self.server_state));
                         }
                         MumbleClientMessage::MyUserRef { callback } => {
                             let _ = callback.send(self.me);
                         }
                         MumbleClientMessage::MyChannel { callback } => {
-                            let user = self.me.get(&self.server_state).expect("failed to find my user");
-                            let _ = callback.send(user.channel().get(&self.server_state).expect("my user is not in a channel?"));
+                            let channel = my_channel_ref(&self.server_state, self.me)
+                                .and_then(|channel| channel.get(&self.server_state));
+                            let _ = callback.send(channel);
                         }
                         MumbleClientMessage::MyChannelRef { callback } => {
-                            let user = self.me.get(&self.server_state).expect("failed to find my user");
-                            let _ = callback.send(user.channel());
+                            let _ = callback.send(my_channel_ref(&self.server_state, self.me));
                         }
                         MumbleClientMessage::GetUser { r, callback } => {
                             let user = r.get(&self.server_state);
@@ -166,16 +252,51 @@ where
                         MumbleClientMessage::MaxMessageLength { callback } => {
                             let _ = callback.send(self.server_state.max_message_length());
                         }
+                        MumbleClientMessage::MaxImageMessageLength { callback } => {
+                            let _ = callback.send(self.server_state.max_image_message_length());
+                        }
                         MumbleClientMessage::AllowHtmlMessages { callback } => {
-                            debug!("unimplemented");
-                            let _ = callback.send(None);
+                            let _ = callback.send(self.server_state.allow_html());
                         }
                         MumbleClientMessage::AudioInput { callback } => {
                             let _ = callback.send(self.output_id);
                         }
+                        MumbleClientMessage::AudioFrameLength { callback } => {
+                            let _ = callback.send(Duration::from_millis(self.frame_ms.into()));
+                        }
                         MumbleClientMessage::EventSubscriber { callback } => {
                             let _ = callback.send(self.event_chan.subscribe());
                         }
+                        MumbleClientMessage::RequestUserList { callback } => {
+                            // a request that is already on its way gets
+                            // answered with the same list
+                            if self.user_list_requests.is_empty() {
+                                try_or_break!(self.tcp.send(msgs::UserList::new().into()).await);
+                            }
+
+                            self.user_list_requests.push(callback);
+                        }
+                        MumbleClientMessage::EventSubscriberWithSnapshot { callback } => {
+                            // State updates and the events describing them
+                            // are both handled by this task, so nothing can
+                            // happen between taking the snapshot and
+                            // subscribing.
+                            let st = &self.server_state;
+                            let users = st.users().map(|u| (**u).clone()).collect();
+                            let channels = st.channels().map(|c| (**c).clone()).collect();
+                            let _ = callback.send((users, channels, self.event_chan.subscribe()));
+                        }
+                        MumbleClientMessage::EncoderStats { callback } => {
+                            let _ = callback.send(*self.encoder_stats.lock().unwrap());
+                        }
+                        MumbleClientMessage::StartLoopback { callback } => {
+                            self.loopback = Some(Loopback::new());
+                            let _ = callback.send(());
+                        }
+                        MumbleClientMessage::StopLoopback { callback } => {
+                            let stats = self.loopback.take().map_or_else(LoopbackStats::default, |l| l.stats());
+                            let _ = callback.send(stats);
+                        }
                         MumbleClientMessage::Close { callback } => {
                             close_callback = Some(callback);
                             break;
@@ -188,18 +309,16 @@ where
                         Some(v) => v,
                     };
 
-                    let packet = VoicePacket::Audio {
-                        _dst: Default::default(),
-                        target: 0,
-                        session_id: (),
-                        seq_num: self.audio_seq,
-                        payload: voice_packet,
-                        position_info: None,
-                    };
+                    if !self.codec_ready() {
+                        continue;
+                    }
 
-                    try_or_break!(self.udp.send((packet, self.peer)).await);
+                    if let Some(loopback) = &mut self.loopback {
+                        loopback.on_encoded();
+                    }
 
-                    self.audio_seq += 1;
+                    let frames = self.send_queue.push(voice_packet);
+                    try_or_break!(self.send_audio(frames).await);
                 }
                 msg = self.tcp.next() => {
                     let msg = match msg {
@@ -243,6 +362,39 @@ where
         }
     }
 
+    /// Sends audio frames taken from the [`SendQueue`] to the server.
+    async fn send_audio(&mut self, frames: Vec<(u64, VoicePacketPayload)>) -> Result<(), U::Error> {
+        let target = match self.loopback {
+            None => self.whisper_mode.target(),
+            Some(_) => LOOPBACK_TARGET,
+        };
+
+        for (seq_num, payload) in frames {
+            let packet = VoicePacket::Audio {
+                _dst: Default::default(),
+                target,
+                session_id: (),
+                seq_num,
+                payload,
+                position_info: None,
+            };
+
+            self.udp.send((packet, self.peer)).await?;
+
+            if let Some(loopback) = &mut self.loopback {
+                loopback.on_sent();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether audio can be sent, i.e. the server said that it wants
+    /// Opus or didn't say anything for too long.
+    fn codec_ready(&self) -> bool {
+        self.server_state.opus() == Some(true) || Instant::now() >= self.codec_deadline
+    }
+
     async fn send_ping(&mut self) -> bool {
         let utime = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -274,6 +426,9 @@ where
             ControlPacket::ChannelRemove(p) => self.handle_channel_remove(*p),
             ControlPacket::TextMessage(p) => self.handle_text_message(*p),
             ControlPacket::ServerConfig(p) => self.handle_server_config(*p),
+            ControlPacket::CodecVersion(p) => self.server_state.update_codec_version(*p),
+            ControlPacket::UserList(p) => self.handle_user_list(*p),
+            ControlPacket::PermissionDenied(p) => self.handle_permission_denied(*p),
             _ => {
                 debug!("Unhandled packet: {:?}", msg);
             }
@@ -283,7 +438,17 @@ where
     async fn handle_voice_packet(&mut self, msg: VoicePacket<Clientbound>) {
         match msg {
             VoicePacket::Ping { .. } => {}
-            VoicePacket::Audio { .. } => {}
+            VoicePacket::Audio {
+                session_id,
+                payload,
+                ..
+            } => {
+                if session_id == self.me.session_id() {
+                    if let Some(loopback) = &mut self.loopback {
+                        loopback.on_received(&payload);
+                    }
+                }
+            }
         }
     }
 
@@ -319,6 +484,11 @@ where
             .iter()
             .map(|v| ChannelRef::new(*v))
             .collect();
+        let tree_channels = msg
+            .get_tree_id()
+            .iter()
+            .map(|v| ChannelRef::new(*v))
+            .collect();
         let message = msg.take_message();
         let dom = match html_parser::Dom::parse(&message) {
             Ok(v) => v,
@@ -357,6 +527,7 @@ where
             actor,
             receivers,
             channels,
+            tree_channels,
             message: buf,
             html_message: message,
         });
@@ -364,7 +535,53 @@ where
         let _ = self.event_chan.send(event);
     }
 
+    fn handle_user_list(&mut self, msg: msgs::UserList) {
+        let users: Vec<_> = msg.get_users().iter().map(RegisteredUser::from_msg).collect();
+
+        for callback in self.user_list_requests.drain(..) {
+            let _ = callback.send(users.clone());
+        }
+    }
+
+    fn handle_permission_denied(&mut self, msg: msgs::PermissionDenied) {
+        debug!("Permission denied: {:?}", msg);
+
+        // listing registered users needs the register permission on the
+        // root channel
+        if msg.get_permission() == PERMISSION_REGISTER && !self.user_list_requests.is_empty() {
+            warn!("not allowed to list registered users");
+            self.user_list_requests.clear();
+        }
+    }
+
     fn handle_server_config(&mut self, msg: msgs::ServerConfig) {
         self.server_state.update_server_config(msg);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use mumble_protocol::control::msgs;
+    use tokio::sync::broadcast;
+
+    use super::my_channel_ref;
+    use crate::server_state::{ChannelRef, ServerState, UserRef};
+
+    #[test]
+    fn test_my_channel_ref() {
+        let (tx, _) = broadcast::channel(20);
+        let mut st = ServerState::new(tx);
+        let me = UserRef::new(10);
+
+        // our own user state hasn't arrived yet
+        assert_eq!(None, my_channel_ref(&st, me));
+
+        let mut state = msgs::UserState::new();
+        state.set_session(10);
+        state.set_channel_id(3);
+        st.update_user(state);
+
+        assert_eq!(Some(ChannelRef::new(3)), my_channel_ref(&st, me));
+        assert_eq!(None, my_channel_ref(&st, UserRef::new(11)));
+    }
+}
@@ -0,0 +1,102 @@
+use mumble_protocol::voice::VoicePacketPayload;
+
+/// Collects encoded audio frames to send them to the server in bursts of a
+/// fixed number of frames, see [`MumbleConfig::send_burst`]. Numbers the
+/// frames in the order they were pushed.
+///
+/// [`MumbleConfig::send_burst`]: crate::MumbleConfig::send_burst
+#[derive(Debug)]
+pub(crate) struct SendQueue {
+    depth: usize,
+    seq: u64,
+    frames: Vec<(u64, VoicePacketPayload)>,
+}
+
+impl SendQueue {
+    /// Creates a queue that sends `depth` frames at once. A depth of 0 is
+    /// treated as 1, which sends every frame right away.
+    pub fn new(depth: usize) -> Self {
+        let depth = depth.max(1);
+
+        SendQueue {
+            depth,
+            seq: 0,
+            frames: Vec::with_capacity(depth),
+        }
+    }
+
+    /// Adds a frame, and returns the frames to send together with their
+    /// sequence numbers once there are enough of them. The last frame of a
+    /// transmission is sent right away along with the ones before it, since
+    /// no more frames follow for a while.
+    pub fn push(&mut self, payload: VoicePacketPayload) -> Vec<(u64, VoicePacketPayload)> {
+        let end = matches!(payload, VoicePacketPayload::Opus(_, true));
+
+        self.frames.push((self.seq, payload));
+        self.seq += 1;
+
+        if end || self.frames.len() >= self.depth {
+            self.flush()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Returns the frames that haven't been sent yet.
+    pub fn flush(&mut self) -> Vec<(u64, VoicePacketPayload)> {
+        std::mem::replace(&mut self.frames, Vec::with_capacity(self.depth))
+    }
+
+    /// Starts numbering frames from 0 again, for a new audio stream. Frames
+    /// that haven't been sent yet are dropped, so [`SendQueue::flush`]
+    /// should be called first.
+    pub fn reset(&mut self) {
+        self.frames.clear();
+        self.seq = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use mumble_protocol::voice::VoicePacketPayload;
+
+    use super::SendQueue;
+
+    fn frame(end: bool) -> VoicePacketPayload {
+        VoicePacketPayload::Opus(Bytes::from_static(&[0]), end)
+    }
+
+    fn seqs(frames: Vec<(u64, VoicePacketPayload)>) -> Vec<u64> {
+        frames.into_iter().map(|(seq, _)| seq).collect()
+    }
+
+    #[test]
+    fn test_contiguous() {
+        let mut queue = SendQueue::new(3);
+        let mut sent = Vec::new();
+
+        for _ in 0..7 {
+            let burst = seqs(queue.push(frame(false)));
+            assert!(burst.is_empty() || burst.len() == 3);
+            sent.extend(burst);
+        }
+
+        assert_eq!(vec![0, 1, 2, 3, 4, 5], sent);
+
+        // the end of the transmission doesn't wait for the burst to fill up
+        sent.extend(seqs(queue.push(frame(true))));
+        assert_eq!((0..8).collect::<Vec<_>>(), sent);
+        assert!(queue.flush().is_empty());
+    }
+
+    #[test]
+    fn test_unbuffered() {
+        let mut queue = SendQueue::new(0);
+        assert_eq!(vec![0], seqs(queue.push(frame(false))));
+        assert_eq!(vec![1], seqs(queue.push(frame(false))));
+
+        queue.reset();
+        assert_eq!(vec![0], seqs(queue.push(frame(false))));
+    }
+}
@@ -0,0 +1,55 @@
+use mumble_protocol::control::msgs;
+
+use crate::server_state::{ChannelRef, UserRef};
+
+/// The voice target which [`WhisperMode::Channel`] and [`WhisperMode::User`]
+/// set up.
+const WHISPER_TARGET: u8 = 1;
+
+/// Who hears the audio sent by the client, see
+/// [`MumbleClient::whisper_mode`](crate::MumbleClient::whisper_mode).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum WhisperMode {
+    /// Talk normally, to the channel the client is in.
+    None,
+    /// Whisper to the given channel.
+    Channel(ChannelRef),
+    /// Whisper to the given user.
+    User(UserRef),
+    /// Send audio to a voice target that was configured some other way.
+    VoiceTarget(u8),
+}
+
+impl WhisperMode {
+    /// Returns the message that sets up the voice target for this mode on
+    /// the server, if it needs one.
+    pub(crate) fn voice_target_message(&self) -> Option<msgs::VoiceTarget> {
+        let mut target = msgs::VoiceTarget_Target::new();
+
+        match self {
+            // an empty target list clears the target
+            WhisperMode::None => {}
+            WhisperMode::Channel(channel) => target.set_channel_id(channel.id()),
+            WhisperMode::User(user) => target.mut_session().push(user.session_id()),
+            WhisperMode::VoiceTarget(_) => return None,
+        }
+
+        let mut msg = msgs::VoiceTarget::new();
+        msg.set_id(WHISPER_TARGET.into());
+
+        if *self != WhisperMode::None {
+            msg.mut_targets().push(target);
+        }
+
+        Some(msg)
+    }
+
+    /// Returns the voice target audio is sent to in this mode.
+    pub(crate) fn target(&self) -> u8 {
+        match self {
+            WhisperMode::None => 0,
+            WhisperMode::Channel(_) | WhisperMode::User(_) => WHISPER_TARGET,
+            WhisperMode::VoiceTarget(target) => *target,
+        }
+    }
+}
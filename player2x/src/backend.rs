@@ -0,0 +1,128 @@
+//! The external programs the player runs, behind traits so that they can be
+//! replaced, for example by the mocks in [`mock`](crate::mock) in tests.
+
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+
+use log::warn;
+use tokio::io::{AsyncRead, DuplexStream, ReadBuf};
+use tokio::process::{Child, ChildStdout};
+
+use crate::ffmpeg::{self, FfmpegConfig, HwAccel};
+use crate::ffprobe::{self, FileInfo};
+
+/// Finds out what a media file contains.
+pub trait Prober: Send + Sync {
+    fn probe(&self, path: &Path) -> ffprobe::Result<FileInfo>;
+}
+
+/// Decodes media files to raw audio.
+pub trait Transcoder: Send + Sync {
+    /// Starts decoding the file at `path` as set up by `config`, and returns
+    /// the decoded audio in the configured output format. Decoding stops
+    /// when the returned reader is dropped.
+    fn decode(&self, path: &Path, config: FfmpegConfig) -> io::Result<Decoded>;
+}
+
+/// The output of [`Transcoder::decode`].
+pub type Decoded = Pin<Box<dyn AsyncRead + Send>>;
+
+/// Runs the real ffprobe, see [`ffprobe::ffprobe`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ffprobe;
+
+impl Prober for Ffprobe {
+    fn probe(&self, path: &Path) -> ffprobe::Result<FileInfo> {
+        ffprobe::ffprobe(path)
+    }
+}
+
+/// Runs the real ffmpeg.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ffmpeg;
+
+/// How much decoded audio is buffered between ffmpeg and the reader when
+/// ffmpeg might have to be restarted without hardware acceleration.
+const FALLBACK_BUFFER: usize = 65536;
+
+impl Transcoder for Ffmpeg {
+    fn decode(&self, path: &Path, config: FfmpegConfig) -> io::Result<Decoded> {
+        let mut child = spawn(path, &config)?;
+        let stdout = child.stdout.take().unwrap();
+
+        if !config.uses_hwaccel() {
+            return Ok(Box::pin(FfmpegOutput {
+                _child: child,
+                stdout,
+            }));
+        }
+
+        let (reader, writer) = tokio::io::duplex(FALLBACK_BUFFER);
+        let path = path.to_path_buf();
+
+        tokio::spawn(async move {
+            // if this fails, the reader was dropped or ffmpeg couldn't be
+            // started again, either way the reader sees the end of the file
+            let _ = copy_with_fallback(child, stdout, &path, config, writer).await;
+        });
+
+        Ok(Box::pin(reader))
+    }
+}
+
+fn spawn(path: &Path, config: &FfmpegConfig) -> io::Result<Child> {
+    let mut command = ffmpeg::command(path.as_os_str(), OsStr::new("-"), config);
+    command.stdout(Stdio::piped()).kill_on_drop(true);
+    command.spawn()
+}
+
+/// Copies the output of `child`, which is ffmpeg decoding `path` with
+/// hardware acceleration, to `out`. If it exits with an error before
+/// producing any output, the acceleration most likely isn't supported, so
+/// the file is decoded again without it.
+async fn copy_with_fallback(
+    mut child: Child,
+    mut stdout: ChildStdout,
+    path: &Path,
+    config: FfmpegConfig,
+    mut out: DuplexStream,
+) -> io::Result<()> {
+    let copied = tokio::io::copy(&mut stdout, &mut out).await?;
+    let status = child.wait().await?;
+
+    if status.success() || copied > 0 {
+        return Ok(());
+    }
+
+    warn!(
+        "ffmpeg failed to decode {} with hardware acceleration ({}), retrying without it",
+        path.display(),
+        status
+    );
+
+    let mut child = spawn(path, &config.hwaccel(HwAccel::None))?;
+    let mut stdout = child.stdout.take().unwrap();
+    tokio::io::copy(&mut stdout, &mut out).await?;
+
+    Ok(())
+}
+
+/// Keeps ffmpeg running for as long as its output is read.
+struct FfmpegOutput {
+    _child: Child,
+    stdout: ChildStdout,
+}
+
+impl AsyncRead for FfmpegOutput {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
@@ -17,6 +17,8 @@ pub struct FfmpegConfig {
     input_format: Format,
     output_format: Format,
     start_at: Duration,
+    seek_accurate: bool,
+    hwaccel: HwAccel,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -26,28 +28,26 @@ pub enum Format {
     Pcm16BitBe(u32),
 }
 
+/// The hardware acceleration ffmpeg uses to decode video, see
+/// [`FfmpegConfig::hwaccel`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HwAccel {
+    /// Decode on the CPU.
+    None,
+    /// NVIDIA's decoder.
+    Nvdec,
+    /// Video Acceleration API, mostly for Intel and AMD GPUs on Linux.
+    Vaapi,
+    /// Apple's framework on macOS.
+    VideoToolbox,
+}
+
 pub async fn ffpipe<'a, I, O>(input: I, output: O, config: FfmpegConfig) -> io::Result<ExitStatus>
 where
     I: TranscoderInput<'a>,
     O: TranscoderOutput<'a>,
 {
-    let mut ffmpeg = Command::new("ffmpeg");
-    ffmpeg.arg("-nostdin");
-
-    ffmpeg.arg("-ss");
-    ffmpeg.arg(format!("{}", config.start_at.as_secs()));
-
-    config.input_format.add_args(&mut ffmpeg);
-
-    ffmpeg.arg("-i");
-    ffmpeg.arg(input.to_arg());
-
-    ffmpeg.arg("-ac");
-    ffmpeg.arg(format!("{}", config.channels));
-
-    config.output_format.add_args(&mut ffmpeg);
-
-    ffmpeg.arg(output.to_arg());
+    let mut ffmpeg = command(input.to_arg(), output.to_arg(), &config);
 
     input.pre_spawn(&mut ffmpeg);
     output.pre_spawn(&mut ffmpeg);
@@ -75,6 +75,38 @@ where
     Ok(r)
 }
 
+/// Returns the ffmpeg command that reads from `input` and writes to `output`,
+/// as set up by `config`.
+pub(crate) fn command(input: &OsStr, output: &OsStr, config: &FfmpegConfig) -> Command {
+    let mut ffmpeg = Command::new("ffmpeg");
+    ffmpeg.arg("-nostdin");
+
+    // ffmpeg seeks accurately unless told otherwise
+    if config.seek_accurate {
+        ffmpeg.arg("-accurate_seek");
+    } else {
+        ffmpeg.arg("-noaccurate_seek");
+    }
+
+    ffmpeg.arg("-ss");
+    ffmpeg.arg(format!("{:.3}", config.start_at.as_secs_f64()));
+
+    config.hwaccel.add_args(&mut ffmpeg);
+    config.input_format.add_args(&mut ffmpeg);
+
+    ffmpeg.arg("-i");
+    ffmpeg.arg(input);
+
+    ffmpeg.arg("-ac");
+    ffmpeg.arg(format!("{}", config.channels));
+
+    config.output_format.add_args(&mut ffmpeg);
+
+    ffmpeg.arg(output);
+
+    ffmpeg
+}
+
 pub trait TranscoderInput<'a>: Sized {
     fn to_arg(&self) -> &OsStr;
 
@@ -143,6 +175,28 @@ impl FfmpegConfig {
         self.start_at = start_at;
         self
     }
+
+    /// Whether to decode from the keyframe before `start_at` and discard
+    /// everything up to it, instead of starting at the keyframe itself. This
+    /// gets the exact position at the cost of a slower start.
+    pub fn seek_accurate(mut self, seek_accurate: bool) -> Self {
+        self.seek_accurate = seek_accurate;
+        self
+    }
+
+    /// Decodes video with the given hardware acceleration. This only helps
+    /// with files that contain video, audio is always decoded on the CPU.
+    /// If ffmpeg fails without producing any output, which is what happens
+    /// when the acceleration isn't supported, [`Ffmpeg`](crate::backend::Ffmpeg)
+    /// decodes the file again without it.
+    pub fn hwaccel(mut self, hwaccel: HwAccel) -> Self {
+        self.hwaccel = hwaccel;
+        self
+    }
+
+    pub(crate) fn uses_hwaccel(&self) -> bool {
+        self.hwaccel != HwAccel::None
+    }
 }
 
 impl Default for FfmpegConfig {
@@ -152,6 +206,8 @@ impl Default for FfmpegConfig {
             input_format: Default::default(),
             output_format: Default::default(),
             start_at: Default::default(),
+            seek_accurate: false,
+            hwaccel: Default::default(),
         }
     }
 }
@@ -188,6 +244,25 @@ impl Default for Format {
     }
 }
 
+impl HwAccel {
+    fn add_args(&self, command: &mut Command) {
+        let name = match self {
+            HwAccel::None => return,
+            HwAccel::Nvdec => "nvdec",
+            HwAccel::Vaapi => "vaapi",
+            HwAccel::VideoToolbox => "videotoolbox",
+        };
+
+        command.args(&["-hwaccel", name]);
+    }
+}
+
+impl Default for HwAccel {
+    fn default() -> Self {
+        HwAccel::None
+    }
+}
+
 impl<'a, T> TranscoderInput<'a> for PathSource<T>
 where
     T: AsRef<Path>,
@@ -239,3 +314,43 @@ where
         connect(stdout, self.pipe).boxed()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+
+    use super::{command, FfmpegConfig, HwAccel};
+
+    fn args(config: FfmpegConfig) -> Vec<String> {
+        let command = command(OsStr::new("in.mkv"), OsStr::new("-"), &config);
+        let args = command.as_std().get_args();
+        args.map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn test_hwaccel() {
+        let accelerated = args(FfmpegConfig::default().hwaccel(HwAccel::Vaapi));
+        let hwaccel = accelerated
+            .iter()
+            .position(|arg| arg == "-hwaccel")
+            .unwrap();
+        let input = accelerated.iter().position(|arg| arg == "-i").unwrap();
+
+        // it's an input option, so it has to come before the input
+        assert_eq!("vaapi", accelerated[hwaccel + 1]);
+        assert!(hwaccel < input);
+
+        let plain = args(FfmpegConfig::default());
+        assert!(!plain.iter().any(|arg| arg == "-hwaccel"));
+    }
+
+    #[test]
+    fn test_seek_accurate() {
+        let fast = args(FfmpegConfig::default());
+        let accurate = args(FfmpegConfig::default().seek_accurate(true));
+
+        assert!(fast.iter().any(|arg| arg == "-noaccurate_seek"));
+        assert!(accurate.iter().any(|arg| arg == "-accurate_seek"));
+        assert!(!accurate.iter().any(|arg| arg == "-noaccurate_seek"));
+    }
+}
@@ -1,41 +1,52 @@
-use std::ffi::OsStr;
 use std::fmt::Debug;
 use std::io;
 use std::io::ErrorKind;
-use std::path::PathBuf;
-use std::process::Stdio;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use dasp::{Frame, Sample};
-use futures::future::BoxFuture;
-use futures::{FutureExt, Sink, SinkExt};
+use futures::{Sink, SinkExt};
 use log::debug;
 use log::error;
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
-use tokio::process::{ChildStdout, Command};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::select;
 use tokio::sync::{broadcast, oneshot, Mutex};
 use tokio::task::JoinHandle;
 
-use audiopipe::AudioSource;
+use audiopipe::{AudioSource, NodeIndex};
 
-use crate::ffmpeg::{ffpipe, FfmpegConfig, Format, PathSource, TranscoderOutput};
+use crate::backend::{Ffmpeg, Ffprobe, Prober, Transcoder};
+use crate::ffmpeg::{FfmpegConfig, Format};
 use crate::ffprobe;
 
+pub(crate) const SAMPLE_RATE: u32 = 48000;
+
 pub struct Player<W> {
     path: PathBuf,
     duration: Duration,
+    /// How many frames [`Player::preload`] decodes.
+    preload_frames: usize,
+    node: NodeIndex,
     pipe: Arc<Mutex<W>>,
+    transcoder: Arc<dyn Transcoder>,
     state: Arc<Mutex<State>>,
     sender: broadcast::Sender<PlayerEvent>,
 }
 
 struct State {
     position: Duration,
+    /// Whether playback starts at exactly `position`, see
+    /// [`FfmpegConfig::seek_accurate`].
+    seek_accurate: bool,
     playing_state: Option<PlayingState>,
     playing_tracker: Option<PlayingTracker>,
+    /// Audio decoded by [`Player::preload`], starting at `position`.
+    preload_buffer: Option<Vec<[f32; 2]>>,
+    /// How long before the end of the file [`PlayerEvent::CrossfadePoint`]
+    /// is sent.
+    crossfade: Option<Duration>,
 }
 
 struct PlayingState {
@@ -49,19 +60,40 @@ struct PlayingTracker {
 
 impl Player<AudioSource> {
     pub fn new<P: Into<PathBuf>>(path: P, pipe: AudioSource) -> Result<Self> {
+        Player::with_backend(path, pipe, &Ffprobe, Arc::new(Ffmpeg))
+    }
+
+    /// Like [`Player::new`], but probes the file with `prober` and decodes
+    /// it with `transcoder` instead of running ffprobe and ffmpeg.
+    pub fn with_backend<P: Into<PathBuf>>(
+        path: P,
+        pipe: AudioSource,
+        prober: &dyn Prober,
+        transcoder: Arc<dyn Transcoder>,
+    ) -> Result<Self> {
         let path = path.into();
-        let info = ffprobe::ffprobe(&path)?;
+        let info = prober.probe(&path)?;
+
+        if !info.has_audio() {
+            return Err(Error::NoAudioStream);
+        }
 
         let (tx, _) = broadcast::channel(20);
 
         Ok(Player {
             path,
             duration: info.duration(),
+            preload_frames: pipe.capacity(),
+            node: pipe.node(),
             pipe: Arc::new(Mutex::new(pipe)),
+            transcoder,
             state: Arc::new(Mutex::new(State {
                 position: Duration::ZERO,
+                seek_accurate: false,
                 playing_state: None,
                 playing_tracker: None,
+                preload_buffer: None,
+                crossfade: None,
             })),
             sender: tx,
         })
@@ -97,9 +129,27 @@ impl Player<AudioSource> {
         position(&*self.state.lock().await)
     }
 
+    /// Returns the node in the audio graph the player plays into.
+    pub fn node(&self) -> NodeIndex {
+        self.node
+    }
+
     pub fn event_listener(&self) -> broadcast::Receiver<PlayerEvent> {
         self.sender.subscribe()
     }
+
+    /// Sets the factor the audio is multiplied with, see
+    /// [`AudioSource::set_gain`].
+    pub async fn set_gain(&self, gain: f32) {
+        self.pipe.lock().await.set_gain(gain);
+    }
+
+    /// Makes the player send [`PlayerEvent::CrossfadePoint`] once `remaining`
+    /// is left to play, or not at all if it is `None`. Takes effect the next
+    /// time playback starts.
+    pub async fn set_crossfade_point(&self, remaining: Option<Duration>) {
+        self.state.lock().await.crossfade = remaining;
+    }
 }
 
 impl Player<AudioSource> {
@@ -113,37 +163,89 @@ impl Player<AudioSource> {
         let (tx, rx) = oneshot::channel();
 
         let pipe = self.pipe.clone();
+        let transcoder = self.transcoder.clone();
         let s = self.state.clone();
         let path = self.path.clone();
         let position = state.position;
         let sender = self.sender.clone();
 
+        // continue decoding exactly where the preloaded audio ends
+        let preloaded = state.preload_buffer.take().unwrap_or_default();
+        let decode_from = position + frames_duration(preloaded.len());
+        let seek_accurate = state.seek_accurate || !preloaded.is_empty();
+
         let now = Instant::now();
 
+        let crossfade = state
+            .crossfade
+            .map(|remaining| self.duration.saturating_sub(remaining))
+            .filter(|at| position <= *at)
+            .map(|at| {
+                let sender = sender.clone();
+
+                tokio::spawn(async move {
+                    tokio::time::sleep(at - position).await;
+
+                    let _ = sender.send(PlayerEvent::CrossfadePoint {
+                        now: Instant::now(),
+                        pos: at,
+                    });
+                })
+            });
+
         let task = tokio::spawn(async move {
             let pipe = pipe;
             let mut pipe = pipe.lock().await;
             pipe.set_running(true);
 
-            let _ = sender.send(PlayerEvent::Playing {
+            let _ = sender.send(PlayerEvent::Buffering {
                 now: Instant::now(),
                 pos: position,
             });
 
-            let r = select!(
-                result = ffpipe(
-                    PathSource::new(path),
-                    Recoder::new(&mut *pipe),
+            // only report the player as playing once ffmpeg actually
+            // delivers audio. This is sent from here and not from a separate
+            // task, so that it can't arrive after the player stopped again.
+            let (started_tx, mut started_rx) = oneshot::channel();
+            let mut started = false;
+
+            let decode = Recoder::new(&mut *pipe)
+                .prepend(preloaded)
+                .notify_started(started_tx)
+                .decode(
+                    &*transcoder,
+                    &path,
                     FfmpegConfig::default()
-                        .start_at(position)
+                        .start_at(decode_from)
+                        .seek_accurate(seek_accurate)
                         .channels(2)
-                        .output_format(Format::native_pcm(48000)),
-                ) => match result {
-                    Ok(_) => Ok(true),
-                    Err(e) => Err(e),
-                },
-                _ = rx => Ok(false),
-            );
+                        .output_format(Format::native_pcm(SAMPLE_RATE)),
+                );
+            tokio::pin!(decode);
+            let mut rx = rx;
+
+            let r = loop {
+                select! {
+                    biased;
+
+                    result = &mut started_rx, if !started => {
+                        started = true;
+
+                        if result.is_ok() {
+                            let _ = sender.send(PlayerEvent::Playing {
+                                now: Instant::now(),
+                                pos: position,
+                            });
+                        }
+                    }
+                    result = &mut decode => break result.map(|_| true),
+                    _ = &mut rx => break Ok(false),
+                }
+            };
+
+            if let Some(crossfade) = crossfade {
+                crossfade.abort();
+            }
 
             let mut state = s.lock().await;
             let playing_state = state.playing_state.take().unwrap();
@@ -160,6 +262,11 @@ impl Player<AudioSource> {
                 }
                 Err(e) => {
                     error!("ffmpeg error: {}", e);
+                    let _ = sender.send(PlayerEvent::Error {
+                        now: Instant::now(),
+                        pos: state.position,
+                        message: e.to_string().into(),
+                    });
                     let _ = sender.send(PlayerEvent::Paused {
                         now,
                         pos: state.position,
@@ -173,17 +280,77 @@ impl Player<AudioSource> {
         state.playing_tracker = Some(PlayingTracker { task, tx });
     }
 
-    pub async fn seek(&mut self, pos: Duration) {
-        if self.is_playing().await {
+    /// Decodes the first buffer of audio from the current position ahead of
+    /// time. The next call to [`Player::play`] outputs it right away while
+    /// ffmpeg is still starting up, instead of staying silent until then.
+    pub async fn preload(&self) -> io::Result<()> {
+        let state = self.state.lock().await;
+
+        if state.playing_state.is_some() || state.preload_buffer.is_some() {
+            return Ok(());
+        }
+
+        let position = state.position;
+        let seek_accurate = state.seek_accurate;
+        drop(state);
+
+        let mut buffer = Vec::with_capacity(self.preload_frames);
+
+        // ffmpeg is stopped once enough frames have been read
+        Recoder::new(&mut buffer)
+            .limit(self.preload_frames)
+            .decode(
+                &*self.transcoder,
+                &self.path,
+                FfmpegConfig::default()
+                    .start_at(position)
+                    .seek_accurate(seek_accurate)
+                    .channels(2)
+                    .output_format(Format::native_pcm(SAMPLE_RATE)),
+            )
+            .await?;
+
+        let mut state = self.state.lock().await;
+
+        // don't keep it if the player was started or moved in the meantime
+        if state.playing_state.is_none() && state.position == position {
+            debug!(
+                "preloaded {} frames of {}",
+                buffer.len(),
+                self.path.display()
+            );
+            state.preload_buffer = Some(buffer);
+        }
+
+        Ok(())
+    }
+
+    /// Moves the playback position to `pos`. If `accurate` is set, playback
+    /// continues from exactly that position instead of the closest keyframe,
+    /// which takes longer to start.
+    pub async fn seek(&mut self, pos: Duration, accurate: bool) {
+        let playing = self.is_playing().await;
+
+        if playing {
             self.pause().await;
-            self.state.lock().await.position = pos.clamp(Duration::ZERO, self.duration);
+        }
+
+        let mut state = self.state.lock().await;
+        state.position = pos.clamp(Duration::ZERO, self.duration);
+        state.seek_accurate = accurate;
+        state.preload_buffer = None;
+        drop(state);
+
+        if playing {
             self.play().await;
-        } else {
-            self.state.lock().await.position = pos.clamp(Duration::ZERO, self.duration);
         }
     }
 }
 
+fn frames_duration(frames: usize) -> Duration {
+    Duration::from_secs_f64(frames as f64 / SAMPLE_RATE as f64)
+}
+
 fn position(state: &State) -> Duration {
     match &state.playing_state {
         None => state.position,
@@ -199,10 +366,17 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 pub enum Error {
     #[error("ffprobe error: {0}")]
     Ffprobe(#[from] ffprobe::Error),
+    #[error("file has no audio stream")]
+    NoAudioStream,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum PlayerEvent {
+    /// Playback was requested, but no audio has been decoded yet.
+    Buffering {
+        now: Instant,
+        pos: Duration,
+    },
     Playing {
         now: Instant,
         pos: Duration,
@@ -212,58 +386,273 @@ pub enum PlayerEvent {
         pos: Duration,
         stopped: bool,
     },
+    /// Only the time set with [`Player::set_crossfade_point`] is left to
+    /// play, so the next track should get ready.
+    CrossfadePoint {
+        now: Instant,
+        pos: Duration,
+    },
+    /// ffmpeg failed while playing. This is followed by a `Paused` event.
+    Error {
+        now: Instant,
+        pos: Duration,
+        message: Arc<str>,
+    },
 }
 
 struct Recoder<T> {
     inner: T,
+    started: Option<oneshot::Sender<()>>,
+    preloaded: Vec<[f32; 2]>,
+    limit: Option<usize>,
 }
 
 impl<T> Recoder<T> {
     pub fn new(inner: T) -> Self {
-        Recoder { inner }
+        Recoder {
+            inner,
+            started: None,
+            preloaded: Vec::new(),
+            limit: None,
+        }
+    }
+
+    /// Sets a channel to be notified when the first frame is received.
+    pub fn notify_started(mut self, tx: oneshot::Sender<()>) -> Self {
+        self.started = Some(tx);
+        self
+    }
+
+    /// Sets frames to send before the ones coming from ffmpeg.
+    pub fn prepend(mut self, frames: Vec<[f32; 2]>) -> Self {
+        self.preloaded = frames;
+        self
+    }
+
+    /// Stops reading from ffmpeg after `frames` frames.
+    pub fn limit(mut self, frames: usize) -> Self {
+        self.limit = Some(frames);
+        self
     }
 }
 
-impl<'a, T> TranscoderOutput<'a> for Recoder<T>
+impl<T> Recoder<T>
 where
-    T: Sink<[f32; 2]> + Unpin + Send + 'a,
+    T: Sink<[f32; 2]> + Unpin,
     T::Error: Debug,
 {
-    fn to_arg(&self) -> &OsStr {
-        OsStr::new("-")
+    async fn send(&mut self, frame: [f32; 2]) -> io::Result<()> {
+        if let Some(tx) = self.started.take() {
+            let _ = tx.send(());
+        }
+
+        self.inner
+            .send(frame)
+            .await
+            .map_err(|e| io::Error::new(ErrorKind::Other, format!("sink error: {:?}", e)))
     }
 
-    fn pre_spawn(&self, command: &mut Command) {
-        command.stdout(Stdio::piped());
+    /// Decodes the file at `path` with `transcoder` and sends the frames to
+    /// the sink.
+    async fn decode(
+        self,
+        transcoder: &dyn Transcoder,
+        path: &Path,
+        config: FfmpegConfig,
+    ) -> io::Result<()> {
+        let pcm = transcoder.decode(path, config)?;
+        self.copy_from(pcm).await
     }
 
-    fn handle_stdout(mut self, mut stdout: ChildStdout) -> BoxFuture<'a, io::Result<()>> {
-        async move {
-            loop {
-                let mut bytes = [0; 4];
+    /// Reads native endian 16 bit stereo PCM from `input` and sends it to the
+    /// sink, after the prepended frames.
+    async fn copy_from<R>(mut self, mut input: R) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        for frame in std::mem::take(&mut self.preloaded) {
+            self.send(frame).await?;
+        }
+
+        loop {
+            match &mut self.limit {
+                Some(0) => break Ok(()),
+                Some(limit) => *limit -= 1,
+                None => {}
+            }
 
-                match stdout.read_exact(&mut bytes).await {
-                    Ok(_) => {}
-                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => break Ok(()),
-                    Err(e) => break Err(e),
-                }
+            let mut bytes = [0; 4];
 
-                let data = [
-                    i16::from_ne_bytes([bytes[0], bytes[1]]),
-                    i16::from_ne_bytes([bytes[2], bytes[3]]),
-                ];
-
-                match self.inner.send(Frame::map(data, Sample::to_sample)).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        break Err(io::Error::new(
-                            ErrorKind::Other,
-                            format!("sink error: {:?}", e),
-                        ))
-                    }
-                }
+            match input.read_exact(&mut bytes).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break Ok(()),
+                Err(e) => break Err(e),
             }
+
+            let data = [
+                i16::from_ne_bytes([bytes[0], bytes[1]]),
+                i16::from_ne_bytes([bytes[2], bytes[3]]),
+            ];
+
+            if let Err(e) = self.send(Frame::map(data, Sample::to_sample)).await {
+                break Err(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use audiopipe::test_util::TestSink;
+    use audiopipe::{AudioSource, Core};
+
+    use super::{Error, Player, PlayerEvent, Recoder, Result, SAMPLE_RATE};
+    use crate::backend::Ffmpeg;
+    use crate::ffmpeg::{FfmpegConfig, Format};
+    use crate::mock::{MockProber, MockTranscoder};
+
+    fn player(prober: MockProber, transcoder: MockTranscoder) -> Result<Player<AudioSource>> {
+        let core = Core::new(SAMPLE_RATE);
+        Player::with_backend("mock.wav", core.add_input(), &prober, Arc::new(transcoder))
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let length = Duration::from_secs(3);
+
+        assert!(matches!(
+            player(MockProber::without_audio(length), MockTranscoder::failing()),
+            Err(Error::NoAudioStream)
+        ));
+        assert!(matches!(
+            player(MockProber::failing(), MockTranscoder::failing()),
+            Err(Error::Ffprobe(_))
+        ));
+
+        let player = player(MockProber::new(length), MockTranscoder::failing()).unwrap();
+        assert_eq!(length, player.length());
+    }
+
+    #[tokio::test]
+    async fn test_play_to_end() {
+        let player = player(
+            MockProber::new(Duration::from_millis(1)),
+            MockTranscoder::new(vec![[0, 0]; 10]),
+        )
+        .unwrap();
+        let mut events = player.event_listener();
+
+        player.play().await;
+
+        assert!(matches!(
+            events.recv().await,
+            Ok(PlayerEvent::Buffering { .. })
+        ));
+        assert!(matches!(
+            events.recv().await,
+            Ok(PlayerEvent::Playing { .. })
+        ));
+        assert!(matches!(
+            events.recv().await,
+            Ok(PlayerEvent::Paused { stopped: true, .. })
+        ));
+        assert!(!player.is_playing().await);
+    }
+
+    #[tokio::test]
+    async fn test_play_error() {
+        let player = player(
+            MockProber::new(Duration::from_secs(3)),
+            MockTranscoder::failing(),
+        )
+        .unwrap();
+        let mut events = player.event_listener();
+
+        player.play().await;
+
+        assert!(matches!(
+            events.recv().await,
+            Ok(PlayerEvent::Buffering { .. })
+        ));
+        assert!(matches!(events.recv().await, Ok(PlayerEvent::Error { .. })));
+        assert!(matches!(
+            events.recv().await,
+            Ok(PlayerEvent::Paused { stopped: false, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_preload() {
+        let player = player(
+            MockProber::new(Duration::from_secs(1)),
+            MockTranscoder::new(vec![[16384, -16384]; 10]),
+        )
+        .unwrap();
+
+        player.preload().await.unwrap();
+
+        let state = player.state.lock().await;
+        assert_eq!(Some(vec![[0.5, -0.5]; 10]), state.preload_buffer);
+    }
+
+    /// Writes `frames` to `path` as a 16 bit stereo WAV file.
+    fn write_wav(path: &Path, frames: &[[i16; 2]]) {
+        let data_len = frames.len() as u32 * 4;
+        let mut wav = Vec::new();
+
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&2u16.to_le_bytes()); // channels
+        wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+        wav.extend_from_slice(&(SAMPLE_RATE * 4).to_le_bytes()); // bytes per second
+        wav.extend_from_slice(&4u16.to_le_bytes()); // bytes per frame
+        wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+
+        for [l, r] in frames {
+            wav.extend_from_slice(&l.to_le_bytes());
+            wav.extend_from_slice(&r.to_le_bytes());
         }
-        .boxed()
+
+        fs::write(path, wav).unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs ffmpeg"]
+    async fn test_decode() {
+        let path = std::env::temp_dir().join(format!("player2x-test-{}.wav", std::process::id()));
+        let frames: Vec<_> = (0..4800).map(|i| [i as i16, -(i as i16)]).collect();
+        write_wav(&path, &frames);
+
+        let sink = TestSink::new();
+
+        let result = Recoder::new(sink.clone())
+            .prepend(vec![[1.0, 1.0]])
+            .decode(
+                &Ffmpeg,
+                &path,
+                FfmpegConfig::default()
+                    .channels(2)
+                    .output_format(Format::native_pcm(SAMPLE_RATE)),
+            )
+            .await;
+
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        let out = sink.frames();
+        assert_eq!(1 + frames.len(), out.len());
+        assert_eq!([1.0, 1.0], out[0]);
+        assert_eq!([0.0, 0.0], out[1]);
+        assert_eq!([4799.0 / 32768.0, -4799.0 / 32768.0], out[4800]);
     }
 }
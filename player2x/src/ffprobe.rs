@@ -49,6 +49,35 @@ pub enum Error {
 #[derive(Deserialize, Debug, Clone)]
 pub struct FileInfo {
     format: Format,
+    #[serde(default)]
+    streams: Vec<Stream>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Stream {
+    index: u32,
+    codec_type: CodecType,
+    codec_name: Option<String>,
+    #[serde(default)]
+    disposition: Disposition,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum CodecType {
+    Audio,
+    Video,
+    Subtitle,
+    Data,
+    Attachment,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Disposition {
+    #[serde(default)]
+    attached_pic: u8,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -58,7 +87,7 @@ pub struct Format {
     tags: Tags,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct Tags {
     track: Option<StrWrapped<u32>>,
     artist: Option<String>,
@@ -72,6 +101,30 @@ pub struct Tags {
 }
 
 impl FileInfo {
+    /// Returns the info of a file of the given length, with a single audio
+    /// stream if `audio` is set.
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) fn synthetic(duration: Duration, audio: bool) -> Self {
+        let streams = audio
+            .then(|| Stream {
+                index: 0,
+                codec_type: CodecType::Audio,
+                codec_name: None,
+                disposition: Default::default(),
+            })
+            .into_iter()
+            .collect();
+
+        FileInfo {
+            format: Format {
+                duration: duration.as_secs_f32().into(),
+                bit_rate: None,
+                tags: Default::default(),
+            },
+            streams,
+        }
+    }
+
     pub fn duration(&self) -> Duration {
         Duration::from_secs_f32(*self.format.duration)
     }
@@ -91,6 +144,50 @@ impl FileInfo {
     pub fn track_index(&self) -> Option<u32> {
         self.format.tags.track.as_deref().cloned()
     }
+
+    pub fn streams(&self) -> &[Stream] {
+        &self.streams
+    }
+
+    pub fn audio_streams(&self) -> impl Iterator<Item = &Stream> {
+        self.streams
+            .iter()
+            .filter(|s| s.codec_type == CodecType::Audio)
+    }
+
+    /// Returns the video streams in the file. Embedded cover art, which
+    /// ffprobe also reports as a video stream, is not included.
+    pub fn video_streams(&self) -> impl Iterator<Item = &Stream> {
+        self.streams
+            .iter()
+            .filter(|s| s.codec_type == CodecType::Video && !s.is_attached_pic())
+    }
+
+    pub fn has_audio(&self) -> bool {
+        self.audio_streams().next().is_some()
+    }
+
+    pub fn has_video(&self) -> bool {
+        self.video_streams().next().is_some()
+    }
+}
+
+impl Stream {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn codec_type(&self) -> CodecType {
+        self.codec_type
+    }
+
+    pub fn codec_name(&self) -> Option<&str> {
+        self.codec_name.as_deref()
+    }
+
+    pub fn is_attached_pic(&self) -> bool {
+        self.disposition.attached_pic != 0
+    }
 }
 
 mod str_wrapped {
@@ -109,6 +206,12 @@ mod str_wrapped {
         parsed: T,
     }
 
+    impl<T> From<T> for StrWrapped<T> {
+        fn from(parsed: T) -> Self {
+            StrWrapped { parsed }
+        }
+    }
+
     impl<T> Deref for StrWrapped<T> {
         type Target = T;
 
@@ -149,3 +252,27 @@ mod str_wrapped {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::FileInfo;
+
+    #[test]
+    fn test_streams() {
+        let json = r#"{
+            "streams": [
+                { "index": 0, "codec_type": "audio", "codec_name": "flac" },
+                { "index": 1, "codec_type": "video", "codec_name": "mjpeg", "disposition": { "attached_pic": 1 } }
+            ],
+            "format": { "duration": "12.5", "tags": {} }
+        }"#;
+
+        let fi: FileInfo = serde_json::from_str(json).unwrap();
+        assert!(fi.has_audio());
+        assert!(!fi.has_video());
+        assert_eq!(
+            Some("flac"),
+            fi.audio_streams().next().unwrap().codec_name()
+        );
+    }
+}
@@ -2,9 +2,12 @@ use std::io;
 
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+pub mod backend;
 pub mod ffmpeg;
 pub mod ffplayer;
 pub mod ffprobe;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
 
 pub async fn connect<I, O>(mut input: I, mut output: O) -> io::Result<()>
 where
@@ -0,0 +1,91 @@
+//! [`Prober`] and [`Transcoder`] implementations that don't run anything,
+//! for testing code that uses a [`Player`](crate::ffplayer::Player).
+
+use std::io::{self, Cursor, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::backend::{Decoded, Prober, Transcoder};
+use crate::ffmpeg::FfmpegConfig;
+use crate::ffplayer::SAMPLE_RATE;
+use crate::ffprobe::{self, FileInfo};
+
+/// Reports the same file info for every file.
+#[derive(Debug, Clone)]
+pub struct MockProber {
+    info: Option<FileInfo>,
+}
+
+impl MockProber {
+    /// Reports files with a single audio stream of the given length.
+    pub fn new(duration: Duration) -> Self {
+        MockProber {
+            info: Some(FileInfo::synthetic(duration, true)),
+        }
+    }
+
+    /// Reports files without an audio stream.
+    pub fn without_audio(duration: Duration) -> Self {
+        MockProber {
+            info: Some(FileInfo::synthetic(duration, false)),
+        }
+    }
+
+    /// Fails like ffprobe does for files that don't exist.
+    pub fn failing() -> Self {
+        MockProber { info: None }
+    }
+}
+
+impl Prober for MockProber {
+    fn probe(&self, _path: &Path) -> ffprobe::Result<FileInfo> {
+        match &self.info {
+            None => Err(io::Error::new(ErrorKind::NotFound, "mock file not found").into()),
+            Some(info) => Ok(info.clone()),
+        }
+    }
+}
+
+/// Outputs the same audio for every file, in native endian 16 bit PCM. The
+/// position to start at in the config is ignored.
+#[derive(Debug, Clone)]
+pub struct MockTranscoder {
+    frames: Option<Arc<[[i16; 2]]>>,
+}
+
+impl MockTranscoder {
+    pub fn new(frames: Vec<[i16; 2]>) -> Self {
+        MockTranscoder {
+            frames: Some(frames.into()),
+        }
+    }
+
+    /// Outputs silence of the given length.
+    pub fn silence(duration: Duration) -> Self {
+        let len = (duration.as_secs_f64() * SAMPLE_RATE as f64) as usize;
+        MockTranscoder::new(vec![[0, 0]; len])
+    }
+
+    /// Fails like ffmpeg does when it can't be started.
+    pub fn failing() -> Self {
+        MockTranscoder { frames: None }
+    }
+}
+
+impl Transcoder for MockTranscoder {
+    fn decode(&self, _path: &Path, _config: FfmpegConfig) -> io::Result<Decoded> {
+        let frames = match &self.frames {
+            None => return Err(io::Error::new(ErrorKind::Other, "mock ffmpeg failed")),
+            Some(frames) => frames,
+        };
+
+        let bytes: Vec<u8> = frames
+            .iter()
+            .flat_map(|[l, r]| [l.to_ne_bytes(), r.to_ne_bytes()])
+            .flatten()
+            .collect();
+
+        Ok(Box::pin(Cursor::new(bytes)))
+    }
+}